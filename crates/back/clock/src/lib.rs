@@ -0,0 +1,316 @@
+//! Cross-sensor clock synchronization
+//!
+//! Every driver stamps its packets on the host when it reads them, which adds USB/serial latency
+//! and jitter. Devices that keep their own clock (the Teensy's `dt` counter, the Structure
+//! Sensor's frame timestamps) let us do better: each `Clock` fits a line mapping device time onto
+//! the host's monotonic clock (an offset plus a drift), and corrects each timestamp using it.
+//! Devices without a clock just get the host time.
+//!
+//! Each packet records both timestamps as a `Stamp`. At the end of an episode, `write_report`
+//! saves the final estimates (`sync.json`) so that offline tools can redo the correction with the
+//! whole episode's data, and convert between the host's monotonic clock and wall-clock time.
+//!
+//! The fit for a device is
+//!
+//! ```text
+//! host_ns = host_origin_ns + offset_ns + rate * (device_ns - device_origin_ns)
+//! ```
+
+#[macro_use] extern crate scribe;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate time;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Version of the `sync.json` layout
+pub const VERSION: u32 = 1;
+
+/// Number of readings before the drift estimate is trusted (until then, only the offset is used)
+const MIN_SAMPLES: u64 = 100;
+
+/// Read the host's monotonic clock (in ns)
+pub fn now() -> u64 {
+    time::precise_time_ns()
+}
+
+/// Raw and corrected timestamps of one reading, both on the host's monotonic clock (in ns)
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stamp {
+    /// When the host read the packet
+    pub raw: u64,
+    /// When the device says the packet happened
+    pub corrected: u64,
+}
+describe!(Stamp { raw, corrected });
+
+impl Stamp {
+    /// Stamp a reading taken right now, without any correction
+    pub fn now() -> Stamp {
+        let now = now();
+        Stamp { raw: now, corrected: now }
+    }
+}
+
+/// Where a device's timestamps come from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Source {
+    /// Only the host clock (no correction possible)
+    Host,
+    /// A clock on the device, fitted against the host clock
+    Device(String),
+    /// The wall clock of another machine, measured over the network
+    Remote(String),
+}
+
+/// Online least-squares fit of host time against device time
+#[derive(Clone, Debug)]
+struct Fit {
+    /// First reading (all the sums are relative to it, to keep the floats small)
+    origin: Option<(u64, u64)>,
+    n: u64,
+    mean_x: f64,
+    mean_y: f64,
+    cov_xy: f64,
+    var_x: f64,
+    residual_sq: f64,
+    residual_max: f64,
+}
+
+impl Fit {
+    fn new() -> Fit {
+        Fit {
+            origin: None,
+            n: 0,
+            mean_x: 0.0,
+            mean_y: 0.0,
+            cov_xy: 0.0,
+            var_x: 0.0,
+            residual_sq: 0.0,
+            residual_max: 0.0,
+        }
+    }
+
+    /// Estimated (offset, rate), relative to the origin
+    fn line(&self) -> (f64, f64) {
+        let rate = if self.n >= MIN_SAMPLES && self.var_x > 0.0 {
+            self.cov_xy / self.var_x
+        } else {
+            1.0
+        };
+        (self.mean_y - rate * self.mean_x, rate)
+    }
+
+    /// Map a device time onto the host clock using the current estimate
+    fn predict(&self, device: u64) -> Option<u64> {
+        self.origin.map(|(device0, host0)| {
+            let (offset, rate) = self.line();
+            let host = host0 as f64 + offset + rate * (device as f64 - device0 as f64);
+            if host < 0.0 { 0 } else { host.round() as u64 }
+        })
+    }
+
+    fn add(&mut self, device: u64, host: u64) {
+        // a device clock going backwards means it was reset, so start over
+        if self.origin.map_or(false, |(device0, _)| device < device0) {
+            *self = Fit::new();
+        }
+
+        if let Some(predicted) = self.predict(device) {
+            let residual = (host as f64 - predicted as f64).abs();
+            self.residual_sq += residual * residual;
+            if residual > self.residual_max {
+                self.residual_max = residual;
+            }
+        }
+
+        let (device0, host0) = *self.origin.get_or_insert((device, host));
+        let x = (device - device0) as f64;
+        let y = host as f64 - host0 as f64;
+
+        self.n += 1;
+        let dx = x - self.mean_x;
+        self.mean_x += dx / self.n as f64;
+        self.mean_y += (y - self.mean_y) / self.n as f64;
+        self.cov_xy += dx * (y - self.mean_y);
+        self.var_x += dx * (x - self.mean_x);
+    }
+}
+
+/// State of one device's clock
+#[derive(Clone, Debug)]
+struct Estimate {
+    source: Source,
+    readings: u64,
+    fit: Fit,
+    /// For remote clocks: (offset from the host's wall clock, round trip) in ns
+    remote: Option<(i64, u64)>,
+}
+
+/// Handle used by a driver to timestamp its readings
+#[derive(Clone)]
+pub struct Clock {
+    estimate: Arc<Mutex<Estimate>>,
+}
+
+lazy_static! {
+    static ref CLOCKS: Mutex<BTreeMap<String, Arc<Mutex<Estimate>>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Start keeping track of a device's clock (replacing any previous estimate under the same name,
+/// since a restarted device starts counting again).
+pub fn register<S: Into<String>>(name: S, source: Source) -> Clock {
+    let estimate = Arc::new(Mutex::new(Estimate {
+        source: source,
+        readings: 0,
+        fit: Fit::new(),
+        remote: None,
+    }));
+    CLOCKS.lock().unwrap().insert(name.into(), estimate.clone());
+    Clock { estimate: estimate }
+}
+
+/// Forget all readings (called at the start of each episode). The clocks stay registered, and
+/// remote offsets are kept since they are only measured once.
+pub fn reset() {
+    for estimate in CLOCKS.lock().unwrap().values() {
+        let mut estimate = estimate.lock().unwrap();
+        estimate.readings = 0;
+        estimate.fit = Fit::new();
+    }
+}
+
+impl Clock {
+    /// Timestamp a reading that the host took at `raw` (see `now()`). If the device has a clock,
+    /// `device` is its reading (in ns) for the same packet.
+    pub fn stamp(&self, raw: u64, device: Option<u64>) -> Stamp {
+        let mut estimate = self.estimate.lock().unwrap();
+        estimate.readings += 1;
+        match device {
+            Some(device) => {
+                estimate.fit.add(device, raw);
+                Stamp { raw: raw, corrected: estimate.fit.predict(device).unwrap_or(raw) }
+            },
+            None => Stamp { raw: raw, corrected: raw },
+        }
+    }
+}
+
+/// Host wall-clock time in ns since the UNIX epoch
+fn unix_now() -> i64 {
+    let now = time::get_time();
+    now.sec * 1_000_000_000 + now.nsec as i64
+}
+
+/// Record the offset of another machine's wall clock, NTP-style.
+///
+/// `query` asks the other machine for its wall-clock time (in ns since the UNIX epoch). The
+/// sample with the shortest round trip wins, assuming the reply was made halfway through.
+pub fn register_remote<S: Into<String>, F: FnMut() -> Option<i64>>(name: S, what: &str, tries: usize, mut query: F) -> Clock {
+    let clock = register(name, Source::Remote(what.into()));
+
+    let mut best: Option<(i64, u64)> = None;
+    for _ in 0..tries {
+        let sent = unix_now();
+        let remote = match query() {
+            Some(remote) => remote,
+            None => continue,
+        };
+        let received = unix_now();
+
+        let rtt = (received - sent) as u64;
+        let offset = remote - (sent + received) / 2;
+        if best.map_or(true, |(_, best_rtt)| rtt < best_rtt) {
+            best = Some((offset, rtt));
+        }
+    }
+    clock.estimate.lock().unwrap().remote = best;
+
+    clock
+}
+
+/// Simultaneous readings of the host's monotonic and wall clocks
+#[derive(Clone, Debug, Serialize)]
+pub struct Epoch {
+    pub monotonic_ns: u64,
+    pub unix_ns: i64,
+}
+
+/// Final estimate for one device (see the module docs for how to apply it)
+#[derive(Clone, Debug, Serialize)]
+pub struct ClockReport {
+    pub source: Source,
+    /// Number of readings stamped
+    pub readings: u64,
+    /// Number of readings that had a device time
+    pub samples: u64,
+    pub device_origin_ns: Option<u64>,
+    pub host_origin_ns: Option<u64>,
+    pub offset_ns: Option<f64>,
+    pub rate: Option<f64>,
+    /// Device clock drift relative to the host, in parts per million
+    pub drift_ppm: Option<f64>,
+    /// Difference between host read times and the (running) fit
+    pub residual_rms_ns: Option<f64>,
+    pub residual_max_ns: Option<f64>,
+    /// For remote clocks: remote wall clock minus host wall clock
+    pub remote_offset_ns: Option<i64>,
+    /// For remote clocks: round trip of the measurement used
+    pub remote_rtt_ns: Option<u64>,
+}
+
+/// Contents of `sync.json`
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub version: u32,
+    /// All `Stamp`s and origins are on this clock
+    pub reference: &'static str,
+    pub epoch: Epoch,
+    pub clocks: BTreeMap<String, ClockReport>,
+}
+
+/// Summarize all registered clocks
+pub fn report() -> Report {
+    let clocks = CLOCKS.lock().unwrap().iter().map(|(name, estimate)| {
+        let estimate = estimate.lock().unwrap();
+        let fit = &estimate.fit;
+        let fitted = fit.origin.is_some();
+        let (offset, rate) = fit.line();
+        (name.clone(), ClockReport {
+            source: estimate.source.clone(),
+            readings: estimate.readings,
+            samples: fit.n,
+            device_origin_ns: fit.origin.map(|(device, _)| device),
+            host_origin_ns: fit.origin.map(|(_, host)| host),
+            offset_ns: if fitted { Some(offset) } else { None },
+            rate: if fitted { Some(rate) } else { None },
+            drift_ppm: if fitted { Some((rate - 1.0) * 1e6) } else { None },
+            residual_rms_ns: if fit.n > 1 { Some((fit.residual_sq / (fit.n - 1) as f64).sqrt()) } else { None },
+            residual_max_ns: if fit.n > 1 { Some(fit.residual_max) } else { None },
+            remote_offset_ns: estimate.remote.map(|(offset, _)| offset),
+            remote_rtt_ns: estimate.remote.map(|(_, rtt)| rtt),
+        })
+    }).collect();
+
+    Report {
+        version: VERSION,
+        reference: "host monotonic clock (ns)",
+        epoch: Epoch {
+            monotonic_ns: now(),
+            unix_ns: unix_now(),
+        },
+        clocks: clocks,
+    }
+}
+
+/// Write the report for all registered clocks (usually to `sync.json` in the episode directory).
+pub fn write_report<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let file = File::create(path)?;
+    serde_json::to_writer_pretty(file, &report()).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
@@ -0,0 +1,124 @@
+//! Service health bookkeeping shared between `go()`, the supervisor and the user interfaces
+//!
+//! `go()` updates a `Heartbeat` around every call to `Controllable::step()`. The supervisor reads
+//! it to enforce step deadlines and measure step rates, combines that with the lifecycle messages
+//! it receives (`CmdFrom::Timeout`, `Timein`, `SetupFailed` and `Panicked`), and answers
+//! `CmdFrom::Health` with a `Report` for each service.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use time;
+
+/// Step progress of a service, written by `go()` and read by the supervisor
+pub struct Heartbeat {
+    /// Number of completed steps
+    steps: AtomicUsize,
+    /// When the current step started (in `time::precise_time_ns` units), or 0 between steps
+    step_start: AtomicUsize,
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat { steps: AtomicUsize::new(0), step_start: AtomicUsize::new(0) }
+    }
+
+    /// Called by `go()` just before `step()`
+    pub fn begin_step(&self) {
+        self.step_start.store(time::precise_time_ns() as usize, Ordering::SeqCst);
+    }
+
+    /// Called by `go()` just after `step()`
+    pub fn end_step(&self) {
+        self.step_start.store(0, Ordering::SeqCst);
+        self.steps.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Forget about a step that never finished (because the service panicked)
+    pub fn clear(&self) {
+        self.step_start.store(0, Ordering::SeqCst);
+    }
+
+    /// Number of steps completed so far
+    pub fn steps(&self) -> usize {
+        self.steps.load(Ordering::SeqCst)
+    }
+
+    /// How long the step in progress has been running (None if the service is between steps)
+    pub fn stepping_for(&self) -> Option<Duration> {
+        match self.step_start.load(Ordering::SeqCst) {
+            0 => None,
+            start => {
+                let ns = (time::precise_time_ns() as usize).saturating_sub(start) as u64;
+                Some(Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32))
+            }
+        }
+    }
+}
+
+/// Health of a service, as judged by the supervisor
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum State {
+    /// Not started, or stopped on request
+    Stopped,
+    /// Inside `setup()`
+    Starting,
+    /// Stepping normally
+    Running,
+    /// Stepping well below its requested rate
+    Slow,
+    /// Missed its deadline in the given phase ("setup" or "step")
+    ///
+    /// Threads can't be killed, so the stuck instance is left behind and the service is restarted
+    /// as if it had panicked. If `services.restart_hung` is off, this is only reported, and the
+    /// state clears if the service eventually gets going again.
+    Hung(&'static str),
+    /// Panicked, and will be restarted after a delay (this is restart number N in a row)
+    Restarting(u32),
+    /// Panicked too many times in a row, and won't be restarted automatically
+    GaveUp,
+}
+
+impl State {
+    /// One-word name for the state (used by the web interface)
+    pub fn key(&self) -> &'static str {
+        match *self {
+            State::Stopped       => "stopped",
+            State::Starting      => "starting",
+            State::Running       => "running",
+            State::Slow          => "slow",
+            State::Hung(_)       => "hung",
+            State::Restarting(_) => "restarting",
+            State::GaveUp        => "gaveup",
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            State::Hung(phase)     => write!(f, "hung ({})", phase),
+            State::Restarting(n)   => write!(f, "restarting (attempt {})", n),
+            State::GaveUp          => write!(f, "gave up"),
+            ref state              => write!(f, "{}", state.key()),
+        }
+    }
+}
+
+/// One row of the supervisor's health table
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub name: &'static str,
+    pub state: State,
+    /// Restarts since the service last ran stably
+    pub restarts: u32,
+    /// Measured step rate (Hz), if the service has been running long enough to tell
+    pub rate: Option<f64>,
+    /// Requested step rate (Hz), for services with a fixed period
+    pub expected_rate: Option<f64>,
+    /// Reason for the most recent panic
+    pub last_panic: Option<String>,
+    /// Counters the service reported about its latest run (see `CmdFrom::Stats`)
+    pub stats: BTreeMap<String, u64>,
+}
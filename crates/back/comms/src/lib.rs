@@ -6,11 +6,24 @@
 extern crate time;
 extern crate libc;
 extern crate hprof;
-
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, SendError};
-use std::thread;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError, SendError};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{fmt, panic, thread};
 use std::{mem, ptr};
 use libc::{nanosleep, timespec};
+use serde::{Serialize, Serializer};
+use utils::RecordingContext;
+use utils::config::{self, Config};
+
+pub mod health;
+use health::Heartbeat;
 
 mod errors {
     use std::sync::mpsc::SendError;
@@ -29,6 +42,11 @@ mod errors {
                         "[unknown]".to_owned()
                     })
             }
+
+            LifecycleTimeout(service: String, action: &'static str) {
+                description("timed out waiting for service")
+                display("timed out waiting for {} to {}", service, action)
+            }
         }
 
         foreign_links {
@@ -41,32 +59,66 @@ pub use errors::*;
 /// Commands sent from the supervisor thread to services
 #[derive(Clone)]
 pub enum CmdTo {
-    /// Start the service
-    Start(Option<String>),
+    /// Start the service, recording into the given context
+    ///
+    /// Progress (`Starting`, then `Running` or `Failed`) is reported back on the Sender.
+    Start(Option<String>, RecordingContext, Sender<Status>),
 
     /// Stop the service (but keep the thread running)
-    Stop,
+    ///
+    /// `Stopped` is reported back on the Sender once teardown() has finished.
+    Stop(Sender<Status>),
 
     /// Stop the service and kill the thread
     Quit,
 
-    /// Some data
-    // TODO enum instead of str, somehow each service defines its own variants
-    Data(String),
+    /// A command for the service, to be parsed into its `Controllable::Command`
+    ///
+    /// Whether it was accepted is reported back on the Sender.
+    Data(String, Sender<Reply>),
+}
+
+/// Outcome of delivering a command to a service (the error explains why it was rejected)
+pub type Reply = ::std::result::Result<(), String>;
+
+/// Lifecycle transitions reported in response to `CmdTo::Start` and `CmdTo::Stop`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Status {
+    /// setup() has been called
+    Starting,
+    /// setup() finished and the service is stepping
+    Running,
+    /// The service could not be started (setup() panicked, or there is no such service)
+    Failed(String),
+    /// teardown() finished (or the service wasn't running)
+    Stopped,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Status::Starting           => write!(f, "starting"),
+            Status::Running            => write!(f, "running"),
+            Status::Failed(ref reason) => write!(f, "failed: {}", reason),
+            Status::Stopped            => write!(f, "stopped"),
+        }
+    }
 }
 
 /// Commands sent from services up to the supervisor thread
 #[derive(Clone)]
 pub enum CmdFrom {
-    /// Start another service
-    Start(String, Option<String>, Sender<bool>),
+    /// Start another service (see `CmdTo::Start`)
+    Start(String, Option<String>, RecordingContext, Sender<Status>),
 
-    /// Stop another service
-    Stop(String, Sender<bool>),
+    /// Stop another service (see `CmdTo::Stop`)
+    Stop(String, Sender<Status>),
 
-    /// Some data
-    // TODO enum instead of str, somehow each service defines its own variants
-    Data(String),
+    /// Deliver a command to another service (see `CmdTo::Data`)
+    Send(String, String, Sender<Reply>),
+
+    /// Set a variable in the supervisor
+    Set(String, String),
 
     /// Shut down everything
     Quit,
@@ -77,21 +129,58 @@ pub enum CmdFrom {
     /// Shut down or reboot the computer
     Power(Power),
 
-    /// Schedule the sending thread to be killed in x ms
+    /// The sending thread is starting setup(), which should finish within x ms
     Timeout {
         thread: &'static str,
         ms: u64,
     },
-    /// Cancel a killing scheduled with Timeout
+    /// The sending thread finished the setup() announced with Timeout
     Timein {
         thread: &'static str,
     },
 
+    /// The sending thread's setup() returned an error (the thread lives on, waiting to be started again)
+    SetupFailed {
+        thread: &'static str,
+        reason: String,
+    },
+
     /// Service thread panicked (obviously, this would only be sent from the middle-manager thread)
     Panicked {
         thread: &'static str,
         panic_reason: String,
     },
+
+    /// Get the supervisor's health table
+    Health(Sender<Vec<health::Report>>),
+
+    /// The sending service identified its sensor (service name, serial number)
+    Serial(String, String),
+
+    /// Get the serial numbers reported so far, by service (see `Serial`)
+    Serials(Sender<BTreeMap<String, String>>),
+
+    /// The sending service's counters (e.g. dropped packets), replacing any it reported before
+    ///
+    /// They show up in the health table (see `health::Report::stats`).
+    Stats(String, BTreeMap<String, u64>),
+
+    /// The end-effector in use changed (see `teensy::park`)
+    ///
+    /// Both are names of `teensy::ParkState` variants (`from` is `None` if the parking lot could
+    /// not be read before).
+    EndEffectorChanged {
+        from: Option<String>,
+        to: String,
+    },
+
+    /// The scribe thread stopped writing a stream (see `scribe::report_to`)
+    Scribe {
+        stream: String,
+        error: String,
+        /// The stream was stopped because the disk is nearly full
+        disk_full: bool,
+    },
 }
 
 #[derive(Clone)]
@@ -128,19 +217,36 @@ guilty!{
         const NAME: &'static str;
         /// Desired blocking mode (see documentation for the Block enum)
         const BLOCK: Block;
+        /// Longest setup() may take before the supervisor considers the service hung (ms)
+        const SETUP_DEADLINE_MS: u64 = 5_000;
+        /// Longest a single step() may take before the supervisor considers the service hung (ms)
+        ///
+        /// `None` for services whose step() legitimately blocks for a long time (e.g. waiting for
+        /// user input).
+        const STEP_DEADLINE_MS: Option<u64> = Some(2_000);
+
+        /// Commands understood by the service
+        ///
+        /// These arrive as strings (from the CLI, web interface, flows and other services) and
+        /// are parsed by `go()`. Parse errors are returned to the sender.
+        type Command: FromStr<Err=String> + Serialize + Send;
 
         /// Setup the service.
         ///
         /// Should initialize any necessary libraries and devices. May be called more than once, but
-        /// teardown() will be called in between.
-        fn setup(Sender<CmdFrom>, Option<String>) -> Self;
+        /// teardown() will be called in between. Anything recorded goes into the given context. Device
+        /// paths and other rig-specific settings come from the configuration.
+        ///
+        /// Errors (e.g. a missing device or replay file) are reported to whoever asked for the start
+        /// and to the supervisor, which treats them like a crash.
+        fn setup(Sender<CmdFrom>, Option<String>, RecordingContext, &Config) -> ::std::result::Result<Self, String>;
 
         /// Run one "step".
         ///
         /// In the case of a device driver, this corresponds to gathering one frame or sample of data.
         ///
         /// Blocking mode controls the delay between steps.
-        fn step(&mut self, data: Option<String>);
+        fn step(&mut self, cmd: Option<Self::Command>);
 
         /// Tear down the service.
         ///
@@ -166,6 +272,13 @@ guilty!{
 ///     - comms::Result\<T> = the response received (or not) from the main thread
 #[macro_export]
 macro_rules! rpc {
+    ($tx:expr, CmdFrom::$name:ident) => {
+        (|| -> $crate::Result<_> {
+            let (msg_tx, msg_rx) = ::std::sync::mpsc::channel();
+            $crate::ResultExt::chain_err($tx.send($crate::CmdFrom::$name(msg_tx)), || $crate::ErrorKind::MpscCmd(None))?;
+            $crate::ResultExt::chain_err(msg_rx.recv(), || $crate::ErrorKind::MpscCmd(None))
+        })()
+    };
     ($tx:expr, CmdFrom::$name:ident, $($param:expr),*) => {
         (|| -> $crate::Result<_> {
             let (msg_tx, msg_rx) = ::std::sync::mpsc::channel();
@@ -175,6 +288,71 @@ macro_rules! rpc {
     }
 }
 
+/// Send a command to another service without waiting to hear whether it was accepted
+///
+/// (The receiving service still prints an error if it rejects the command.)
+pub fn tell<S: Into<String>, C: Into<String>>(tx: &Sender<CmdFrom>, service: S, cmd: C) -> Result<()> {
+    let (reply_tx, _) = channel();
+    tx.send(CmdFrom::Send(service.into(), cmd.into(), reply_tx)).chain_err(|| ErrorKind::MpscCmd(None))
+}
+
+/// Start another service (recording into `ctx`) and wait until it is running (or has failed to start)
+///
+/// Gives up with `ErrorKind::LifecycleTimeout` if the service hasn't finished starting within
+/// `timeout`. (It may still come up later.)
+pub fn start<S: Into<String>>(tx: &Sender<CmdFrom>, service: S, param: Option<String>, ctx: RecordingContext, timeout: Duration) -> Result<Status> {
+    let service = service.into();
+    let (status_tx, status_rx) = channel();
+    tx.send(CmdFrom::Start(service.clone(), param, ctx, status_tx)).chain_err(|| ErrorKind::MpscCmd(None))?;
+    await_status(status_rx, service, "start", timeout)
+}
+
+/// Stop another service and wait until it has been torn down
+///
+/// Gives up with `ErrorKind::LifecycleTimeout` if teardown() hasn't finished within `timeout`.
+pub fn stop<S: Into<String>>(tx: &Sender<CmdFrom>, service: S, timeout: Duration) -> Result<Status> {
+    let service = service.into();
+    let (status_tx, status_rx) = channel();
+    tx.send(CmdFrom::Stop(service.clone(), status_tx)).chain_err(|| ErrorKind::MpscCmd(None))?;
+    await_status(status_rx, service, "stop", timeout)
+}
+
+/// Helper function for start() and stop()
+///
+/// Waits for a status other than `Starting`
+fn await_status(rx: Receiver<Status>, service: String, action: &'static str, timeout: Duration) -> Result<Status> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            bail!(ErrorKind::LifecycleTimeout(service, action));
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(Status::Starting) => continue,
+            Ok(status) => return Ok(status),
+            Err(RecvTimeoutError::Timeout) => bail!(ErrorKind::LifecycleTimeout(service, action)),
+            Err(RecvTimeoutError::Disconnected) => return Ok(Status::Failed(format!("{} thread went away", service))),
+        }
+    }
+}
+
+/// Command type for services that don't accept any commands
+pub enum NoCommands {}
+
+impl FromStr for NoCommands {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, String> {
+        Err(format!("unexpected command {:?} (this service takes no commands)", s))
+    }
+}
+
+impl Serialize for NoCommands {
+    fn serialize<S: Serializer>(&self, _: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {}
+    }
+}
+
 /// Convenience macro for defining a stub service that doesn't do anything (yet). Defines a
 /// zero-sized struct and an impl that blocks between receiving messages from the main thread (so
 /// it doesn't do anything, but it doesn't sit in a CPU-busy loop either).
@@ -193,13 +371,13 @@ macro_rules! stub {
             impl Controllable for $t {
                 const NAME: &'static str = stringify!($t);
                 const BLOCK: Block = Block::Infinite;
+                type Command = ::comms::NoCommands;
 
-                fn setup(_: ::std::sync::mpsc::Sender<CmdFrom>, _: Option<String>) -> $t {
-                    $t
+                fn setup(_: ::std::sync::mpsc::Sender<CmdFrom>, _: Option<String>, _: ::utils::RecordingContext, _: &::utils::config::Config) -> ::std::result::Result<$t, String> {
+                    Ok($t)
                 }
 
-                fn step(&mut self, cmd: Option<String>) {
-                    if cmd.is_some() { unimplemented!(); }
+                fn step(&mut self, _: Option<::comms::NoCommands>) {
                 }
 
                 fn teardown(&mut self) {
@@ -211,7 +389,8 @@ macro_rules! stub {
 
 /// Represents the two loops in `go()`, used in conjunction with `maybe_break!`
 enum Break {
-    Running,
+    /// Stop requested (`Stopped` is to be reported on the Sender after teardown)
+    Running(Sender<Status>),
     Alive,
 }
 
@@ -222,12 +401,13 @@ macro_rules! as_expr { ($e:expr) => ($e) }
 
 /// Helper macro for go()
 ///
-/// Breaks out of the run loop, the life loop, or does nothing based on return value from handle()
+/// Breaks out of the run loop, the life loop, or does nothing based on return value from handle().
+/// When breaking out of the run loop, the stop request's Sender is stored in `$stopped`.
 #[macro_export]
 macro_rules! maybe_break {
-    ($v:expr, $running:tt, $alive:tt) => (as_expr!({
+    ($v:expr, $running:tt, $alive:tt, $stopped:ident) => (as_expr!({
         match $v {
-            Some(Break::Running) => break $running,
+            Some(Break::Running(reply)) => { $stopped = Some(reply); break $running }
             Some(Break::Alive)   => break $alive,
             None => ()
         }
@@ -237,12 +417,23 @@ macro_rules! maybe_break {
 /// Helper function for handle()
 ///
 /// Called in the case of a command from the main thread when the service is already running
-fn handle_ok<C: Controllable>(cmd: CmdTo, c: &mut C, data: &mut Option<String>) -> Option<Break> {
+fn handle_ok<C: Controllable>(cmd: CmdTo, c: &mut C, data: &mut Option<C::Command>) -> Option<Break> {
     match cmd {
-        CmdTo::Start(_) => {}                       // already started
-        CmdTo::Stop => return Some(Break::Running), // shutdown command
-        CmdTo::Quit => return handle_err(c),        // real shutdown command
-        CmdTo::Data(d) => *data = Some(d),
+        CmdTo::Start(_, _, reply) => {                          // already started
+            let _ = reply.send(Status::Running);
+        }
+        CmdTo::Stop(reply) => return Some(Break::Running(reply)), // shutdown command
+        CmdTo::Quit => return handle_err(c),                      // real shutdown command
+        CmdTo::Data(d, reply) => match d.parse() {
+            Ok(cmd) => {
+                *data = Some(cmd);
+                let _ = reply.send(Ok(()));
+            }
+            Err(e) => {
+                errorln!("{}: rejected command {:?}: {}", guilty!(C::NAME), d, e);
+                let _ = reply.send(Err(e));
+            }
+        },
     }
     None
 }
@@ -258,7 +449,7 @@ fn handle_err<C: Controllable>(c: &mut C) -> Option<Break> {
 /// Helper function for go()
 ///
 /// Handles communications from the main thread
-fn handle<C: Controllable>(block: bool, c: &mut C, rx: &Receiver<CmdTo>, data: &mut Option<String>) -> Option<Break> {
+fn handle<C: Controllable>(block: bool, c: &mut C, rx: &Receiver<CmdTo>, data: &mut Option<C::Command>) -> Option<Break> {
     if block {
         match rx.recv() {
             Ok(cmd) => handle_ok(cmd, c, data),
@@ -275,11 +466,34 @@ fn handle<C: Controllable>(block: bool, c: &mut C, rx: &Receiver<CmdTo>, data: &
     }
 }
 
+/// Helper function for go()
+///
+/// Calls step() while keeping the heartbeat up to date
+fn step<C: Controllable>(c: &mut C, data: Option<C::Command>, heartbeat: &Heartbeat) {
+    heartbeat.begin_step();
+    prof!("step", c.step(data));
+    heartbeat.end_step();
+}
+
+/// Helper function for go()
+///
+/// Extracts the message from a panic payload
+fn panic_reason(e: &Box<Any + Send + 'static>) -> String {
+    if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = e.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else {
+        "unknown reason".to_owned()
+    }
+}
+
 /// Service driving function
 ///
 /// Runs in a loop receiving commands from the supervisor thread. Manages a Controllable instance,
-/// calling its setup()/step()/teardown() methods as necessary.
-pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) -> Result<()> {
+/// calling its setup()/step()/teardown() methods as necessary, and reports progress through the
+/// heartbeat. Lifecycle transitions are reported to whoever sent the Start/Stop command.
+pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>, heartbeat: Arc<Heartbeat>) -> Result<()> {
     let mut block = guilty!(C::BLOCK);
     let actual_period = match block {
         Block::Immediate      => 0,
@@ -288,24 +502,48 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) -> Result<(
     };
 
     'alive: loop {
-        let mut data;
+        let param;
+        let ctx;
+        let started;
 
         'hatching: loop {
             match rx.recv() {
                 Ok(cmd) => match cmd {
-                    CmdTo::Start(d) => { data = d; break 'hatching } // let's go!
-                    CmdTo::Data(_) | CmdTo::Stop => continue 'hatching, // sorry, not listening yet
+                    CmdTo::Start(d, c, reply) => { param = d; ctx = c; started = reply; break 'hatching } // let's go!
+                    CmdTo::Data(_, reply) => { // sorry, not listening yet
+                        let _ = reply.send(Err(format!("{} is not running", guilty!(C::NAME))));
+                        continue 'hatching;
+                    }
+                    CmdTo::Stop(reply) => { // already stopped
+                        let _ = reply.send(Status::Stopped);
+                        continue 'hatching;
+                    }
                     CmdTo::Quit => break 'alive,
                 },
                 Err(_) => bail!("main thread exploded"),
             }
         }
 
-        tx.send(CmdFrom::Timeout { thread: guilty!(C::NAME), ms: 1000 }).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
-        let mut c = C::setup(tx.clone(), data);
+        heartbeat.clear();
+        let _ = started.send(Status::Starting);
+        tx.send(CmdFrom::Timeout { thread: guilty!(C::NAME), ms: guilty!(C::SETUP_DEADLINE_MS) }).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+        let mut c = match panic::catch_unwind(panic::AssertUnwindSafe(|| C::setup(tx.clone(), param, ctx, &config::get()))) {
+            Ok(Ok(c)) => c,
+            Ok(Err(reason)) => {
+                let _ = started.send(Status::Failed(reason.clone()));
+                tx.send(CmdFrom::SetupFailed { thread: guilty!(C::NAME), reason: reason }).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+                continue 'alive;
+            }
+            Err(e) => {
+                // let whoever asked for the start know, then carry on panicking for the supervisor
+                let _ = started.send(Status::Failed(panic_reason(&e)));
+                panic::resume_unwind(e);
+            }
+        };
         tx.send(CmdFrom::Timein { thread: guilty!(C::NAME) }).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+        let _ = started.send(Status::Running);
 
-        tx.send(CmdFrom::Data(format!("to web start {}", guilty!(C::NAME)))).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+        tell(&tx, "web", format!("start {}", guilty!(C::NAME))).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
 
         utils::PROF.with(|wrapped_prof| {
             *wrapped_prof.borrow_mut() = Some(hprof::Profiler::new(guilty!(C::NAME)));
@@ -313,24 +551,25 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) -> Result<(
 
         let mut i = 0;
         let mut life_start = time::now();
+        let mut stopped = None;
         'running: loop {
-            data = None;
+            let mut data = None;
             i += 1;
 
             let start = time::now();
 
             match block {
                 Block::Immediate => {
-                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
-                    prof!("step", c.step(data));
+                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive, stopped);
+                    step(&mut c, data, &heartbeat);
                 }
                 Block::Infinite => {
-                    maybe_break!(handle(true, &mut c, &rx, &mut data), 'running, 'alive);
-                    prof!("step", c.step(data));
+                    maybe_break!(handle(true, &mut c, &rx, &mut data), 'running, 'alive, stopped);
+                    step(&mut c, data, &heartbeat);
                 }
                 Block::Period(desired_period) => {
-                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive);
-                    prof!("step", c.step(data));
+                    maybe_break!(handle(false, &mut c, &rx, &mut data), 'running, 'alive, stopped);
+                    step(&mut c, data, &heartbeat);
                     if let Some(nanos) = (time::now() - start).num_nanoseconds() {
                         if nanos < desired_period {
                             unsafe {
@@ -352,7 +591,10 @@ pub fn go<C: Controllable>(rx: Receiver<CmdTo>, tx: Sender<CmdFrom>) -> Result<(
         }
 
         c.teardown();
-        tx.send(CmdFrom::Data(format!("to web stop {}", guilty!(C::NAME)))).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+        tell(&tx, "web", format!("stop {}", guilty!(C::NAME))).chain_err(|| ErrorKind::MpscCmd(Some(guilty!(C::NAME))))?;
+        if let Some(reply) = stopped {
+            let _ = reply.send(Status::Stopped);
+        }
     }
 
     println!("\n\n");
@@ -0,0 +1,14 @@
+use std::process::Command;
+
+/// Record the git revision so that episode manifests can say which software recorded them
+fn main() {
+    let described = Command::new("git").args(&["describe", "--always", "--dirty"]).output();
+    if let Ok(output) = described {
+        if output.status.success() {
+            println!("cargo:rustc-env=NRI_REVISION={}", String::from_utf8_lossy(&output.stdout).trim());
+        }
+    }
+
+    println!("cargo:rerun-if-changed=../../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../../.git/index");
+}
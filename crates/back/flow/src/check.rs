@@ -0,0 +1,265 @@
+//! Checking flows without running them on the rig
+//!
+//! `validate` looks for mistakes that parse fine but would only show up halfway through an episode,
+//! and `dry_run` walks through a flow with scripted answers to show what it would do.
+
+use std::collections::HashSet;
+use std::{fmt, fs, mem};
+use std::path::Path;
+use teensy::ParkState;
+use parse::{interpolate, escape};
+use super::{Flow, FlowCmd, Script, Vars, Result, ResultExt, ErrorKind};
+
+/// Something wrong with a flow
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// Flow file (or flow name) with the problem
+    pub flow: String,
+    /// State with the problem, if it is in one
+    pub state: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            Some(ref state) => write!(f, "{}: state {:?}: {}", self.flow, state, self.message),
+            None => write!(f, "{}: {}", self.flow, self.message),
+        }
+    }
+}
+
+/// One command in a dry run
+#[derive(Clone, Debug)]
+pub struct Step {
+    /// Simulated time since the flow started (only `wait` takes any time)
+    pub ms: u64,
+    pub state: String,
+    /// The command as it would be written in the flow file
+    pub command: String,
+}
+
+/// Parse and validate every flow in a directory
+///
+/// Parse errors are reported as problems too. See `validate` for `services`.
+pub fn check_dir<P: AsRef<Path>>(dir: P, services: Option<&[&str]>) -> Result<Vec<Problem>> {
+    let dir = dir.as_ref();
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).chain_err(|| ErrorKind::Io(format!("list {:?}", dir)))? {
+        let path = entry.chain_err(|| ErrorKind::Io(format!("list {:?}", dir)))?.path();
+        if path.extension().map_or(false, |ext| ext == "flow") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut problems = vec![];
+    for path in paths {
+        match Flow::parse_file(&path) {
+            Ok(flow) => problems.extend(validate(&flow, services).into_iter().map(|p| Problem { flow: path.display().to_string(), .. p })),
+            Err(e) => problems.push(Problem { flow: path.display().to_string(), state: None, message: e.to_string() }),
+        }
+    }
+    Ok(problems)
+}
+
+/// Look for mistakes in a flow that the parser can't catch
+///
+/// - a state triggered by an end-effector that is not listed in the flow's header (everything being
+///   parked is always fine)
+/// - `disk stop` sent to a service that isn't recording (or `disk start` never stopped)
+/// - starting, stopping, waiting for or sending to a service that doesn't exist (only checked if
+///   the names of the registered services are given)
+///
+/// Both branches of an `if` and one pass through a `repeat` are checked.
+pub fn validate(flow: &Flow, services: Option<&[&str]>) -> Vec<Problem> {
+    let mut checker = Checker {
+        flow: flow.name.clone(),
+        services: services,
+        recording: HashSet::new(),
+        problems: vec![],
+    };
+
+    for state in &flow.states {
+        if let Some(park) = state.park {
+            if park != ParkState::None && !flow.endeffs.is_empty() && !flow.endeffs.contains(&park) {
+                checker.problem(&state.name, format!("triggered by {:?}, which is not one of the flow's end-effectors {:?}", park, flow.endeffs));
+            }
+        }
+        checker.script(&state.name, &state.script);
+    }
+
+    let mut recording = checker.recording.iter().cloned().collect::<Vec<_>>();
+    recording.sort();
+    for service in recording {
+        checker.problems.push(Problem {
+            flow: flow.name.clone(),
+            state: None,
+            message: format!("{} is still recording to disk when the flow ends", service),
+        });
+    }
+
+    checker.problems
+}
+
+struct Checker<'a> {
+    flow: String,
+    services: Option<&'a [&'a str]>,
+    /// Services that have been sent `disk start` (and not `disk stop`)
+    recording: HashSet<String>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn problem(&mut self, state: &str, message: String) {
+        self.problems.push(Problem { flow: self.flow.clone(), state: Some(state.into()), message: message });
+    }
+
+    fn service(&mut self, state: &str, service: &str) {
+        if let Some(services) = self.services {
+            // (a service named by a variable is only known once the flow runs)
+            if !service.contains('$') && !services.contains(&service) {
+                self.problem(state, format!("there is no service called {:?}", service));
+            }
+        }
+    }
+
+    fn script(&mut self, state: &str, script: &Script) {
+        for &(ref cmd, _) in script {
+            match *cmd {
+                FlowCmd::Start(ref service, _) | FlowCmd::Stop(ref service) | FlowCmd::WaitReady(ref service) => {
+                    self.service(state, service);
+                    if let FlowCmd::Stop(_) = *cmd {
+                        self.recording.remove(service);
+                    }
+                }
+                FlowCmd::Send(ref string) => {
+                    let mut words = string.split_whitespace();
+                    let service = words.next().unwrap_or("");
+                    let command = words.collect::<Vec<_>>().join(" ");
+                    self.service(state, service);
+                    if command == "disk start" {
+                        if !self.recording.insert(service.into()) {
+                            self.problem(state, format!("{} is already recording to disk", service));
+                        }
+                    } else if command == "disk stop" && !self.recording.remove(service) {
+                        self.problem(state, format!("disk stop sent to {}, which is not recording (no disk start before it)", service));
+                    }
+                }
+                FlowCmd::StopSensors => self.recording.clear(),
+                FlowCmd::If { ref then, ref otherwise, .. } => {
+                    let before = self.recording.clone();
+                    self.script(state, then);
+                    let after_then = mem::replace(&mut self.recording, before);
+                    self.script(state, otherwise);
+                    self.recording.extend(after_then);
+                }
+                FlowCmd::Repeat { ref body, .. } => self.script(state, body),
+                FlowCmd::Message(_) | FlowCmd::Str { .. } | FlowCmd::Int { .. } | FlowCmd::Wait(_) => {}
+            }
+        }
+    }
+}
+
+/// Walk through a flow without touching any services, and list the commands it would run
+///
+/// Prompts take their answers from `answers` in order. Once those run out, text prompts get
+/// "dry run" and number prompts get the bottom of their range. Each state is assumed to run with
+/// the end-effector it asks for.
+pub fn dry_run<I: Iterator<Item=String>>(flow: &Flow, answers: I) -> Result<Vec<Step>> {
+    let mut sim = Sim {
+        answers: answers,
+        vars: Vars::new(),
+        ms: 0,
+        steps: vec![],
+    };
+
+    let mut park = flow.endeffs.first().cloned().unwrap_or(ParkState::None);
+    for state in &flow.states {
+        park = state.park.unwrap_or(park);
+        sim.vars.insert("park".into(), format!("{:?}", park));
+        sim.script(&state.name, &state.script)?;
+    }
+
+    Ok(sim.steps)
+}
+
+struct Sim<I> {
+    answers: I,
+    vars: Vars,
+    ms: u64,
+    steps: Vec<Step>,
+}
+
+impl<I: Iterator<Item=String>> Sim<I> {
+    fn step(&mut self, state: &str, command: String) {
+        self.steps.push(Step { ms: self.ms, state: state.into(), command: command });
+    }
+
+    fn answer(&mut self, var: &Option<String>, value: String) -> String {
+        if let Some(ref var) = *var {
+            self.vars.insert(var.clone(), value.clone());
+            format!("{} = ", var)
+        } else {
+            String::new()
+        }
+    }
+
+    fn script(&mut self, state: &str, script: &Script) -> Result<()> {
+        for &(ref cmd, _) in script {
+            match *cmd {
+                FlowCmd::Message(ref msg) => {
+                    let msg = interpolate(msg, &self.vars)?;
+                    self.step(state, format!("{:?}", escape(&msg)));
+                }
+                FlowCmd::Str { ref prompt, ref var, .. } => {
+                    let prompt = interpolate(prompt, &self.vars)?;
+                    let data = self.answers.next().unwrap_or_else(|| "dry run".into());
+                    let lhs = self.answer(var, data.clone());
+                    self.step(state, format!("> {}{:?} [{:?}]", lhs, escape(&prompt), data));
+                }
+                FlowCmd::Int { ref prompt, limits: (low, high), ref var, .. } => {
+                    let prompt = interpolate(prompt, &self.vars)?;
+                    let data = match self.answers.next() {
+                        Some(answer) => match answer.trim().parse::<i32>() {
+                            Ok(i) if i >= low && i <= high => i,
+                            _ => bail!(ErrorKind::Rejected(format!("{:?}", prompt), format!("scripted answer {:?} is not a number from {} to {}", answer, low, high))),
+                        },
+                        None => low,
+                    };
+                    let lhs = self.answer(var, data.to_string());
+                    self.step(state, format!("> {}{:?} ({}..{}) [{:?}]", lhs, escape(&prompt), low, high, data));
+                }
+                FlowCmd::Start(ref service, ref data) => {
+                    let command = match *data {
+                        Some(ref data) => format!("start {}/{}", service, escape(&interpolate(data, &self.vars)?)),
+                        None => format!("start {}", service),
+                    };
+                    self.step(state, command);
+                }
+                FlowCmd::Stop(ref service) => self.step(state, format!("stop {}", service)),
+                FlowCmd::Send(ref string) => {
+                    let string = interpolate(string, &self.vars)?;
+                    self.step(state, format!(": {}", escape(&string)));
+                }
+                FlowCmd::StopSensors => self.step(state, "stop".into()),
+                FlowCmd::Wait(ms) => {
+                    self.step(state, format!("wait {}", ms));
+                    self.ms += ms;
+                }
+                FlowCmd::WaitReady(ref service) => self.step(state, format!("wait until {} ready", service)),
+                FlowCmd::If { ref cond, ref then, ref otherwise, .. } => {
+                    let branch = cond.eval(&self.vars)?;
+                    self.script(state, if branch { then } else { otherwise })?;
+                }
+                FlowCmd::Repeat { ref count, ref body, .. } => {
+                    for _ in 0..count.resolve(&self.vars)? {
+                        self.script(state, body)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Progress of a flow saved to disk, so that a run interrupted by a crash or reboot can be resumed
+//!
+//! `Flow::run` writes `checkpoint.json` into the episode directory after every state, and deletes it
+//! when the flow finishes. At startup, `recover` looks for episode directories that still have one.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use serde_json;
+use uuid::Uuid;
+use manifest::Log;
+use super::{FlowState, Vars, Result, ResultExt, ErrorKind};
+
+/// Name of the checkpoint in each episode directory
+pub const FILE_NAME: &'static str = "checkpoint.json";
+
+/// Everything needed to pick up a flow where it left off
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub shortname: String,
+    pub id: Uuid,
+    pub stamp: DateTime<Local>,
+    pub states: Vec<FlowState>,
+    pub vars: Vars,
+    pub log: Log,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint from an episode directory
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Checkpoint> {
+        let path = dir.as_ref().join(FILE_NAME);
+        let file = File::open(&path).chain_err(|| ErrorKind::Io(format!("open {:?}", path)))?;
+        Ok(serde_json::from_reader(file).chain_err(|| ErrorKind::Io(format!("read {:?}", path)))?)
+    }
+
+    /// Write the checkpoint into an episode directory
+    ///
+    /// The new checkpoint replaces the old one in one step, so a crash while saving leaves the
+    /// previous checkpoint intact.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let path = dir.as_ref().join(FILE_NAME);
+        let tmp = path.with_extension("json.tmp");
+        {
+            let file = File::create(&tmp).chain_err(|| ErrorKind::Io(format!("create {:?}", tmp)))?;
+            serde_json::to_writer(&file, self).chain_err(|| ErrorKind::Io(format!("write {:?}", tmp)))?;
+            file.sync_all().chain_err(|| ErrorKind::Io(format!("sync {:?}", tmp)))?;
+        }
+        fs::rename(&tmp, &path).chain_err(|| ErrorKind::Io(format!("rename {:?} to {:?}", tmp, path)))?;
+        Ok(())
+    }
+
+    /// Delete the checkpoint from an episode directory (if there is one)
+    pub fn remove<P: AsRef<Path>>(dir: P) -> Result<()> {
+        let path = dir.as_ref().join(FILE_NAME);
+        if path.exists() {
+            fs::remove_file(&path).chain_err(|| ErrorKind::Io(format!("delete {:?}", path)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Find episode directories (laid out as `datadir/date/flow/number`) that still have a checkpoint
+///
+/// Only an unreadable `datadir` is an error. Directories below it that cannot be listed are
+/// skipped (with a warning).
+pub fn scan<P: AsRef<Path>>(datadir: P) -> Result<Vec<PathBuf>> {
+    fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut dirs = vec![];
+        for entry in fs::read_dir(dir).chain_err(|| ErrorKind::Io(format!("list {:?}", dir)))? {
+            let entry = entry.chain_err(|| ErrorKind::Io(format!("list {:?}", dir)))?;
+            if entry.file_type().chain_err(|| ErrorKind::Io(format!("read metadata of {:?}", entry.path())))?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    let datadir = datadir.as_ref();
+    let mut found = vec![];
+    if !datadir.is_dir() {
+        return Ok(found);
+    }
+    let readable = |dir: &Path| subdirs(dir).unwrap_or_else(|e| {
+        errorln!("WARNING: skipping {:?} while looking for checkpoints: {}", dir, e);
+        vec![]
+    });
+    for datedir in subdirs(datadir)? {
+        for flowdir in readable(&datedir) {
+            for epdir in readable(&flowdir) {
+                if epdir.join(FILE_NAME).exists() {
+                    found.push(epdir);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
@@ -1,14 +1,17 @@
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate error_chain;
-extern crate utils;
+#[macro_use] extern crate utils;
 #[macro_use] extern crate comms;
 extern crate teensy;
+extern crate clock;
+extern crate scribe;
+extern crate serde_json;
 extern crate chrono;
 extern crate uuid;
 
-use std::{env, fmt, mem, thread};
+use std::{fmt, mem};
 use std::sync::mpsc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Write, BufRead, BufReader};
 use std::ffi::OsString;
 use std::fs::{self, File};
@@ -16,14 +19,25 @@ use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::error::Error as StdError;
 use std::sync::RwLock;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 use chrono::{DateTime, Local, Timelike};
 use teensy::ParkState;
-use utils::config;
-use comms::CmdFrom;
+use utils::{config, RecordingContext};
+use comms::{CmdFrom, Status};
+use comms::health;
 use uuid::Uuid;
 #[macro_use] extern crate serde_derive;
 
+mod parse;
+pub mod manifest;
+pub mod checkpoint;
+pub mod check;
+
+use parse::{read_lines, parse_block, interpolate, escape};
+use manifest::{Log, Manifest, Value};
+use checkpoint::Checkpoint;
+
 error_chain! {
     errors {
         Io(action: String) {
@@ -31,9 +45,34 @@ error_chain! {
             display("I/O error while trying to {}", action)
         }
 
-        ParseFlow(line: usize, error: &'static str) {
+        ParseFlow(file: String, line: usize, error: String) {
             description("syntax error in flow")
-            display("flow syntax error: line {}: {}", line, error)
+            display("flow syntax error: {}:{}: {}", file, line, error)
+        }
+
+        Undefined(var: String) {
+            description("undefined flow variable")
+            display("flow variable {:?} has not been set", var)
+        }
+
+        NotANumber(var: String, value: String) {
+            description("flow variable is not a number")
+            display("flow variable {:?} is {:?}, which is not a number", var, value)
+        }
+
+        Interrupted(flow: String) {
+            description("flow was interrupted")
+            display("flow {:?} was interrupted, so it must be resumed or aborted", flow)
+        }
+
+        NotInterrupted(flow: String) {
+            description("flow was not interrupted")
+            display("flow {:?} was not interrupted, so it cannot be resumed", flow)
+        }
+
+        Timeout(what: String) {
+            description("timed out")
+            display("timed out waiting for {}", what)
         }
 
         Rpc(action: String) {
@@ -41,6 +80,16 @@ error_chain! {
             display("RPC error while trying to {}", action)
         }
 
+        Rejected(command: String, reason: String) {
+            description("command rejected")
+            display("command {:?} rejected: {}", command, reason)
+        }
+
+        Lifecycle(action: String, status: Status) {
+            description("service did not start or stop")
+            display("could not {} (service is {})", action, status)
+        }
+
         FlowCanceled {}
 
         Comms {}
@@ -86,18 +135,62 @@ impl fmt::Display for OsStringError {
 lazy_static! {
     pub static ref FLOWS: RwLock<HashMap<String, Flow>> = utils::watch(HashMap::new(),
                                                                        &FLOWS,
-                                                                       Path::new(config::FLOW_PATH),
+                                                                       Path::new(&config::get().flows.path),
                                                                        "flow",
                                                                        |flows, path| {
-        let flow = Flow::parse(path.file_stem().expect(&format!("no file stem for {:?}", path))
-                                    .to_str().expect(&format!("bad UTF-8 in {:?}", path))
-                                    .to_owned(),
-                               BufReader::new(File::open(&path).expect(&format!("could not open flow {:?}", path))))
-                        .expect(&format!("unable to parse flow {:?}", path));
-        flows.insert(flow.shortname.clone(), flow);
+        // a broken flow file must not take down the watcher (nri-flowcheck gives the full story)
+        match Flow::parse_file(&path) {
+            Ok(flow) => {
+                for problem in check::validate(&flow, None) {
+                    errorln!("WARNING: {}", check::Problem { flow: path.display().to_string(), .. problem });
+                }
+                flows.insert(flow.shortname.clone(), flow);
+            }
+            Err(e) => errorln!("ERROR: unable to parse flow {:?} (keeping the old version, if any): {}", path, e),
+        }
     });
 
-    pub static ref DATADIR: RwLock<String> = RwLock::new(utils::config::DATADIR.into());
+    pub static ref DATADIR: RwLock<String> = RwLock::new(config::get().flows.datadir.clone());
+}
+
+/// Find runs that were interrupted (by a crash or reboot) and load them back into their flows
+///
+/// Each one can then be continued with `Flow::resume`, or cleaned up with `Flow::abort`. Only one
+/// run per flow can be loaded at a time; any others are left alone until the next scan. Returns the
+/// episode directories that were loaded.
+///
+/// This is best-effort: a checkpoint that cannot be read or restored (e.g. it was half-written, or
+/// the flow has changed since) is skipped with a warning, and left on disk for a human to look at.
+pub fn recover() -> Vec<PathBuf> {
+    let datadir = utils::original_dir().join(&*DATADIR.read().unwrap());
+    let dirs = match checkpoint::scan(&datadir) {
+        Ok(dirs) => dirs,
+        Err(e) => {
+            errorln!("WARNING: could not look for interrupted flows in {:?}: {}", datadir, e);
+            return vec![];
+        }
+    };
+
+    let mut recovered = vec![];
+    let mut flows = FLOWS.write().unwrap();
+    for dir in dirs {
+        let checkpoint = match Checkpoint::load(&dir) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                errorln!("WARNING: skipping unreadable checkpoint in {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        if let Some(flow) = flows.get_mut(&checkpoint.shortname) {
+            if !flow.active {
+                match flow.restore(dir.clone(), checkpoint) {
+                    Ok(()) => recovered.push(dir),
+                    Err(e) => errorln!("WARNING: could not restore the interrupted flow in {:?}: {}", dir, e),
+                }
+            }
+        }
+    }
+    recovered
 }
 
 pub trait Comms: Clone {
@@ -136,11 +229,17 @@ pub struct Flow {
     active: bool,
     /// All states done but one?
     almostdone: bool,
+    /// Was this run interrupted (by a crash or reboot) and not yet resumed or aborted?
+    interrupted: bool,
 
     stamp: Option<DateTime<Local>>,
-    original_dir: Option<PathBuf>,
     episode_dir: Option<PathBuf>,
     id: Option<Uuid>,
+    /// Variables set so far in this run of the flow
+    vars: Vars,
+    /// Record of this run of the flow so far (for the manifest)
+    #[serde(skip_serializing)]
+    log: Log,
 
     #[serde(skip_serializing)]
     file: Option<File>,
@@ -154,46 +253,105 @@ impl Clone for Flow {
             states: self.states.clone(),
             active: self.active,
             almostdone: self.almostdone,
+            interrupted: self.interrupted,
             stamp: self.stamp.clone(),
-            original_dir: self.original_dir.clone(),
             episode_dir: self.episode_dir.clone(),
             id: self.id.clone(),
+            vars: self.vars.clone(),
+            log: self.log.clone(),
             file: None,
         }
     }
 }
 
 /// One state in a data collection flow
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FlowState {
     /// Name of the flow state
     pub name: String,
     /// State of parking lot that allows this state (if applicable)
     park: Option<ParkState>,
     /// Commands to run for this state
-    pub script: Vec<(FlowCmd, Option<DateTime<Local>>)>,
+    pub script: Script,
     /// Has this state been completed?
     done: bool,
     stamp: Option<DateTime<Local>>,
 }
 
+/// Commands in a state or block, each with the time it was run
+pub type Script = Vec<(FlowCmd, Option<DateTime<Local>>)>;
+
+/// Values of flow variables, by name
+///
+/// Prompts set variables, and `park` always holds the current parking lot state.
+pub type Vars = HashMap<String, String>;
+
 /// Different actions that a flow can perform at each state
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FlowCmd {
     Message(String),
     Str {
         prompt: String,
+        /// Variable to set to the answer
+        var: Option<String>,
         data: Option<String>,
     },
     Int {
         prompt: String,
         limits: (i32, i32),
+        /// Variable to set to the answer
+        var: Option<String>,
         data: Option<i32>,
     },
     Start(String, Option<String>),
     Stop(String),
     Send(String),
     StopSensors,
+    /// Pause for some milliseconds
+    Wait(u64),
+    /// Wait until a service is up and stepping
+    WaitReady(String),
+    /// Run one block or the other
+    If {
+        cond: Cond,
+        then: Script,
+        otherwise: Script,
+        /// Which block ran (if any yet)
+        taken: Option<bool>,
+    },
+    /// Run a block several times
+    Repeat {
+        count: Count,
+        body: Script,
+        /// Copy of the block for each time it ran
+        runs: Vec<Script>,
+    },
+}
+
+/// Comparison in a flow condition
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Condition of an `if` block: compare a variable with a value
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cond {
+    pub var: String,
+    pub op: Op,
+    pub value: String,
+}
+
+/// Number of times to run a `repeat` block
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Count {
+    Times(u32),
+    Var(String),
 }
 
 impl Flow {
@@ -208,6 +366,7 @@ impl Flow {
         comms.print("Aborting flow.".into()).comms_err()?;
 
         // reset state
+        let interrupted = mem::replace(&mut self.interrupted, false);
         self.active = false;
         self.almostdone = false;
         let cap = Vec::with_capacity(self.states.len());
@@ -215,17 +374,23 @@ impl Flow {
         for mut state in states.into_iter().rev() {
             if state.done {
                 comms.print(format!("\tCleaning up state \"{}\"", state.name)).comms_err()?;
-                state.cleanup(tx, comms.clone())?;
+                match state.cleanup(tx, comms.clone(), &self.vars) {
+                    Ok(()) => {}
+                    // the services died along with the interrupted run, so some of the undoing is moot
+                    Err(e) if interrupted => comms.print(format!("\tIgnoring cleanup error: {}", e)).comms_err()?,
+                    Err(e) => return Err(e),
+                }
             }
-            let FlowState { name, park, script, .. } = state;
-            self.states.push(FlowState::new(name, park, script));
+            state.finalize()?;
+            self.states.push(state);
         }
         self.states.reverse();
+        self.vars.clear();
+        self.log = Log::default();
 
         // delete the files
         drop(self.file.take());
-        if let (Some(original_dir), Some(episode_dir)) = (self.original_dir.take(), self.episode_dir.take()) {
-            env::set_current_dir(&original_dir).chain_err(|| Io(format!("set current directory to {:?}", original_dir)))?;
+        if let Some(episode_dir) = self.episode_dir.take() {
             fs::remove_dir_all(&episode_dir).chain_err(|| Io(format!("delete directory {:?}", episode_dir)))?;
         }
 
@@ -237,6 +402,10 @@ impl Flow {
         
         use self::ErrorKind::*;
 
+        if self.interrupted {
+            bail!(Interrupted(self.shortname.clone()));
+        }
+
         let mut ret = EventContour::In;
 
         // are we just starting the flow now?
@@ -247,14 +416,15 @@ impl Flow {
             let stamp = self.stamp.put(Local::now());
             self.id = Some(Uuid::new_v4());
             self.active = true;
+            self.vars.clear();
+            self.log = Log::default();
 
-            let datedir = stamp.format("%Y%m%d").to_string();
-            let fldir = format!("{}/{}/{}", *DATADIR.read().unwrap(), datedir, self.shortname); // TODO use PathBuf::push
+            let fldir = utils::original_dir().join(&*DATADIR.read().unwrap())
+                                             .join(stamp.format("%Y%m%d").to_string())
+                                             .join(&self.shortname);
             fs::create_dir_all(&fldir).chain_err(|| Io(format!("create episode directory {:?}", fldir)))?;
-            self.original_dir = Some(env::current_dir().chain_err(|| Io("get current directory".into()))?);
-            env::set_current_dir(&fldir).chain_err(|| Io(format!("set current directory to {:?}", fldir)))?;
             let mut epnum = 1;
-            for entry in fs::read_dir(".").chain_err(|| Io("list current directory".into()))? {
+            for entry in fs::read_dir(&fldir).chain_err(|| Io(format!("list {:?}", fldir)))? {
                 let entry = entry.chain_err(|| Io("read directory entry".into()))?;
                 if entry.file_type().chain_err(|| Io(format!("read metadata of {:?}", entry.file_name())))?.is_dir() {
                     let name = entry.file_name().into_string().map_err(OsStringError).chain_err(|| Io(format!("invalid UTF-8 in dir name {:?}", entry.file_name())))?;
@@ -264,20 +434,25 @@ impl Flow {
                     }
                 }
             }
-            fs::create_dir(epnum.to_string()).chain_err(|| Io(format!("create directory \"{}\"", epnum)))?;
-            env::set_current_dir(epnum.to_string()).chain_err(|| Io(format!("set current directory to \"{}\"", epnum)))?;
-            self.episode_dir = Some(env::current_dir().chain_err(|| Io("get current directory".into()))?);
+            let epdir = fldir.join(epnum.to_string());
+            fs::create_dir(&epdir).chain_err(|| Io(format!("create directory {:?}", epdir)))?;
+            clock::reset();
 
             // start off the flow file
             let shortname = &self.shortname;
-            let file = self.file.put(File::create(format!("{}.flow", shortname)).chain_err(|| Io(format!("create flow file \"{}.flow\"", shortname)))?);
+            let flowpath = epdir.join(format!("{}.flow", shortname));
+            let file = self.file.put(File::create(&flowpath).chain_err(|| Io(format!("create flow file {:?}", flowpath)))?);
+            self.episode_dir = Some(epdir);
             writeln!(file, "{} [{}]", self.name, StampPrinter(stamp)).chain_err(|| Io(format!("write to flow file \"{}.flow\"", shortname)))?;
             writeln!(file, "").chain_err(|| Io(format!("write to flow file \"{}.flow\"", shortname)))?;
 
             ret = EventContour::Starting;
         }
 
+        self.vars.insert("park".into(), format!("{:?}", park));
+
         // find the next eligible state (if there is one)
+        let ctx = self.context();
         let mut abort = false;
         if let Some(state) = self.states.iter_mut().skip_while(|s| s.done).next() {
             if state.park.map_or(true, |p| p == park) {
@@ -285,7 +460,7 @@ impl Flow {
                 comms.print(format!("Executing state {}", state.name)).comms_err()?;
                 let file = self.file.as_mut().expect("flow file missing");
                 let shortname = &self.shortname;
-                match state.run(tx, file, comms.clone()) {
+                match state.run(tx, &ctx, file, comms.clone(), &mut self.vars, &mut self.log) {
                     Ok(()) => (),
                     Err(Error(ErrorKind::FlowCanceled, ..)) => abort = true,
                     Err(e) => return Err(e)
@@ -305,6 +480,10 @@ impl Flow {
             return Ok(EventContour::Finishing);
         }
 
+        if ret == EventContour::Continuing {
+            self.checkpoint()?;
+        }
+
         let almostdone = match self.states.last() {
             Some(state) if state.done => true,
             _ => false,
@@ -315,15 +494,21 @@ impl Flow {
 
                 self.active = false;
                 self.almostdone = false;
+
+                // save the clock estimates so timestamps can be corrected offline
+                if let Some(ref episode_dir) = self.episode_dir {
+                    let report = episode_dir.join("sync.json");
+                    clock::write_report(&report).chain_err(|| Io(format!("write {:?}", report)))?;
+                    Checkpoint::remove(episode_dir)?;
+                }
+                self.write_manifest(tx, comms.clone())?;
+
                 let mut states = mem::replace(&mut self.states, vec![]);
                 for state in &mut states {
                     state.finalize()?;
                 }
                 self.states = states;
-
-                if let Some(original_dir) = self.original_dir.take() {
-                    env::set_current_dir(&original_dir).chain_err(|| Io(format!("set current directory to {:?}", original_dir)))?;
-                }
+                self.vars.clear();
 
                 ret = EventContour::Finishing;
             } else {
@@ -334,172 +519,264 @@ impl Flow {
         Ok(ret)
     }
 
+    /// Where the services started by this run should record (the episode directory)
+    fn context(&self) -> RecordingContext {
+        RecordingContext::new(self.episode_dir.as_ref().expect("active flow without an episode directory"))
+    }
+
+    /// Save the progress of this run into the episode directory (see `checkpoint`)
+    fn checkpoint(&self) -> Result<()> {
+        let dir = match self.episode_dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+
+        Checkpoint {
+            shortname: self.shortname.clone(),
+            id: self.id.expect("active flow without an ID"),
+            stamp: self.stamp.expect("active flow without a timestamp"),
+            states: self.states.clone(),
+            vars: self.vars.clone(),
+            log: self.log.clone(),
+        }.save(dir)
+    }
+
+    /// Load an interrupted run into this flow, so that it can be resumed or aborted
+    fn restore(&mut self, episode_dir: PathBuf, checkpoint: Checkpoint) -> Result<()> {
+        self.active = true;
+        self.interrupted = true;
+        self.almostdone = checkpoint.states.last().map_or(false, |s| s.done);
+        self.stamp = Some(checkpoint.stamp);
+        self.id = Some(checkpoint.id);
+        self.states = checkpoint.states;
+        self.vars = checkpoint.vars;
+        self.log = checkpoint.log;
+        self.episode_dir = Some(episode_dir);
+        self.file = None;
+
+        Ok(())
+    }
+
+    /// Is there an interrupted run waiting to be resumed or aborted?
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// Is the flow being run (or waiting to be resumed)?
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// End-effector the next state of a running flow needs (if it needs a particular one)
+    ///
+    /// Once that end-effector is in, `run` carries on with the state.
+    pub fn waiting_for(&self) -> Option<ParkState> {
+        if !self.active || self.interrupted {
+            return None;
+        }
+        self.states.iter().skip_while(|s| s.done).next().and_then(|s| s.park)
+    }
+
+    /// Pick up an interrupted run where it left off
+    ///
+    /// The services that were running when the run was interrupted are started again. They would
+    /// overwrite what they recorded before the interruption, so those files are first moved into a
+    /// subdirectory of the episode. The next call to `run` carries on with the next state.
+    pub fn resume<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, comms: C) -> Result<()> {
+        use self::ErrorKind::*;
+
+        if !self.interrupted {
+            bail!(NotInterrupted(self.shortname.clone()));
+        }
+        let episode_dir = self.episode_dir.clone().expect("interrupted flow without an episode directory");
+        comms.print(format!("Resuming flow {} in {:?}", self.name, episode_dir)).comms_err()?;
+
+        let part = format!("interrupted{}", self.log.resumed.len() + 1);
+        self.set_aside(&episode_dir, &part)?;
+
+        let flowpath = episode_dir.join(format!("{}.flow", self.shortname));
+        self.file = Some(fs::OpenOptions::new().append(true).open(&flowpath)
+                         .chain_err(|| Io(format!("open flow file {:?}", flowpath)))?);
+
+        clock::reset();
+        let ctx = self.context();
+        for service in self.log.running() {
+            comms.print(format!("Restarting service {}", service.name)).comms_err()?;
+            self.log.stopped(&service.name);
+            start(tx, &ctx, &service.name, service.data.clone())?;
+            self.log.service(&service.name, service.data);
+        }
+
+        self.log.resumed.push(Local::now());
+        self.interrupted = false;
+        self.checkpoint()?;
+        comms.send(format!("msg Resumed flow {} (earlier recordings are in {:?})", self.name, part)).comms_err()?;
+
+        Ok(())
+    }
+
+    /// Move everything recorded so far (except the transcript and checkpoint) into a subdirectory
+    fn set_aside(&self, episode_dir: &Path, part: &str) -> Result<()> {
+        use self::ErrorKind::*;
+
+        let keep = [format!("{}.flow", self.shortname), checkpoint::FILE_NAME.to_owned()];
+        let partdir = episode_dir.join(part);
+        fs::create_dir(&partdir).chain_err(|| Io(format!("create directory {:?}", partdir)))?;
+        for entry in fs::read_dir(episode_dir).chain_err(|| Io(format!("list {:?}", episode_dir)))? {
+            let entry = entry.chain_err(|| Io(format!("list {:?}", episode_dir)))?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if keep.iter().any(|k| *k == name) || name.starts_with("interrupted") {
+                continue;
+            }
+            fs::rename(entry.path(), partdir.join(&*name)).chain_err(|| Io(format!("move {:?} into {:?}", entry.path(), partdir)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write `manifest.json` into the episode directory, once everything else has been written
+    fn write_manifest<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, comms: C) -> Result<()> {
+        use self::ErrorKind::*;
+
+        let dir = match self.episode_dir {
+            Some(ref dir) => dir.clone(),
+            None => return Ok(()),
+        };
+
+        comms.print("Writing episode manifest".into()).comms_err()?;
+        scribe::flush(); // make sure the data files are complete before checksumming them
+        let serials = rpc!(tx, CmdFrom::Serials).chain_err(|| Rpc("get serial numbers".into()))?;
+        let log = mem::replace(&mut self.log, Log::default());
+        Manifest::gather(self, log, serials, &dir)?.save(&dir)?;
+        comms.print(format!("Wrote {:?}", dir.join(manifest::FILE_NAME))).comms_err()?;
+
+        Ok(())
+    }
+
+    /// Parse a flow, resolving includes in the flow directory
     pub fn parse<R: BufRead>(shortname: String, reader: R) -> Result<Flow> {
+        let source = shortname.clone();
+        Flow::parse_from(shortname, &source, reader, Path::new(&config::get().flows.path))
+    }
+
+    /// Parse a flow file, resolving includes next to it
+    pub fn parse_file(path: &Path) -> Result<Flow> {
         use self::ErrorKind::*;
 
-        let lines = reader.lines()
-                          .collect::<StdResult<Vec<_>, _>>()
-                          .chain_err(|| ParseFlow(0, "I/O error"))?;
-        let mut lines = lines.into_iter()
-                             .map(|s| s.trim_right().to_owned())
-                             .peekable();
-                          
-        let header = lines.find(|s| !s.is_empty()).ok_or(ParseFlow(0, "Empty file"))?;
-        let header = header.split(":").collect::<Vec<_>>();
-        let (name, endeffs) = match header.len() {
-            1 => (header[0].trim().to_owned(), vec![]),
-            2 => (header[0].trim().to_owned(), header[1].trim().split(",").map(|s| s.trim().parse()).collect::<StdResult<Vec<_>, _>>().chain_err(|| ParseFlow(0, "invalid end-effector specifier"))?),
-            _ => Err(ParseFlow(0, "bad header"))?
+        let shortname = path.file_stem().and_then(|s| s.to_str())
+                            .ok_or_else(|| Io(format!("get flow name from {:?}", path)))?
+                            .to_owned();
+        let file = File::open(path).chain_err(|| Io(format!("open flow {:?}", path)))?;
+        Flow::parse_from(shortname, &path.display().to_string(), BufReader::new(file), path.parent().unwrap_or(Path::new(".")))
+    }
+
+    /// Parse a flow
+    ///
+    /// The first line is the name of the flow, optionally followed by a colon and the
+    /// end-effectors it uses. Then come states, each a line starting with `-` (optionally with a
+    /// parking lot state trigger, as in `- BioTac => Capture`), followed by indented commands:
+    ///
+    /// ```text
+    /// : service command               send a command to a service
+    /// "message"                       show a message
+    /// > name = "prompt"               ask for a string (the `name =` part is optional)
+    /// > name = "prompt" (1..5)        ask for an integer in a range
+    /// start service[/data] ...        start services
+    /// stop [service ...]              stop services (or all the sensors)
+    /// wait 500                        pause for some milliseconds
+    /// wait until service ready        wait until a service is running
+    /// if name == "value"              run the indented block below if the condition holds
+    /// else                            ... or else this block (optional)
+    /// repeat 3                        run the indented block below several times
+    /// repeat name                     (the count can come from a variable)
+    /// include name                    paste in the lines of `name.subflow`
+    /// ```
+    ///
+    /// Conditions compare a variable with a value using `==`, `!=`, `<`, `<=`, `>` or `>=` (the
+    /// last four need numbers). The variable `park` holds the parking lot state (e.g. `if park ==
+    /// BioTac`). Messages, prompts, sent commands and start data can mention variables as
+    /// `$name` (`$$` is a literal dollar sign).
+    ///
+    /// Includes work at any indentation, so a sub-flow can hold whole states or some commands.
+    /// Lines of recorded flows end with a timestamp in brackets, which is ignored.
+    fn parse_from<R: BufRead>(shortname: String, source: &str, reader: R, dir: &Path) -> Result<Flow> {
+        let mut lines = vec![];
+        read_lines(source, reader, dir, 0, &mut vec![], &mut lines)?;
+        let mut pos = 0;
+
+        let header = lines.get(pos).ok_or_else(|| ErrorKind::ParseFlow(source.into(), 0, "empty file".into()))?;
+        pos += 1;
+        let parts = header.text.split(":").collect::<Vec<_>>();
+        let (name, endeffs) = match parts.len() {
+            1 => (parts[0].trim().to_owned(), vec![]),
+            2 => (parts[0].trim().to_owned(), parts[1].trim().split(",").map(|s| s.trim().parse()).collect::<StdResult<Vec<_>, _>>().map_err(|_| header.error("invalid end-effector specifier"))?),
+            _ => Err(header.error("bad header"))?
         };
+
+        let mut vars = HashSet::new();
+        vars.insert(String::from("park"));
         let mut states = vec![];
-        
-        let mut i = 0;
-        while lines.peek().is_some() {
-            i += 1;
-            let header = lines.next().unwrap(); // ok because of the peek in the loop condition
-            
-            if header.is_empty() { continue; }
-            let header = header.split("=>").collect::<Vec<_>>();
-            if header.len() > 2 { Err(ParseFlow(i, "too many arrows"))?; }
-            
-            match header[0].chars().next() {
-                Some('-') => {},
-                Some(' ') => Err(ParseFlow(i, "command outside of state"))?,
-                _ => Err(ParseFlow(i, "unindented line"))?,
-            }
-            
-            let (name, park) = match header.len() {
-                1 => { (header[0][1..].trim().to_owned(), None) },
-                2 => { (header[1].trim().to_owned(),
-                        Some(match header[0][1..].trim() {
-                                ""          => ParkState::None,
-                                "BioTac"    => ParkState::BioTac,
-                                "OptoForce" => ParkState::OptoForce,
-                                "Stick"     => ParkState::Stick,
-                                _           => Err(ParseFlow(i, "bad trigger"))?,
-                            })
-                        )
-                      },
-                _ => unreachable!(), // due to early return above
+        while pos < lines.len() {
+            let line = &lines[pos];
+            pos += 1;
+
+            if line.indent > 0 { Err(line.error("command outside of state"))?; }
+            if !line.text.starts_with('-') { Err(line.error("unindented line"))?; }
+
+            let parts = line.text[1..].split("=>").collect::<Vec<_>>();
+            let (name, park) = match parts.len() {
+                1 => (parts[0].trim().to_owned(), None),
+                2 => (parts[1].trim().to_owned(),
+                      Some(match parts[0].trim() {
+                          ""          => ParkState::None,
+                          "BioTac"    => ParkState::BioTac,
+                          "OptoForce" => ParkState::OptoForce,
+                          "Stick"     => ParkState::Stick,
+                          _           => Err(line.error("bad trigger"))?,
+                      })),
+                _ => Err(line.error("too many arrows"))?,
             };
-            
-            let mut script = vec![];
-            
-            while lines.peek().is_some()
-                  && lines.peek().unwrap().starts_with(' ') { // ok because of the peek
-                i += 1;
-                let line = lines.next().unwrap(); // ok because of the peek in the loop condition
-                let line = line.trim();
-                
-                if        line.starts_with(':') {
-                    script.push((FlowCmd::Send(line[1..].trim().to_owned()), None));
-                } else if line.starts_with('"') {
-                    script.push((FlowCmd::Message(line[1..line.len()-1].to_owned()), None));
-                } else if line.starts_with('>') {
-                    let q1 = line.find('"').ok_or(ParseFlow(i, "expected quoted string"))?;
-                    let q2 = q1+1 + line[q1+1 ..].find('"').ok_or(ParseFlow(i, "unterminated string"))?;
-                    let s = line[q1+1 .. q2].trim();
-                    let range_start = line[q2+1 ..].find('(').map(|i| q2+1 + i);
-                    
-                    let mut cmd;
-                    if let Some(range_start) = range_start {
-                        let range_end = range_start+1 + line[range_start+1 ..].find(')').ok_or(ParseFlow(i, "unterminated range"))?;
-                        let range = line[range_start .. range_end+1].trim();
-                        if let Some(dots) = range.find("..") {
-                            let low = range[1..dots].parse().chain_err(|| ParseFlow(i, "bad start of range"))?;
-                            let high = range[dots+2..range.len()-1].parse().chain_err(|| ParseFlow(i, "bad end of range"))?;
-                            
-                            cmd = FlowCmd::int(s.to_owned(), (low, high));
-                        } else {
-                            cmd = FlowCmd::str(s.to_owned());
-                        }
-                    } else {
-                        cmd = FlowCmd::str(s.to_owned());
-                    }
 
-                    // look for answer
-                    let answer_start = line[q2+1 ..].find('[').map(|i| q2+1 + i);
-                    if let Some(answer_start) = answer_start {
-                        let answer_end = answer_start+1 + line[answer_start+1 ..].find(']').ok_or(ParseFlow(i, "unterminated answer"))?;
-                        let answer = line[answer_start+1 .. answer_end].trim();
-                        match cmd {
-                            FlowCmd::Int { ref mut data, .. } => *data = Some(answer.parse().map_err(|_| ParseFlow(i, "range answer not an integer"))?),
-                            FlowCmd::Str { ref mut data, .. } => *data = Some(answer.to_owned()),
-                            _ => unreachable!()
-                        }
-                    }
-
-                    script.push((cmd, None));
-                } else {
-                    let mut words = line.split(' ').map(str::trim);
-                    match words.next() {
-                        None | Some("") => Err(ParseFlow(i, "empty command"))?,
-                        Some("stop") => {
-                            let mut words = words.peekable();
-                            if words.peek().is_some() {
-                                for word in words {
-                                    script.push((FlowCmd::Stop(word.to_owned()), None));
-                                }
-                            } else {
-                                script.push((FlowCmd::StopSensors, None));
-                            }
-                        },
-                        Some("start") => {
-                            for word in words {
-                                let mut split = word.splitn(2, '/');
-                                script.push((FlowCmd::Start(split.next().unwrap().to_owned(),
-                                                // ok because splitn always returns at least one
-                                             split.next().map(|s| s.to_owned())), None));
-                            }
-                        },
-                        Some(_) => Err(ParseFlow(i, "invalid command"))?,
-                    }
-                }
+            let script = if pos < lines.len() && lines[pos].indent > 0 {
+                parse_block(&lines, &mut pos, &mut vars)?
+            } else {
+                vec![]
+            };
+            if pos < lines.len() && lines[pos].indent > 0 {
+                Err(lines[pos].error("inconsistent indentation"))?;
             }
-            
+
             states.push(FlowState::new(name, park, script));
         }
-        
+
         Ok(Flow::new(name, shortname, endeffs, states))
     }
-
 }
 
 impl FlowState {
-    pub fn new(name: String, park: Option<ParkState>, script: Vec<(FlowCmd, Option<DateTime<Local>>)>) -> FlowState {
+    pub fn new(name: String, park: Option<ParkState>, script: Script) -> FlowState {
         FlowState { name: name, park: park, script: script, stamp: None, done: false }
     }
 
-    pub fn run<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, file: &mut File, comms: C) -> Result<()> {
+    pub fn run<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, ctx: &RecordingContext, file: &mut File, comms: C, vars: &mut Vars, log: &mut Log) -> Result<()> {
         use self::ErrorKind::*;
 
         let stamp = self.stamp.put(Local::now());
         writeln!(file, "- {} [{}]", self.name, StampPrinter(stamp)).chain_err(|| Io("write to flow file".into()))?;
-        for &mut (ref mut c, ref mut stamp) in &mut self.script {
-            *stamp = Some(Local::now());
-            write!(file, "    ").chain_err(|| Io("write to flow file".into()))?;
-            c.run(tx, file, comms.clone())?;
-            writeln!(file, " [{}]", StampPrinter(stamp.as_ref().expect("missing timestamp"))).chain_err(|| Io("write to flow file".into()))?;
-        }
+        log.state(&self.name, *stamp);
+        run_script(&mut self.script, tx, ctx, file, comms, vars, log)?;
         self.done = true;
         
         Ok(())
     }
 
-    pub fn cleanup<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, comms: C) -> Result<()> {
-        for &mut (ref mut c, _) in self.script.iter_mut().rev() {
-            comms.print(format!("\t\tUndoing {:?}", c)).comms_err()?;
-            c.cleanup(tx)?;
-        }
-
-        Ok(())
+    pub fn cleanup<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, comms: C, vars: &Vars) -> Result<()> {
+        cleanup_script(&mut self.script, tx, comms, vars)
     }
 
     pub fn finalize(&mut self) -> Result<()> {
-        for &mut (ref mut c, ref mut stamp) in &mut self.script {
-            c.finalize()?;
-            *stamp = None;
-        }
+        finalize_script(&mut self.script)?;
         self.done = false;
         self.stamp = None;
 
@@ -507,27 +784,167 @@ impl FlowState {
     }
 }
 
+/// Run the commands in a script, writing each one to the flow file and the log
+///
+/// Blocks are not written themselves, only the commands they ran, so the flow file is a plain
+/// list of what happened.
+fn run_script<C: Comms, W: Write>(script: &mut Script, tx: &mpsc::Sender<CmdFrom>, ctx: &RecordingContext, file: &mut W, comms: C, vars: &mut Vars, log: &mut Log) -> Result<()> {
+    use self::ErrorKind::*;
+
+    for &mut (ref mut c, ref mut stamp) in script {
+        let stamp = *stamp.put(Local::now());
+        if c.is_block() {
+            c.run(tx, ctx, file, comms.clone(), vars, log)?;
+        } else {
+            let mut line = vec![];
+            c.run(tx, ctx, &mut line, comms.clone(), vars, log)?;
+            let line = String::from_utf8(line).expect("flow command wrote invalid UTF-8");
+            writeln!(file, "    {} [{}]", line, StampPrinter(&stamp)).chain_err(|| Io("write to flow file".into()))?;
+            log.command(line, stamp);
+        }
+    }
+
+    Ok(())
+}
+
+/// Undo the commands in a script that has run, in reverse order
+fn cleanup_script<C: Comms>(script: &mut Script, tx: &mpsc::Sender<CmdFrom>, comms: C, vars: &Vars) -> Result<()> {
+    for &mut (ref mut c, _) in script.iter_mut().rev() {
+        if !c.is_block() {
+            comms.print(format!("\t\tUndoing {:?}", c)).comms_err()?;
+        }
+        c.cleanup(tx, comms.clone(), vars)?;
+    }
+
+    Ok(())
+}
+
+/// Forget answers and timestamps so that a script can run again
+fn finalize_script(script: &mut Script) -> Result<()> {
+    for &mut (ref mut c, ref mut stamp) in script {
+        c.finalize()?;
+        *stamp = None;
+    }
+
+    Ok(())
+}
+
+/// Deliver a "<service> <command>" line and wait for the service to accept the command
+fn send(tx: &mpsc::Sender<CmdFrom>, string: &str) -> Result<()> {
+    let mut words = string.trim().splitn(2, ' ');
+    let service = words.next().unwrap_or("").to_owned();
+    let cmd = words.next().unwrap_or("").trim().to_owned();
+
+    rpc!(tx, CmdFrom::Send, service, cmd)
+        .chain_err(|| ErrorKind::Rpc(format!("send to {}", string)))?
+        .map_err(|reason| ErrorKind::Rejected(string.to_owned(), reason).into())
+}
+
+/// Start a service (recording into `ctx`) and wait until it is running
+fn start(tx: &mpsc::Sender<CmdFrom>, ctx: &RecordingContext, service: &str, data: Option<String>) -> Result<()> {
+    let action = format!("start {}", service);
+    match comms::start(tx, service, data, ctx.clone(), Duration::from_millis(config::get().services.lifecycle_timeout_ms)).chain_err(|| ErrorKind::Rpc(action.clone()))? {
+        Status::Running => Ok(()),
+        status => Err(ErrorKind::Lifecycle(action, status).into()),
+    }
+}
+
+/// Stop a service and wait until it has been torn down
+fn stop(tx: &mpsc::Sender<CmdFrom>, service: &str) -> Result<()> {
+    let action = format!("stop {}", service);
+    match comms::stop(tx, service, Duration::from_millis(config::get().services.lifecycle_timeout_ms)).chain_err(|| ErrorKind::Rpc(action.clone()))? {
+        Status::Stopped => Ok(()),
+        status => Err(ErrorKind::Lifecycle(action, status).into()),
+    }
+}
+
+/// Wait until a service is running (according to the supervisor's health reports)
+fn wait_ready(tx: &mpsc::Sender<CmdFrom>, service: &str) -> Result<()> {
+    let action = format!("wait until {} ready", service);
+    let deadline = Instant::now() + Duration::from_millis(config::get().flows.ready_timeout_ms);
+    loop {
+        let reports = rpc!(tx, CmdFrom::Health).chain_err(|| ErrorKind::Rpc(action.clone()))?;
+        match reports.into_iter().find(|r| r.name == service) {
+            Some(health::Report { state: health::State::Running, .. }) |
+            Some(health::Report { state: health::State::Slow, .. }) => return Ok(()),
+            Some(_) => {},
+            None => bail!(ErrorKind::Rejected(action, "no such service".into())),
+        }
+
+        if Instant::now() >= deadline {
+            bail!(ErrorKind::Timeout(format!("{} to be ready", service)));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+impl Cond {
+    /// Check the condition against the current values of the variables
+    pub fn eval(&self, vars: &Vars) -> Result<bool> {
+        let value = vars.get(&self.var).ok_or_else(|| ErrorKind::Undefined(self.var.clone()))?;
+        Ok(match self.op {
+            Op::Eq => *value == self.value,
+            Op::Ne => *value != self.value,
+            op => {
+                let lhs = value.parse::<i64>().map_err(|_| ErrorKind::NotANumber(self.var.clone(), value.clone()))?;
+                let rhs = self.value.parse::<i64>().expect("comparison with a non-number"); // checked by the parser
+                match op {
+                    Op::Lt => lhs < rhs,
+                    Op::Le => lhs <= rhs,
+                    Op::Gt => lhs > rhs,
+                    Op::Ge => lhs >= rhs,
+                    Op::Eq | Op::Ne => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+impl Count {
+    /// Number of times to repeat, given the current values of the variables
+    pub fn resolve(&self, vars: &Vars) -> Result<u32> {
+        match *self {
+            Count::Times(n) => Ok(n),
+            Count::Var(ref var) => {
+                let value = vars.get(var).ok_or_else(|| ErrorKind::Undefined(var.clone()))?;
+                Ok(value.parse().map_err(|_| ErrorKind::NotANumber(var.clone(), value.clone()))?)
+            }
+        }
+    }
+}
+
 impl FlowCmd {
     pub fn str(prompt: String) -> FlowCmd {
-        FlowCmd::Str { prompt: prompt, data: None }
+        FlowCmd::Str { prompt: prompt, var: None, data: None }
     }
 
     pub fn int(prompt: String, limits: (i32, i32)) -> FlowCmd {
-        FlowCmd::Int { prompt: prompt, limits: limits, data: None }
+        FlowCmd::Int { prompt: prompt, limits: limits, var: None, data: None }
     }
 
-    pub fn run<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, file: &mut File, comms: C) -> Result<()> {
+    /// Does this command contain other commands?
+    pub fn is_block(&self) -> bool {
+        match *self {
+            FlowCmd::If { .. } | FlowCmd::Repeat { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Run the command (services record into `ctx`), writing it (as it should appear in the flow file) to `file`
+    pub fn run<C: Comms, W: Write>(&mut self, tx: &mpsc::Sender<CmdFrom>, ctx: &RecordingContext, file: &mut W, comms: C, vars: &mut Vars, log: &mut Log) -> Result<()> {
         use self::ErrorKind::*;
 
         match *self {
             FlowCmd::Message(ref msg) => {
+                let msg = interpolate(msg, vars)?;
                 comms.send(format!("msg {}", msg)).comms_err()?;
 
-                write!(file, "{:?}", msg).chain_err(|| Io("write to flow file".into()))?;
+                write!(file, "{:?}", escape(&msg)).chain_err(|| Io("write to flow file".into()))?;
             }
 
-            FlowCmd::Str { ref prompt, ref mut data } => {
+            FlowCmd::Str { ref prompt, ref var, ref mut data } => {
                 assert!(data.is_none());
+                let prompt = interpolate(prompt, vars)?;
                 let data = data.put(comms.rpc(format!("prompt Please enter {}",
                                                prompt),
                                        |x| {
@@ -539,11 +956,18 @@ impl FlowCmd {
                                        }).comms_err()?
                                        .ok_or(ErrorKind::FlowCanceled)?);
 
-                write!(file, "> {:?} [{:?}]", prompt, data).chain_err(|| Io("write to flow file".into()))?;
+                write!(file, "> ").chain_err(|| Io("write to flow file".into()))?;
+                if let Some(ref var) = *var {
+                    vars.insert(var.clone(), data.clone());
+                    write!(file, "{} = ", var).chain_err(|| Io("write to flow file".into()))?;
+                }
+                write!(file, "{:?} [{:?}]", escape(&prompt), data).chain_err(|| Io("write to flow file".into()))?;
+                log.answer(&prompt, var.clone(), Value::Str(data.clone()));
             }
 
-            FlowCmd::Int { ref prompt, limits: (low, high), ref mut data } => {
+            FlowCmd::Int { ref prompt, limits: (low, high), ref var, ref mut data } => {
                 assert!(data.is_none());
+                let prompt = interpolate(prompt, vars)?;
                 let data = data.put(comms.rpc(format!("prompt Please select {} ({}-{} scale)",
                                                prompt, low, high),
                                        |x| {
@@ -561,59 +985,109 @@ impl FlowCmd {
                                        }).comms_err()?
                                        .ok_or(ErrorKind::FlowCanceled)?);
 
-                write!(file, "> {:?} ({}..{}) [{:?}]", prompt, low, high, data).chain_err(|| Io("write to flow file".into()))?;
+                write!(file, "> ").chain_err(|| Io("write to flow file".into()))?;
+                if let Some(ref var) = *var {
+                    vars.insert(var.clone(), data.to_string());
+                    write!(file, "{} = ", var).chain_err(|| Io("write to flow file".into()))?;
+                }
+                write!(file, "{:?} ({}..{}) [{:?}]", escape(&prompt), low, high, data).chain_err(|| Io("write to flow file".into()))?;
+                log.answer(&prompt, var.clone(), Value::Int(*data));
             }
 
             FlowCmd::Start(ref service, ref data) => {
+                let data = match *data {
+                    Some(ref data) => Some(interpolate(data, vars)?),
+                    None => None,
+                };
                 comms.print(format!("Flow starting service {}", service)).comms_err()?;
-                rpc!(tx, CmdFrom::Start, service.clone(), data.clone()).chain_err(|| Rpc(format!("start {}", service)))?;
-                comms.print(format!("Flow waiting for service {} to start", service)).comms_err()?;
-                thread::sleep(Duration::from_millis(2000));
-                comms.print(format!("Flow done waiting for service {}", service)).comms_err()?;
+                start(tx, ctx, service, data.clone())?;
+                log.service(service, data.clone());
+                comms.print(format!("Flow started service {}", service)).comms_err()?;
 
                 write!(file, "start {}", service).chain_err(|| Io("write to flow file".into()))?;
-                if let Some(ref data) = *data {
-                    write!(file, "/{}", data).chain_err(|| Io("write to flow file".into()))?;
+                if let Some(ref data) = data {
+                    write!(file, "/{}", escape(data)).chain_err(|| Io("write to flow file".into()))?;
                 }
             }
 
             FlowCmd::Stop(ref service) => {
-                rpc!(tx, CmdFrom::Stop, service.clone()).chain_err(|| Rpc(format!("stop {}", service)))?;
+                stop(tx, service)?;
+                log.stopped(service);
 
                 write!(file, "stop {}", service).chain_err(|| Io("write to flow file".into()))?;
             }
 
             FlowCmd::Send(ref string) => {
-                tx.send(CmdFrom::Data(String::from("to ") + string)).chain_err(|| Rpc(format!("send to {}", string)))?;
+                let string = interpolate(string, vars)?;
+                send(tx, &string).chain_err(|| Rpc(format!("send to {}", string)))?;
 
-                write!(file, ": {}", string).chain_err(|| Io("write to flow file".into()))?;
+                write!(file, ": {}", escape(&string)).chain_err(|| Io("write to flow file".into()))?;
             }
 
             FlowCmd::StopSensors => {
                 for &svc in &["bluefox", "structure", "biotac", "optoforce", "teensy"] {
-                    rpc!(tx, CmdFrom::Stop, svc.to_owned()).chain_err(|| Rpc(format!("stop {}", svc)))?;
+                    stop(tx, svc)?;
+                    log.stopped(svc);
                 }
 
                 write!(file, "stop").chain_err(|| Io("write to flow file".into()))?;
             }
+
+            FlowCmd::Wait(ms) => {
+                thread::sleep(Duration::from_millis(ms));
+
+                write!(file, "wait {}", ms).chain_err(|| Io("write to flow file".into()))?;
+            }
+
+            FlowCmd::WaitReady(ref service) => {
+                comms.print(format!("Flow waiting for service {}", service)).comms_err()?;
+                wait_ready(tx, service)?;
+
+                write!(file, "wait until {} ready", service).chain_err(|| Io("write to flow file".into()))?;
+            }
+
+            FlowCmd::If { ref cond, ref mut then, ref mut otherwise, ref mut taken } => {
+                let branch = cond.eval(vars)?;
+                *taken = Some(branch);
+                run_script(if branch { then } else { otherwise }, tx, ctx, file, comms, vars, log)?;
+            }
+
+            FlowCmd::Repeat { ref count, ref body, ref mut runs } => {
+                for _ in 0..count.resolve(vars)? {
+                    runs.push(body.clone());
+                    run_script(runs.last_mut().unwrap(), tx, ctx, file, comms.clone(), vars, log)?; // ok because of the push
+                }
+            }
         }
 
         Ok(())
     }
 
-    pub fn cleanup(&mut self, tx: &mpsc::Sender<CmdFrom>) -> Result<()> {
+    pub fn cleanup<C: Comms>(&mut self, tx: &mpsc::Sender<CmdFrom>, comms: C, vars: &Vars) -> Result<()> {
         use self::ErrorKind::*;
 
         match *self {
             FlowCmd::Start(ref service, _) => {
                 // if a service was started, stop it
-                rpc!(tx, CmdFrom::Stop, service.clone()).chain_err(|| Rpc(format!("[cleanup] stop {}", service)))?;
+                stop(tx, service).chain_err(|| Rpc(format!("[cleanup] stop {}", service)))?;
             }
             FlowCmd::Send(ref string) => {
                 // if a "disk start" message was sent, send a "disk stop"
                 if string.contains("disk start") {
-                    let string = &string.replace("disk start", "disk stop");
-                    tx.send(CmdFrom::Data(String::from("to ") + string)).chain_err(|| Rpc(format!("[cleanup] send to {}", string)))?;
+                    let string = &interpolate(&string.replace("disk start", "disk stop"), vars)?;
+                    send(tx, string).chain_err(|| Rpc(format!("[cleanup] send to {}", string)))?;
+                }
+            }
+            FlowCmd::If { ref mut then, ref mut otherwise, taken, .. } => {
+                match taken {
+                    Some(true) => cleanup_script(then, tx, comms, vars)?,
+                    Some(false) => cleanup_script(otherwise, tx, comms, vars)?,
+                    None => {}
+                }
+            }
+            FlowCmd::Repeat { ref mut runs, .. } => {
+                for run in runs.iter_mut().rev() {
+                    cleanup_script(run, tx, comms.clone(), vars)?;
                 }
             }
             _ => { /* nothing to do */ }
@@ -626,10 +1100,15 @@ impl FlowCmd {
         match *self {
             FlowCmd::Str { ref mut data, .. } => *data = None,
             FlowCmd::Int { ref mut data, .. } => *data = None,
+            FlowCmd::If { ref mut then, ref mut otherwise, ref mut taken, .. } => {
+                finalize_script(then)?;
+                finalize_script(otherwise)?;
+                *taken = None;
+            }
+            FlowCmd::Repeat { ref mut runs, .. } => runs.clear(),
             _ => {}
         }
 
         Ok(())
     }
 }
-
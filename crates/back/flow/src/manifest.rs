@@ -0,0 +1,247 @@
+//! Machine-readable record of an episode, written next to the flow transcript
+//!
+//! The `.flow` transcript is meant for humans. Everything that later tools need to know about an
+//! episode (answers, timings, services, sensors and the files that were produced) goes into
+//! `manifest.json` instead, so that they don't have to parse the transcript.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use chrono::{DateTime, Local};
+use scribe::sink::crc32_update;
+use uuid::Uuid;
+use serde_json;
+use super::{Flow, Result, ResultExt, ErrorKind};
+
+/// Layout version of the manifest (increment when fields change meaning)
+pub const VERSION: u32 = 1;
+
+/// Name of the manifest in each episode directory
+pub const FILE_NAME: &'static str = "manifest.json";
+
+/// Version of the software, as `git describe` saw it at build time
+pub const REVISION: Option<&'static str> = option_env!("NRI_REVISION");
+
+/// Everything about one episode
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Name of the flow
+    pub flow: String,
+    pub shortname: String,
+    pub id: Uuid,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    /// Version of the software that recorded the episode (if it was built from git)
+    pub revision: Option<String>,
+    /// Operator answers to prompts, in order
+    pub answers: Vec<Answer>,
+    /// States in the order they ran, with the commands that ran in each
+    pub states: Vec<StateRecord>,
+    /// Services started by the flow, with their start parameters
+    pub services: Vec<ServiceRecord>,
+    /// Times the flow was resumed after being interrupted (see `Flow::resume`)
+    pub resumed: Vec<DateTime<Local>>,
+    /// Serial numbers of the sensors, by service
+    pub serials: BTreeMap<String, String>,
+    /// Every file in the episode directory (except the manifest itself)
+    pub files: Vec<FileRecord>,
+}
+
+/// Answer to a prompt
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Str(String),
+    Int(i32),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Answer {
+    /// State in which the prompt was shown
+    pub state: String,
+    pub prompt: String,
+    /// Variable set to the answer
+    pub var: Option<String>,
+    pub value: Value,
+    pub stamp: DateTime<Local>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateRecord {
+    pub name: String,
+    pub started: DateTime<Local>,
+    pub commands: Vec<CommandRecord>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandRecord {
+    /// The command as written in the transcript
+    pub command: String,
+    pub stamp: DateTime<Local>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceRecord {
+    pub name: String,
+    /// Start parameter (with variables filled in)
+    pub data: Option<String>,
+    pub started: DateTime<Local>,
+    /// When the flow stopped the service (or found it dead when resuming after an interruption)
+    pub stopped: Option<DateTime<Local>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// Path relative to the episode directory
+    pub path: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// What has happened so far in a run of a flow
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Log {
+    pub answers: Vec<Answer>,
+    pub states: Vec<StateRecord>,
+    pub services: Vec<ServiceRecord>,
+    pub resumed: Vec<DateTime<Local>>,
+}
+
+impl Log {
+    /// A state started running
+    pub fn state(&mut self, name: &str, stamp: DateTime<Local>) {
+        self.states.push(StateRecord { name: name.into(), started: stamp, commands: vec![] });
+    }
+
+    /// A command finished running in the current state
+    pub fn command(&mut self, command: String, stamp: DateTime<Local>) {
+        self.states.last_mut().expect("command outside of a state").commands.push(CommandRecord { command: command, stamp: stamp });
+    }
+
+    /// The operator answered a prompt in the current state
+    pub fn answer(&mut self, prompt: &str, var: Option<String>, value: Value) {
+        let state = self.states.last().expect("prompt outside of a state").name.clone();
+        self.answers.push(Answer { state: state, prompt: prompt.into(), var: var, value: value, stamp: Local::now() });
+    }
+
+    /// A service was started
+    pub fn service(&mut self, name: &str, data: Option<String>) {
+        self.services.push(ServiceRecord { name: name.into(), data: data, started: Local::now(), stopped: None });
+    }
+
+    /// A service was stopped
+    pub fn stopped(&mut self, name: &str) {
+        for service in self.services.iter_mut().filter(|s| s.name == name && s.stopped.is_none()) {
+            service.stopped = Some(Local::now());
+        }
+    }
+
+    /// Services that have been started and not stopped
+    pub fn running(&self) -> Vec<ServiceRecord> {
+        self.services.iter().filter(|s| s.stopped.is_none()).cloned().collect()
+    }
+}
+
+impl Manifest {
+    /// Put together the manifest for a flow that has just finished (in the episode directory `dir`)
+    ///
+    /// This reads every file in the directory, so it can take a while.
+    pub fn gather(flow: &Flow, log: Log, serials: BTreeMap<String, String>, dir: &Path) -> Result<Manifest> {
+        let mut files = vec![];
+        list_files(dir, dir, &mut files).chain_err(|| ErrorKind::Io(format!("list files in {:?}", dir)))?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Manifest {
+            version: VERSION,
+            flow: flow.name.clone(),
+            shortname: flow.shortname.clone(),
+            id: flow.id.expect("finished flow without an ID"),
+            started: flow.stamp.expect("finished flow without a timestamp"),
+            finished: Local::now(),
+            revision: REVISION.map(String::from),
+            answers: log.answers,
+            states: log.states,
+            services: log.services,
+            resumed: log.resumed,
+            serials: serials,
+            files: files,
+        })
+    }
+
+    /// Read the manifest from an episode directory
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Manifest> {
+        let path = dir.as_ref().join(FILE_NAME);
+        let file = File::open(&path).chain_err(|| ErrorKind::Io(format!("open {:?}", path)))?;
+        Ok(serde_json::from_reader(file).chain_err(|| ErrorKind::Io(format!("read {:?}", path)))?)
+    }
+
+    /// Write the manifest into an episode directory
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let path = dir.as_ref().join(FILE_NAME);
+        let file = File::create(&path).chain_err(|| ErrorKind::Io(format!("create {:?}", path)))?;
+        serde_json::to_writer_pretty(file, self).chain_err(|| ErrorKind::Io(format!("write {:?}", path)))?;
+        Ok(())
+    }
+
+    /// The answer to a prompt, if it was asked
+    pub fn answer(&self, prompt: &str) -> Option<&Value> {
+        self.answers.iter().find(|a| a.prompt == prompt).map(|a| &a.value)
+    }
+
+    /// Was a service started during the episode?
+    pub fn ran(&self, service: &str) -> bool {
+        self.services.iter().any(|s| s.name == service)
+    }
+}
+
+impl FileRecord {
+    /// Measure and checksum a file in the episode directory `dir`
+    pub fn read(dir: &Path, path: &Path) -> io::Result<FileRecord> {
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; 1 << 20];
+        let mut size = 0;
+        let mut crc = 0;
+        loop {
+            match file.read(&mut buf)? {
+                0 => break,
+                n => {
+                    size += n as u64;
+                    crc = crc32_update(crc, &buf[..n]);
+                }
+            }
+        }
+
+        Ok(FileRecord {
+            path: path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned(),
+            size: size,
+            crc32: crc,
+        })
+    }
+
+    /// Compare the file in the episode directory `dir` with this record (returns what is wrong with it, if anything)
+    pub fn check(&self, dir: &Path) -> io::Result<Option<String>> {
+        let actual = FileRecord::read(dir, &dir.join(&self.path))?;
+        Ok(if actual.size != self.size {
+            Some(format!("size is {} bytes (expected {})", actual.size, self.size))
+        } else if actual.crc32 != self.crc32 {
+            Some(format!("CRC is {:08x} (expected {:08x})", actual.crc32, self.crc32))
+        } else {
+            None
+        })
+    }
+}
+
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<FileRecord>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            list_files(root, &path, files)?;
+        } else if path != root.join(FILE_NAME) {
+            files.push(FileRecord::read(root, &path)?);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,355 @@
+//! Reading flow source: includes, indentation, commands and variable mentions
+//!
+//! See `Flow::parse` for the grammar.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use teensy::ParkState;
+use super::{ErrorKind, Result, ResultExt, FlowCmd, Script, Cond, Op, Count, Vars};
+
+/// Extension of files that can be included into flows
+pub const SUBFLOW_EXT: &'static str = "subflow";
+
+/// A non-blank line of flow source, remembering where it came from
+pub struct Line {
+    source: String,
+    number: usize,
+    pub indent: usize,
+    pub text: String,
+}
+
+impl Line {
+    /// Syntax error pointing at this line
+    pub fn error<S: Into<String>>(&self, msg: S) -> ErrorKind {
+        ErrorKind::ParseFlow(self.source.clone(), self.number, msg.into())
+    }
+}
+
+/// Recorded flows end each line with a timestamp like `[1500000000.123456789]`, which is ignored
+fn strip_stamp(line: &str) -> &str {
+    if line.ends_with(']') {
+        if let Some(open) = line.rfind('[') {
+            let inner = &line[open+1 .. line.len()-1];
+            if inner.contains('.') && inner.parse::<f64>().is_ok() {
+                return line[..open].trim_right();
+            }
+        }
+    }
+    line
+}
+
+/// Read the non-blank lines of a flow, pasting in included sub-flows
+///
+/// `indent` is added to the indentation of every line (for sub-flows included inside a state).
+/// `including` is the chain of sub-flows being read, to catch includes that go in circles.
+pub fn read_lines<R: BufRead>(source: &str, reader: R, dir: &Path, indent: usize, including: &mut Vec<PathBuf>, lines: &mut Vec<Line>) -> Result<()> {
+    for (i, text) in reader.lines().enumerate() {
+        let number = i + 1;
+        let text = text.chain_err(|| ErrorKind::ParseFlow(source.into(), number, "I/O error".into()))?;
+        let text = strip_stamp(text.trim_right());
+        let body = text.trim_left_matches(' ');
+        if body.is_empty() { continue; }
+
+        let line = Line {
+            source: source.into(),
+            number: number,
+            indent: indent + text.len() - body.len(),
+            text: body.to_owned(),
+        };
+        if body.starts_with('\t') { Err(line.error("tab in indentation"))?; }
+
+        if body == "include" || body.starts_with("include ") {
+            let name = body["include".len()..].trim();
+            if name.is_empty() { Err(line.error("missing sub-flow name"))?; }
+            let path = dir.join(format!("{}.{}", name, SUBFLOW_EXT));
+            if including.contains(&path) { Err(line.error(format!("{:?} includes itself", path)))?; }
+            let file = File::open(&path).chain_err(|| line.error(format!("could not open sub-flow {:?}", path)))?;
+
+            including.push(path.clone());
+            read_lines(&path.display().to_string(), BufReader::new(file), path.parent().unwrap_or(dir), line.indent, including, lines)?;
+            including.pop();
+        } else {
+            lines.push(line);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse the commands indented like `lines[*pos]`, stopping at the first line indented less
+///
+/// `vars` holds the variables that have been set by the lines so far.
+pub fn parse_block(lines: &[Line], pos: &mut usize, vars: &mut HashSet<String>) -> Result<Script> {
+    let indent = lines[*pos].indent;
+    let mut script = vec![];
+    while *pos < lines.len() && lines[*pos].indent >= indent {
+        let line = &lines[*pos];
+        if line.indent > indent { Err(line.error("unexpected indentation"))?; }
+        *pos += 1;
+
+        for cmd in parse_command(line, lines, pos, vars)? {
+            script.push((cmd, None));
+        }
+    }
+    Ok(script)
+}
+
+/// Parse the block indented under `header`
+fn parse_body(header: &Line, lines: &[Line], pos: &mut usize, vars: &mut HashSet<String>) -> Result<Script> {
+    if *pos < lines.len() && lines[*pos].indent > header.indent {
+        parse_block(lines, pos, vars)
+    } else {
+        Err(header.error("expected an indented block").into())
+    }
+}
+
+/// Parse one command (reading the rest of its block, if it has one)
+fn parse_command(line: &Line, lines: &[Line], pos: &mut usize, vars: &mut HashSet<String>) -> Result<Vec<FlowCmd>> {
+    let text = &line.text[..];
+    let mut cmds = vec![];
+
+    if text.starts_with(':') {
+        let cmd = text[1..].trim();
+        check_mentions(line, cmd, vars)?;
+        cmds.push(FlowCmd::Send(cmd.to_owned()));
+    } else if text.starts_with('"') {
+        if text.len() < 2 || !text.ends_with('"') { Err(line.error("unterminated string"))?; }
+        let msg = &text[1..text.len()-1];
+        check_mentions(line, msg, vars)?;
+        cmds.push(FlowCmd::Message(msg.to_owned()));
+    } else if text.starts_with('>') {
+        cmds.push(parse_prompt(line, vars)?);
+    } else {
+        let mut words = text.split_whitespace();
+        match words.next() {
+            None => Err(line.error("empty command"))?,
+            Some("stop") => {
+                let mut words = words.peekable();
+                if words.peek().is_some() {
+                    for word in words {
+                        cmds.push(FlowCmd::Stop(word.to_owned()));
+                    }
+                } else {
+                    cmds.push(FlowCmd::StopSensors);
+                }
+            },
+            Some("start") => {
+                for word in words {
+                    let mut split = word.splitn(2, '/');
+                    let service = split.next().unwrap().to_owned(); // ok because splitn always returns at least one
+                    let data = split.next().map(|s| s.to_owned());
+                    if let Some(ref data) = data {
+                        check_mentions(line, data, vars)?;
+                    }
+                    cmds.push(FlowCmd::Start(service, data));
+                }
+            },
+            Some("wait") => {
+                let args = words.collect::<Vec<_>>();
+                if args.len() == 3 && args[0] == "until" && args[2] == "ready" {
+                    cmds.push(FlowCmd::WaitReady(args[1].to_owned()));
+                } else if args.len() == 1 {
+                    cmds.push(FlowCmd::Wait(args[0].parse().map_err(|_| line.error("bad wait time (expected milliseconds)"))?));
+                } else {
+                    Err(line.error("expected `wait <milliseconds>` or `wait until <service> ready`"))?;
+                }
+            },
+            Some("if") => {
+                let cond = parse_cond(line, text["if".len()..].trim(), vars)?;
+                // only one branch runs, so afterwards only what both of them bind is known
+                let mut then_vars = vars.clone();
+                let mut otherwise_vars = vars.clone();
+                let then = parse_body(line, lines, pos, &mut then_vars)?;
+                let otherwise = if *pos < lines.len() && lines[*pos].indent == line.indent && lines[*pos].text == "else" {
+                    let header = &lines[*pos];
+                    *pos += 1;
+                    parse_body(header, lines, pos, &mut otherwise_vars)?
+                } else {
+                    vec![]
+                };
+                *vars = then_vars.intersection(&otherwise_vars).cloned().collect();
+                cmds.push(FlowCmd::If { cond: cond, then: then, otherwise: otherwise, taken: None });
+            },
+            Some("else") => Err(line.error("else without if"))?,
+            Some("repeat") => {
+                let count = match (words.next(), words.next()) {
+                    (Some(n), None) => match n.parse() {
+                        Ok(n) => Count::Times(n),
+                        Err(_) => {
+                            check_var(line, n, vars)?;
+                            Count::Var(n.to_owned())
+                        }
+                    },
+                    _ => Err(line.error("expected `repeat <count>`"))?,
+                };
+                let body = parse_body(line, lines, pos, vars)?;
+                cmds.push(FlowCmd::Repeat { count: count, body: body, runs: vec![] });
+            },
+            Some(_) => Err(line.error("invalid command"))?,
+        }
+    }
+
+    Ok(cmds)
+}
+
+/// Parse a prompt like `> name = "prompt" (1..5)`, with an answer in brackets if it was recorded
+fn parse_prompt(line: &Line, vars: &mut HashSet<String>) -> Result<FlowCmd> {
+    let text = &line.text[1..];
+
+    let q1 = text.find('"').ok_or_else(|| line.error("expected quoted string"))?;
+    let var = match text[..q1].trim() {
+        "" => None,
+        binding => {
+            if !binding.ends_with('=') { Err(line.error("expected `name =` before the prompt"))?; }
+            let name = binding[..binding.len()-1].trim();
+            if !is_name(name) { Err(line.error(format!("bad variable name {:?}", name)))?; }
+            if name == "park" { Err(line.error("the variable `park` is set automatically"))?; }
+            Some(name.to_owned())
+        }
+    };
+    let q2 = q1+1 + text[q1+1 ..].find('"').ok_or_else(|| line.error("unterminated string"))?;
+    let prompt = text[q1+1 .. q2].trim().to_owned();
+    check_mentions(line, &prompt, vars)?;
+
+    let mut cmd = FlowCmd::Str { prompt: prompt.clone(), var: var.clone(), data: None };
+    if let Some(range_start) = text[q2+1 ..].find('(').map(|i| q2+1 + i) {
+        let range_end = range_start+1 + text[range_start+1 ..].find(')').ok_or_else(|| line.error("unterminated range"))?;
+        let range = text[range_start .. range_end+1].trim();
+        if let Some(dots) = range.find("..") {
+            let low = range[1..dots].parse().map_err(|_| line.error("bad start of range"))?;
+            let high = range[dots+2..range.len()-1].parse().map_err(|_| line.error("bad end of range"))?;
+            cmd = FlowCmd::Int { prompt: prompt, limits: (low, high), var: var.clone(), data: None };
+        }
+    }
+
+    // look for answer
+    if let Some(answer_start) = text[q2+1 ..].find('[').map(|i| q2+1 + i) {
+        let answer_end = answer_start+1 + text[answer_start+1 ..].find(']').ok_or_else(|| line.error("unterminated answer"))?;
+        let answer = text[answer_start+1 .. answer_end].trim();
+        match cmd {
+            FlowCmd::Int { ref mut data, .. } => *data = Some(answer.parse().map_err(|_| line.error("range answer not an integer"))?),
+            FlowCmd::Str { ref mut data, .. } => *data = Some(answer.to_owned()),
+            _ => unreachable!()
+        }
+    }
+
+    if let Some(name) = var {
+        vars.insert(name);
+    }
+    Ok(cmd)
+}
+
+/// Parse a condition like `name == "value"`
+fn parse_cond(line: &Line, text: &str, vars: &HashSet<String>) -> Result<Cond> {
+    const USAGE: &'static str = "expected a condition like `name == \"value\"`";
+
+    let mut words = text.split_whitespace();
+    let (var, op) = match (words.next(), words.next()) {
+        (Some(var), Some(op)) => (var, op),
+        _ => Err(line.error(USAGE))?,
+    };
+    let value = text[var.len()..].trim_left()[op.len()..].trim();
+    if value.is_empty() { Err(line.error(USAGE))?; }
+
+    let op = match op {
+        "==" => Op::Eq,
+        "!=" => Op::Ne,
+        "<"  => Op::Lt,
+        "<=" => Op::Le,
+        ">"  => Op::Gt,
+        ">=" => Op::Ge,
+        _    => Err(line.error(format!("unknown comparison {:?}", op)))?,
+    };
+    let mut value = if value.starts_with('"') {
+        if value.len() < 2 || !value.ends_with('"') { Err(line.error("unterminated string"))?; }
+        value[1..value.len()-1].to_owned()
+    } else {
+        value.to_owned()
+    };
+
+    check_var(line, var, vars)?;
+    if var == "park" {
+        let state = value.parse::<ParkState>().map_err(|_| line.error(format!("unknown parking lot state {:?}", value)))?;
+        if op != Op::Eq && op != Op::Ne { Err(line.error("the parking lot state can only be compared with == or !="))?; }
+        value = format!("{:?}", state);
+    } else if op != Op::Eq && op != Op::Ne && value.parse::<i64>().is_err() {
+        Err(line.error(format!("expected a number to compare with, not {:?}", value)))?;
+    }
+
+    Ok(Cond { var: var.to_owned(), op: op, value: value })
+}
+
+fn is_name(s: &str) -> bool {
+    s.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Check that a variable has been set before this line
+fn check_var(line: &Line, name: &str, vars: &HashSet<String>) -> Result<()> {
+    if !is_name(name) {
+        Err(line.error(format!("bad variable name {:?}", name)).into())
+    } else if !vars.contains(name) {
+        Err(line.error(format!("variable {:?} is used before it is set", name)).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the variables mentioned in a string have been set before this line
+fn check_mentions(line: &Line, s: &str, vars: &HashSet<String>) -> Result<()> {
+    for piece in pieces(s) {
+        if let Piece::Var(name) = piece {
+            check_var(line, name, vars)?;
+        }
+    }
+    Ok(())
+}
+
+/// Part of a string that may mention variables
+enum Piece<'a> {
+    Text(&'a str),
+    Var(&'a str),
+}
+
+/// Split a string into text and `$name` mentions
+fn pieces(s: &str) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        pieces.push(Piece::Text(&rest[..i]));
+        let after = &rest[i+1 ..];
+        if after.starts_with('$') {
+            pieces.push(Piece::Text("$"));
+            rest = &after[1..];
+        } else if after.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_') {
+            let len = after.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(after.len());
+            pieces.push(Piece::Var(&after[..len]));
+            rest = &after[len..];
+        } else {
+            // a lone dollar sign
+            pieces.push(Piece::Text("$"));
+            rest = after;
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+/// Replace `$name` mentions with the values of the variables
+pub fn interpolate(s: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    for piece in pieces(s) {
+        match piece {
+            Piece::Text(text) => out.push_str(text),
+            Piece::Var(name) => out.push_str(vars.get(name).ok_or_else(|| ErrorKind::Undefined(name.into()))?),
+        }
+    }
+    Ok(out)
+}
+
+/// Protect dollar signs in text that has already been interpolated (for writing to the flow file)
+pub fn escape(s: &str) -> String {
+    s.replace('$', "$$")
+}
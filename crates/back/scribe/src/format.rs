@@ -0,0 +1,486 @@
+//! Self-describing on-disk format for files of `Writable` packets
+//!
+//! A file written by `Writer<T: Writable>` starts with a header:
+//!
+//! * the magic bytes `MAGIC`
+//! * the format version (u16, little endian)
+//! * the length of the rest of the header (u32, little endian)
+//! * the rest of the header: a `Header`, in JSON
+//!
+//! and then the packets, back to back, as raw memory dumps in the layout given by the header's
+//! schema. Files written before the header existed (format version 0) are just the packets, so
+//! the `Reader` needs to be told the schema they were written with.
+
+use std::{fmt, mem, ptr};
+use std::fs::File;
+use std::io::{self, Read, BufReader};
+use std::path::Path;
+use serde_json;
+use time;
+use Writable;
+
+/// First bytes of every file with a header
+pub const MAGIC: &'static [u8; 8] = b"NRIscrib";
+
+/// Current format version (0 is reserved for headerless files)
+pub const VERSION: u16 = 1;
+
+/// Primitive field types
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Prim {
+    U8, I8, U16, I16, U32, I32, U64, I64, F32, F64,
+}
+
+impl Prim {
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            Prim::U8  | Prim::I8                => 1,
+            Prim::U16 | Prim::I16               => 2,
+            Prim::U32 | Prim::I32 | Prim::F32   => 4,
+            Prim::U64 | Prim::I64 | Prim::F64   => 8,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8], endian: Endian) -> Value {
+        let mut bits = 0u64;
+        for i in 0..self.size() {
+            let b = match endian {
+                Endian::Little => bytes[self.size() - 1 - i],
+                Endian::Big    => bytes[i],
+            };
+            bits = (bits << 8) | b as u64;
+        }
+
+        match *self {
+            Prim::U8 | Prim::U16 | Prim::U32 | Prim::U64 => Value::UInt(bits),
+            Prim::I8  => Value::Int(bits as u8  as i8  as i64),
+            Prim::I16 => Value::Int(bits as u16 as i16 as i64),
+            Prim::I32 => Value::Int(bits as u32 as i32 as i64),
+            Prim::I64 => Value::Int(bits as i64),
+            Prim::F32 => Value::Float(unsafe { mem::transmute::<u32, f32>(bits as u32) } as f64),
+            Prim::F64 => Value::Float(unsafe { mem::transmute::<u64, f64>(bits) }),
+        }
+    }
+}
+
+/// Layout of a (possibly nested) field
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Type {
+    Prim(Prim),
+    Array(Box<Type>, usize),
+    /// Struct or tuple (`size` includes any padding)
+    Struct { size: usize, fields: Vec<Field> },
+}
+
+impl Type {
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            Type::Prim(ref p)         => p.size(),
+            Type::Array(ref t, n)     => t.size() * n,
+            Type::Struct { size, .. } => size,
+        }
+    }
+
+    fn decode(&self, bytes: &[u8], endian: Endian) -> Value {
+        match *self {
+            Type::Prim(ref p) => p.decode(bytes, endian),
+            Type::Array(ref t, n) => {
+                let size = t.size();
+                Value::Array((0..n).map(|i| t.decode(&bytes[i*size..], endian)).collect())
+            }
+            Type::Struct { ref fields, .. } => {
+                Value::Struct(fields.iter()
+                                    .map(|f| (f.name.clone(), f.ty.decode(&bytes[f.offset..], endian)))
+                                    .collect())
+            }
+        }
+    }
+}
+
+/// One field of a struct
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    pub name   : String,
+    /// Offset from the start of the struct (bytes)
+    pub offset : usize,
+    pub ty     : Type,
+}
+
+impl Field {
+    pub fn new<S: Into<String>>(name: S, offset: usize, ty: Type) -> Field {
+        Field { name: name.into(), offset: offset, ty: ty }
+    }
+}
+
+/// Types whose memory layout can be written into a `Schema`
+///
+/// Implement this for structs with the `describe!` macro.
+pub trait Describe {
+    fn describe() -> Type;
+}
+
+/// Helper for `describe!` (takes a pointer, since fields of packed structs can't be borrowed)
+pub fn type_of<T: Describe>(_: *const T) -> Type {
+    T::describe()
+}
+
+/// Implement `Describe` for a struct by listing its fields
+///
+/// The offsets are measured (through raw pointers, never references), so this works for
+/// `#[repr(packed)]` and `#[repr(C)]` structs alike. Every field type must implement `Describe` too.
+#[macro_export]
+macro_rules! describe {
+    ($t:ident { $($field:ident),* }) => {
+        impl $crate::Describe for $t {
+            describe!(@body $t { $($field),* });
+        }
+    };
+
+    ($t:ident<$gen:ident> { $($field:ident),* }) => {
+        impl<$gen: $crate::Describe> $crate::Describe for $t<$gen> {
+            describe!(@body $t<$gen> { $($field),* });
+        }
+    };
+
+    (@body $t:ty { $($field:ident),* }) => {
+        #[allow(unused_unsafe)]
+        fn describe() -> $crate::Type {
+            unsafe {
+                let it = ::std::mem::MaybeUninit::<$t>::uninit();
+                let p = it.as_ptr();
+                let fields = vec![$(
+                    $crate::Field::new(stringify!($field),
+                                       ::std::ptr::addr_of!((*p).$field) as usize - p as usize,
+                                       $crate::type_of(::std::ptr::addr_of!((*p).$field)))
+                ),*];
+                $crate::Type::Struct { size: ::std::mem::size_of::<$t>(), fields: fields }
+            }
+        }
+    };
+}
+
+macro_rules! describe_prims {
+    ($($t:ty => $p:ident),*) => {
+        $(
+            impl Describe for $t {
+                fn describe() -> Type { Type::Prim(Prim::$p) }
+            }
+        )*
+    }
+}
+
+describe_prims!(u8 => U8, i8 => I8, u16 => U16, i16 => I16, u32 => U32, i32 => I32,
+                u64 => U64, i64 => I64, f32 => F32, f64 => F64);
+
+macro_rules! describe_arrays {
+    ($($n:expr)*) => {
+        $(
+            impl<T: Describe> Describe for [T; $n] {
+                fn describe() -> Type { Type::Array(Box::new(T::describe()), $n) }
+            }
+        )*
+    }
+}
+
+describe_arrays!( 1  2  3  4  5  6  7  8  9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+                 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64);
+
+impl<A: Describe, B: Describe> Describe for (A, B) {
+    fn describe() -> Type {
+        unsafe {
+            let it = mem::MaybeUninit::<(A, B)>::uninit();
+            let p = it.as_ptr();
+            let fields = vec![Field::new("0", ptr::addr_of!((*p).0) as usize - p as usize, A::describe()),
+                              Field::new("1", ptr::addr_of!((*p).1) as usize - p as usize, B::describe())];
+            Type::Struct { size: mem::size_of::<(A, B)>(), fields: fields }
+        }
+    }
+}
+
+impl Describe for time::Timespec {
+    fn describe() -> Type {
+        let it = time::Timespec::new(0, 0);
+        let base = &it as *const time::Timespec as usize;
+        Type::Struct {
+            size: mem::size_of::<time::Timespec>(),
+            fields: vec![Field::new("sec",  &it.sec  as *const i64 as usize - base, i64::describe()),
+                         Field::new("nsec", &it.nsec as *const i32 as usize - base, i32::describe())],
+        }
+    }
+}
+
+/// Layout of the packets in a file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    /// What kind of packets these are (e.g. "teensy")
+    pub name : String,
+    pub ty   : Type,
+}
+
+impl Schema {
+    pub fn new<S: Into<String>>(name: S, ty: Type) -> Schema {
+        Schema { name: name.into(), ty: ty }
+    }
+
+    /// Size of one packet (bytes)
+    pub fn size(&self) -> usize {
+        self.ty.size()
+    }
+}
+
+/// Byte order of the numbers in a file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Byte order of this machine
+    pub fn host() -> Endian {
+        if cfg!(target_endian = "little") { Endian::Little } else { Endian::Big }
+    }
+}
+
+/// Everything in a file besides the packets
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    /// Format version (0 for headerless files)
+    pub version : u16,
+    pub schema  : Schema,
+    pub endian  : Endian,
+    /// When the file was created (seconds and nanoseconds since the Unix epoch)
+    pub start   : (i64, i32),
+}
+
+impl Header {
+    /// Make a header for a new file, created now
+    pub fn new(schema: Schema) -> Header {
+        let now = time::get_time();
+        Header {
+            version : VERSION,
+            schema  : schema,
+            endian  : Endian::host(),
+            start   : (now.sec, now.nsec),
+        }
+    }
+
+    /// Serialize the header (including the magic bytes and lengths)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("header can always be serialized");
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 6 + json.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[self.version as u8, (self.version >> 8) as u8]);
+        let len = json.len() as u32;
+        bytes.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        bytes.extend_from_slice(&json);
+        bytes
+    }
+}
+
+/// A decoded packet (or part of one)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Array(Vec<Value>),
+    Struct(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Look up a struct field
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Struct(ref fields) => fields.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref v)| v),
+            _ => None,
+        }
+    }
+
+    /// Look up an array element
+    pub fn at(&self, i: usize) -> Option<&Value> {
+        match *self {
+            Value::Array(ref elems) => elems.get(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i)  => Some(i),
+            Value::UInt(u) => Some(u as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Int(i)  => Some(i as u64),
+            Value::UInt(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(i)   => Some(i as f64),
+            Value::UInt(u)  => Some(u as f64),
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Interpret a `time::Timespec` field as seconds since the epoch
+    pub fn as_secs(&self) -> Option<f64> {
+        match (self.get("sec").and_then(Value::as_i64), self.get("nsec").and_then(Value::as_i64)) {
+            (Some(sec), Some(nsec)) => Some(sec as f64 + nsec as f64 / 1_000_000_000f64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(i)   => write!(f, "{}", i),
+            Value::UInt(u)  => write!(f, "{}", u),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Array(ref elems) => {
+                for (i, e) in elems.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            Value::Struct(ref fields) => {
+                for (i, &(_, ref v)) in fields.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    write!(f, "{}", v)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn invalid<E: Into<Box<::std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Read as many bytes as possible into `buf` (stopping early only at EOF)
+fn fill<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Reads packets from a file written by `Writer<T: Writable>`, in any format version
+pub struct Reader<R: Read> {
+    header : Header,
+    inner  : io::Chain<io::Cursor<Vec<u8>>, R>,
+    buf    : Vec<u8>,
+}
+
+impl Reader<BufReader<File>> {
+    /// Open a file
+    ///
+    /// `legacy` is the schema to assume if the file turns out to have no header.
+    pub fn open<P: AsRef<Path>>(path: P, legacy: Option<Schema>) -> io::Result<Self> {
+        Reader::new(BufReader::new(File::open(path)?), legacy)
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the header from a stream
+    ///
+    /// `legacy` is the schema to assume if the stream turns out to have no header.
+    pub fn new(mut inner: R, legacy: Option<Schema>) -> io::Result<Self> {
+        let mut magic = vec![0; MAGIC.len()];
+        let n = fill(&mut inner, &mut magic)?;
+        magic.truncate(n);
+
+        let (header, prefix) = if &magic[..] == &MAGIC[..] {
+            let mut lens = [0u8; 6];
+            if fill(&mut inner, &mut lens)? < lens.len() {
+                return Err(invalid("truncated header"));
+            }
+            let version = lens[0] as u16 | (lens[1] as u16) << 8;
+            let len = lens[2] as usize | (lens[3] as usize) << 8 | (lens[4] as usize) << 16 | (lens[5] as usize) << 24;
+            if version > VERSION {
+                return Err(invalid(format!("format version {} is newer than this reader (version {})", version, VERSION)));
+            }
+
+            let mut json = vec![0; len];
+            if fill(&mut inner, &mut json)? < len {
+                return Err(invalid("truncated header"));
+            }
+            let header: Header = serde_json::from_slice(&json).map_err(invalid)?;
+            (header, vec![])
+        } else {
+            // no header: the bytes we just read are the start of the first packet
+            let schema = legacy.ok_or_else(|| invalid("file has no header and no legacy schema was given"))?;
+            (Header { version: 0, schema: schema, endian: Endian::host(), start: (0, 0) }, magic)
+        };
+
+        Ok(Reader {
+            buf    : vec![0; header.schema.size()],
+            header : header,
+            inner  : io::Cursor::new(prefix).chain(inner),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read the next packet into the buffer (false at the end of the file)
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        match fill(&mut self.inner, &mut self.buf)? {
+            0 => Ok(false),
+            n if n == self.buf.len() => Ok(true),
+            n => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("truncated packet ({} of {} bytes)", n, self.buf.len()))),
+        }
+    }
+
+    /// Get the raw bytes of the next packet (None at the end of the file)
+    pub fn next_raw(&mut self) -> io::Result<Option<&[u8]>> {
+        Ok(if self.fill_buf()? { Some(&self.buf) } else { None })
+    }
+
+    /// Decode the next packet according to the schema (None at the end of the file)
+    pub fn next_value(&mut self) -> io::Result<Option<Value>> {
+        Ok(if self.fill_buf()? { Some(self.header.schema.ty.decode(&self.buf, self.header.endian)) } else { None })
+    }
+
+    /// Read the next packet directly into a `T` (None at the end of the file)
+    ///
+    /// Fails unless the file was written with exactly `T`'s layout on a machine with the same byte
+    /// order. Use `next_value` to read other layouts.
+    pub fn next_packet<T: Writable>(&mut self) -> io::Result<Option<T>> {
+        if self.header.schema.ty != T::describe() || self.header.endian != Endian::host() {
+            return Err(invalid(format!("file layout ({}) doesn't match the requested packet type", self.header.schema.name)));
+        }
+        Ok(if self.fill_buf()? { Some(unsafe { ptr::read_unaligned(self.buf.as_ptr() as *const T) }) } else { None })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Value>;
+
+    fn next(&mut self) -> Option<io::Result<Value>> {
+        match self.next_value() {
+            Ok(Some(v)) => Some(Ok(v)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -3,19 +3,33 @@
 #[macro_use] extern crate utils;
 
 extern crate libc;
+extern crate time;
+extern crate serde_json;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate serde_derive;
 
-use std::{mem, ptr};
-use std::fs::File;
-use std::io::{self, Write};
-use std::collections::HashMap;
-use std::sync::{self, mpsc, Mutex};
+#[cfg(feature = "zstd")] extern crate zstd;
+#[cfg(feature = "lz4")] extern crate lz4;
+
+#[macro_use] mod format;
+pub use format::{MAGIC, VERSION, Prim, Type, Field, Describe, type_of, Schema, Endian, Header, Value, Reader};
+
+pub mod sink;
+pub use sink::{Sink, FileSink, PatternSink, ChunkedSink, Compression, Container};
+
+use std::{cmp, mem, ptr};
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{self, mpsc, Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use std::marker::PhantomData;
 use std::thread;
 use std::convert::Into;
 use std::ops::DerefMut;
 use std::panic;
+use std::time::{Duration, Instant};
+use utils::{config, RecordingContext};
 
 /// Helper struct to wrap an auto-incrementing integer.
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
@@ -78,59 +92,319 @@ impl_nofail!(<U> sync::PoisonError<U>, "Mutex poisoned. This can't happen."
 impl_nofail!(<>  io::Error,            "I/O error. Latest data may not have been written to disk."                           );
 impl_nofail!(<>  (),                   "Invalid handle sent to scribe thread. Latest data may not have been written to disk.");
 
-impl<'a> NoFail<&'a mut File> for Option<&'a mut File> {
-    fn nofail(self) -> &'a mut File {
+impl<'a> NoFail<&'a mut Entry> for Option<&'a mut Entry> {
+    fn nofail(self) -> &'a mut Entry {
         self.ok_or(()).nofail()
     }
 }
 
+/// What a `Writer` does when its queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum Policy {
+    /// Wait for the worker thread to catch up
+    Block,
+    /// Throw away the oldest queued packet to make room
+    DropOldest,
+    /// Throw away the packet being written
+    DropNewest,
+}
+
+/// Throughput, latency and queue statistics for one stream (i.e. one `Writer`)
+#[derive(Clone, Debug, Serialize)]
+pub struct Stats {
+    /// Name the stream was opened with (usually a file name)
+    pub name: String,
+    pub policy: Policy,
+    /// Maximum number of queued packets
+    pub capacity: usize,
+    /// Number of packets currently queued
+    pub depth: usize,
+    /// Largest queue depth seen so far
+    pub max_depth: usize,
+    /// Packets that reached the sink
+    pub written: u64,
+    /// Bytes that reached the sink
+    pub bytes: u64,
+    /// Packets thrown away (because the queue was full or the stream was stopped)
+    pub dropped: u64,
+    /// Average time from `write()` to the sink, in milliseconds
+    pub mean_latency_ms: f64,
+    /// Longest time from `write()` to the sink, in milliseconds
+    pub max_latency_ms: f64,
+    /// Bytes per second since the stream was opened
+    pub throughput: f64,
+    /// Why the worker thread stopped writing this stream, if it did
+    pub stopped: Option<String>,
+}
+
+/// Problems found by the worker thread (see `report_to`)
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// Writing to a stream failed, so it was closed
+    Failed { stream: String, error: String },
+    /// The disk is nearly full, so a stream was closed
+    DiskFull { stream: String, free: u64 },
+}
+
+/// Queue shared between a `Writer` and the worker thread
+struct Stream {
+    queue: Mutex<Queue>,
+    /// Signalled when the worker takes a packet out of the queue
+    space: Condvar,
+}
+
+struct Queue {
+    packets: VecDeque<(Instant, Box<[u8]>)>,
+    stats: Stats,
+    opened: Instant,
+    latency: Duration,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+impl Stream {
+    fn new(name: String) -> Stream {
+        Stream {
+            queue: Mutex::new(Queue {
+                packets: VecDeque::new(),
+                stats: Stats {
+                    name: name,
+                    policy: Policy::Block,
+                    capacity: config::get().scribe.queue_depth,
+                    depth: 0,
+                    max_depth: 0,
+                    written: 0,
+                    bytes: 0,
+                    dropped: 0,
+                    mean_latency_ms: 0.0,
+                    max_latency_ms: 0.0,
+                    throughput: 0.0,
+                    stopped: None,
+                },
+                opened: Instant::now(),
+                latency: Duration::from_secs(0),
+            }),
+            space: Condvar::new(),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.queue.lock().nofail().stats.name.clone()
+    }
+
+    fn stats(&self) -> Stats {
+        let queue = self.queue.lock().nofail();
+        let mut stats = queue.stats.clone();
+        stats.depth = queue.packets.len();
+        if stats.written > 0 {
+            stats.mean_latency_ms = millis(queue.latency) / stats.written as f64;
+        }
+        let elapsed = millis(queue.opened.elapsed()) / 1000.0;
+        if elapsed > 0.0 {
+            stats.throughput = stats.bytes as f64 / elapsed;
+        }
+        stats
+    }
+
+    /// Add a packet to the queue, applying the policy if it is full. Returns false if the packet
+    /// was thrown away.
+    fn push(&self, data: Box<[u8]>) -> bool {
+        let mut queue = self.queue.lock().nofail();
+        loop {
+            if queue.stats.stopped.is_some() {
+                queue.stats.dropped += 1;
+                return false;
+            }
+            if queue.packets.len() < cmp::max(queue.stats.capacity, 1) {
+                break;
+            }
+            match queue.stats.policy {
+                Policy::Block => {
+                    queue = self.space.wait(queue).nofail();
+                },
+                Policy::DropOldest => {
+                    queue.packets.pop_front();
+                    queue.stats.dropped += 1;
+                    COUNT.fetch_sub(1, Ordering::SeqCst);
+                },
+                Policy::DropNewest => {
+                    queue.stats.dropped += 1;
+                    return false;
+                },
+            }
+        }
+
+        queue.packets.push_back((Instant::now(), data));
+        queue.stats.max_depth = cmp::max(queue.stats.max_depth, queue.packets.len());
+        COUNT.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Take the next packet out of the queue (on the worker thread)
+    fn pop(&self) -> Option<(Instant, Box<[u8]>)> {
+        let packet = self.queue.lock().nofail().packets.pop_front();
+        if packet.is_some() {
+            COUNT.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.space.notify_all();
+        packet
+    }
+
+    /// Count a packet that made it to the sink
+    fn record(&self, queued: Instant, len: usize) {
+        let latency = queued.elapsed();
+        let mut queue = self.queue.lock().nofail();
+        queue.stats.written += 1;
+        queue.stats.bytes += len as u64;
+        queue.latency += latency;
+        if millis(latency) > queue.stats.max_latency_ms {
+            queue.stats.max_latency_ms = millis(latency);
+        }
+    }
+
+    /// Stop accepting packets, throwing away anything still queued
+    fn stop(&self, reason: String) {
+        let mut queue = self.queue.lock().nofail();
+        let n = queue.packets.len();
+        queue.packets.clear();
+        queue.stats.dropped += n as u64;
+        queue.stats.stopped = Some(reason);
+        COUNT.fetch_sub(n, Ordering::SeqCst);
+        self.space.notify_all();
+    }
+}
+
+/// Worker thread's view of one stream
+struct Entry {
+    /// None once the stream has been stopped
+    sink: Option<Box<Sink>>,
+    stream: Arc<Stream>,
+}
+
+impl Entry {
+    /// Close the sink after a problem, so that whatever was written so far stays readable
+    fn stop(&mut self, event: Event) {
+        if let Some(mut sink) = self.sink.take() {
+            let _ = sink.finish();
+        }
+        self.stream.stop(match event {
+            Event::Failed { ref error, .. } => format!("write failed: {}", error),
+            Event::DiskFull { free, .. } => format!("low disk space ({} bytes free)", free),
+        });
+        report(event);
+    }
+
+    /// Check the free space where the sink is writing, stopping the stream if it is too low
+    fn check_disk(&mut self) {
+        let free = match self.sink.as_ref().and_then(|sink| sink.path()) {
+            Some(path) => {
+                let dir = match path.parent() {
+                    Some(dir) if dir != Path::new("") => dir,
+                    _ => Path::new("."),
+                };
+                match utils::df(dir) {
+                    Ok(free) => free,
+                    Err(_) => return,
+                }
+            },
+            None => return,
+        };
+
+        if free < config::get().scribe.min_free_bytes {
+            let stream = self.stream.name();
+            self.stop(Event::DiskFull { stream: stream, free: free });
+        }
+    }
+}
+
 lazy_static! {
     static ref WORKER: Mutex<Worker> = {
         let (tx, rx) = mpsc::channel();
 
         let mutex = Mutex::new(Worker {
             thread: thread::spawn(move || {
-                let mut files = HashMap::<Handle, File>::new();
-                let mut patterns = HashMap::<Handle, String>::new();
-                let mut indices = HashMap::<Handle, usize>::new();
-
-                let mut max_file = Handle::new();
-                let mut max_pattern = Handle::new();
+                let mut entries = HashMap::<Handle, Entry>::new();
+                let mut max_handle = Handle::new();
+                let mut last_disk_check = Instant::now();
+                let disk_check_period = Duration::from_millis(config::get().scribe.disk_check_ms);
 
                 for msg in rx {
                     match msg {
-                        Message::Open(s, tx) => {
-                            max_file = max_file.next();
-                            files.insert(max_file, File::create(&s).nofail());
-                            tx.send(max_file).nofail();
+                        Message::Open(sink, stream, tx) => {
+                            max_handle = max_handle.next();
+                            STREAMS.lock().nofail().insert(max_handle, stream.clone());
+                            let mut entry = Entry { sink: None, stream: stream };
+                            match sink {
+                                Ok(sink) => entry.sink = Some(sink),
+                                Err(e) => {
+                                    // (stopped before the Writer gets its handle, so nothing is ever queued)
+                                    let stream = entry.stream.name();
+                                    entry.stop(Event::Failed { stream: stream, error: format!("could not create: {}", e) });
+                                }
+                            }
+                            entries.insert(max_handle, entry);
+                            tx.send(max_handle).nofail();
                         },
                         Message::Close(h) => {
-                            files.remove(&h);
-                        },
-                        Message::Write(h, data) => {
-                            files.get_mut(&h).nofail().write_all(&data).nofail();
-                            COUNT.fetch_sub(1, Ordering::SeqCst);
-                        },
-
-                        Message::Register(s, tx) => {
-                            max_pattern = max_pattern.next();
-                            patterns.insert(max_pattern, s);
-                            indices.insert(max_pattern, 1);
-                            tx.send(max_pattern).nofail();
-                        },
-                        Message::Unregister(h) => {
-                            patterns.remove(&h);
-                            indices.remove(&h);
+                            STREAMS.lock().nofail().remove(&h);
+                            if let Some(mut entry) = entries.remove(&h) {
+                                if let Some(mut sink) = entry.sink.take() {
+                                    if let Err(e) = sink.finish() {
+                                        report(Event::Failed { stream: entry.stream.name(), error: e.to_string() });
+                                    }
+                                }
+                            }
                         },
-                        Message::Packet(h, data) => {
-                            let i = indices[&h];
-                            indices.insert(h, i + 1);
-                            File::create(patterns[&h].replace("{}", &i.to_string())).nofail().write_all(&data).nofail();
-                            COUNT.fetch_sub(1, Ordering::SeqCst);
+                        Message::Write(h) => {
+                            let entry = entries.get_mut(&h).nofail();
+                            if let Some((queued, data)) = entry.stream.pop() {
+                                let result = match entry.sink {
+                                    Some(ref mut sink) => Some(sink.write(&data)),
+                                    None => None,
+                                };
+                                match result {
+                                    Some(Ok(())) => entry.stream.record(queued, data.len()),
+                                    Some(Err(e)) => {
+                                        let stream = entry.stream.name();
+                                        entry.stop(Event::Failed { stream: stream, error: e.to_string() });
+                                    },
+                                    None => {},
+                                }
+                            }
                         },
                         Message::SetIndex(h, index) => {
-                            indices.insert(h, index);
+                            let entry = entries.get_mut(&h).nofail();
+                            let result = match entry.sink {
+                                Some(ref mut sink) => sink.set_index(index),
+                                None => Ok(()),
+                            };
+                            if let Err(e) = result {
+                                let stream = entry.stream.name();
+                                entry.stop(Event::Failed { stream: stream, error: e.to_string() });
+                            }
                         },
+                        Message::Flush(tx) => {
+                            // everything sent before this message has been handled
+                            let _ = tx.send(());
+                        },
+                    }
+
+                    if last_disk_check.elapsed() >= disk_check_period {
+                        last_disk_check = Instant::now();
+                        for entry in entries.values_mut() {
+                            entry.check_disk();
+                        }
+                    }
+                }
+
+                // the channel is closed, so finish off any Writers that were never dropped
+                for (_, entry) in entries {
+                    if let Some(mut sink) = entry.sink {
+                        if let Err(e) = sink.finish() {
+                            report(Event::Failed { stream: entry.stream.name(), error: e.to_string() });
+                        }
                     }
                 }
             }),
@@ -142,98 +416,185 @@ lazy_static! {
 
         mutex
     };
-}
 
-pub static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// All open streams, for `stats()`
+    static ref STREAMS: Mutex<HashMap<Handle, Arc<Stream>>> = Mutex::new(HashMap::new());
 
-#[derive(PartialEq)]
-enum Destination {
-    Name,
-    Pattern,
+    /// Where to send `Event`s (see `report_to`)
+    static ref REPORTER: Mutex<Option<Box<Fn(Event) + Send>>> = Mutex::new(None);
 }
 
+/// Number of packets queued (across all streams) that have not been written yet
+pub static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
 enum Message {
-    Open(String, mpsc::Sender<Handle>),
+    /// A new stream (already stopped if its sink could not be created)
+    Open(io::Result<Box<Sink>>, Arc<Stream>, mpsc::Sender<Handle>),
     Close(Handle),
-    Write(Handle, Box<[u8]>),
-
-    Register(String, mpsc::Sender<Handle>),
-    Unregister(Handle),
-    Packet(Handle, Box<[u8]>),
+    /// A packet was added to the stream's queue
+    Write(Handle),
     SetIndex(Handle, usize),
+    /// Reply once every earlier message has been handled
+    Flush(mpsc::Sender<()>),
+}
+
+/// Get statistics for every open stream
+pub fn stats() -> Vec<Stats> {
+    let mut stats = STREAMS.lock().nofail().values().map(|s| s.stats()).collect::<Vec<_>>();
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
+}
+
+/// Wait until the worker thread has handled everything sent to it so far.
+///
+/// Writers that were dropped before calling this have been finished, so their files are complete
+/// on disk.
+pub fn flush() {
+    let (tx, rx) = mpsc::channel();
+    send(Message::Flush(tx));
+    rx.recv().nofail();
+}
+
+/// Send problems found by the worker thread to `f` instead of printing them.
+///
+/// Whenever a stream is stopped (because of an I/O error or low disk space), the worker closes it
+/// cleanly, throws away anything else written to it, and calls `f`.
+pub fn report_to<F: Fn(Event) + Send + 'static>(f: F) {
+    *REPORTER.lock().nofail() = Some(Box::new(f));
+}
+
+fn report(event: Event) {
+    match *REPORTER.lock().nofail() {
+        Some(ref f) => f(event),
+        None => errorln!("Scribe thread: {:?}", event),
+    }
 }
 
 /// Marks a type that can be "serialized" by transmuting to `[u8]`
-pub unsafe trait Writable {}
+///
+/// The layout is recorded in the header of each file, so that it can be read back after the type
+/// changes. Implement this (along with `Describe`) using the `writable!` macro.
+pub unsafe trait Writable: Describe {
+    /// Name of the packet type (recorded in file headers)
+    fn name() -> &'static str;
+
+    fn schema() -> Schema where Self: Sized {
+        Schema::new(Self::name(), Self::describe())
+    }
+}
+
+/// Implement `Writable` and `Describe` for a struct by listing its fields
+///
+/// ```ignore
+/// writable!(Packet as "optoforce" { stamp, xyz });
+/// ```
+#[macro_export]
+macro_rules! writable {
+    ($t:ident as $name:tt { $($field:ident),* }) => {
+        describe!($t { $($field),* });
+
+        unsafe impl $crate::Writable for $t {
+            fn name() -> &'static str { $name }
+        }
+    }
+}
 
 /// Helper type used for writing a sequence of `Writable` packets into a file or files
 pub struct Writer<T: ?Sized> {
     handle : Handle,
-    dst    : Destination,
+    stream : Arc<Stream>,
     _ghost : PhantomData<*const T>,
 }
 
 impl<T: ?Sized> Writer<T> {
-    /// Create a new `Writer` that creates the file `name` and writes `T`s into it.
+    /// Create a new `Writer` that sends everything written to `sink` (on the worker thread).
     ///
-    /// The file is closed when the `Writer` is dropped.
-    pub fn with_file<S: Into<String>>(name: S) -> Writer<T> {
+    /// The sink is finished when the `Writer` is dropped. `name` identifies the stream in
+    /// statistics and error reports.
+    pub fn with_sink<S: Into<String>>(name: S, sink: Box<Sink>) -> Writer<T> {
+        Writer::open(name, Ok(sink))
+    }
+
+    /// Like `with_sink`, but if creating the sink failed, the stream starts out stopped and the
+    /// failure is reported as an `Event` (every write is then thrown away).
+    fn open<S: Into<String>>(name: S, sink: io::Result<Box<Sink>>) -> Writer<T> {
+        let stream = Arc::new(Stream::new(name.into()));
         let (tx, rx) = mpsc::channel();
-        send(Message::Open(name.into(), tx));
+        send(Message::Open(sink, stream.clone(), tx));
 
         Writer {
             handle: rx.recv().nofail(),
-            dst: Destination::Name,
+            stream: stream,
             _ghost: PhantomData
         }
     }
 
-    /// Create a new `Writer` that creates files based on `pattern` for each `T`.
-    pub fn with_files<S: Into<String>>(pattern: S) -> Writer<T> {
-        let (tx, rx) = mpsc::channel();
-        send(Message::Register(pattern.into(), tx));
-
-        Writer {
-            handle: rx.recv().nofail(),
-            dst: Destination::Pattern,
-            _ghost: PhantomData
+    /// Change what happens when more than `capacity` packets are waiting to be written (by
+    /// default, `write` blocks once `scribe.queue_depth` (see `utils::config`) are queued).
+    pub fn with_policy(self, policy: Policy, capacity: usize) -> Writer<T> {
+        {
+            let mut queue = self.stream.queue.lock().nofail();
+            queue.stats.policy = policy;
+            queue.stats.capacity = capacity;
         }
+        self
+    }
+
+    /// Get the statistics for this `Writer`'s stream
+    pub fn stats(&self) -> Stats {
+        self.stream.stats()
     }
 
     /// Fix up the internal packet index of an existing `Writer` (in the common case, it is automatically incremented).
     pub fn set_index(&mut self, index: usize) {
-        if self.dst == Destination::Pattern {
-            send(Message::SetIndex(self.handle, index));
+        send(Message::SetIndex(self.handle, index));
+    }
+
+    /// Send some bytes off to the worker thread (unless the queue policy throws them away)
+    fn send_raw(&mut self, raw_data: Box<[u8]>) -> bool {
+        if self.stream.push(raw_data) {
+            send(Message::Write(self.handle));
+            true
+        } else {
+            false
         }
     }
 }
 
-impl<T: Writable + Send + 'static> Writer<T> {
-    /// Write a serializable type to disk (into the open file or a new one, according to whether
-    /// this `Writer` was constructed with `with_file` or `with_files`).
-    pub fn write(&mut self, data: T) {
-        // "serialize" by copying into a Box<[u8]>
-        let mut raw_data = vec![0u8; mem::size_of::<T>()].into_boxed_slice();
-        unsafe {
-            ptr::copy_nonoverlapping::<T>(&data as *const T,
-                                          raw_data.deref_mut() as *mut [u8] as *mut T,
-                                          1);
-        }
+impl Writer<[u8]> {
+    /// Create a new `Writer` that creates the file `name` (in the recording context) and writes
+    /// byte strings into it (with no header).
+    ///
+    /// The file is closed when the `Writer` is dropped. If it cannot be created, the failure is
+    /// reported (see `report_to`) and everything written is thrown away.
+    pub fn with_file<S: Into<String>>(ctx: &RecordingContext, name: S) -> Writer<[u8]> {
+        let name = name.into();
+        let sink = FileSink::create(ctx.path(&name)).map(|sink| Box::new(sink) as Box<Sink>);
+        Writer::open(name, sink)
+    }
 
-        // internal scribe status counter
-        COUNT.fetch_add(1, Ordering::SeqCst);
+    /// Create a new `Writer` that creates a file based on `pattern` (in the recording context) for
+    /// each byte string.
+    pub fn with_files<S: Into<String>>(ctx: &RecordingContext, pattern: S) -> Writer<[u8]> {
+        let pattern = pattern.into();
+        let path = ctx.path(&pattern).to_string_lossy().into_owned();
+        Writer::with_sink(pattern, Box::new(PatternSink::new(path)))
+    }
 
-        send(match self.dst {
-                Destination::Name    => Message::Write(self.handle, raw_data),
-                Destination::Pattern => Message::Packet(self.handle, raw_data),
-            });
+    /// Create a new `Writer` that stores each byte string as a chunk in the container `name` (in
+    /// the recording context; see the `sink` module). The byte strings that reach the container are
+    /// numbered from 1, which is the index `Container::get` takes.
+    pub fn with_container<S: Into<String>>(ctx: &RecordingContext, name: S, compression: Compression) -> Writer<[u8]> {
+        let name = name.into();
+        let sink = ChunkedSink::create(ctx.path(&name), compression, 0).map(|sink| Box::new(sink) as Box<Sink>);
+        Writer::open(name, sink)
     }
-}
 
-impl Writer<[u8]> {
-    /// Special case for `&[u8]`. Write to disk (into the open file or a new one, according to
-    /// whether this `Writer` was constructed with `with_file` or `with_files`).
-    pub fn write(&mut self, data: &[u8]) {
+    /// Special case for `&[u8]`. Write to disk (into the open file, a new one, or a container,
+    /// according to how this `Writer` was constructed).
+    ///
+    /// Returns false if the data was thrown away (see `Policy`).
+    pub fn write(&mut self, data: &[u8]) -> bool {
         // copy to Box<[u8]>
         let mut raw_data = vec![0u8; data.len()].into_boxed_slice();
         unsafe {
@@ -242,13 +603,36 @@ impl Writer<[u8]> {
                             data.len());
         }
 
-        // internal scribe status counter
-        COUNT.fetch_add(1, Ordering::SeqCst);
+        self.send_raw(raw_data)
+    }
+}
+
+impl<T: Writable + Send + 'static> Writer<T> {
+    /// Create a new `Writer` that creates the file `name` (in the recording context) and writes
+    /// `T`s into it, after a header describing their layout (see the `format` module).
+    ///
+    /// The file is closed when the `Writer` is dropped. If it cannot be created, the failure is
+    /// reported (see `report_to`) and everything written is thrown away.
+    pub fn with_file<S: Into<String>>(ctx: &RecordingContext, name: S) -> Writer<T> {
+        let name = name.into();
+        let header = Header::new(T::schema()).to_bytes();
+        let sink = FileSink::with_header(ctx.path(&name), &header).map(|sink| Box::new(sink) as Box<Sink>);
+        Writer::open(name, sink)
+    }
+
+    /// Write a serializable type to disk (into the open file).
+    ///
+    /// Returns false if the packet was thrown away (see `Policy`).
+    pub fn write(&mut self, data: T) -> bool {
+        // "serialize" by copying into a Box<[u8]>
+        let mut raw_data = vec![0u8; mem::size_of::<T>()].into_boxed_slice();
+        unsafe {
+            ptr::copy_nonoverlapping::<T>(&data as *const T,
+                                          raw_data.deref_mut() as *mut [u8] as *mut T,
+                                          1);
+        }
 
-        send(match self.dst {
-                Destination::Name    => Message::Write(self.handle, raw_data),
-                Destination::Pattern => Message::Packet(self.handle, raw_data),
-            });
+        self.send_raw(raw_data)
     }
 }
 
@@ -259,10 +643,7 @@ fn send(m: Message) {
 
 impl<T: ?Sized> Drop for Writer<T> {
     fn drop(&mut self) {
-        send(match self.dst {
-                Destination::Name    => Message::Close(self.handle),
-                Destination::Pattern => Message::Unregister(self.handle),
-            });
+        send(Message::Close(self.handle));
     }
 }
 
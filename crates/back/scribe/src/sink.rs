@@ -0,0 +1,476 @@
+//! Destinations for the worker thread's writes
+//!
+//! Each `Writer` owns one `Sink` (living on the worker thread). `FileSink` and `PatternSink` write
+//! plain files, as scribe always has. `ChunkedSink` writes a whole stream into one append-only
+//! container instead, optionally compressed, with a CRC per chunk and an index for random access.
+//!
+//! Container layout (all numbers little endian):
+//!
+//! * the magic bytes `CONTAINER_MAGIC`
+//! * chunks, each of which is
+//!     - the magic bytes `CHUNK_MAGIC`
+//!     - index of the first write in the chunk (u64)
+//!     - number of writes in the chunk (u32)
+//!     - uncompressed length (u32)
+//!     - stored length (u32)
+//!     - compression (u8, see `Compression::code`)
+//!     - CRC-32 of the stored bytes (u32)
+//!     - the stored bytes
+//!
+//! The index (`<container>.idx`) has an entry per chunk: index of the first write (u64) and offset
+//! of the chunk in the container (u64). It is only a shortcut -- `Container` rebuilds it by
+//! scanning if it is missing or short.
+
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom, BufWriter};
+use std::path::{Path, PathBuf};
+use utils::replay::FrameStore;
+
+/// First bytes of a container
+pub const CONTAINER_MAGIC: &'static [u8; 8] = b"NRIchnks";
+
+/// First bytes of each chunk in a container
+pub const CHUNK_MAGIC: &'static [u8; 4] = b"SCHK";
+
+const CHUNK_HEADER_LEN: usize = 4 + 8 + 4 + 4 + 4 + 1 + 4;
+
+/// Where the worker thread puts the data for one `Writer`
+pub trait Sink: Send {
+    /// Write one packet (or frame, or string)
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Change the index of the next write (for sinks that care)
+    fn set_index(&mut self, _index: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Make sure everything has been written (called when the `Writer` is dropped)
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Where the data is going (used to keep an eye on free disk space)
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Appends everything to one file
+pub struct FileSink {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSink> {
+        Ok(FileSink {
+            file: File::create(&path)?,
+            path: path.as_ref().to_owned(),
+        })
+    }
+
+    /// Create the file and write `header` into it straight away (so that no queue policy can
+    /// throw it away)
+    pub fn with_header<P: AsRef<Path>>(path: P, header: &[u8]) -> io::Result<FileSink> {
+        let mut sink = FileSink::create(path)?;
+        sink.file.write_all(header)?;
+        Ok(sink)
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Writes each packet to its own file, named by replacing `{}` in a pattern with an index
+pub struct PatternSink {
+    pattern: String,
+    index: usize,
+}
+
+impl PatternSink {
+    pub fn new<S: Into<String>>(pattern: S) -> PatternSink {
+        PatternSink { pattern: pattern.into(), index: 1 }
+    }
+}
+
+impl Sink for PatternSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let name = self.pattern.replace("{}", &self.index.to_string());
+        self.index += 1;
+        File::create(name)?.write_all(data)
+    }
+
+    fn set_index(&mut self, index: usize) -> io::Result<()> {
+        self.index = index;
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(Path::new(&self.pattern))
+    }
+}
+
+/// Compression applied to each chunk of a container
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// zstd at the given level (needs the "zstd" feature)
+    Zstd(i32),
+    /// LZ4 (needs the "lz4" feature)
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        if cfg!(feature = "zstd") {
+            Compression::Zstd(1)
+        } else if cfg!(feature = "lz4") {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("scribe was built without {} support", what))
+}
+
+fn invalid<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+impl Compression {
+    /// Number stored in the chunk header
+    fn code(&self) -> u8 {
+        match *self {
+            Compression::None    => 0,
+            Compression::Zstd(_) => 1,
+            Compression::Lz4     => 2,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Compression::None => Ok(data.to_vec()),
+
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => ::zstd::block::compress(data, level),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => Err(unsupported("zstd")),
+
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => ::lz4::block::compress(data, None, false),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => Err(unsupported("lz4")),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn decompress(code: u8, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+        match code {
+            0 => Ok(data.to_vec()),
+
+            #[cfg(feature = "zstd")]
+            1 => ::zstd::block::decompress(data, raw_len),
+            #[cfg(not(feature = "zstd"))]
+            1 => Err(unsupported("zstd")),
+
+            #[cfg(feature = "lz4")]
+            2 => ::lz4::block::decompress(data, Some(raw_len as i32)),
+            #[cfg(not(feature = "lz4"))]
+            2 => Err(unsupported("lz4")),
+
+            _ => Err(invalid(format!("unknown compression {}", code))),
+        }
+    }
+}
+
+/// CRC-32 (IEEE) of a byte string
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue a CRC-32 over more data (start with 0), for checksumming a file in pieces
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    lazy_static! {
+        static ref TABLE: [u32; 256] = {
+            let mut table = [0u32; 256];
+            for i in 0..256 {
+                let mut c = i as u32;
+                for _ in 0..8 {
+                    c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+                }
+                table[i] = c;
+            }
+            table
+        };
+    }
+
+    !data.iter().fold(!crc, |crc, &b| TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    for i in 0..4 { buf.push((n >> (8*i)) as u8); }
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    for i in 0..8 { buf.push((n >> (8*i)) as u8); }
+}
+
+fn get_u32(buf: &[u8]) -> u32 {
+    (0..4).fold(0, |n, i| n | (buf[i] as u32) << (8*i))
+}
+
+fn get_u64(buf: &[u8]) -> u64 {
+    (0..8).fold(0, |n, i| n | (buf[i] as u64) << (8*i))
+}
+
+/// Path of the index belonging to a container
+pub fn index_path<P: AsRef<Path>>(container: P) -> PathBuf {
+    let mut name = container.as_ref().as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// Writes a stream into a container (see the module documentation)
+pub struct ChunkedSink {
+    path: PathBuf,
+    file: BufWriter<File>,
+    index: BufWriter<File>,
+    compression: Compression,
+    /// Writes are buffered until there are at least this many bytes (0 = one write per chunk)
+    chunk_size: usize,
+    offset: u64,
+    /// Index of the next write
+    next: usize,
+    /// Writes waiting to be stored, and the index of the first one
+    pending: Vec<u8>,
+    pending_start: usize,
+    pending_count: u32,
+}
+
+impl ChunkedSink {
+    pub fn create<P: AsRef<Path>>(path: P, compression: Compression, chunk_size: usize) -> io::Result<ChunkedSink> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(CONTAINER_MAGIC)?;
+        Ok(ChunkedSink {
+            path: path.as_ref().to_owned(),
+            file: file,
+            index: BufWriter::new(File::create(index_path(&path))?),
+            compression: compression,
+            chunk_size: chunk_size,
+            offset: CONTAINER_MAGIC.len() as u64,
+            next: 1,
+            pending: vec![],
+            pending_start: 1,
+            pending_count: 0,
+        })
+    }
+
+    /// Store the pending writes as one chunk
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if self.pending_count == 0 {
+            return Ok(());
+        }
+
+        let stored = self.compression.compress(&self.pending)?;
+        let mut header = Vec::with_capacity(CHUNK_HEADER_LEN);
+        header.extend_from_slice(CHUNK_MAGIC);
+        put_u64(&mut header, self.pending_start as u64);
+        put_u32(&mut header, self.pending_count);
+        put_u32(&mut header, self.pending.len() as u32);
+        put_u32(&mut header, stored.len() as u32);
+        header.push(self.compression.code());
+        put_u32(&mut header, crc32(&stored));
+
+        self.file.write_all(&header)?;
+        self.file.write_all(&stored)?;
+
+        let mut entry = Vec::with_capacity(16);
+        put_u64(&mut entry, self.pending_start as u64);
+        put_u64(&mut entry, self.offset);
+        self.index.write_all(&entry)?;
+
+        self.offset += (header.len() + stored.len()) as u64;
+        self.pending.clear();
+        self.pending_count = 0;
+
+        // don't leave finished chunks sitting in the buffers
+        self.file.flush()?;
+        self.index.flush()
+    }
+}
+
+impl Sink for ChunkedSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.pending_count == 0 {
+            self.pending_start = self.next;
+        }
+        self.pending.extend_from_slice(data);
+        self.pending_count += 1;
+        self.next += 1;
+
+        if self.pending.len() >= self.chunk_size {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn set_index(&mut self, index: usize) -> io::Result<()> {
+        // keep chunks contiguous
+        self.flush_chunk()?;
+        self.next = index;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_chunk()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// One chunk of a container, as found by `Container`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Index of the first write in the chunk
+    pub start: u64,
+    /// Number of writes in the chunk
+    pub count: u32,
+    /// Offset of the chunk header in the container
+    pub offset: u64,
+}
+
+/// Reads a container written by `ChunkedSink`
+pub struct Container {
+    file: File,
+    chunks: Vec<Chunk>,
+    /// Whether the container ends in a partial chunk
+    truncated: bool,
+}
+
+impl Container {
+    /// Open a container, and its index if there is a usable one
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Container> {
+        let mut file = File::open(&path)?;
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != CONTAINER_MAGIC {
+            return Err(invalid(format!("{} is not a scribe container", path.as_ref().display())));
+        }
+
+        let mut container = Container { file: file, chunks: vec![], truncated: false };
+
+        let mut index = vec![];
+        if File::open(index_path(&path)).and_then(|mut f| f.read_to_end(&mut index)).is_ok() {
+            for entry in index.chunks(16).filter(|e| e.len() == 16) {
+                let offset = get_u64(&entry[8..]);
+                match container.read_header(offset) {
+                    Ok((start, count, _, _, _, _)) => container.chunks.push(Chunk { start: start, count: count, offset: offset }),
+                    Err(_) => {
+                        // bad index, ignore it
+                        container.chunks.clear();
+                        break;
+                    }
+                }
+            }
+        }
+        // pick up anything the index is missing (e.g. after a crash)
+        container.scan()?;
+
+        Ok(container)
+    }
+
+    /// Add chunks past the last known one
+    fn scan(&mut self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        let mut offset = match self.chunks.last() {
+            Some(&chunk) => {
+                let (_, _, _, stored, _, _) = self.read_header(chunk.offset)?;
+                chunk.offset + CHUNK_HEADER_LEN as u64 + stored as u64
+            }
+            None => CONTAINER_MAGIC.len() as u64,
+        };
+        while offset + CHUNK_HEADER_LEN as u64 <= len {
+            let (start, count, _, stored, _, _) = self.read_header(offset)?;
+            if offset + CHUNK_HEADER_LEN as u64 + stored as u64 > len {
+                break;
+            }
+            self.chunks.push(Chunk { start: start, count: count, offset: offset });
+            offset += CHUNK_HEADER_LEN as u64 + stored as u64;
+        }
+        self.truncated = offset < len;
+        Ok(())
+    }
+
+    /// Read a chunk header: (start, count, raw length, stored length, compression, CRC)
+    fn read_header(&mut self, offset: u64) -> io::Result<(u64, u32, u32, u32, u8, u32)> {
+        let mut header = [0u8; CHUNK_HEADER_LEN];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut header)?;
+        if &header[..4] != CHUNK_MAGIC {
+            return Err(invalid(format!("no chunk at offset {}", offset)));
+        }
+        Ok((get_u64(&header[4..]), get_u32(&header[12..]), get_u32(&header[16..]), get_u32(&header[20..]), header[24], get_u32(&header[25..])))
+    }
+
+    /// Read, check and decompress a chunk
+    pub fn read(&mut self, chunk: &Chunk) -> io::Result<Vec<u8>> {
+        let (_, _, raw_len, stored_len, code, crc) = self.read_header(chunk.offset)?;
+        let mut stored = vec![0; stored_len as usize];
+        self.file.read_exact(&mut stored)?;
+        if crc32(&stored) != crc {
+            return Err(invalid(format!("CRC mismatch in chunk at offset {}", chunk.offset)));
+        }
+        let data = Compression::decompress(code, &stored, raw_len as usize)?;
+        if data.len() != raw_len as usize {
+            return Err(invalid(format!("chunk at offset {} decompressed to {} bytes instead of {}", chunk.offset, data.len(), raw_len)));
+        }
+        Ok(data)
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Total number of writes stored
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|c| c.count as usize).sum()
+    }
+
+    /// Get the data from write number `index`, if it was stored in a chunk by itself (as frames are)
+    pub fn get(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        match self.chunks.iter().find(|c| c.start == index as u64 && c.count == 1).cloned() {
+            Some(chunk) => self.read(&chunk).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Check every chunk against its CRC and length, returning the number of writes stored
+    pub fn verify(&mut self) -> io::Result<usize> {
+        if self.truncated {
+            return Err(invalid("container ends with a partial chunk"));
+        }
+        for chunk in self.chunks.clone() {
+            self.read(&chunk)?;
+        }
+        Ok(self.len())
+    }
+}
+
+/// Lets `utils::replay::Frames` read frames out of containers
+impl FrameStore for Container {
+    fn frame(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        self.get(index)
+    }
+}
@@ -0,0 +1,198 @@
+//! Live sensor data for the web interface
+//!
+//! Drivers publish samples to named channels (one per sensor), no more than
+//! `telemetry.series_hz` times per second (or `telemetry.image_hz` for camera thumbnails; see
+//! `utils::config`). Each channel keeps its latest samples, numbered in order, so that a reader
+//! can ask for everything after the last sample it saw. The web server does that for each client
+//! that subscribes to a channel.
+
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate serde_derive;
+extern crate serde;
+extern crate utils;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utils::config;
+
+lazy_static! {
+    /// Every channel that has been published to
+    static ref CHANNELS: Mutex<BTreeMap<String, Arc<Mutex<Channel>>>> = Mutex::new(BTreeMap::new());
+
+    /// Time zero for sample timestamps
+    static ref EPOCH: Instant = Instant::now();
+}
+
+/// Contents of a sample
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    /// One number for each field of the channel
+    Series(Vec<f64>),
+    /// A camera frame, as a PNG data URL
+    Image { frame: usize, url: String },
+}
+
+/// One published sample
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    /// Position in the channel (consecutive, unless a reader fell behind)
+    pub seq: u64,
+    /// When the sample was published (in seconds, on the same clock for all channels)
+    pub t: f64,
+    pub value: Value,
+}
+
+/// Description of a channel
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Info {
+    pub name: String,
+    /// Names of the numbers in each sample (empty for cameras)
+    pub fields: Vec<String>,
+    /// Maximum number of samples per second
+    pub rate: f64,
+}
+
+/// What was published to a channel since a reader last looked
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub samples: Vec<Sample>,
+    /// Number of samples that fell out of the history before the reader got to them
+    pub missed: u64,
+    /// Where the reader should continue next time
+    pub next: u64,
+}
+
+struct Channel {
+    info: Info,
+    samples: VecDeque<Sample>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl Channel {
+    fn push(&mut self, value: Value) {
+        while self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { seq: self.next_seq, t: now(), value: value });
+        self.next_seq += 1;
+    }
+
+    fn since(&self, from: Option<u64>) -> Delta {
+        let oldest = self.samples.front().map_or(self.next_seq, |s| s.seq);
+        let from = from.unwrap_or(oldest);
+        Delta {
+            samples: self.samples.iter().filter(|s| s.seq >= from).cloned().collect(),
+            missed: oldest.saturating_sub(from),
+            next: self.next_seq,
+        }
+    }
+}
+
+fn now() -> f64 {
+    let elapsed = EPOCH.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1.0e9
+}
+
+/// A driver's handle on its channel
+///
+/// Clones publish to the same channel, but keep their own rate limit.
+#[derive(Clone)]
+pub struct Publisher {
+    channel: Arc<Mutex<Channel>>,
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl Publisher {
+    /// Publish numbers, one for each of the named fields
+    pub fn series(name: &str, fields: &[&str]) -> Publisher {
+        Publisher::new(name,
+                       fields.iter().map(|&f| f.to_owned()).collect(),
+                       config::get().telemetry.series_hz,
+                       config::get().telemetry.history)
+    }
+
+    /// Publish camera thumbnails (only the latest one is kept)
+    pub fn images(name: &str) -> Publisher {
+        Publisher::new(name, vec![], config::get().telemetry.image_hz, 1)
+    }
+
+    fn new(name: &str, fields: Vec<String>, rate: f64, capacity: usize) -> Publisher {
+        let channel = CHANNELS.lock().unwrap()
+            .entry(name.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(Channel {
+                info: Info { name: name.to_owned(), fields: vec![], rate: rate },
+                samples: VecDeque::new(),
+                capacity: capacity,
+                next_seq: 0,
+            })))
+            .clone();
+
+        {
+            // a restarted driver carries on where it left off, so readers see no gap
+            let mut ch = channel.lock().unwrap();
+            if ch.info.fields != fields {
+                ch.samples.clear();
+            }
+            ch.info.fields = fields;
+            ch.info.rate = rate;
+            ch.capacity = capacity;
+        }
+
+        Publisher {
+            channel: channel,
+            period: Duration::from_millis((1000.0 / rate) as u64),
+            last: None,
+        }
+    }
+
+    /// Whether it is time to publish another sample
+    ///
+    /// Returns true at most once per period, so drivers can call this on every step and only do
+    /// the work of preparing a sample when it will be published.
+    pub fn due(&mut self) -> bool {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < self.period => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Publish a sample of a numeric channel
+    pub fn publish(&self, values: Vec<f64>) {
+        self.channel.lock().unwrap().push(Value::Series(values));
+    }
+
+    /// Publish a camera thumbnail
+    pub fn publish_image(&self, frame: usize, url: String) {
+        self.channel.lock().unwrap().push(Value::Image { frame: frame, url: url });
+    }
+}
+
+/// All channels that have been published to
+pub fn channels() -> Vec<Info> {
+    CHANNELS.lock().unwrap().values().map(|ch| ch.lock().unwrap().info.clone()).collect()
+}
+
+/// Description of one channel
+pub fn info(channel: &str) -> Option<Info> {
+    CHANNELS.lock().unwrap().get(channel).map(|ch| ch.lock().unwrap().info.clone())
+}
+
+/// Samples in a channel from number `from` onwards (or all that are kept, if `from` is `None`)
+///
+/// Returns `None` if nothing has been published to the channel yet.
+pub fn since(channel: &str, from: Option<u64>) -> Option<Delta> {
+    let ch = match CHANNELS.lock().unwrap().get(channel) {
+        Some(ch) => ch.clone(),
+        None => return None,
+    };
+    let delta = ch.lock().unwrap().since(from);
+    Some(delta)
+}
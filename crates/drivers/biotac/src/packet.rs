@@ -0,0 +1,162 @@
+//! BioTac packet layout and live plots (shared by the hardware and simulated backends)
+
+use comms::CmdFrom;
+use std::mem;
+use std::ops::Range;
+use std::sync::mpsc::Sender;
+use time;
+use clock::Stamp;
+use utils::replay::Player;
+
+#[derive(Clone)]
+#[repr(packed)]
+pub struct Packet {
+    pub stamp: time::Timespec,
+    pub sync: Stamp,
+    pub pdc: u32,
+    pub pac: [u32; 22],
+    pub tdc: u32,
+    pub tac: u32,
+    pub electrode: [u32; 19],
+}
+
+writable!(Packet as "biotac" { stamp, sync, pdc, pac, tdc, tac, electrode });
+
+/// Layout of `Packet` in files written before they had headers (which also predate `sync`)
+#[repr(packed)]
+#[allow(dead_code)]
+pub struct LegacyPacket {
+    pub stamp: time::Timespec,
+    pub pdc: u32,
+    pub pac: [u32; 22],
+    pub tdc: u32,
+    pub tac: u32,
+    pub electrode: [u32; 19],
+}
+
+writable!(LegacyPacket as "biotac" { stamp, pdc, pac, tdc, tac, electrode });
+
+/// Decode one SPI batch into a packet
+///
+/// `frame_structure` is the sequence of channel commands in each frame (as configured in the
+/// Cheetah), and `finger` is the BioTac whose parity errors are worth reporting.
+pub fn parse_batch(raw: &[u8], frame_structure: &[i8], finger: u8) -> Packet {
+    static PARITY: [u8; 128] = [0x01, 0x02, 0x04, 0x07, 0x08, 0x0B, 0x0D, 0x0E,
+                                0x10, 0x13, 0x15, 0x16, 0x19, 0x1A, 0x1C, 0x1F,
+                                0x20, 0x23, 0x25, 0x26, 0x29, 0x2A, 0x2C, 0x2F,
+                                0x31, 0x32, 0x34, 0x37, 0x38, 0x3B, 0x3D, 0x3E,
+                                0x40, 0x43, 0x45, 0x46, 0x49, 0x4A, 0x4C, 0x4F,
+                                0x51, 0x52, 0x54, 0x57, 0x58, 0x5B, 0x5D, 0x5E,
+                                0x61, 0x62, 0x64, 0x67, 0x68, 0x6B, 0x6D, 0x6E,
+                                0x70, 0x73, 0x75, 0x76, 0x79, 0x7A, 0x7C, 0x7F,
+                                0x80, 0x83, 0x85, 0x86, 0x89, 0x8A, 0x8C, 0x8F,
+                                0x91, 0x92, 0x94, 0x97, 0x98, 0x9B, 0x9D, 0x9E,
+                                0xA1, 0xA2, 0xA4, 0xA7, 0xA8, 0xAB, 0xAD, 0xAE,
+                                0xB0, 0xB3, 0xB5, 0xB6, 0xB9, 0xBA, 0xBC, 0xBF,
+                                0xC1, 0xC2, 0xC4, 0xC7, 0xC8, 0xCB, 0xCD, 0xCE,
+                                0xD0, 0xD3, 0xD5, 0xD6, 0xD9, 0xDA, 0xDC, 0xDF,
+                                0xE0, 0xE3, 0xE5, 0xE6, 0xE9, 0xEA, 0xEC, 0xEF,
+                                0xF1, 0xF2, 0xF4, 0xF7, 0xF8, 0xFB, 0xFD, 0xFE];
+
+    let mut packet: Packet = unsafe { mem::zeroed::<Packet>() };
+    packet.stamp = time::get_time();
+    packet.sync = Stamp::now();
+
+    let byte_shift = 8;
+    let n_samples = raw.len() / byte_shift;
+    let mut pac_index = 0;
+    for i in 0..n_samples {
+        let channel_id: i8 = (frame_structure[i % frame_structure.len()] & 0x7E) >> 1;
+        for j in 0..3 {
+            let high = raw[i*byte_shift + j*2 + 2];
+            let low  = raw[i*byte_shift + j*2 + 3];
+            let spi_data: u32 = (high as u32 >> 1) * 32 + (low as u32 >> 3);
+            if (PARITY[(low >> 1) as usize] == low) && (PARITY[(high >> 1) as usize] == high) {
+                match channel_id {
+                    3 => packet.tdc = spi_data,
+                    2 => packet.tac = spi_data,
+                    1 => packet.pdc = spi_data,
+                    0 => packet.pac[pac_index] = spi_data,
+                    c @ 17...35 => packet.electrode[(c - 17) as usize] = spi_data,
+                    _ => println!("bad channel ID at ({}, {})", i, j),
+                }
+            } else if (j+1) as u8 == finger {
+                println!("bad parity at ({}, {})", i, j);
+            }
+        }
+        if channel_id == 0 {
+            pac_index += 1;
+        }
+    }
+
+    packet
+}
+
+/// A BioTac dump being replayed
+///
+/// The first chunk of a dump (`biotacdump.dat`) holds the finger number followed by the frame
+/// structure; every following chunk is one raw SPI batch.
+pub struct Replay {
+    player: Player,
+    finger: u8,
+    frame_structure: Vec<i8>,
+    finished: bool,
+}
+
+impl Replay {
+    pub fn new(mut player: Player) -> Result<Replay, String> {
+        match player.next_chunk() {
+            Ok(Some(ref header)) if header.len() > 1 => Ok(Replay {
+                player: player,
+                finger: header[0],
+                frame_structure: header[1..].iter().map(|&b| b as i8).collect(),
+                finished: false,
+            }),
+            Ok(_) => Err("BioTac dump has no frame structure".into()),
+            Err(e) => Err(format!("Error reading BioTac dump: {:?}", e)),
+        }
+    }
+
+    /// Decode the next recorded batch
+    ///
+    /// Returns `None` on errors (which are printed) and at the end of the dump (which is announced
+    /// once).
+    pub fn next(&mut self, tx: &Sender<CmdFrom>) -> Option<Packet> {
+        match self.player.next_chunk() {
+            Ok(Some(raw)) => Some(parse_batch(&raw, &self.frame_structure, self.finger)),
+            Ok(None) => {
+                if !self.finished {
+                    comms::tell(tx, "web", "msg BioTac replay finished").unwrap();
+                    self.finished = true;
+                }
+                None
+            },
+            Err(e) => {
+                errorln!("Error reading BioTac replay: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Plotted quantities, in the order they are published
+pub const FIELDS: [&'static str; 5] = ["pdc", "et", "eb", "el", "er"];
+
+/// Process a packet into pressure and electrode readings for the live plot
+///
+/// The electrodes are averaged over the top, bottom, left and right of the finger.
+pub fn plot(packet: &Packet) -> Vec<f64> {
+    fn s(f: u32, n: usize) -> f64 {
+        (((f as i32) - 2048) as f64) / (n as f64)
+    }
+    fn m(v: &[u32], r: Range<usize>) -> f64 {
+        s(v[r.clone()].iter().sum(), r.end - r.start)
+    }
+
+    let electrode = packet.electrode;
+    vec![s(packet.pdc, 1),
+         m(&electrode, 6..9),
+         m(&electrode, 17..19),
+         m(&electrode, 10..16),
+         m(&electrode, 0..6)]
+}
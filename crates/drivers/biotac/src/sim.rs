@@ -0,0 +1,117 @@
+//! Simulated BioTac, used when the `hardware` feature is off
+//!
+//! Produces one packet per 10 ms batch with a slow press/release cycle: the fluid pressure (PDC)
+//! rises, the fingertip electrodes respond, and the vibration channel (PAC) gets a burst of
+//! texture while the finger is loaded.
+//!
+//! Started with `replay:<dump>[,<speed>]`, it instead decodes a `biotacdump.dat` captured by the
+//! hardware backend.
+
+use comms::{Controllable, CmdFrom, Block, NoCommands};
+use scribe::Writer;
+use clock::{self, Clock, Stamp, Source};
+use utils::RecordingContext;
+use utils::config::Config;
+use std::f64::consts::PI;
+use std::sync::mpsc::Sender;
+use time;
+use utils::replay::{self, Player};
+
+use telemetry::Publisher;
+
+use packet::{self, Packet};
+
+pub struct Biotac {
+    file: Writer<Packet>,
+    clock: Clock,
+    plot: Publisher,
+    tx: Sender<CmdFrom>,
+    i: usize,
+    start: time::Tm,
+    /// Dump being replayed instead of synthesizing packets
+    replay: Option<packet::Replay>,
+}
+
+impl Biotac {
+    /// Make up a plausible packet for time `t` (in seconds)
+    fn synthesize(t: f64) -> Packet {
+        // 0 (unloaded) to 1 (fully pressed), period 4 s
+        let load = (0.5 - 0.5 * (2.0 * PI * 0.25 * t).cos()).max(0.0);
+
+        let mut pac = [0u32; 22];
+        for (j, p) in pac.iter_mut().enumerate() {
+            let tj = t + j as f64 / 2200.0;
+            *p = (2048.0 + 300.0 * load * (2.0 * PI * 120.0 * tj).sin()) as u32;
+        }
+
+        let mut electrode = [0u32; 19];
+        for (j, e) in electrode.iter_mut().enumerate() {
+            // electrodes closer to the tip (higher index) see more of the load
+            *e = (3000.0 - 800.0 * load * (0.5 + j as f64 / 38.0)) as u32;
+        }
+
+        Packet {
+            stamp: time::get_time(),
+            sync: Stamp::now(),
+            pdc: (2048.0 + 600.0 * load) as u32,
+            pac: pac,
+            tdc: 2600,
+            tac: 2048,
+            electrode: electrode,
+        }
+    }
+}
+
+guilty! {
+    impl Controllable for Biotac {
+        const NAME: &'static str = "biotac";
+        const BLOCK: Block = Block::Period(10_000_000);
+        type Command = NoCommands;
+
+        fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, _: &Config) -> Result<Biotac, String> {
+            let replay = replay::Spec::from_param(data.as_ref().map(|s| s as &str)).map(|spec| {
+                println!("Biotac: replaying {}", spec.path.display());
+                packet::Replay::new(Player::open(&spec).unwrap()).unwrap()
+            });
+            if replay.is_none() {
+                println!("finger #1 serial number = SIMULATED");
+                tx.send(CmdFrom::Serial(Self::NAME.into(), "SIMULATED".into())).unwrap();
+            }
+
+            Ok(Biotac {
+                file: Writer::with_file(&ctx, "biotac.dat"),
+                clock: clock::register("biotac", Source::Host),
+                plot: Publisher::series("biotac", &packet::FIELDS),
+                tx: tx,
+                i: 0,
+                start: time::now(),
+                replay: replay,
+            })
+        }
+
+        fn step(&mut self, _: Option<NoCommands>) {
+            self.i += 1;
+
+            let mut packet = match self.replay {
+                Some(ref mut replay) => match replay.next(&self.tx) {
+                    Some(packet) => packet,
+                    None => return
+                },
+                None => Biotac::synthesize(self.i as f64 * 0.010)
+            };
+
+            packet.sync = self.clock.stamp(packet.sync.raw, None);
+            if self.plot.due() {
+                self.plot.publish(packet::plot(&packet));
+            }
+
+            self.file.write(packet);
+        }
+
+        fn teardown(&mut self) {
+            let end = time::now();
+            let millis = (end - self.start).num_milliseconds() as f64;
+            println!("{} simulated Biotac packets generated in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+        }
+    }
+}
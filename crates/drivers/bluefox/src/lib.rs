@@ -4,35 +4,72 @@
 
 #[macro_use] extern crate guilt_by_association;
 #[macro_use] extern crate utils;
-#[cfg_attr(not(feature="hardware"), macro_use)] extern crate comms;
+extern crate comms;
+extern crate scribe;
+extern crate clock;
+extern crate telemetry;
+extern crate time;
+extern crate image;
+extern crate rustc_serialize as serialize;
+extern crate serde_json;
+#[macro_use] extern crate serde_derive;
+
+mod png;
+
+use std::str::FromStr;
+
+/// Commands understood by the Bluefox service
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Command {
+    /// Start saving frames to disk
+    DiskStart,
+    /// Stop saving frames to disk
+    DiskStop,
+    /// Apply new camera settings (JSON, in the format of the settings file)
+    Settings(serde_json::Value),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        let s = s.trim();
+        let (word, rest) = match s.find(char::is_whitespace) {
+            Some(i) => (&s[..i], s[i..].trim()),
+            None => (s, ""),
+        };
+        match (word, rest) {
+            ("disk", "start") => Ok(Command::DiskStart),
+            ("disk", "stop")  => Ok(Command::DiskStop),
+            ("settings", json) => serde_json::from_str(json)
+                                      .map(Command::Settings)
+                                      .map_err(|e| format!("invalid settings: {}", e)),
+            _ => Err(format!("unknown command {:?}", s)),
+        }
+    }
+}
 
 group_attr!{
     #[cfg(feature = "hardware")]
 
     #[macro_use] extern crate lazy_static;
-    extern crate time;
-    extern crate image;
-    extern crate rustc_serialize as serialize;
-    extern crate serde_json;
 
-    extern crate scribe;
     extern crate bluefox_sys as ll;
 
-    use image::{imageops, ImageBuffer, ColorType, FilterType, Pixel};
-    use image::png::PNGEncoder;
-    use serialize::base64;
-    use serialize::base64::ToBase64;
+    use image::ColorType;
     use std::{fs, thread};
     use std::sync::{Mutex, RwLock};
     use std::sync::mpsc::Sender;
     use std::time::Duration;
     use comms::{Controllable, CmdFrom, Block, RestartableThread};
-    use utils::config;
-    use scribe::Writer;
+    use telemetry::Publisher;
+    use utils::RecordingContext;
+    use utils::config::Config;
+    use scribe::{Writer, Compression, Policy};
+    use clock::{Clock, Source};
     use ll::Device;
     use ll::settings::*;
-
-    type PngStuff = (usize, Vec<u8>, (usize, usize), ColorType, Option<usize>);
+    use png::PngStuff;
 
     /// Controllable struct for the camera
     pub struct Bluefox {
@@ -50,18 +87,34 @@ group_attr!{
         /// PNG writer rebootable thread
         png: RestartableThread<PngStuff>,
 
+        /// Rate limit for thumbnails (the PNG thread publishes them)
+        thumbs: Publisher,
+
         /// Timestamp file handle
         stampfile: Writer<[u8]>,
 
-        writer: Writer<[u8]>
+        /// Clock used to correct the timestamps
+        clock: Clock,
+
+        writer: Writer<[u8]>,
+
+        /// Frames stored in the container so far (chunk indices start at 1)
+        chunks: usize,
+
+        /// Frames the writer can queue before dropping new ones
+        queue_depth: usize,
+
+        /// Where recordings go
+        ctx: RecordingContext,
     }
 
     guilty!{
         impl Controllable for Bluefox {
             const NAME: &'static str = "bluefox";
             const BLOCK: Block = Block::Immediate;
+            type Command = Command;
 
-            fn setup(tx: Sender<CmdFrom>, data: Option<String>) -> Bluefox {
+            fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Bluefox, String> {
                 let mut fps = 15.0;
                 let mut format = (CameraPixelFormat::RGB8, DestPixelFormat::Auto);
                 if let Some(ref data) = data {
@@ -87,19 +140,20 @@ group_attr!{
                 lazy_static! {
                     static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
                 }
-                let settings_data = utils::in_original_dir("read bluefox settings", || utils::slurp(config::BLUEFOX_SETTINGS)).unwrap().unwrap();
+                let settings_path = fs::canonicalize(config.path(&config.bluefox.settings)).unwrap();
+                let settings_data = utils::slurp(&settings_path).unwrap();
                 let default_settings: Settings = serde_json::from_str(&settings_data).unwrap();
 
                 let txc = Mutex::new(tx.clone());
                 utils::watch(default_settings.clone(),
                              &SETTINGS,
-                             utils::in_original_dir("find bluefox dir", || fs::canonicalize(config::BLUEFOX_SETTINGS)).unwrap().unwrap().parent().unwrap(),
+                             settings_path.parent().unwrap(),
                              "json",
                              move |_, path| {
                                  println!("BLUEFOX: updating settings from {}", path.display());
                                  thread::sleep(Duration::from_millis(500));
-                                 let data = utils::in_original_dir("read bluefox settings", || utils::slurp(path).unwrap()).unwrap();
-                                 txc.lock().unwrap().send(CmdFrom::Data(format!("to bluefox settings {}", data))).unwrap();
+                                 let data = utils::slurp(path).unwrap();
+                                 comms::tell(&txc.lock().unwrap(), "bluefox", format!("settings {}", data)).unwrap();
                              });
 
                 let settings = Settings {
@@ -114,71 +168,43 @@ group_attr!{
                 device.request_reset().unwrap();
                 device.set(&settings).unwrap();
 
-                let mtx = Mutex::new(tx);
-                Bluefox {
+                let thumbs = Publisher::images("bluefox");
+                let png_thumbs = thumbs.clone();
+                Ok(Bluefox {
                     device: device,
                     i: 0,
                     writing: false,
                     balanced: Some(0),
                     start: time::now(),
 
-                    png: RestartableThread::new("Bluefox PNG thread",
-                                                move |(i, unencoded, (h, w), bd, id)| {
-                        let mut encoded = Vec::with_capacity(w*h);
-                        let to_resize = prof!("imagebuffer",
-                                              ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32,
-                                                                                         h as u32,
-                                                                                         unencoded)
-                                              .unwrap());
-
-                        let brightness = to_resize.pixels()
-                                                  .fold(0.0, |acc, rgb| acc + rgb.to_luma()[0] as f64);
-                        println!("brightness={}", brightness);
-
-                        //let (ww, hh) = (200, 150);
-                        let (ww, hh) = (w as u32, h as u32);
-                        let resized = prof!("resize", 
-                                            if (w as u32, h as u32) == (ww, hh) {
-                                                to_resize
-                                            } else {
-                                                imageops::resize(&to_resize,
-                                                                 ww,
-                                                                 hh,
-                                                                 FilterType::Nearest)
-                                            });
-
-                        prof!("encode",
-                              PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd).unwrap());
-                        let id_str = if let Some(id) = id { format!(" {}", id) } else { String::new() };
-                        prof!("send",
-                              mtx
-                                .lock()
-                                .unwrap()
-                                .send(
-                                    CmdFrom::Data(
-                                        format!("send{} kick bluefox {} data:image/png;base64,{}",
-                                                id_str, i,
-                                                prof!("base64",
-                                                      encoded.to_base64(base64::STANDARD)))))
-                                .unwrap());
+                    png: RestartableThread::new("Bluefox PNG thread", move |data: PngStuff| {
+                        png::send(&png_thumbs, data);
                     }),
-
-                    stampfile: Writer::with_file("bluefox_times.csv"),
-                    writer: Writer::with_files("bluefox{}.dat"),
-                }
+                    thumbs: thumbs,
+
+                    stampfile: Writer::with_file(&ctx, "bluefox_times.csv"),
+                    clock: clock::register("bluefox", Source::Host),
+                    writer: Writer::with_container(&ctx, "bluefox.chunks", Compression::default())
+                            .with_policy(Policy::DropNewest, config.scribe.frame_queue_depth),
+                    chunks: 0,
+                    queue_depth: config.scribe.frame_queue_depth,
+                    ctx: ctx,
+                })
             }
 
-            fn step(&mut self, data: Option<String>) {
+            fn step(&mut self, cmd: Option<Command>) {
                 self.i += 1;
 
-                match data.as_ref().map(|s| s as &str) {
-                    Some("disk start") => {
+                match cmd {
+                    Some(Command::DiskStart) => {
                         println!("Started Bluefox recording.");
-                        self.stampfile = Writer::with_file("bluefox_times.csv");
+                        self.stampfile = Writer::with_file(&self.ctx, "bluefox_times.csv");
+                        self.writer = Writer::with_container(&self.ctx, "bluefox.chunks", Compression::default())
+                                      .with_policy(Policy::DropNewest, self.queue_depth);
+                        self.chunks = 0;
                         self.writing = true;
-                        self.writer.set_index(self.i);
                     },
-                    Some("disk stop") => {
+                    Some(Command::DiskStop) => {
                         println!("Stopped Bluefox recording.");
                         self.writing = false;
                     },
@@ -212,20 +238,24 @@ group_attr!{
                         self.device.set(&set).unwrap();
                     },
                     */
-                    Some(s) if s.starts_with("settings") => {
+                    Some(Command::Settings(ref settings)) => {
                         if self.writing {
                             println!("BLUEFOX: currently writing, ignoring new settings");
                         } else if self.balanced.is_some() {
                             println!("BLUEFOX: not white balanced yet, ignoring new setings");
                         } else {
-                            println!("BLUEFOX: applying new settings");
-                            let set: Settings = serde_json::from_str(&s[9..]).unwrap();
-                            self.device.request_reset().unwrap();
-                            self.device.set(&set).unwrap();
-                            self.balanced = Some(self.i);
+                            match serde_json::from_value::<Settings>(settings.clone()) {
+                                Ok(set) => {
+                                    println!("BLUEFOX: applying new settings");
+                                    self.device.request_reset().unwrap();
+                                    self.device.set(&set).unwrap();
+                                    self.balanced = Some(self.i);
+                                }
+                                Err(e) => errorln!("BLUEFOX: ignoring invalid settings: {}", e),
+                            }
                         }
                     },
-                    Some(_) | None => ()
+                    None => ()
                 }
 
                 if let Some(from) = self.balanced {
@@ -250,30 +280,31 @@ group_attr!{
 
                 if self.writing {
                     let stamp = time::get_time();
-                    self.writer.write(image.data());
-                    self.stampfile.write(format!("{},bluefox{}.dat,{:.9}\n",
-                                                 self.i,
-                                                 self.i,
-                                                 (stamp.sec as f64
-                                                  + stamp.nsec as f64
-                                                  / 1_000_000_000f64))
-                                         .as_bytes());
+                    let sync = self.clock.stamp(clock::now(), None);
+                    if self.writer.write(image.data()) {
+                        self.chunks += 1;
+                        // only timestamp frames that will actually be written
+                        self.stampfile.write(format!("{},bluefox.chunks,{:.9},{},{},{}\n",
+                                                     self.i,
+                                                     (stamp.sec as f64
+                                                      + stamp.nsec as f64
+                                                      / 1_000_000_000f64),
+                                                     sync.raw,
+                                                     sync.corrected,
+                                                     self.chunks)
+                                             .as_bytes());
+                    }
                 }
 
-                match data.as_ref().map(|s| s as &str) {
-                    Some(s) if s.starts_with("kick") => {
-                        //self.device.set_reverse_x(!self.device.get_reverse_x().unwrap());
-                        //self.device.set_reverse_y(!self.device.get_reverse_y().unwrap());
-                        println!("buf = {:?}", image.buf);
-                        prof!("send to thread",
-                              self.png.send((self.i,
-                                             image.data().into(),
-                                             image.size(),
-                                             ColorType::RGB(8),
-                                             s.split(' ').skip(1).next().map(|s| s.parse().unwrap())))
-                              .unwrap())
-                    },
-                    _ => {}
+                if self.thumbs.due() {
+                    //self.device.set_reverse_x(!self.device.get_reverse_x().unwrap());
+                    //self.device.set_reverse_y(!self.device.get_reverse_y().unwrap());
+                    prof!("send to thread",
+                          self.png.send((self.i,
+                                         image.data().into(),
+                                         image.size(),
+                                         ColorType::RGB(8)))
+                          .unwrap())
                 }
                 /*
                 PNGEncoder::new(&mut f).encode(image.data(),
@@ -299,4 +330,6 @@ group_attr!{
 }
 
 #[cfg(not(feature = "hardware"))]
-stub!(Bluefox);
+mod sim;
+#[cfg(not(feature = "hardware"))]
+pub use sim::Bluefox;
@@ -0,0 +1,44 @@
+//! Live camera previews for the web interface (shared by the hardware and simulated backends)
+
+use telemetry::Publisher;
+use image::{self, imageops, ImageBuffer, ColorType, FilterType, Pixel};
+use image::png::PNGEncoder;
+use serialize::base64;
+use serialize::base64::ToBase64;
+
+/// (frame number, raw pixels, (height, width), pixel format)
+pub type PngStuff = (usize, Vec<u8>, (usize, usize), ColorType);
+
+/// Encode a raw RGB frame as a PNG and publish it as a thumbnail
+pub fn send(thumbs: &Publisher, (i, unencoded, (h, w), bd): PngStuff) {
+    let mut encoded = Vec::with_capacity(w*h);
+    let to_resize = prof!("imagebuffer",
+                          ImageBuffer::<image::Rgb<u8>, _>::from_raw(w as u32,
+                                                                     h as u32,
+                                                                     unencoded)
+                          .unwrap());
+
+    let brightness = to_resize.pixels()
+                              .fold(0.0, |acc, rgb| acc + rgb.to_luma()[0] as f64);
+    println!("brightness={}", brightness);
+
+    //let (ww, hh) = (200, 150);
+    let (ww, hh) = (w as u32, h as u32);
+    let resized = prof!("resize", 
+                        if (w as u32, h as u32) == (ww, hh) {
+                            to_resize
+                        } else {
+                            imageops::resize(&to_resize,
+                                             ww,
+                                             hh,
+                                             FilterType::Nearest)
+                        });
+
+    prof!("encode",
+          PNGEncoder::new(&mut encoded).encode(&resized, ww, hh, bd).unwrap());
+    prof!("publish",
+          thumbs.publish_image(i,
+                               format!("data:image/png;base64,{}",
+                                       prof!("base64",
+                                             encoded.to_base64(base64::STANDARD)))));
+}
@@ -0,0 +1,241 @@
+//! Simulated mvBlueFOX3, used when the `hardware` feature is off
+//!
+//! Produces 1600x1200 RGB8 frames (a color gradient with a moving bar) at the requested frame
+//! rate (default 15 FPS).
+//!
+//! Started with `replay:<episode dir or bluefox_times.csv>[,<speed>]`, it instead plays back frames
+//! recorded by the hardware backend, on their original schedule.
+
+use comms::{Controllable, CmdFrom, Block, RestartableThread};
+use telemetry::Publisher;
+use scribe::{Writer, Compression, Container, Policy};
+use clock::{self, Clock, Source};
+use utils::prelude::*;
+use utils::RecordingContext;
+use utils::config::Config;
+use std::sync::mpsc::Sender;
+use image::ColorType;
+use time::{self, Duration};
+use utils::replay::{self, Frames};
+
+use png::{self, PngStuff};
+use super::Command;
+
+const WIDTH: usize = 1600;
+const HEIGHT: usize = 1200;
+
+/// Controllable struct for the simulated camera
+pub struct Bluefox {
+    /// Time that setup() was last called (used for calculating frame rates)
+    start: time::Tm,
+
+    /// Number of frames captured since setup() was last called (used for calculating frame rates)
+    i: usize,
+    writing: bool,
+
+    /// PNG writer rebootable thread
+    png: RestartableThread<PngStuff>,
+
+    /// Rate limit for thumbnails (the PNG thread publishes them)
+    thumbs: Publisher,
+
+    /// Timestamp file handle
+    stampfile: Writer<[u8]>,
+
+    /// Clock used to correct the timestamps
+    clock: Clock,
+
+    writer: Writer<[u8]>,
+
+    /// Frames stored in the container so far (chunk indices start at 1)
+    chunks: usize,
+
+    /// Frame period (ns)
+    period: u64,
+
+    /// Deadline for the next frame (in `time::precise_time_ns` units)
+    next: u64,
+
+    /// Recording being replayed instead of synthesizing frames (and whether it has run out)
+    replay: Option<(Frames, bool)>,
+
+    tx: Sender<CmdFrom>,
+
+    /// Frames the writer can queue before dropping new ones
+    queue_depth: usize,
+
+    /// Where recordings go
+    ctx: RecordingContext,
+}
+
+impl Bluefox {
+    /// Make up an RGB frame for frame number `i`
+    fn synthesize(i: usize) -> Vec<u8> {
+        let bar = (i * 16) % WIDTH;
+        let mut data = Vec::with_capacity(WIDTH * HEIGHT * 3);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if x >= bar && x < bar + 32 {
+                    data.extend_from_slice(&[255, 255, 255]);
+                } else {
+                    data.push((x * 255 / WIDTH) as u8);
+                    data.push((y * 255 / HEIGHT) as u8);
+                    data.push(128);
+                }
+            }
+        }
+        data
+    }
+}
+
+guilty!{
+    impl Controllable for Bluefox {
+        const NAME: &'static str = "bluefox";
+        const BLOCK: Block = Block::Immediate;
+        type Command = Command;
+
+        fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Bluefox, String> {
+            let replay = replay::Spec::from_param(data.as_ref().map(|s| s as &str)).map(|spec| {
+                let frames = Frames::open(&spec, "bluefox", Container::open).unwrap();
+                println!("BLUEFOX: replaying {} frames from {}", frames.len(), spec.path.display());
+                (frames, false)
+            });
+
+            let mut fps = 15.0;
+            if replay.is_none() {
+                if let Some(ref data) = data {
+                    if let Some(fps_str) = data.split(",").next() {
+                        if let Ok(fps_num) = fps_str.parse::<f64>() {
+                            fps = fps_num;
+                        } else {
+                            println!("WARNING: invalid FPS {:?}", fps_str);
+                        }
+                    }
+                }
+
+                println!("BLUEFOX: simulated device at {} FPS", fps);
+            }
+
+            let thumbs = Publisher::images("bluefox");
+            let png_thumbs = thumbs.clone();
+            Ok(Bluefox {
+                i: 0,
+                writing: false,
+                start: time::now(),
+
+                png: RestartableThread::new("Bluefox PNG thread", move |data: PngStuff| {
+                    png::send(&png_thumbs, data);
+                }),
+                thumbs: thumbs,
+
+                stampfile: Writer::with_file(&ctx, "bluefox_times.csv"),
+                clock: clock::register("bluefox", Source::Host),
+                writer: Writer::with_container(&ctx, "bluefox.chunks", Compression::default())
+                        .with_policy(Policy::DropNewest, config.scribe.frame_queue_depth),
+                chunks: 0,
+                period: (1.0e9 / fps) as u64,
+                next: time::precise_time_ns(),
+                replay: replay,
+                tx: tx,
+                queue_depth: config.scribe.frame_queue_depth,
+                ctx: ctx,
+            })
+        }
+
+        fn step(&mut self, cmd: Option<Command>) {
+            self.i += 1;
+
+            match cmd {
+                Some(Command::DiskStart) => {
+                    println!("Started Bluefox recording.");
+                    self.stampfile = Writer::with_file(&self.ctx, "bluefox_times.csv");
+                    self.writer = Writer::with_container(&self.ctx, "bluefox.chunks", Compression::default())
+                                  .with_policy(Policy::DropNewest, self.queue_depth);
+                    self.chunks = 0;
+                    self.writing = true;
+                },
+                Some(Command::DiskStop) => {
+                    println!("Stopped Bluefox recording.");
+                    self.writing = false;
+                },
+                Some(Command::Settings(_)) => {
+                    println!("BLUEFOX: simulated device, ignoring new settings");
+                },
+                None => ()
+            }
+
+            let image = match self.replay {
+                Some((ref mut frames, ref mut finished)) => match frames.next_frame() {
+                    Ok(Some((_, data))) => {
+                        if data.len() != WIDTH * HEIGHT * 3 {
+                            errorln!("Recorded Bluefox frame has an unexpected size ({})!", data.len());
+                            return;
+                        }
+                        data
+                    },
+                    Ok(None) => {
+                        if !*finished {
+                            comms::tell(&self.tx, "web", "msg Bluefox replay finished").unwrap();
+                            *finished = true;
+                        }
+                        Duration::milliseconds(100).sleep();
+                        return;
+                    },
+                    Err(e) => {
+                        errorln!("Error reading Bluefox replay: {:?}", e);
+                        return;
+                    }
+                },
+                None => {
+                    // wait for the "camera" to produce the next frame
+                    let now = time::precise_time_ns();
+                    if now < self.next {
+                        Duration::nanoseconds((self.next - now) as i64).sleep();
+                    } else if now - self.next > 10 * self.period {
+                        self.next = now;
+                    }
+                    self.next += self.period;
+
+                    prof!("synthesize", Bluefox::synthesize(self.i))
+                }
+            };
+
+            if self.writing {
+                let stamp = time::get_time();
+                let sync = self.clock.stamp(clock::now(), None);
+                if self.writer.write(&image) {
+                    self.chunks += 1;
+                    // only timestamp frames that will actually be written
+                    self.stampfile.write(format!("{},bluefox.chunks,{:.9},{},{},{}\n",
+                                                 self.i,
+                                                 (stamp.sec as f64
+                                                  + stamp.nsec as f64
+                                                  / 1_000_000_000f64),
+                                                 sync.raw,
+                                                 sync.corrected,
+                                                 self.chunks)
+                                         .as_bytes());
+                }
+            }
+
+            if self.thumbs.due() {
+                prof!("send to thread",
+                      self.png.send((self.i,
+                                     image,
+                                     (HEIGHT, WIDTH),
+                                     ColorType::RGB(8)))
+                      .unwrap())
+            }
+        }
+
+        fn teardown(&mut self) {
+            self.png.join();
+            let end = time::now();
+            let millis = (end - self.start).num_milliseconds() as f64;
+            println!("{} simulated bluefox frames generated in {} s ({} FPS)!",
+                     self.i,
+                     millis/1000.0,
+                     1000.0*(self.i as f64)/millis);
+        }
+    }
+}
@@ -45,127 +45,122 @@
 //! [liboptoforce]: https://github.com/ethz-asl/liboptoforce
 
 #[macro_use] extern crate utils;
-#[cfg_attr(not(feature="hardware"), macro_use)] extern crate comms;
+extern crate comms;
+#[macro_use] extern crate scribe;
+extern crate clock;
+extern crate telemetry;
+extern crate time;
 
 #[macro_use] extern crate guilt_by_association;
-#[macro_use] extern crate serde_derive;
+
+mod packet;
+
+/// Layout of the packets in `optoforce.dat` (needed to read files written before they had headers)
+pub fn packet_schema() -> scribe::Schema {
+    <packet::LegacyPacket as scribe::Writable>::schema()
+}
 
 group_attr!{
     #[cfg(feature = "hardware")]
 
-    extern crate scribe;
-
-    extern crate time;
     extern crate libc;
     extern crate rustc_serialize as serialize;
-    extern crate serde_json;
 
     use std::thread;
     use std::default::Default;
+    use std::fs::File;
+    use std::io::BufWriter;
     use std::sync::mpsc::Sender;
     use std::time::Duration;
-    use std::ptr;
-    use comms::{Controllable, CmdFrom, Block, RestartableThread};
-    use scribe::{Writer, Writable};
+    use comms::{Controllable, CmdFrom, Block, NoCommands};
+    use scribe::Writer;
+    use clock::{Clock, Source as ClockSource};
+    use utils::replay::{self, Player, Recorder};
+    use utils::RecordingContext;
+    use utils::config::Config;
+    use telemetry::Publisher;
+    use packet::Packet;
 
     mod wrapper;
 
-    type PngStuff = (Sender<CmdFrom>, Vec<Packet>, Option<usize>);
+    /// Where readings come from
+    enum Source {
+        /// The sensor (with a dump of everything it sends)
+        Device(wrapper::Device, Recorder<BufWriter<File>>),
+        /// A previously recorded dump (and whether it has run out)
+        Replay(Player, bool),
+    }
 
     pub struct Optoforce {
         tx: Sender<CmdFrom>,
-        device: wrapper::Device,
+        source: Source,
         i: usize,
-        buf: Vec<Packet>,
-        png: RestartableThread<PngStuff>,
+        plot: Publisher,
         file: Writer<Packet>,
+        clock: Clock,
         start: time::Tm
     }
 
-    #[repr(packed)]
-    #[allow(dead_code)]
-    #[derive(Copy, Clone)]
-    struct Packet {
-        stamp: time::Timespec,
-        xyz  : wrapper::XYZ,
-    }
-
-    unsafe impl Writable for Packet {}
-
-    const BUF_LEN: usize = 2000;
-    #[derive(Serialize)] struct Data<'a> { t: &'a [i32], fx: &'a [i32], fy: &'a [i32], fz: &'a [i32] } // FIXME #41053
-
     guilty!{
         impl Controllable for Optoforce {
             const NAME: &'static str = "optoforce";
             const BLOCK: Block = Block::Period(1_000_000);
+            type Command = NoCommands;
 
-            fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Optoforce {
-                let dev = wrapper::Device::new(Default::default());
-                dev.connect(wrapper::ConnectOptions { path: "/dev/ttyOPTO", ..Default::default() }).unwrap();
-                thread::sleep(Duration::from_millis(100));
-                dev.set(wrapper::Settings::new()
-                        .set_speed(wrapper::settings::Speed::Hz1000)
-                       );
-                println!("Optoforce settings: {:?}", dev.get().unwrap());
-
-                // some stuff for the RestartableThread
-                let mut idx = 0;
-                let start = time::get_time();
+            fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Optoforce, String> {
+                let source = match replay::Spec::from_param(data.as_ref().map(|s| s as &str)) {
+                    Some(spec) => {
+                        println!("Optoforce: replaying {}", spec.path.display());
+                        Source::Replay(Player::open(&spec).unwrap(), false)
+                    }
+                    None => {
+                        let dev = wrapper::Device::new(Default::default());
+                        dev.connect(wrapper::ConnectOptions { path: &config.optoforce.port, ..Default::default() }).unwrap();
+                        thread::sleep(Duration::from_millis(100));
+                        dev.set(wrapper::Settings::new()
+                                .set_speed(wrapper::settings::Speed::Hz1000)
+                               );
+                        println!("Optoforce settings: {:?}", dev.get().unwrap());
+                        Source::Device(dev, Recorder::create(ctx.path("optoforcedump.dat")).unwrap())
+                    }
+                };
 
-                Optoforce {
+                Ok(Optoforce {
                     tx: tx,
-                    device: dev,
+                    source: source,
                     i: 0,
-                    file: Writer::with_file("optoforce.dat"),
+                    file: Writer::with_file(&ctx, "optoforce.dat"),
+                    clock: clock::register("optoforce", ClockSource::Host),
                     start: time::now(),
-                    buf: Vec::with_capacity(BUF_LEN),
-                    png: RestartableThread::new("Optoforce PNG thread", move |(tx, vec, id): PngStuff| {
-                        // process data
-                        let len = vec.len();
-                        let mut t  = vec![0; len];
-                        let mut fx = vec![0; len];
-                        let mut fy = vec![0; len];
-                        let mut fz = vec![0; len];
-                        macro_rules! r { ($f:expr) => { ($f * 1000.0) as i32 } }
-                        for i in 0..len {
-                            let diff = (vec[i].stamp - start).to_std().unwrap();
-                            t[i] = r!(diff.as_secs() as f64 + (diff.subsec_nanos() as f64 / 1.0e9));
-                            fx[i] = r!(vec[i].xyz.x.0 as f64);
-                            fy[i] = r!(vec[i].xyz.y.0 as f64);
-                            fz[i] = r!(32.0 - vec[i].xyz.z.0 as f64); // HACK
-                        }
-
-                        let id_str = if let Some(id) = id { format!(" {}", id) } else { String::new() };
-                        tx.send(CmdFrom::Data(format!("send{} kick optoforce {} {}", id_str, idx, serde_json::to_string(&Data { t: &t, fx: &fx, fy: &fy, fz: &fz }).unwrap()))).unwrap();
-                        idx += 1;
-                    })
-                }
+                    plot: Publisher::series("optoforce", &packet::FIELDS),
+                })
             }
 
-            fn step(&mut self, cmd: Option<String>) {
+            fn step(&mut self, _: Option<NoCommands>) {
+                let xyz = match self.source {
+                    Source::Device(ref dev, ref mut dump) => {
+                        let xyz = dev.read();
+                        dump.record(&xyz.to_bytes()).unwrap();
+                        xyz
+                    }
+                    Source::Replay(ref mut player, ref mut finished) => {
+                        match packet::replay_next(player, finished, &self.tx) {
+                            Some(xyz) => xyz,
+                            None => return
+                        }
+                    }
+                };
                 let packet = Packet {
                     stamp: time::get_time(),
-                    xyz: self.device.read()
+                    sync: self.clock.stamp(clock::now(), None),
+                    xyz: xyz
                 };
                 //println!("[OPTO] {:?}", packet.xyz);
 
-                match cmd.as_ref().map(|s| s as &str) {
-                    Some(s) if s.starts_with("kick") => {
-                        println!("Opto: transmitting plot");
-                        self.png.send((self.tx.clone(), self.buf.clone(), s.split(' ').skip(1).next().map(|s| s.parse().unwrap()))).unwrap();
-                    }
-                    _ => {}
+                if self.plot.due() {
+                    self.plot.publish(packet::plot(&packet));
                 }
 
-                if self.buf.len() == self.buf.capacity() {
-                    let len = self.buf.len()-1;
-                    unsafe {
-                        ptr::copy(&self.buf[1], &mut self.buf[0], len);
-                    }
-                    self.buf.truncate(len);
-                }
-                self.buf.push(packet.clone());
                 self.file.write(packet);
                 self.i += 1;
             }
@@ -180,4 +175,6 @@ group_attr!{
 }
 
 #[cfg(not(feature = "hardware"))]
-stub!(Optoforce);
+mod sim;
+#[cfg(not(feature = "hardware"))]
+pub use sim::Optoforce;
@@ -0,0 +1,122 @@
+//! OptoForce packet layout and live plots (shared by the hardware and simulated backends)
+
+use comms::CmdFrom;
+use scribe::{Describe, Type};
+use std::{fmt, mem, ptr};
+use std::ops::Deref;
+use std::sync::mpsc::Sender;
+use time;
+use clock::Stamp;
+use utils::replay::Player;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Double(pub f64);
+
+impl fmt::Debug for Double {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{: >7.3}", self.deref())
+    }
+}
+
+impl Describe for Double {
+    fn describe() -> Type {
+        f64::describe()
+    }
+}
+
+impl Deref for Double {
+    type Target = f64;
+
+    fn deref(&self) -> &f64 {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct XYZ {
+    pub x: Double,
+    pub y: Double,
+    pub z: Double,
+}
+describe!(XYZ { x, y, z });
+
+impl XYZ {
+    /// Raw bytes of a reading (as recorded in `optoforcedump.dat`)
+    pub fn to_bytes(&self) -> [u8; 24] {
+        unsafe { mem::transmute::<XYZ, [u8; 24]>(*self) }
+    }
+
+    /// Reconstitute a reading from its raw bytes
+    pub fn from_bytes(buf: &[u8]) -> Result<XYZ, String> {
+        if buf.len() != mem::size_of::<XYZ>() {
+            return Err(format!("Recorded OptoForce reading has the wrong size ({})!", buf.len()));
+        }
+
+        unsafe {
+            let mut xyz = mem::zeroed::<XYZ>();
+            ptr::copy_nonoverlapping(buf.as_ptr(), &mut xyz as *mut XYZ as *mut u8, buf.len());
+            Ok(xyz)
+        }
+    }
+}
+
+/// Get the next reading from a replayed dump
+///
+/// Returns `None` on errors (which are printed) and at the end of the dump (which is announced
+/// once, using `finished` to remember).
+pub fn replay_next(player: &mut Player, finished: &mut bool, tx: &Sender<CmdFrom>) -> Option<XYZ> {
+    match player.next_chunk() {
+        Ok(Some(chunk)) => match XYZ::from_bytes(&chunk) {
+            Ok(xyz) => Some(xyz),
+            Err(s) => {
+                errorln!("{}", s);
+                None
+            }
+        },
+        Ok(None) => {
+            if !*finished {
+                comms::tell(tx, "web", "msg OptoForce replay finished").unwrap();
+                *finished = true;
+            }
+            None
+        },
+        Err(e) => {
+            errorln!("Error reading OptoForce replay: {:?}", e);
+            None
+        }
+    }
+}
+
+#[repr(packed)]
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub struct Packet {
+    pub stamp: time::Timespec,
+    pub sync : Stamp,
+    pub xyz  : XYZ,
+}
+
+writable!(Packet as "optoforce" { stamp, sync, xyz });
+
+/// Layout of `Packet` in files written before they had headers (which also predate `sync`)
+#[repr(packed)]
+#[allow(dead_code)]
+pub struct LegacyPacket {
+    pub stamp: time::Timespec,
+    pub xyz  : XYZ,
+}
+
+writable!(LegacyPacket as "optoforce" { stamp, xyz });
+
+/// Plotted quantities, in the order they are published
+pub const FIELDS: [&'static str; 3] = ["fx", "fy", "fz"];
+
+/// Process a packet into forces for the live plot
+pub fn plot(packet: &Packet) -> Vec<f64> {
+    vec![packet.xyz.x.0,
+         packet.xyz.y.0,
+         32.0 - packet.xyz.z.0] // HACK
+}
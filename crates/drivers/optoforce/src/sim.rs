@@ -0,0 +1,106 @@
+//! Simulated OptoForce, used when the `hardware` feature is off
+//!
+//! Produces a slowly varying force vector with a little noise. The period is managed by `go()`
+//! exactly as for the real sensor.
+//!
+//! Started with `replay:<dump>[,<speed>]`, it instead plays back an `optoforcedump.dat` captured by
+//! the hardware backend.
+
+use comms::{Controllable, CmdFrom, Block, NoCommands};
+use scribe::Writer;
+use clock::{self, Clock, Source};
+use utils::RecordingContext;
+use utils::config::Config;
+use std::f64::consts::PI;
+use std::sync::mpsc::Sender;
+use time;
+use utils::replay::{self, Player};
+
+use telemetry::Publisher;
+
+use packet::{self, Packet, Double, XYZ};
+
+pub struct Optoforce {
+    tx: Sender<CmdFrom>,
+    i: usize,
+    plot: Publisher,
+    file: Writer<Packet>,
+    clock: Clock,
+    start: time::Tm,
+    /// Dump being replayed instead of synthesizing readings (and whether it has run out)
+    replay: Option<(Player, bool)>,
+}
+
+impl Optoforce {
+    /// Make up a plausible reading for time `t` (in seconds)
+    fn synthesize(t: f64, i: usize) -> XYZ {
+        // cheap deterministic jitter so the plot doesn't look too perfect
+        let noise = ((i.wrapping_mul(2654435761) % 1000) as f64 / 1000.0 - 0.5) * 0.05;
+        XYZ {
+            x: Double(0.5 * (2.0 * PI * 0.3 * t).sin() + noise),
+            y: Double(0.5 * (2.0 * PI * 0.3 * t).cos() + noise),
+            z: Double(32.0 - 2.0 * (1.0 + (2.0 * PI * 0.5 * t).sin()) + noise), // the plot subtracts from 32
+        }
+    }
+}
+
+guilty!{
+    impl Controllable for Optoforce {
+        const NAME: &'static str = "optoforce";
+        const BLOCK: Block = Block::Period(1_000_000);
+        type Command = NoCommands;
+
+        fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, _: &Config) -> Result<Optoforce, String> {
+            let replay = replay::Spec::from_param(data.as_ref().map(|s| s as &str)).map(|spec| {
+                println!("Optoforce: replaying {}", spec.path.display());
+                (Player::open(&spec).unwrap(), false)
+            });
+            if replay.is_none() {
+                println!("Optoforce: simulated device");
+            }
+
+            Ok(Optoforce {
+                tx: tx,
+                i: 0,
+                file: Writer::with_file(&ctx, "optoforce.dat"),
+                clock: clock::register("optoforce", Source::Host),
+                start: time::now(),
+                replay: replay,
+                plot: Publisher::series("optoforce", &packet::FIELDS),
+            })
+        }
+
+        fn step(&mut self, _: Option<NoCommands>) {
+            let xyz = match self.replay {
+                Some((ref mut player, ref mut finished)) => {
+                    match packet::replay_next(player, finished, &self.tx) {
+                        Some(xyz) => xyz,
+                        None => return
+                    }
+                }
+                None => {
+                    let t = (time::now() - self.start).num_microseconds().unwrap_or(0) as f64 / 1.0e6;
+                    Optoforce::synthesize(t, self.i)
+                }
+            };
+            let packet = Packet {
+                stamp: time::get_time(),
+                sync: self.clock.stamp(clock::now(), None),
+                xyz: xyz
+            };
+
+            if self.plot.due() {
+                self.plot.publish(packet::plot(&packet));
+            }
+
+            self.file.write(packet);
+            self.i += 1;
+        }
+
+        fn teardown(&mut self) {
+            let end = time::now();
+            let millis = (end - self.start).num_milliseconds() as f64;
+            println!("{} simulated optoforce frames generated in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+        }
+    }
+}
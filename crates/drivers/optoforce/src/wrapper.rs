@@ -1,10 +1,9 @@
 #![allow(dead_code)] // these are full bindings to the adapter lib
 
 use libc::{c_void, c_int, c_char, c_uchar, c_float, c_double};
-use std::{f32, fmt, thread};
+use std::{f32, thread};
 use std::default::Default;
 use std::ffi::CString;
-use std::ops::Deref;
 use std::time::Duration;
 
 macro_rules! try_opt {
@@ -36,32 +35,7 @@ pub struct Version {
     revision : c_int,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct Double(pub c_double);
-
-impl fmt::Debug for Double {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{: >7.3}", self.deref())
-    }
-}
-
-impl Deref for Double {
-    type Target = f64;
-
-    fn deref(&self) -> &f64 {
-        &self.0
-    }
-}
-
-#[derive(Debug)]
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct XYZ {
-    pub x: Double,
-    pub y: Double,
-    pub z: Double,
-}
+pub use packet::{Double, XYZ};
 
 type Handle = *mut c_void;
 
@@ -1,33 +1,67 @@
 //! Service to capture frames from the Structure Sensor
 
 #[macro_use] extern crate utils;
-#[cfg_attr(not(feature="hardware"), macro_use)] extern crate comms;
+extern crate comms;
+extern crate scribe;
+extern crate clock;
+extern crate telemetry;
+extern crate time;
+extern crate image;
+extern crate rustc_serialize as serialize;
 
 #[macro_use] extern crate guilt_by_association;
 #[macro_use] extern crate macro_attr;
 #[macro_use] extern crate conv;
+#[macro_use] extern crate serde_derive;
+
+mod png;
+
+use std::str::FromStr;
+
+/// Commands understood by the Structure Sensor service
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum Command {
+    /// Start saving frames to disk
+    DiskStart,
+    /// Stop saving frames to disk
+    DiskStop,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        let mut words = s.split_whitespace();
+        let cmd = match (words.next(), words.next()) {
+            (Some("disk"), Some("start")) => Command::DiskStart,
+            (Some("disk"), Some("stop"))  => Command::DiskStop,
+            _ => return Err(format!("unknown command {:?}", s)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected {:?} after {:?}", extra, cmd)),
+            None => Ok(cmd),
+        }
+    }
+}
 
 group_attr!{
     #[cfg(feature = "hardware")]
 
-    extern crate scribe;
-    extern crate time;
     extern crate libc;
-    extern crate image;
-    extern crate rustc_serialize as serialize;
-    use std::process::Command;
+    use std::process;
     use std::sync::{Arc, Mutex, Condvar};
     use std::sync::mpsc::Sender;
     use time::Duration;
-    use image::{imageops, ImageBuffer, ColorType, FilterType, Pixel};
-    use image::png::PNGEncoder;
-    use serialize::base64;
-    use serialize::base64::ToBase64;
+    use image::ColorType;
     use comms::{Controllable, CmdFrom, Block, RestartableThread};
-    use scribe::Writer;
+    use scribe::{Writer, Compression, Policy};
+    use clock::{Clock, Source};
     use utils::prelude::*;
+    use utils::RecordingContext;
+    use utils::config::Config;
+    use telemetry::Publisher;
+    use png::PngData;
 
-    type PngData = (usize, Vec<u8>, bool, (i32, i32), ColorType, Option<usize>);
     type WatchdogData = (Arc<(Mutex<bool>, Condvar)>, String, Duration);
 
     mod wrapper;
@@ -55,17 +89,32 @@ group_attr!{
         /// PNG writer/sender
         png: RestartableThread<PngData>,
 
+        /// Rate limit for thumbnails (the PNG thread publishes them)
+        thumbs: Publisher,
+
         /// Watchdog thread to raise the alarm when the sensor hangs
         watchdog: RestartableThread<WatchdogData>,
 
         /// Timestamp file handle
         stampfile: Writer<[u8]>,
 
-        /// PNG writer handle
+        /// Clock used to correct the timestamps
+        clock: Clock,
+
+        /// Frame container handle
         writer: Writer<[u8]>,
 
+        /// Frames stored in the container so far (chunk indices start at 1)
+        chunks: usize,
+
         /// Sender to communicate with core
         tx: Sender<CmdFrom>,
+
+        /// Frames the writer can queue before dropping new ones
+        queue_depth: usize,
+
+        /// Where recordings go
+        ctx: RecordingContext,
     }
 
     impl Structure {
@@ -87,25 +136,30 @@ group_attr!{
         impl Controllable for Structure {
             const NAME: &'static str = "structure";
             const BLOCK: Block = Block::Immediate;
+            // setup() may cycle the sensor's power (uhubctl retries, then a second to come back)
+            // before OpenNI finds it
+            const SETUP_DEADLINE_MS: u64 = 30_000;
+            type Command = Command;
 
-            fn setup(tx: Sender<CmdFrom>, data: Option<String>) -> Structure {
+            fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Structure, String> {
                 if data.map_or(false, |s| s == "power") {
                     // The Structure Sensor behaves badly if a program terminates without calling the shutdown
                     // function. Software reset (via ioctl) does not help -- the only way is to cycle power by
                     // unplugging the device. We take advantage of the fact that it is plugged in through a USB
                     // hub, and use uhubctl (https://github.com/mvp/uhubctl) to turn it off and on again.
-                    assert!(Command::new("sudo")
-                                    .args(&["/home/nri/software/uhubctl/uhubctl",
+                    assert!(process::Command::new("sudo")
+                                    .args(&[&*config.structure.uhubctl,
                                             "-a", "cycle", // cycle power
                                             "-r", "10", // try 10 times to turn off power
                                             "-d", "1", // keep power off for 1 sec
-                                            "-l", "2-3", "-p", "3"]) // USB hub at address 2-3, port 3
+                                            "-l", &*config.structure.hub, // USB hub address
+                                            "-p", &*config.structure.hub_port.to_string()])
                                     .status().unwrap()
                                     .success());
                     Duration::milliseconds(1000).sleep();
                 }
 
-                utils::in_original_dir("structure init", || wrapper::initialize().unwrap()).unwrap();
+                wrapper::initialize().unwrap();
                 let device = wrapper::Device::new(None).unwrap();
 
                 let depth = wrapper::VideoStream::new(&device, wrapper::OniSensorType::Depth).unwrap();
@@ -131,7 +185,8 @@ group_attr!{
                             fps: 30
                         }).unwrap();
 
-                let png_tx = tx.clone();
+                let thumbs = Publisher::images("structure");
+                let png_thumbs = thumbs.clone();
                 let wd_tx = tx.clone();
                 let this = Structure {
                     device: device,
@@ -142,42 +197,10 @@ group_attr!{
                     writing: false,
                     tx: tx,
 
-                    png: RestartableThread::new("Structure PNG thread", move |(i, unenc8, do_resize, (h, w), bd, id): PngData| {
-                        let mut encoded = Vec::with_capacity((w*h) as usize);
-
-                        let unenc16 = unenc8.as_vec_of::<u16>().unwrap();
-
-                        let to_resize = prof!("imagebuffer", ImageBuffer::<image::Luma<u16>, _>::from_raw(w as u32, h as u32, unenc16).unwrap());
-                        let (flipped, ww, hh);
-                        if do_resize {
-                            ww = (w as u32)/4;
-                            hh = (h as u32)/4;
-                            let resized = prof!("resize", imageops::resize(&to_resize, ww, hh, FilterType::Nearest));
-                            flipped = prof!("flip", imageops::flip_horizontal(&resized));
-                        } else {
-                            ww = w as u32;
-                            hh = h as u32;
-                            flipped = prof!("flip", imageops::flip_horizontal(&to_resize));
-                        }
-
-                        if bd == ColorType::RGB(8) {
-                            let raw = flipped.into_raw();
-                            prof!("encode", PNGEncoder::new(&mut encoded).encode(raw.as_slice_of::<u8>().unwrap(), ww, hh, ColorType::RGB(8)).unwrap());
-                        } else {
-                            //prof!("encode", PNGEncoder::new(&mut encoded).encode(flipped.into_raw().as_slice_of::<u8>().unwrap(), ww, hh, bd).unwrap());
-                            let mut mapped = ImageBuffer::<image::Rgb<u8>, _>::new(ww, hh);
-                            for y in 0..hh {
-                                for x in 0..ww {
-                                    mapped[(x, y)] = image::Pixel::from_channels(flipped[(x, y)].channels()[0] as u8, 0, 0, 0);
-                                }
-                            }
-                            let raw = mapped.into_raw();
-                            prof!("encode", PNGEncoder::new(&mut encoded).encode(&raw, ww, hh, ColorType::RGB(8)).unwrap());
-                        }
-
-                        let id_str = if let Some(id) = id { format!(" {}", id) } else { String::new() };
-                        prof!("send", png_tx.send(CmdFrom::Data(format!("send{} kick structure {} data:image/png;base64,{}", id_str, i, encoded.to_base64(base64::STANDARD)))).unwrap());
+                    png: RestartableThread::new("Structure PNG thread", move |data: PngData| {
+                        png::send(&png_thumbs, data);
                     }),
+                    thumbs: thumbs,
 
                     watchdog: RestartableThread::new("Structure watchdog thread", move |(pair, gerund, timeout): WatchdogData| {
                         let &(ref lock, ref cvar) = &*pair;
@@ -185,13 +208,18 @@ group_attr!{
                         if !*guard {
                             if cvar.wait_timeout(guard, timeout.to_std().unwrap()).unwrap().1.timed_out() {
                                 println!("ERROR!!! Structure Sensor timed out while {}", gerund);
-                                wd_tx.send(CmdFrom::Data("send msg Structure Sensor froze!".into())).unwrap();
+                                comms::tell(&wd_tx, "web", "msg Structure Sensor froze!").unwrap();
                             }
                         }
                     }),
 
-                    stampfile: Writer::with_file("structure_times.csv"),
-                    writer: Writer::with_files("structure{}.dat"),
+                    stampfile: Writer::with_file(&ctx, "structure_times.csv"),
+                    clock: clock::register("structure", Source::Device("OpenNI frame timestamps".into())),
+                    writer: Writer::with_container(&ctx, "structure.chunks", Compression::default())
+                            .with_policy(Policy::DropNewest, config.scribe.frame_queue_depth),
+                    chunks: 0,
+                    queue_depth: config.scribe.frame_queue_depth,
+                    ctx: ctx,
                 };
 
                 this.timeout(Duration::milliseconds(500), "starting depth", || this.depth.start().unwrap());
@@ -199,32 +227,36 @@ group_attr!{
 
                 println!("structure started!");
 
-                this
+                Ok(this)
             }
 
-            fn step(&mut self, cmd: Option<String>) {
+            fn step(&mut self, cmd: Option<Command>) {
                 self.i += 1;
 
-                match cmd.as_ref().map(|s| s as &str) {
-                    Some("disk start") => {
+                match cmd {
+                    Some(Command::DiskStart) => {
                         println!("Started Structure recording.");
-                        self.stampfile = Writer::with_file("structure_times.csv");
+                        self.stampfile = Writer::with_file(&self.ctx, "structure_times.csv");
+                        self.writer = Writer::with_container(&self.ctx, "structure.chunks", Compression::default())
+                                      .with_policy(Policy::DropNewest, self.queue_depth);
+                        self.chunks = 0;
                         self.writing = true;
-                        self.writer.set_index(self.i);
                     },
-                    Some("disk stop") => {
+                    Some(Command::DiskStop) => {
                         println!("Stopped Structure recording.");
                         self.writing = false;
                     },
                     _ => {},
                 }
 
+                let thumb = self.thumbs.due();
+
                 if self.depth.is_running() {
                     prof!("depth", {
                         let frame = match prof!("readFrame", self.timeout(Duration::milliseconds(100), "getting depth frame", || self.depth.read_frame(Duration::milliseconds(100)))) {
                             Ok(frame) => frame,
                             Err(ref e) if e.code() == wrapper::OniErrorCode::TimeOut => {
-                                self.tx.send(CmdFrom::Data("send msg Structure Sensor froze and will be stopped!".into())).unwrap();
+                                comms::tell(&self.tx, "web", "msg Structure Sensor froze and will be stopped!").unwrap();
                                 self.timeout(Duration::seconds(2), "stopping depth", || self.depth.stop());
                                 return;
                             },
@@ -239,14 +271,15 @@ group_attr!{
 
                         if self.writing {
                             let stamp = time::get_time();
-                            self.writer.write(&data);
-                            self.stampfile.write(format!("{},structure{}.dat,{:.9}\n", self.i, self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64).as_bytes());
+                            let sync = self.clock.stamp(clock::now(), Some(frame.timestamp() * 1000));
+                            if self.writer.write(&data) {
+                                self.chunks += 1;
+                                // only timestamp frames that will actually be written
+                                self.stampfile.write(format!("{},structure.chunks,{:.9},{},{},{}\n", self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64, sync.raw, sync.corrected, self.chunks).as_bytes());
+                            }
                         }
-                        match cmd.as_ref().map(|s| s as &str) {
-                            Some(s) if s.starts_with("kick") => {
-                                prof!("send to thread", self.png.send((self.i, data, false, (frame.height, frame.width), ColorType::Gray(16), s.split(' ').skip(1).next().map(|s| s.parse().unwrap()))).unwrap());
-                            },
-                            Some(_) | None => ()
+                        if thumb {
+                            prof!("send to thread", self.png.send((self.i, data, false, (frame.height, frame.width), ColorType::Gray(16))).unwrap());
                         }
                     });
                 }
@@ -258,14 +291,15 @@ group_attr!{
 
                         if self.writing {
                             let stamp = time::get_time();
-                            self.writer.write(data);
-                            self.stampfile.write(format!("{},structure{}.dat,{:.9}\n", self.i, self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64).as_bytes());
+                            let sync = self.clock.stamp(clock::now(), Some(frame.timestamp() * 1000));
+                            if self.writer.write(data) {
+                                self.chunks += 1;
+                                // only timestamp frames that will actually be written
+                                self.stampfile.write(format!("{},structure.chunks,{:.9},{},{},{}\n", self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64, sync.raw, sync.corrected, self.chunks).as_bytes());
+                            }
                         }
-                        match cmd.as_ref().map(|s| s as &str) {
-                            Some(s) if s.starts_with("kick") => {
-                                prof!("send to thread", self.png.send((self.i, data.into(), true, (frame.height, frame.width), ColorType::RGB(8), s.split(' ').skip(1).next().map(|s| s.parse().unwrap()))).unwrap());
-                            },
-                            Some(_) | None => ()
+                        if thumb {
+                            prof!("send to thread", self.png.send((self.i, data.into(), true, (frame.height, frame.width), ColorType::RGB(8))).unwrap());
                         }
                     });
                 }
@@ -287,4 +321,6 @@ group_attr!{
 }
 
 #[cfg(not(feature = "hardware"))]
-stub!(Structure);
+mod sim;
+#[cfg(not(feature = "hardware"))]
+pub use sim::Structure;
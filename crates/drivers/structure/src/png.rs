@@ -0,0 +1,48 @@
+//! Live camera previews for the web interface (shared by the hardware and simulated backends)
+
+use telemetry::Publisher;
+use image::{self, imageops, ImageBuffer, ColorType, FilterType, Pixel};
+use image::png::PNGEncoder;
+use serialize::base64;
+use serialize::base64::ToBase64;
+use utils::prelude::*;
+
+/// (frame number, raw pixels, downsize?, (height, width), pixel format)
+pub type PngData = (usize, Vec<u8>, bool, (i32, i32), ColorType);
+
+/// Encode a raw depth or IR frame as a PNG and publish it as a thumbnail
+pub fn send(thumbs: &Publisher, (i, unenc8, do_resize, (h, w), bd): PngData) {
+    let mut encoded = Vec::with_capacity((w*h) as usize);
+
+    let unenc16 = unenc8.as_vec_of::<u16>().unwrap();
+
+    let to_resize = prof!("imagebuffer", ImageBuffer::<image::Luma<u16>, _>::from_raw(w as u32, h as u32, unenc16).unwrap());
+    let (flipped, ww, hh);
+    if do_resize {
+        ww = (w as u32)/4;
+        hh = (h as u32)/4;
+        let resized = prof!("resize", imageops::resize(&to_resize, ww, hh, FilterType::Nearest));
+        flipped = prof!("flip", imageops::flip_horizontal(&resized));
+    } else {
+        ww = w as u32;
+        hh = h as u32;
+        flipped = prof!("flip", imageops::flip_horizontal(&to_resize));
+    }
+
+    if bd == ColorType::RGB(8) {
+        let raw = flipped.into_raw();
+        prof!("encode", PNGEncoder::new(&mut encoded).encode(raw.as_slice_of::<u8>().unwrap(), ww, hh, ColorType::RGB(8)).unwrap());
+    } else {
+        //prof!("encode", PNGEncoder::new(&mut encoded).encode(flipped.into_raw().as_slice_of::<u8>().unwrap(), ww, hh, bd).unwrap());
+        let mut mapped = ImageBuffer::<image::Rgb<u8>, _>::new(ww, hh);
+        for y in 0..hh {
+            for x in 0..ww {
+                mapped[(x, y)] = image::Pixel::from_channels(flipped[(x, y)].channels()[0] as u8, 0, 0, 0);
+            }
+        }
+        let raw = mapped.into_raw();
+        prof!("encode", PNGEncoder::new(&mut encoded).encode(&raw, ww, hh, ColorType::RGB(8)).unwrap());
+    }
+
+    prof!("publish", thumbs.publish_image(i, format!("data:image/png;base64,{}", encoded.to_base64(base64::STANDARD))));
+}
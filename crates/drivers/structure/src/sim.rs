@@ -0,0 +1,216 @@
+//! Simulated Structure Sensor, used when the `hardware` feature is off
+//!
+//! Produces 640x480 depth frames (in 100 um units, like `Depth100um`) of a tilted plane with a
+//! bump sliding across it, at 30 FPS.
+//!
+//! Started with `replay:<episode dir or structure_times.csv>[,<speed>]`, it instead plays back
+//! frames recorded by the hardware backend, on their original schedule.
+
+use comms::{Controllable, CmdFrom, Block, RestartableThread};
+use telemetry::Publisher;
+use scribe::{Writer, Compression, Container, Policy};
+use clock::{self, Clock, Source};
+use utils::prelude::*;
+use utils::RecordingContext;
+use utils::config::Config;
+use std::sync::mpsc::Sender;
+use image::ColorType;
+use time::{self, Duration};
+use utils::replay::{self, Frames};
+
+use png::{self, PngData};
+use super::Command;
+
+const WIDTH: usize = 640;
+const HEIGHT: usize = 480;
+/// Nominal frame period (ns)
+const PERIOD_NS: u64 = 1_000_000_000 / 30;
+
+/// Controllable struct for the simulated camera
+pub struct Structure {
+    /// Time that setup() was last called (used for calculating frame rates)
+    start: time::Tm,
+
+    /// Number of frames captured since setup() was last called (used for calculating frame rates)
+    i: usize,
+
+    /// Whether we are currently recording frames to images
+    writing: bool,
+
+    /// PNG writer/sender
+    png: RestartableThread<PngData>,
+
+    /// Rate limit for thumbnails (the PNG thread publishes them)
+    thumbs: Publisher,
+
+    /// Timestamp file handle
+    stampfile: Writer<[u8]>,
+
+    /// Clock used to correct the timestamps
+    clock: Clock,
+
+    /// Frame container handle
+    writer: Writer<[u8]>,
+
+    /// Frames stored in the container so far (chunk indices start at 1)
+    chunks: usize,
+
+    /// Deadline for the next frame (in `time::precise_time_ns` units)
+    next: u64,
+
+    /// Recording being replayed instead of synthesizing frames (and whether it has run out)
+    replay: Option<(Frames, bool)>,
+
+    tx: Sender<CmdFrom>,
+
+    /// Frames the writer can queue before dropping new ones
+    queue_depth: usize,
+
+    /// Where recordings go
+    ctx: RecordingContext,
+}
+
+impl Structure {
+    /// Make up a depth frame for frame number `i`
+    fn synthesize(i: usize) -> Vec<u8> {
+        let bump_x = (i * 4) % WIDTH;
+        let mut depth = vec![0u16; WIDTH * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let plane = 5000 + 4 * y; // 0.5 m, tilting away
+                let dx = x as isize - bump_x as isize;
+                let dy = y as isize - (HEIGHT / 2) as isize;
+                let bump = if dx*dx + dy*dy < 60*60 { 800 } else { 0 };
+                depth[y*WIDTH + x] = (plane - bump) as u16;
+            }
+        }
+
+        let mut data = vec![0u8; WIDTH * HEIGHT * 2];
+        data.as_mut_slice_of::<u16>().unwrap().copy_from_slice(&depth);
+        data
+    }
+}
+
+guilty!{
+    impl Controllable for Structure {
+        const NAME: &'static str = "structure";
+        const BLOCK: Block = Block::Immediate;
+        type Command = Command;
+
+        fn setup(tx: Sender<CmdFrom>, data: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Structure, String> {
+            let replay = replay::Spec::from_param(data.as_ref().map(|s| s as &str)).map(|spec| {
+                let frames = Frames::open(&spec, "structure", Container::open).unwrap();
+                println!("structure: replaying {} frames from {}", frames.len(), spec.path.display());
+                (frames, false)
+            });
+            if replay.is_none() {
+                println!("structure: simulated device");
+            }
+
+            let thumbs = Publisher::images("structure");
+            let png_thumbs = thumbs.clone();
+            let this = Structure {
+                start: time::now(),
+                i: 0,
+                writing: false,
+
+                png: RestartableThread::new("Structure PNG thread", move |data: PngData| {
+                    png::send(&png_thumbs, data);
+                }),
+                thumbs: thumbs,
+
+                stampfile: Writer::with_file(&ctx, "structure_times.csv"),
+                clock: clock::register("structure", Source::Host),
+                writer: Writer::with_container(&ctx, "structure.chunks", Compression::default())
+                        .with_policy(Policy::DropNewest, config.scribe.frame_queue_depth),
+                chunks: 0,
+                next: time::precise_time_ns(),
+                replay: replay,
+                tx: tx,
+                queue_depth: config.scribe.frame_queue_depth,
+                ctx: ctx,
+            };
+
+            println!("structure started!");
+
+            Ok(this)
+        }
+
+        fn step(&mut self, cmd: Option<Command>) {
+            self.i += 1;
+
+            match cmd {
+                Some(Command::DiskStart) => {
+                    println!("Started Structure recording.");
+                    self.stampfile = Writer::with_file(&self.ctx, "structure_times.csv");
+                    self.writer = Writer::with_container(&self.ctx, "structure.chunks", Compression::default())
+                                  .with_policy(Policy::DropNewest, self.queue_depth);
+                    self.chunks = 0;
+                    self.writing = true;
+                },
+                Some(Command::DiskStop) => {
+                    println!("Stopped Structure recording.");
+                    self.writing = false;
+                },
+                _ => {},
+            }
+
+            let (data, (h, w), bd, resize) = match self.replay {
+                Some((ref mut frames, ref mut finished)) => match frames.next_frame() {
+                    Ok(Some((_, data))) => match data.len() {
+                        // depth frames are 16-bit, IR frames are RGB at a higher resolution
+                        n if n == WIDTH * HEIGHT * 2 => (data, (HEIGHT, WIDTH), ColorType::Gray(16), false),
+                        n if n == 1280 * 1024 * 3    => (data, (1024, 1280), ColorType::RGB(8), true),
+                        n => {
+                            errorln!("Recorded Structure frame has an unexpected size ({})!", n);
+                            return;
+                        }
+                    },
+                    Ok(None) => {
+                        if !*finished {
+                            comms::tell(&self.tx, "web", "msg Structure Sensor replay finished").unwrap();
+                            *finished = true;
+                        }
+                        Duration::milliseconds(100).sleep();
+                        return;
+                    },
+                    Err(e) => {
+                        errorln!("Error reading Structure replay: {:?}", e);
+                        return;
+                    }
+                },
+                None => {
+                    // wait for the "sensor" to produce the next frame
+                    let now = time::precise_time_ns();
+                    if now < self.next {
+                        Duration::nanoseconds((self.next - now) as i64).sleep();
+                    } else if now - self.next > 10 * PERIOD_NS {
+                        self.next = now;
+                    }
+                    self.next += PERIOD_NS;
+
+                    (prof!("synthesize", Structure::synthesize(self.i)), (HEIGHT, WIDTH), ColorType::Gray(16), false)
+                }
+            };
+
+            if self.writing {
+                let stamp = time::get_time();
+                let sync = self.clock.stamp(clock::now(), None);
+                if self.writer.write(&data) {
+                    self.chunks += 1;
+                    // only timestamp frames that will actually be written
+                    self.stampfile.write(format!("{},structure.chunks,{:.9},{},{},{}\n", self.i, stamp.sec as f64 + stamp.nsec as f64 / 1_000_000_000f64, sync.raw, sync.corrected, self.chunks).as_bytes());
+                }
+            }
+            if self.thumbs.due() {
+                prof!("send to thread", self.png.send((self.i, data, resize, (h as i32, w as i32), bd)).unwrap());
+            }
+        }
+
+        fn teardown(&mut self) {
+            let end = time::now();
+            let millis = (end - self.start).num_milliseconds() as f64;
+            println!("{} simulated structure frames generated in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+        }
+    }
+}
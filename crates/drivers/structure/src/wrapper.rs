@@ -375,6 +375,11 @@ impl VideoStream {
 }
 
 impl Frame {
+    /// Timestamp assigned by the device (µs)
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
     pub fn data<T>(&self) -> &[T] {
         &unsafe { slice::from_raw_parts(self.data as *const T, self.data_size as usize) }
     }
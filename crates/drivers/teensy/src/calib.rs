@@ -0,0 +1,371 @@
+//! Force/torque calibration of the transducer on the Teensy's strain gauges
+//!
+//! Calibrations are ATI `.cal` files (one per transducer, identified by the `Serial` attribute
+//! inside rather than by file name) in the directory named by `teensy.calibrations`. Next to them,
+//! `profiles.toml` names the transducer, and optionally the zero-load gauge voltages, for each
+//! profile. A profile is picked by the start parameter (`start teensy/proton`), or else by the
+//! short name of the end-effector that is out (`stick`, `opto`, `bio`), or else by
+//! `teensy.calibration`. The `raw` profile passes the gauge voltages through unchanged.
+//!
+//! The calibration in use is written to `teensy_calibration.json` in the recording directory,
+//! along with every bias captured by `tare` while recording, so that the offline converter can
+//! compute the same wrenches as the live plot.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use comms::{self, CmdFrom};
+use utils::RecordingContext;
+use utils::config::Config;
+use serde_json;
+use toml;
+
+use packet::Packet;
+use super::ParkState;
+
+/// Name of the recorded calibration in the recording directory
+pub const RECORD: &'static str = "teensy_calibration.json";
+
+/// Profile that applies no calibration
+pub const RAW: &'static str = "raw";
+
+/// Volts per count of the 12-bit strain gauge ADC
+const VOLTS_PER_COUNT: f64 = 0.002;
+
+/// Axes of the calibration matrix, in row order
+const AXES: [&'static str; 6] = ["Fx", "Fy", "Fz", "Tx", "Ty", "Tz"];
+
+/// Strain gauge voltages from a packet's `ft` bytes (six signed 12-bit big-endian readings)
+pub fn gauges(ft: &[u8]) -> [f64; 6] {
+    let mut volts = [0.0; 6];
+    for g in 0..6 {
+        let mut count = (((ft[2*g] as u32) << 8) + (ft[2*g + 1] as u32)) as i32;
+        if count >= 2048 {
+            count -= 4096;
+        }
+        volts[g] = count as f64 * VOLTS_PER_COUNT;
+    }
+    volts
+}
+
+/// Zero-load gauge voltages, from some time on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bias {
+    /// Host time (s) of the first packet the bias applies to (0 for the profile's own bias)
+    pub since: f64,
+    pub volts: [f64; 6],
+}
+
+/// A transducer's calibration matrix and the biases used with it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Profile that picked this calibration
+    pub profile: String,
+    /// Transducer serial number (from the `.cal` file)
+    pub serial: String,
+    pub force_units: String,
+    pub torque_units: String,
+    /// Rows are Fx, Fy, Fz, Tx, Ty, Tz; columns are gauges (units per volt)
+    pub matrix: [[f64; 6]; 6],
+    /// The profile's bias, followed by any captured with `tare`
+    pub biases: Vec<Bias>,
+}
+
+/// An entry in `profiles.toml`
+#[derive(Deserialize)]
+struct Profile {
+    serial: String,
+    bias: Option<[f64; 6]>,
+}
+
+/// Start tags of an XML document, as names and attributes
+///
+/// This is just enough XML for ATI's calibration files (no entities, and no `<` or `>` other
+/// than around tags).
+fn tags(xml: &str) -> Vec<(&str, BTreeMap<&str, &str>)> {
+    let mut tags = vec![];
+    for chunk in xml.split('<').skip(1) {
+        if chunk.starts_with('?') || chunk.starts_with('!') || chunk.starts_with('/') {
+            continue;
+        }
+
+        let body = chunk.splitn(2, '>').next().unwrap().trim_right_matches('/').trim();
+        let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+        let mut rest = &body[name_end..];
+        let mut attrs = BTreeMap::new();
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let value = rest[eq+1..].trim_left();
+            let quote = match value.chars().next() {
+                Some(q @ '"') | Some(q @ '\'') => q,
+                _ => break
+            };
+            let value = &value[1..];
+            let end = match value.find(quote) {
+                Some(end) => end,
+                None => break
+            };
+            attrs.insert(key, &value[..end]);
+            rest = &value[end+1..];
+        }
+        tags.push((&body[..name_end], attrs));
+    }
+    tags
+}
+
+impl Calibration {
+    /// No calibration: the "wrench" is the gauge voltages
+    pub fn raw() -> Calibration {
+        let mut matrix = [[0.0; 6]; 6];
+        for i in 0..6 {
+            matrix[i][i] = 1.0;
+        }
+        Calibration {
+            profile: RAW.into(),
+            serial: String::new(),
+            force_units: "V".into(),
+            torque_units: "V".into(),
+            matrix: matrix,
+            biases: vec![Bias { since: 0.0, volts: [0.0; 6] }],
+        }
+    }
+
+    /// Parse an ATI `.cal` file (the bias is left at zero)
+    pub fn parse(profile: &str, xml: &str) -> Result<Calibration, String> {
+        let mut serial = None;
+        let mut units = ("N".to_owned(), "N-m".to_owned());
+        let mut rows = [None; 6];
+
+        for (name, attrs) in tags(xml) {
+            match name {
+                "FTSensor" => serial = attrs.get("Serial").map(|s| s.to_string()),
+                "Calibration" => {
+                    if let Some(f) = attrs.get("ForceUnits") { units.0 = f.to_string(); }
+                    if let Some(t) = attrs.get("TorqueUnits") { units.1 = t.to_string(); }
+                }
+                "Axis" => {
+                    let axis = attrs.get("Name").cloned().unwrap_or("");
+                    let i = AXES.iter().position(|a| *a == axis).ok_or_else(|| format!("unknown axis {:?}", axis))?;
+                    if rows[i].is_some() {
+                        return Err(format!("axis {} appears twice", axis));
+                    }
+                    let values = attrs.get("values").cloned().unwrap_or("")
+                                      .split_whitespace()
+                                      .map(|v| v.parse::<f64>().map_err(|_| format!("invalid number {:?} for axis {}", v, axis)))
+                                      .collect::<Result<Vec<_>, _>>()?;
+                    if values.len() != 6 {
+                        return Err(format!("axis {} has {} values (expected 6)", axis, values.len()));
+                    }
+                    let mut row = [0.0; 6];
+                    row.copy_from_slice(&values);
+                    rows[i] = Some(row);
+                }
+                _ => {}
+            }
+        }
+
+        let mut matrix = [[0.0; 6]; 6];
+        for i in 0..6 {
+            matrix[i] = rows[i].ok_or_else(|| format!("axis {} is missing", AXES[i]))?;
+        }
+        Ok(Calibration {
+            profile: profile.into(),
+            serial: serial.ok_or("no FTSensor serial number")?,
+            force_units: units.0,
+            torque_units: units.1,
+            matrix: matrix,
+            biases: vec![Bias { since: 0.0, volts: [0.0; 6] }],
+        })
+    }
+
+    /// Read the calibration recorded alongside a data file
+    pub fn recorded<P: AsRef<Path>>(dir: P) -> Result<Calibration, String> {
+        let path = dir.as_ref().join(RECORD);
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the calibration into the recording directory
+    pub fn record(&self, ctx: &RecordingContext) -> Result<(), String> {
+        let path = ctx.path(RECORD);
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The bias currently in use
+    pub fn bias(&self) -> &[f64; 6] {
+        &self.biases.last().unwrap().volts
+    }
+
+    /// Use a new bias from time `since` (s) on
+    pub fn tare(&mut self, since: f64, volts: [f64; 6]) {
+        self.biases.push(Bias { since: since, volts: volts });
+    }
+
+    /// Apply the calibration (with the current bias) to gauge voltages
+    pub fn wrench(&self, volts: &[f64; 6]) -> [f64; 6] {
+        self.apply(self.bias(), volts)
+    }
+
+    /// Apply the calibration (with the bias in use at time `t`) to gauge voltages
+    pub fn wrench_at(&self, t: f64, volts: &[f64; 6]) -> [f64; 6] {
+        let bias = self.biases.iter().rev().find(|b| b.since <= t).unwrap_or(&self.biases[0]);
+        self.apply(&bias.volts, volts)
+    }
+
+    fn apply(&self, bias: &[f64; 6], volts: &[f64; 6]) -> [f64; 6] {
+        let mut wrench = [0.0; 6];
+        for i in 0..6 {
+            for g in 0..6 {
+                wrench[i] += self.matrix[i][g] * (volts[g] - bias[g]);
+            }
+        }
+        wrench
+    }
+}
+
+/// Read `profiles.toml` from the calibration directory
+fn profiles(dir: &Path) -> Result<BTreeMap<String, Profile>, String> {
+    let path = dir.join("profiles.toml");
+    let mut text = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| format!("{}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Find the `.cal` file for a transducer in the calibration directory
+fn find(dir: &Path, profile: &str, serial: &str) -> Result<Calibration, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| format!("{}: {}", dir.display(), e))?.path();
+        if path.extension().map_or(true, |ext| ext != "cal") {
+            continue;
+        }
+
+        let mut xml = String::new();
+        File::open(&path).and_then(|mut f| f.read_to_string(&mut xml)).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let cal = Calibration::parse(profile, &xml).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if cal.serial == serial {
+            return Ok(cal);
+        }
+    }
+    Err(format!("no calibration file in {} for transducer {}", dir.display(), serial))
+}
+
+/// Pick a calibration by profile name, else by end-effector, else from the configuration
+pub fn choose(config: &Config, profile: Option<&str>, endeff: Option<ParkState>) -> Result<Calibration, String> {
+    let dir = config.path(&config.teensy.calibrations);
+    let mut profiles = profiles(&dir)?;
+
+    let name = match (profile, endeff) {
+        (Some(name), _) => name.to_owned(),
+        (None, Some(ps)) if profiles.contains_key(ps.short()) => ps.short().to_owned(),
+        _ => config.teensy.calibration.clone(),
+    };
+    if name == RAW {
+        return Ok(Calibration::raw());
+    }
+
+    let entry = profiles.remove(&name).ok_or_else(|| format!("no calibration profile {:?} in {}", name, dir.display()))?;
+    let mut cal = find(&dir, &name, &entry.serial)?;
+    if let Some(bias) = entry.bias {
+        cal.biases[0].volts = bias;
+    }
+    Ok(cal)
+}
+
+/// Averages the gauge voltages over some packets to find a new bias
+struct Tare {
+    left: usize,
+    count: usize,
+    sum: [f64; 6],
+}
+
+/// The calibration used by a running Teensy service, and any tare in progress
+pub struct Active {
+    pub calibration: Calibration,
+    ctx: RecordingContext,
+    tx: Sender<CmdFrom>,
+    tare: Option<Tare>,
+    tare_packets: usize,
+}
+
+impl Active {
+    /// Choose the calibration (see `choose`), report the transducer and record the calibration
+    ///
+    /// `endeff` is only asked for the park state if the profile was not given. If the calibration
+    /// can't be loaded, the error is shown in the web interface and the raw profile is used.
+    pub fn setup<F>(tx: Sender<CmdFrom>, profile: Option<&str>, endeff: F, ctx: RecordingContext, config: &Config) -> Active
+        where F: FnOnce() -> Option<ParkState>
+    {
+        let endeff = match profile {
+            Some(_) => None,
+            None => endeff(),
+        };
+        let calibration = match choose(config, profile, endeff) {
+            Ok(cal) => cal,
+            Err(e) => {
+                errorln!("Teensy calibration: {}", e);
+                comms::tell(&tx, "web", format!("msg Teensy is uncalibrated ({})", e)).unwrap();
+                Calibration::raw()
+            }
+        };
+        println!("TEENSY: using calibration {:?} (transducer {:?})", calibration.profile, calibration.serial);
+        if !calibration.serial.is_empty() {
+            tx.send(CmdFrom::Serial("teensy".into(), calibration.serial.clone())).unwrap();
+        }
+
+        let active = Active {
+            calibration: calibration,
+            ctx: ctx,
+            tx: tx,
+            tare: None,
+            tare_packets: config.teensy.tare_packets,
+        };
+        active.record();
+        active
+    }
+
+    fn record(&self) {
+        if let Err(e) = self.calibration.record(&self.ctx) {
+            errorln!("Could not record Teensy calibration: {}", e);
+        }
+    }
+
+    /// Start capturing a new bias from the next packets
+    pub fn tare(&mut self) {
+        println!("TEENSY: taring over {} packets", self.tare_packets);
+        self.tare = Some(Tare { left: self.tare_packets, count: 0, sum: [0.0; 6] });
+    }
+
+    /// Feed a (stamped) packet to the tare in progress, if any
+    pub fn packet(&mut self, packet: &Packet) {
+        let done = match self.tare {
+            Some(ref mut tare) => {
+                let volts = gauges(&packet.ft);
+                for g in 0..6 {
+                    tare.sum[g] += volts[g];
+                }
+                tare.count += 1;
+                tare.left = tare.left.saturating_sub(1);
+                tare.left == 0
+            }
+            None => false
+        };
+
+        if done {
+            let tare = self.tare.take().unwrap();
+            let mut bias = [0.0; 6];
+            for g in 0..6 {
+                bias[g] = tare.sum[g] / tare.count as f64;
+            }
+            let stamp = packet.stamp;
+            self.calibration.tare(stamp.sec as f64 + stamp.nsec as f64 / 1e9, bias);
+            self.record();
+            println!("TEENSY: new bias {:?}", bias);
+            comms::tell(&self.tx, "web", "msg Teensy tared").unwrap();
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Requests to the Teensy firmware, and matching them up with its acknowledgements
+//!
+//! Every request is sent as the marker `ccc`, a request ID, an opcode, the length of the
+//! arguments (one byte), the arguments, and a checksum of everything after the marker. The opcodes
+//! are the single bytes older firmware understood on their own ('1' start, '2' stop, and so on).
+//! The firmware answers each one with an `Ack` in the data stream (see `framing`), carrying the
+//! same ID, so `Commander` knows which requests took effect and which were never answered.
+//!
+//! Older firmware reads every byte it is sent as a command, and never acknowledges anything. So
+//! request IDs stay clear of the digits, and `Commander::negotiate` asks for the firmware version
+//! first: if that goes unanswered, requests are sent the old way, as bare opcodes.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::num::Wrapping;
+
+use time::{self, Duration};
+
+use framing::{Ack, Framer, Message};
+use imu::Reference;
+
+const MARKER: &'static [u8] = b"ccc";
+
+/// The first request ID (the firmware version probe, whose bytes mean nothing to older firmware)
+const FIRST_ID: u8 = b'@';
+
+/// The ID after this one, skipping the opcodes older firmware would act on
+fn next_id(id: u8) -> u8 {
+    match id.wrapping_add(1) {
+        b'1'...b'9' => b':',
+        id => id,
+    }
+}
+
+/// How to talk to the firmware
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    /// Framed requests, acknowledged in the data stream
+    Acked,
+    /// Bare opcodes, never acknowledged (the park state comes back as one raw byte)
+    Legacy,
+}
+
+/// Something the Teensy can be asked to do
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Start sending packets
+    StartStreaming,
+    /// Stop sending packets
+    StopStreaming,
+    /// Report the park state (one byte)
+    ParkState,
+    /// Read the analog accelerometers against another reference
+    Reference(Reference),
+    /// Collect N IMU samples per packet
+    Burst(u8),
+    /// Sample the sensors at this rate (Hz)
+    SampleRate(u16),
+    /// Report the firmware version (ASCII)
+    FirmwareVersion,
+}
+
+impl Request {
+    fn opcode(&self) -> u8 {
+        (match *self {
+            Request::StartStreaming            => '1',
+            Request::StopStreaming             => '2',
+            Request::ParkState                 => '4',
+            Request::Reference(Reference::Int) => '5',
+            Request::Reference(Reference::Ext) => '6',
+            Request::Burst(_)                  => '7',
+            Request::SampleRate(_)             => '8',
+            Request::FirmwareVersion           => '9',
+        }) as u8
+    }
+
+    /// The byte older firmware understands for this request, if any (sent before the arguments)
+    fn legacy_opcode(&self) -> Option<u8> {
+        match *self {
+            Request::StartStreaming | Request::StopStreaming | Request::ParkState | Request::Reference(_)
+                | Request::Burst(_) => Some(self.opcode()),
+            Request::SampleRate(_) | Request::FirmwareVersion => None,
+        }
+    }
+
+    fn args(&self) -> Vec<u8> {
+        match *self {
+            Request::Burst(n)         => vec![n],
+            Request::SampleRate(hz)   => vec![(hz >> 8) as u8, (hz & 0xFF) as u8],
+            _                         => vec![],
+        }
+    }
+
+    /// The bytes to send for this request with the given ID
+    pub fn encode(&self, id: u8) -> Vec<u8> {
+        let args = self.args();
+        let mut bytes = MARKER.to_vec();
+        bytes.extend_from_slice(&[id, self.opcode(), args.len() as u8]);
+        bytes.extend_from_slice(&args);
+        let sum = bytes[MARKER.len()..].iter().fold(Wrapping(0u8), |sum, &b| sum + Wrapping(b));
+        bytes.push(sum.0);
+        bytes
+    }
+}
+
+/// What an acknowledgement's status byte means
+fn status(code: u8) -> Result<(), String> {
+    match code {
+        0 => Ok(()),
+        1 => Err("unknown request".into()),
+        2 => Err("bad argument".into()),
+        3 => Err("busy".into()),
+        x => Err(format!("error {}", x)),
+    }
+}
+
+/// A request still waiting for its acknowledgement
+struct Pending {
+    id: u8,
+    request: Request,
+    /// Give up at this time (in `time::precise_time_ns` units)
+    deadline: u64,
+}
+
+/// Sends requests and keeps track of which ones have been acknowledged
+pub struct Commander {
+    protocol: Protocol,
+    next_id: u8,
+    timeout: Duration,
+    pending: VecDeque<Pending>,
+}
+
+impl Commander {
+    /// Requests not acknowledged within `timeout` are given up on
+    pub fn new(timeout: Duration) -> Commander {
+        Commander { protocol: Protocol::Acked, next_id: FIRST_ID, timeout: timeout, pending: VecDeque::new() }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Find out which protocol the firmware speaks, by asking for its version
+    ///
+    /// Returns the version, or `None` for older firmware (which does not answer).
+    pub fn negotiate<R: Read + Write>(&mut self, framer: &mut Framer<R>) -> Result<Option<String>, String> {
+        self.protocol = Protocol::Acked;
+        match self.ask(framer, Request::FirmwareVersion)? {
+            Some(version) => Ok(Some(String::from_utf8_lossy(&version).into_owned())),
+            None => {
+                self.protocol = Protocol::Legacy;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Send a request, returning its ID (the acknowledgement comes later through `ack`)
+    ///
+    /// Older firmware is sent the bare opcode (and arguments), and nothing waits for an
+    /// acknowledgement.
+    pub fn send<W: Write>(&mut self, port: &mut W, request: Request) -> io::Result<u8> {
+        let id = self.next_id;
+        self.next_id = next_id(id);
+        if self.protocol == Protocol::Legacy {
+            let opcode = request.legacy_opcode()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not understood by legacy firmware"))?;
+            port.write_all(&[opcode])?;
+            port.write_all(&request.args())?;
+            port.flush()?;
+            return Ok(id);
+        }
+        port.write_all(&request.encode(id))?;
+        port.flush()?;
+        self.pending.push_back(Pending {
+            id: id,
+            request: request,
+            deadline: time::precise_time_ns() + self.timeout.num_nanoseconds().unwrap() as u64,
+        });
+        Ok(id)
+    }
+
+    /// Match up an acknowledgement with its request
+    ///
+    /// Returns the request and its reply (or why it failed), or `None` if nothing was waiting for
+    /// this ID (e.g. it was already given up on).
+    pub fn ack(&mut self, ack: Ack) -> Option<(Request, Result<Vec<u8>, String>)> {
+        let pos = match self.pending.iter().position(|p| p.id == ack.id) {
+            Some(pos) => pos,
+            None => return None,
+        };
+        let pending = self.pending.remove(pos).unwrap();
+        Some((pending.request, status(ack.status).map(|()| ack.reply)))
+    }
+
+    /// Requests whose time is up (they are forgotten)
+    pub fn expired(&mut self) -> Vec<Request> {
+        let now = time::precise_time_ns();
+        let (late, waiting) = self.pending.drain(..).partition::<Vec<_>, _>(|p| p.deadline <= now);
+        self.pending = waiting.into_iter().collect();
+        late.into_iter().map(|p| p.request).collect()
+    }
+
+    /// Send a request and wait for its reply, when nothing else is reading the stream
+    ///
+    /// Packets and other acknowledgements read in the meantime are thrown away. Older firmware
+    /// answers only the park state (with one raw byte), so anything else succeeds once it is sent.
+    pub fn query<R: Read + Write>(&mut self, framer: &mut Framer<R>, request: Request) -> Result<Vec<u8>, String> {
+        if self.protocol == Protocol::Legacy {
+            self.send(framer.get_mut(), request).map_err(|e| format!("sending {:?}: {}", request, e))?;
+            return match request {
+                Request::ParkState => {
+                    let mut buf = [0u8; 1];
+                    framer.get_mut().read_exact(&mut buf).map_err(|e| format!("waiting for {:?}: {}", request, e))?;
+                    Ok(buf.to_vec())
+                }
+                _ => Ok(vec![]),
+            };
+        }
+        self.ask(framer, request)?.ok_or_else(|| format!("{:?} was not acknowledged", request))
+    }
+
+    /// Send a request and wait for its reply (`None` if it was never acknowledged)
+    fn ask<R: Read + Write>(&mut self, framer: &mut Framer<R>, request: Request) -> Result<Option<Vec<u8>>, String> {
+        let id = self.send(framer.get_mut(), request).map_err(|e| format!("sending {:?}: {}", request, e))?;
+        loop {
+            match framer.next_message() {
+                Ok(Message::Ack(ack)) => {
+                    let mine = ack.id == id;
+                    if let Some((_, reply)) = self.ack(ack) {
+                        if mine {
+                            return reply.map(Some).map_err(|e| format!("{:?}: {}", request, e));
+                        }
+                    }
+                }
+                Ok(Message::Data(_)) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(format!("waiting for {:?}: {}", request, e)),
+            }
+            self.expired();
+            if !self.pending.iter().any(|p| p.id == id) {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use time::Duration;
+    use framing::{Framer, Kind};
+    use imu::Reference;
+    use super::*;
+
+    /// Older firmware, as far as the version request can tell: it never says anything
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "nothing to read"))
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+        fn flush(&mut self)             -> io::Result<()>    { Ok(())        }
+    }
+
+    /// A `Commander` that has found older firmware on the other end
+    fn legacy() -> Commander {
+        let mut framer = Framer::new(Silent, Kind::Teensy);
+        let mut commands = Commander::new(Duration::milliseconds(0));
+        // (nothing answers the version request)
+        assert_eq!(commands.negotiate(&mut framer), Ok(None));
+        assert_eq!(commands.protocol(), Protocol::Legacy);
+        commands
+    }
+
+    #[test]
+    fn ids_avoid_opcodes() {
+        let mut commands = Commander::new(Duration::milliseconds(0));
+        for _ in 0..600 {
+            let id = commands.send(&mut vec![], Request::StopStreaming).unwrap();
+            assert!(id < b'1' || id > b'9', "request ID {:?} is an opcode", id as char);
+        }
+    }
+
+    #[test]
+    fn legacy_requests() {
+        let mut commands = legacy();
+        let sent = |commands: &mut Commander, request| {
+            let mut port = vec![];
+            commands.send(&mut port, request).map(|_| port)
+        };
+
+        assert_eq!(sent(&mut commands, Request::StartStreaming).unwrap(), b"1");
+        assert_eq!(sent(&mut commands, Request::StopStreaming).unwrap(), b"2");
+        assert_eq!(sent(&mut commands, Request::Reference(Reference::Int)).unwrap(), b"5");
+        assert_eq!(sent(&mut commands, Request::Reference(Reference::Ext)).unwrap(), b"6");
+        assert_eq!(sent(&mut commands, Request::Burst(3)).unwrap(), vec![b'7', 3]);
+        assert!(sent(&mut commands, Request::SampleRate(1000)).is_err());
+        assert!(sent(&mut commands, Request::FirmwareVersion).is_err());
+    }
+}
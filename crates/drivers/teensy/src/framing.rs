@@ -0,0 +1,657 @@
+//! Framing of the Teensy's serial stream (shared by the drivers and `stbdump`)
+//!
+//! Every packet is sent as the marker `aaa`, its length (two bytes, big-endian, from the Teensy;
+//! one byte from the older STB firmware) and the payload. A payload that starts with IMU data
+//! gives the number of accelerometer and gyroscope samples in its first two bytes, so only certain
+//! lengths are possible for each, and some firmware versions append `dt` and/or a checksum.
+//!
+//! In between packets, the Teensy acknowledges requests (see `commands`) with the marker `kkk`,
+//! the request ID, a status byte, the length of the reply (one byte), the reply, and a checksum
+//! of everything after the marker.
+//!
+//! `Framer` pulls frames out of a byte stream. Whenever a length is impossible or a payload fails
+//! its checksum, it throws away one byte and looks for the next marker, so a glitch costs a packet
+//! or two instead of the rest of the session. What was thrown away is counted in `Stats`. Bytes
+//! that were read are never lost to a read error, so a timeout on the serial port can be retried.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::num::Wrapping;
+
+const MARKER: &'static [u8] = b"aaa";
+const ACK_MARKER: &'static [u8] = b"kkk";
+
+/// Longest reply in an acknowledgement
+const MAX_REPLY: usize = 64;
+
+/// Length of the analog part of a packet (strain gauges, accelerometers and park state)
+pub const ANALOG_LEN: usize = 31;
+
+/// Packets with `dt` above this (µs) count as delayed
+const DELAY_US: u16 = 1000;
+
+/// Which firmware is on the other end
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+    /// Teensy: two-byte lengths, optional `dt`, up to 63 IMU entries
+    Teensy,
+    /// STB: one-byte lengths, no `dt`, up to 37 IMU entries
+    Stb,
+}
+
+impl Kind {
+    fn length_bytes(&self) -> usize {
+        match *self { Kind::Teensy => 2, Kind::Stb => 1 }
+    }
+
+    fn max_imu(&self) -> usize {
+        match *self { Kind::Teensy => 63, Kind::Stb => 37 }
+    }
+
+    fn has_dt(&self) -> bool {
+        *self == Kind::Teensy
+    }
+
+    fn has_acks(&self) -> bool {
+        *self == Kind::Teensy
+    }
+
+    /// Longest possible payload
+    fn max_len(&self) -> usize {
+        ANALOG_LEN + 2 + 6*self.max_imu() + if self.has_dt() { 4 } else { 0 } + 1
+    }
+}
+
+/// What a payload contains, and where
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub n_acc: usize,
+    pub n_gyro: usize,
+    /// Whether there are IMU entries (the samples, then the magnetometer) at `2..imu_end()`
+    pub imu: bool,
+    /// Whether `dt` follows the IMU entries
+    pub dt: bool,
+    /// Whether the last byte is a checksum
+    pub checksum: bool,
+}
+
+impl Layout {
+    /// Work out the layout of a payload from its length, and check its checksum
+    pub fn of(payload: &[u8], kind: Kind) -> Result<Layout, String> {
+        let len = payload.len();
+        let analog = |dt, checksum| Layout { n_acc: 0, n_gyro: 0, imu: false, dt: dt, checksum: checksum };
+        let layout = match len {
+            x if x < ANALOG_LEN => return Err(format!("implausibly small packet ({})", x)),
+            31 => analog(false, false),
+            32 => analog(false, true),
+            35 if kind.has_dt() => analog(true, false),
+            36 if kind.has_dt() => analog(true, true),
+            x => {
+                let (a, g) = (payload[0] as usize, payload[1] as usize);
+                if a + g + 1 > kind.max_imu() {
+                    return Err(format!("impossible packet size ({} with a={}, g={})", x, a, g));
+                }
+                let imu = ANALOG_LEN + 2 + 6*(a + g + 1);
+                let layout = |dt, checksum| Layout { n_acc: a, n_gyro: g, imu: true, dt: dt, checksum: checksum };
+                match x {
+                    t if t == imu                           => layout(false, false),
+                    t if t == imu + 1                       => layout(false, true),
+                    t if t == imu + 4 && kind.has_dt()      => layout(true, false),
+                    t if t == imu + 4 + 1 && kind.has_dt()  => layout(true, true),
+                    _ => return Err(format!("impossible packet size ({} with a={}, g={})", x, a, g)),
+                }
+            }
+        };
+
+        if layout.checksum {
+            let sum = checksum(&payload[..len-1]);
+            if payload[len-1] != sum {
+                return Err(format!("wrong checksum (it says {}, I calculate {})", payload[len-1], sum));
+            }
+        }
+        Ok(layout)
+    }
+
+    /// End of the IMU entries (0 if there are none)
+    pub fn imu_end(&self) -> usize {
+        if self.imu { 2 + 6*(self.n_acc + self.n_gyro + 1) } else { 0 }
+    }
+
+    /// Start of the analog part
+    pub fn analog_start(&self) -> usize {
+        self.imu_end() + if self.dt { 4 } else { 0 }
+    }
+
+    /// The `dt` pair, if the payload has one
+    pub fn dt(&self, payload: &[u8]) -> Option<(u16, u16)> {
+        if self.dt {
+            let s = self.imu_end();
+            Some((payload[s] as u16 | (payload[s+1] as u16) << 8,
+                  payload[s+2] as u16 | (payload[s+3] as u16) << 8))
+        } else {
+            None
+        }
+    }
+}
+
+/// Counters kept by a `Framer`
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    /// Good frames
+    pub frames: u64,
+    /// Times the stream had to be searched for the next marker (at least one packet lost each)
+    pub dropped: u64,
+    /// Frames with an impossible length or a bad checksum
+    pub corrupt: u64,
+    /// Good frames whose `dt` says the Teensy was late sending them
+    pub delayed: u64,
+    /// Acknowledgements received
+    pub acks: u64,
+    /// Bytes thrown away while looking for a marker
+    pub skipped_bytes: u64,
+}
+
+impl Stats {
+    /// The counters by name (for `CmdFrom::Stats`)
+    pub fn to_map(&self) -> BTreeMap<String, u64> {
+        let mut map = BTreeMap::new();
+        map.insert("frames".to_owned(), self.frames);
+        map.insert("dropped".to_owned(), self.dropped);
+        map.insert("corrupt".to_owned(), self.corrupt);
+        map.insert("delayed".to_owned(), self.delayed);
+        map.insert("acks".to_owned(), self.acks);
+        map.insert("skipped_bytes".to_owned(), self.skipped_bytes);
+        map
+    }
+}
+
+/// A frame's payload and what is in it
+pub struct Frame {
+    pub payload: Vec<u8>,
+    pub layout: Layout,
+}
+
+/// The Teensy's answer to a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ack {
+    /// ID of the request
+    pub id: u8,
+    /// 0 if the request was carried out, otherwise an error code
+    pub status: u8,
+    pub reply: Vec<u8>,
+}
+
+/// Something read from the Teensy
+pub enum Message {
+    Data(Frame),
+    Ack(Ack),
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(Wrapping(0u8), |sum, &b| sum + Wrapping(b)).0
+}
+
+/// Reads frames from a byte stream
+pub struct Framer<R: Read> {
+    inner: R,
+    kind: Kind,
+    buf: Vec<u8>,
+    /// Whether the last thing read was a good frame (so that a search starts a new drop)
+    synced: bool,
+    stats: Stats,
+}
+
+impl<R: Read> Framer<R> {
+    pub fn new(inner: R, kind: Kind) -> Framer<R> {
+        Framer { inner: inner, kind: kind, buf: Vec::with_capacity(2 * kind.max_len()), synced: true, stats: Stats::default() }
+    }
+
+    /// The underlying stream (e.g. to send commands to the Teensy)
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Start counting again (e.g. when a new session takes over the stream)
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Read some more bytes into the buffer (running out is an `UnexpectedEof` error)
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 512];
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of Teensy stream")),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Throw away bytes from the front of the buffer while searching for a marker
+    fn skip(&mut self, n: usize) {
+        if self.synced {
+            self.synced = false;
+            self.stats.dropped += 1;
+        }
+        self.stats.skipped_bytes += n as u64;
+        self.buf.drain(..n);
+    }
+
+    fn is_marker(&self, bytes: &[u8]) -> bool {
+        bytes == MARKER || (self.kind.has_acks() && bytes == ACK_MARKER)
+    }
+
+    /// Read the next good frame (skipping acknowledgements)
+    ///
+    /// Errors only come from the stream itself; bad data is skipped (and counted).
+    pub fn next_frame(&mut self) -> io::Result<Frame> {
+        loop {
+            if let Message::Data(frame) = self.next_message()? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Read the next good frame or acknowledgement
+    ///
+    /// Errors only come from the stream itself; bad data is skipped (and counted).
+    pub fn next_message(&mut self) -> io::Result<Message> {
+        let header = MARKER.len() + self.kind.length_bytes();
+        loop {
+            // line up on a marker
+            if self.buf.len() < MARKER.len() || !self.is_marker(&self.buf[..MARKER.len()]) {
+                match self.buf.windows(MARKER.len()).position(|w| self.is_marker(w)) {
+                    Some(pos) => self.skip(pos),
+                    None => {
+                        // keep anything that could be the start of a marker
+                        let last = self.buf.last().cloned();
+                        let keep = self.buf.iter().rev().take(MARKER.len() - 1).take_while(|&&b| Some(b) == last).count();
+                        let n = self.buf.len() - keep;
+                        if n > 0 {
+                            self.skip(n);
+                        }
+                        self.fill()?;
+                        continue;
+                    }
+                }
+            }
+
+            if self.buf.starts_with(ACK_MARKER) {
+                // marker, ID, status, reply length, reply, checksum
+                if self.buf.len() < ACK_MARKER.len() + 3 {
+                    self.fill()?;
+                    continue;
+                }
+                let len = self.buf[ACK_MARKER.len() + 2] as usize;
+                let end = ACK_MARKER.len() + 3 + len + 1;
+                if len > MAX_REPLY {
+                    self.stats.corrupt += 1;
+                    self.skip(1);
+                    continue;
+                }
+                if self.buf.len() < end {
+                    self.fill()?;
+                    continue;
+                }
+                if checksum(&self.buf[ACK_MARKER.len()..end - 1]) != self.buf[end - 1] {
+                    self.stats.corrupt += 1;
+                    self.skip(1);
+                    continue;
+                }
+                let ack = Ack {
+                    id: self.buf[ACK_MARKER.len()],
+                    status: self.buf[ACK_MARKER.len() + 1],
+                    reply: self.buf[ACK_MARKER.len() + 3..end - 1].to_vec(),
+                };
+                self.buf.drain(..end);
+                self.synced = true;
+                self.stats.acks += 1;
+                return Ok(Message::Ack(ack));
+            }
+
+            if self.buf.len() < header {
+                self.fill()?;
+                continue;
+            }
+            let len = self.buf[MARKER.len()..header].iter().fold(0, |len, &b| (len << 8) + b as usize);
+            if len < ANALOG_LEN || len > self.kind.max_len() {
+                self.stats.corrupt += 1;
+                self.skip(1);
+                continue;
+            }
+
+            if self.buf.len() < header + len {
+                self.fill()?;
+                continue;
+            }
+            match Layout::of(&self.buf[header..header + len], self.kind) {
+                Ok(layout) => {
+                    let payload = self.buf[header..header + len].to_vec();
+                    self.buf.drain(..header + len);
+                    self.synced = true;
+                    self.stats.frames += 1;
+                    if layout.dt(&payload).map_or(false, |dt| dt.0 > DELAY_US) {
+                        self.stats.delayed += 1;
+                    }
+                    return Ok(Message::Data(Frame { payload: payload, layout: layout }));
+                }
+                Err(_) => {
+                    // maybe the marker was really data: look for another one just after it
+                    self.stats.corrupt += 1;
+                    self.skip(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, Cursor, Read};
+    use super::*;
+
+    /// A payload with only the analog part (and a checksum, if asked)
+    fn analog(checksum: bool) -> Vec<u8> {
+        let mut payload = (0..ANALOG_LEN as u8).collect::<Vec<_>>();
+        if checksum {
+            let sum = super::checksum(&payload);
+            payload.push(sum);
+        }
+        payload
+    }
+
+    /// A payload with one accelerometer and one gyroscope sample, `dt` and a checksum
+    fn imu() -> Vec<u8> {
+        let mut payload = vec![1, 1];
+        payload.extend((0..6*3).map(|i| i as u8));
+        payload.extend(&[0xE8, 0x03, 0x10, 0x00]); // dt = (1000, 16)
+        payload.extend(analog(false));
+        let sum = super::checksum(&payload);
+        payload.push(sum);
+        payload
+    }
+
+    /// How the Teensy sends a payload
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = MARKER.to_vec();
+        bytes.extend(&[(payload.len() >> 8) as u8, payload.len() as u8]);
+        bytes.extend(payload);
+        bytes
+    }
+
+    /// How the Teensy acknowledges a request
+    fn ack(id: u8, status: u8, reply: &[u8]) -> Vec<u8> {
+        let mut bytes = ACK_MARKER.to_vec();
+        bytes.extend(&[id, status, reply.len() as u8]);
+        bytes.extend(reply);
+        let sum = super::checksum(&bytes[ACK_MARKER.len()..]);
+        bytes.push(sum);
+        bytes
+    }
+
+    /// A stream that hands out its bytes in the given pieces
+    struct Pieces(VecDeque<Vec<u8>>);
+
+    impl Pieces {
+        fn of(pieces: &[&[u8]]) -> Pieces {
+            Pieces(pieces.iter().map(|p| p.to_vec()).collect())
+        }
+
+        /// One byte per read
+        fn trickle(bytes: &[u8]) -> Pieces {
+            Pieces(bytes.iter().map(|&b| vec![b]).collect())
+        }
+    }
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(mut piece) => {
+                    let n = piece.len().min(buf.len());
+                    buf[..n].copy_from_slice(&piece[..n]);
+                    if n < piece.len() {
+                        self.0.push_front(piece.split_off(n));
+                    }
+                    Ok(n)
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn data<R: Read>(framer: &mut Framer<R>) -> Frame {
+        match framer.next_message().unwrap() {
+            Message::Data(frame) => frame,
+            Message::Ack(ack) => panic!("expected a frame, got {:?}", ack),
+        }
+    }
+
+    fn acked<R: Read>(framer: &mut Framer<R>) -> Ack {
+        match framer.next_message().unwrap() {
+            Message::Ack(ack) => ack,
+            Message::Data(frame) => panic!("expected an ack, got a {}-byte frame", frame.payload.len()),
+        }
+    }
+
+    fn at_end<R: Read>(framer: &mut Framer<R>) {
+        match framer.next_message() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(e) => panic!("expected the end of the stream, got {}", e),
+            Ok(_) => panic!("expected the end of the stream, got a message"),
+        }
+    }
+
+    fn stream(parts: &[Vec<u8>]) -> Vec<u8> {
+        parts.iter().flat_map(|p| p.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn frames_and_acks() {
+        let bytes = stream(&[frame(&analog(false)), ack(7, 0, b"v2"), frame(&imu())]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, analog(false));
+        assert_eq!(acked(&mut framer), Ack { id: 7, status: 0, reply: b"v2".to_vec() });
+        let frame = data(&mut framer);
+        assert_eq!(frame.payload, imu());
+        assert_eq!(frame.layout.dt(&frame.payload), Some((1000, 16)));
+        at_end(&mut framer);
+
+        assert_eq!(*framer.stats(), Stats { frames: 2, acks: 1, ..Stats::default() });
+    }
+
+    #[test]
+    fn next_frame_skips_acks() {
+        let bytes = stream(&[ack(1, 0, &[]), frame(&analog(true))]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+        assert_eq!(framer.next_frame().unwrap().payload, analog(true));
+        assert_eq!(framer.stats().acks, 1);
+    }
+
+    #[test]
+    fn split_reads() {
+        let bytes = stream(&[frame(&imu()), ack(3, 2, &[]), frame(&analog(true))]);
+        let mut framer = Framer::new(Pieces::trickle(&bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, imu());
+        assert_eq!(acked(&mut framer), Ack { id: 3, status: 2, reply: vec![] });
+        assert_eq!(data(&mut framer).payload, analog(true));
+        at_end(&mut framer);
+        assert_eq!(framer.stats().dropped, 0);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let bytes = stream(&[b"\x00\x01junk".to_vec(), frame(&analog(false)), b"xy".to_vec(), frame(&imu())]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, analog(false));
+        assert_eq!(data(&mut framer).payload, imu());
+        at_end(&mut framer);
+
+        let stats = framer.stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.skipped_bytes, 8);
+        assert_eq!(stats.corrupt, 0);
+    }
+
+    #[test]
+    fn impossible_length() {
+        let bytes = stream(&[b"aaa\xFF\xFF".to_vec(), frame(&analog(false))]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, analog(false));
+        assert_eq!(framer.stats().corrupt, 1);
+        assert_eq!(framer.stats().dropped, 1);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut bad = analog(true);
+        *bad.last_mut().unwrap() ^= 0xFF;
+        let bytes = stream(&[frame(&bad), frame(&analog(true))]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, analog(true));
+        at_end(&mut framer);
+        assert_eq!(framer.stats().frames, 1);
+        assert_eq!(framer.stats().corrupt, 1);
+        assert_eq!(framer.stats().dropped, 1);
+    }
+
+    #[test]
+    fn bad_ack_checksum() {
+        let mut bad = ack(9, 0, b"x");
+        *bad.last_mut().unwrap() ^= 0xFF;
+        let bytes = stream(&[bad, ack(10, 0, b"y")]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(acked(&mut framer), Ack { id: 10, status: 0, reply: b"y".to_vec() });
+        assert_eq!(framer.stats().acks, 1);
+        assert_eq!(framer.stats().corrupt, 1);
+    }
+
+    #[test]
+    fn oversized_ack() {
+        let bytes = stream(&[vec![b'k', b'k', b'k', 1, 0, MAX_REPLY as u8 + 1], frame(&analog(false))]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Teensy);
+
+        assert_eq!(data(&mut framer).payload, analog(false));
+        assert_eq!(framer.stats().corrupt, 1);
+    }
+
+    #[test]
+    fn marker_split_across_reads() {
+        let packet = frame(&analog(false));
+        let mut framer = Framer::new(Pieces::of(&[b"junka", &packet[1..2], &packet[2..]]), Kind::Teensy);
+        assert_eq!(data(&mut framer).payload, analog(false));
+        assert_eq!(framer.stats().skipped_bytes, 4);
+
+        let reply = ack(4, 0, b"ok");
+        let mut framer = Framer::new(Pieces::of(&[b"zzkk", &reply[2..]]), Kind::Teensy);
+        assert_eq!(acked(&mut framer), Ack { id: 4, status: 0, reply: b"ok".to_vec() });
+        assert_eq!(framer.stats().skipped_bytes, 2);
+    }
+
+    #[test]
+    fn read_errors_lose_nothing() {
+        struct Flaky { bytes: Vec<u8>, pos: usize, timed_out: bool }
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                // every other read times out
+                self.timed_out = !self.timed_out;
+                if self.timed_out {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+                }
+                let n = 10.min(buf.len()).min(self.bytes.len() - self.pos);
+                buf[..n].copy_from_slice(&self.bytes[self.pos..self.pos + n]);
+                self.pos += n;
+                Ok(n)
+            }
+        }
+
+        let mut framer = Framer::new(Flaky { bytes: frame(&imu()), pos: 0, timed_out: false }, Kind::Teensy);
+        let frame = loop {
+            match framer.next_message() {
+                Ok(Message::Data(frame)) => break frame,
+                Ok(Message::Ack(ack)) => panic!("unexpected {:?}", ack),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(frame.payload, imu());
+    }
+
+    #[test]
+    fn stb_framing() {
+        let payload = analog(true);
+        let mut bytes = MARKER.to_vec();
+        bytes.push(payload.len() as u8);
+        bytes.extend(&payload);
+        // the STB does not acknowledge anything, so this is just noise
+        let bytes = stream(&[ack(1, 0, &[]), bytes]);
+        let mut framer = Framer::new(Cursor::new(bytes), Kind::Stb);
+
+        assert_eq!(data(&mut framer).payload, payload);
+        at_end(&mut framer);
+        assert_eq!(framer.stats().acks, 0);
+        assert_eq!(framer.stats().dropped, 1);
+    }
+
+    #[test]
+    fn layouts() {
+        let plain = |dt, checksum| Layout { n_acc: 0, n_gyro: 0, imu: false, dt: dt, checksum: checksum };
+        assert_eq!(Layout::of(&analog(false), Kind::Teensy), Ok(plain(false, false)));
+        assert_eq!(Layout::of(&analog(true), Kind::Teensy), Ok(plain(false, true)));
+        assert_eq!(Layout::of(&[0; 35], Kind::Teensy), Ok(plain(true, false)));
+        assert_eq!(Layout::of(&[0; 36], Kind::Teensy), Ok(plain(true, true)));
+
+        let layout = Layout::of(&imu(), Kind::Teensy).unwrap();
+        assert_eq!(layout, Layout { n_acc: 1, n_gyro: 1, imu: true, dt: true, checksum: true });
+        assert_eq!(layout.imu_end(), 2 + 6*3);
+        assert_eq!(layout.analog_start(), 2 + 6*3 + 4);
+
+        // the same samples without dt or a checksum
+        let mut bare = imu();
+        bare.pop();
+        bare.drain(20..24);
+        let layout = Layout::of(&bare, Kind::Stb).unwrap();
+        assert_eq!(layout, Layout { n_acc: 1, n_gyro: 1, imu: true, dt: false, checksum: false });
+        assert_eq!(layout.dt(&bare), None);
+        assert_eq!(&bare[layout.analog_start()..], &analog(false)[..]);
+    }
+
+    #[test]
+    fn bad_layouts() {
+        assert!(Layout::of(&[0; ANALOG_LEN - 1], Kind::Teensy).is_err());
+
+        // STB payloads never carry dt
+        assert!(Layout::of(&imu(), Kind::Stb).is_err());
+
+        // too many IMU entries for the firmware
+        let mut payload = vec![40, 0];
+        payload.resize(ANALOG_LEN + 2 + 6*41, 0);
+        assert!(Layout::of(&payload, Kind::Stb).is_err());
+        assert!(Layout::of(&payload, Kind::Teensy).is_ok());
+
+        // a length that fits no layout
+        let mut payload = imu();
+        payload.push(0);
+        assert!(Layout::of(&payload, Kind::Teensy).is_err());
+
+        let mut payload = imu();
+        *payload.last_mut().unwrap() ^= 1;
+        assert!(Layout::of(&payload, Kind::Teensy).unwrap_err().contains("checksum"));
+    }
+}
@@ -0,0 +1,275 @@
+//! Decoding of the Teensy's inertial sensors into physical units
+//!
+//! Each packet carries whatever the digital IMU's FIFO collected since the previous packet:
+//! `n_acc` accelerometer samples, then `n_gyro` gyroscope samples, then one (big-endian)
+//! magnetometer sample, all in `imu`. The three analog accelerometers ride along in `ft`, read
+//! against the reference chosen with `ref int`/`ref ext`. Raw counts become m/s², rad/s and µT as
+//! `(raw - offset) * scale` for each axis, using the `[imu]` configuration.
+//!
+//! Since the FIFO samples arrive in bursts, `Timeline` spreads each burst evenly over the time
+//! since the previous packet and interpolates all the sensors onto one uniform timeline.
+//!
+//! The configuration in use and every reference switch are written to `teensy_imu.json` in the
+//! recording directory, so that the offline converter decodes exactly as the live plot did.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use utils::RecordingContext;
+use utils::config::{Axes, Config, Imu};
+use serde_json;
+
+use packet::Packet;
+
+/// Name of the recorded IMU settings in the recording directory
+pub const RECORD: &'static str = "teensy_imu.json";
+
+/// Sensors in a `Row`, in order
+pub const SENSORS: [&'static str; 4] = ["acc", "gyro", "mag", "analog"];
+
+/// Resampled values further apart than this many periods are not interpolated between
+const MAX_GAP_PERIODS: u64 = 50;
+
+/// Rows wait this many periods for the other sensors' samples before going out incomplete
+const WAIT_PERIODS: u64 = 100;
+
+/// Analog accelerometer reference voltage
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Reference {
+    Int,
+    Ext,
+}
+
+/// A reference switch, from some time on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Switch {
+    /// Host time (s) of the first packet read with this reference (0 for the initial one)
+    pub since: f64,
+    pub reference: Reference,
+}
+
+/// One packet's sensor readings, in physical units
+#[derive(Clone, Debug)]
+pub struct Decoded {
+    pub acc: Vec<[f64; 3]>,
+    pub gyro: Vec<[f64; 3]>,
+    pub mag: Option<[f64; 3]>,
+    pub analog: [f64; 3],
+}
+
+/// The IMU settings and reference switches of a recording
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Decoder {
+    pub config: Imu,
+    pub references: Vec<Switch>,
+}
+
+/// Raw IMU entries of a packet (accelerometer, gyroscope, then magnetometer)
+pub fn raw(packet: &Packet) -> Vec<[i16; 3]> {
+    let n = packet.n_acc as usize + packet.n_gyro as usize;
+    let count = if n > 0 { n + 1 } else { 0 };
+    packet.imu[..count].iter().map(|&xyz| [xyz.x, xyz.y, xyz.z]).collect()
+}
+
+/// Raw analog accelerometer readings from a packet's `ft` bytes (12-bit big-endian)
+pub fn analog(ft: &[u8]) -> [i32; 3] {
+    let mut counts = [0; 3];
+    for (i, &ch) in [18, 22, 24].iter().enumerate() {
+        counts[i] = (((ft[ch] as u32) << 8) + (ft[ch + 1] as u32)) as i32;
+    }
+    counts
+}
+
+fn scale(axes: &Axes, raw: [f64; 3]) -> [f64; 3] {
+    [(raw[0] - axes.offset[0]) * axes.scale[0],
+     (raw[1] - axes.offset[1]) * axes.scale[1],
+     (raw[2] - axes.offset[2]) * axes.scale[2]]
+}
+
+impl Decoder {
+    /// Decode with the configured settings, starting on the configured reference
+    pub fn new(config: &Config) -> Decoder {
+        let reference = if config.imu.reference == "ext" { Reference::Ext } else { Reference::Int };
+        Decoder {
+            config: config.imu.clone(),
+            references: vec![Switch { since: 0.0, reference: reference }],
+        }
+    }
+
+    /// Read the settings recorded alongside a data file
+    pub fn recorded<P: AsRef<Path>>(dir: P) -> Result<Decoder, String> {
+        let path = dir.as_ref().join(RECORD);
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_reader(file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the settings into the recording directory
+    pub fn record(&self, ctx: &RecordingContext) -> Result<(), String> {
+        let path = ctx.path(RECORD);
+        let file = File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The reference currently in use
+    pub fn reference(&self) -> Reference {
+        self.references.last().unwrap().reference
+    }
+
+    /// Use a different reference from time `since` (s) on
+    pub fn switch(&mut self, since: f64, reference: Reference) {
+        self.references.push(Switch { since: since, reference: reference });
+    }
+
+    /// Decode a packet taken at host time `t` (s), given its raw IMU entries and `ft` bytes
+    pub fn decode(&self, t: f64, imu: &[[i16; 3]], n_acc: usize, n_gyro: usize, ft: &[u8]) -> Decoded {
+        let float = |xyz: [i16; 3]| [xyz[0] as f64, xyz[1] as f64, xyz[2] as f64];
+
+        let reference = self.references.iter().rev().find(|s| s.since <= t).unwrap_or(&self.references[0]).reference;
+        let counts = analog(ft);
+        let analog = scale(match reference {
+                               Reference::Int => &self.config.analog_int,
+                               Reference::Ext => &self.config.analog_ext,
+                           },
+                           [counts[0] as f64, counts[1] as f64, counts[2] as f64]);
+
+        if imu.len() < n_acc + n_gyro + 1 {
+            return Decoded { acc: vec![], gyro: vec![], mag: None, analog: analog };
+        }
+        let mag = imu[n_acc + n_gyro];
+        Decoded {
+            acc: imu[..n_acc].iter().map(|&xyz| scale(&self.config.acc, float(xyz))).collect(),
+            gyro: imu[n_acc..n_acc + n_gyro].iter().map(|&xyz| scale(&self.config.gyro, float(xyz))).collect(),
+            mag: Some(scale(&self.config.mag, float([i16::from_be(mag[0]), i16::from_be(mag[1]), i16::from_be(mag[2])]))),
+            analog: analog,
+        }
+    }
+
+    /// Decode a (stamped) packet
+    pub fn packet(&self, packet: &Packet) -> Decoded {
+        let stamp = packet.stamp;
+        self.decode(stamp.sec as f64 + stamp.nsec as f64 / 1e9,
+                    &raw(packet), packet.n_acc as usize, packet.n_gyro as usize, &packet.ft)
+    }
+}
+
+/// Puts one sensor's bursts of samples onto a uniform timeline
+struct Resampler {
+    period: u64,
+    /// Time of the previous burst (ns)
+    last: Option<u64>,
+    /// Latest sample placed on the timeline
+    prev: Option<(u64, [f64; 3])>,
+    /// Next time to produce a value for (a multiple of the period)
+    next: u64,
+}
+
+impl Resampler {
+    fn new(period: u64) -> Resampler {
+        Resampler { period: period, last: None, prev: None, next: 0 }
+    }
+
+    /// Add the samples of a burst that ended at time `t` (ns), returning values for the timeline
+    fn burst(&mut self, t: u64, samples: &[[f64; 3]], out: &mut Vec<(u64, [f64; 3])>) {
+        let last = self.last.unwrap_or(t);
+        self.last = Some(t);
+        let n = samples.len() as u64;
+
+        for (i, &v) in samples.iter().enumerate() {
+            // the first burst has nothing to spread over, so it all lands at `t`
+            let ti = last + (t.saturating_sub(last)) * (i as u64 + 1) / n;
+            match self.prev {
+                Some((tp, vp)) if ti > tp && ti - tp <= MAX_GAP_PERIODS * self.period => {
+                    while self.next <= ti {
+                        let f = (self.next - tp) as f64 / (ti - tp) as f64;
+                        out.push((self.next, [vp[0] + f * (v[0] - vp[0]),
+                                              vp[1] + f * (v[1] - vp[1]),
+                                              vp[2] + f * (v[2] - vp[2])]));
+                        self.next += self.period;
+                    }
+                }
+                Some((tp, _)) if ti <= tp => continue,
+                _ => {
+                    // first sample, or after a gap: start again at the next multiple of the period
+                    self.next = (ti + self.period - 1) / self.period * self.period;
+                    if self.next == ti {
+                        out.push((ti, v));
+                        self.next += self.period;
+                    }
+                }
+            }
+            self.prev = Some((ti, v));
+        }
+    }
+}
+
+/// A moment on the uniform timeline, with each sensor's value (in the order of `SENSORS`)
+#[derive(Clone, Debug)]
+pub struct Row {
+    /// Device time (ns)
+    pub t: u64,
+    pub values: [Option<[f64; 3]>; 4],
+}
+
+/// Resamples all the IMU sensors onto one uniform timeline
+///
+/// A row comes out once every sensor that has produced anything has a value for it, or once it is
+/// `WAIT_PERIODS` behind the latest sample (with the missing values left out).
+pub struct Timeline {
+    period: u64,
+    sensors: Vec<Resampler>,
+    active: [bool; 4],
+    pending: BTreeMap<u64, [Option<[f64; 3]>; 4]>,
+}
+
+impl Timeline {
+    pub fn new(rate_hz: f64) -> Timeline {
+        let period = ((1e9 / rate_hz) as u64).max(1);
+        Timeline {
+            period: period,
+            sensors: (0..SENSORS.len()).map(|_| Resampler::new(period)).collect(),
+            active: [false; 4],
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Add a packet's readings, taken at device time `t` (ns), returning the rows now complete
+    pub fn push(&mut self, t: u64, decoded: &Decoded) -> Vec<Row> {
+        let mag = decoded.mag.as_ref().map(|m| vec![*m]).unwrap_or_default();
+        let analog = [decoded.analog];
+        let bursts = [&decoded.acc[..], &decoded.gyro[..], &mag[..], &analog[..]];
+
+        let mut out = vec![];
+        for (s, burst) in bursts.iter().enumerate() {
+            if burst.is_empty() {
+                continue;
+            }
+            self.active[s] = true;
+            self.sensors[s].burst(t, burst, &mut out);
+            for (ts, v) in out.drain(..) {
+                self.pending.entry(ts).or_insert([None; 4])[s] = Some(v);
+            }
+        }
+
+        let cutoff = t.saturating_sub(WAIT_PERIODS * self.period);
+        let mut rows = vec![];
+        loop {
+            let ready = match self.pending.iter().next() {
+                Some((&ts, values)) => ts < cutoff || (0..4).all(|s| !self.active[s] || values[s].is_some()),
+                None => false
+            };
+            if !ready {
+                break;
+            }
+            let ts = *self.pending.keys().next().unwrap();
+            let values = self.pending.remove(&ts).unwrap();
+            rows.push(Row { t: ts, values: values });
+        }
+        rows
+    }
+
+    /// The rows still waiting for other sensors
+    pub fn finish(self) -> Vec<Row> {
+        self.pending.into_iter().map(|(t, values)| Row { t: t, values: values }).collect()
+    }
+}
@@ -1,19 +1,26 @@
 //! Service to read data from the Teensy and attached sensors
 
 #[macro_use] extern crate utils;
-#[cfg_attr(not(feature = "hardware"), macro_use)] extern crate comms;
+extern crate comms;
+#[macro_use] extern crate scribe;
+extern crate clock;
+extern crate telemetry;
+extern crate time;
+#[macro_use] extern crate lazy_static;
 
 #[macro_use] extern crate guilt_by_association;
 #[macro_use] extern crate macro_attr;
 #[macro_use] extern crate conv;
 #[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
 
 extern crate strum;
 #[macro_use] extern crate strum_macros;
 
 macro_attr! {
     /// Which end effector is in use (i.e. not parked)
-    #[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, EnumString, TryFrom!(u8))]
+    #[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize, EnumString, TryFrom!(u8))]
     pub enum ParkState {
         /// All end effectors parked
         None = 0,
@@ -40,538 +47,509 @@ impl ParkState {
     }
 }
 
-#[cfg(not(feature = "hardware"))]
 impl ParkState {
+    /// Read the parking lot (`None` if it can't be read, after logging why)
     pub fn metermaid() -> Option<ParkState> {
-        Some(ParkState::Stick)
+        ParkState::read().map_err(|e| errorln!("Could not read ParkState: {}", e)).ok()
+    }
+}
+
+#[cfg(not(feature = "hardware"))]
+impl ParkState {
+    /// Read the parking lot
+    pub fn read() -> Result<ParkState, String> {
+        Ok(ParkState::Stick)
+    }
+}
+
+mod packet;
+mod plot;
+pub mod calib;
+pub mod imu;
+pub mod framing;
+pub mod commands;
+pub mod park;
+
+/// Layout of the packets in `teensy.dat` (needed to read files written before they had headers)
+pub fn packet_schema() -> scribe::Schema {
+    <packet::LegacyPacket as scribe::Writable>::schema()
+}
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::Sender;
+use comms::CmdFrom;
+
+static PARK_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Passes the framing counters on to the supervisor, at most once a second and only when they change
+struct StatsReporter {
+    last: u64,
+    sent: framing::Stats,
+}
+
+impl StatsReporter {
+    fn new() -> StatsReporter {
+        StatsReporter { last: 0, sent: framing::Stats::default() }
+    }
+
+    /// Report the counters if it is time (or right away if `now`)
+    fn report(&mut self, tx: &Sender<CmdFrom>, stats: &framing::Stats, now: bool) {
+        let t = time::precise_time_ns();
+        if *stats != self.sent && (now || t - self.last >= 1_000_000_000) {
+            let _ = tx.send(CmdFrom::Stats("teensy".into(), stats.to_map()));
+            self.sent = stats.clone();
+            self.last = t;
+        }
+    }
+}
+
+/// Commands understood by the Teensy service
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum Command {
+    /// Report the park state to the web interface
+    Metermaid,
+    /// Switch the accelerometers to the internal reference
+    RefInt,
+    /// Switch the accelerometers to the external reference
+    RefExt,
+    /// Set burst mode to N samples
+    Burst(u8),
+    /// Capture the current strain gauge readings as the zero-load bias
+    Tare,
+    /// Set the sensor sample rate (Hz)
+    Rate(u16),
+    /// Report the firmware version to the web interface
+    Version,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        let mut words = s.split_whitespace();
+        let cmd = match (words.next(), words.next()) {
+            (Some("metermaid"), None)   => Command::Metermaid,
+            (Some("ref"), Some("int"))  => Command::RefInt,
+            (Some("ref"), Some("ext"))  => Command::RefExt,
+            (Some("burst"), Some(n))    => Command::Burst(n.parse().map_err(|_| format!("invalid burst count {:?}", n))?),
+            (Some("tare"), None)        => Command::Tare,
+            (Some("rate"), Some(hz))    => Command::Rate(hz.parse().map_err(|_| format!("invalid sample rate {:?}", hz))?),
+            (Some("version"), None)     => Command::Version,
+            _ => return Err(format!("unknown command {:?}", s)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected {:?} after {:?}", extra, cmd)),
+            None => Ok(cmd),
+        }
     }
 }
 
 group_attr!{
     #[cfg(feature = "hardware")]
 
-    extern crate scribe;
-
     extern crate serial;
-    extern crate time;
     extern crate rustc_serialize as serialize;
-    extern crate serde_json;
 
-    use comms::{Controllable, CmdFrom, Block, RestartableThread};
-    use scribe::{Writer, Writable};
+    use comms::{Controllable, Block};
+    use scribe::Writer;
     use utils::prelude::*;
-    use std::io::{self, Read, Write};
+    use utils::RecordingContext;
+    use utils::config::{self, Config};
     use std::fs::File;
-    use std::sync::mpsc::Sender;
-    use std::{u8, ptr, mem};
-    use std::fmt::{self, Display, Debug, Formatter};
+    use std::mem;
+    use std::io::{self, BufWriter, Read, Write};
     use time::Duration;
-    use std::num::Wrapping;
-    use std::sync::atomic::{AtomicUsize, AtomicBool, ATOMIC_USIZE_INIT, ATOMIC_BOOL_INIT, Ordering};
-    use std::panic::{catch_unwind, resume_unwind};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
     use serial::prelude::*;
     use conv::TryFrom;
-
-    trait Coffee: Read + Write {
-        fn coffee<W: Write>(self, w: W) -> CoffeeImpl<Self, W> where Self: Sized {
-            CoffeeImpl { parent: self, writer: w }
-        }
-    }
-
-    struct CoffeeImpl<RW: Read + Write, W: Write> {
-        parent: RW,
-        writer: W,
+    use utils::replay::{self, Player, Recorder};
+    use packet::{Packet, DeviceClock};
+    use plot::Plot;
+    use calib;
+    use imu::{self, Reference};
+    use framing::{self, Framer, Kind, Message, Ack};
+    use commands::{Commander, Request};
+    use super::StatsReporter;
+
+    /// The Teensy's serial port, teeing the raw input into the current recording's dump (if any)
+    struct Port {
+        serial: serial::SystemPort,
+        dump: Option<Recorder<BufWriter<File>>>,
     }
 
-    impl<RW: Read + Write, W: Write> Read for CoffeeImpl<RW, W> {
+    impl Read for Port {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            let n = try!(self.parent.read(buf));
-            try!(self.writer.write_all(&buf[..n]));
+            let n = try!(self.serial.read(buf));
+            if let Some(ref mut dump) = self.dump {
+                try!(dump.record(&buf[..n]));
+            }
             Ok(n)
         }
     }
 
-    impl<RW: Read + Write, W: Write> Write for CoffeeImpl<RW, W> {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.parent.write(buf) }
-        fn flush(&mut self)             -> io::Result<()>    { self.parent.flush()    }
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.serial.write(buf) }
+        fn flush(&mut self)             -> io::Result<()>    { self.serial.flush()    }
     }
 
-    impl<T: Read + Write> Coffee for T {}
+    /// The one open handle on the Teensy, with the requests sent through it
+    struct Link {
+        framer: Framer<Port>,
+        commands: Commander,
+        /// As reported when the link was opened (`None` for firmware that predates acknowledgements)
+        firmware: Option<String>,
+    }
 
-    trait StaticReadWrite: Read + Write + 'static {}
-    impl<T: Read + Write + 'static> StaticReadWrite for T {}
+    impl Link {
+        fn open(config: &Config) -> io::Result<Link> {
+            let mut serial = try!(serial::open(&config.teensy.port));
+            try!(serial.reconfigure(&|settings| {
+                try!(settings.set_baud_rate(serial::Baud115200));
+                settings.set_flow_control(serial::FlowNone);
+                Ok(())
+            }));
+            try!(serial.set_timeout(Duration::milliseconds(100).to_std().unwrap()));
+            let mut framer = Framer::new(Port { serial: serial, dump: None }, Kind::Teensy);
+            let mut commands = Commander::new(Duration::milliseconds(config.teensy.ack_timeout_ms as i64));
+            let firmware = try!(commands.negotiate(&mut framer).map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+            if firmware.is_none() {
+                println!("TEENSY: no firmware version reported, falling back to the legacy protocol");
+            }
+            Ok(Link {
+                framer: framer,
+                commands: commands,
+                firmware: firmware,
+            })
+        }
 
-    fn byte_copy(from: &[u8], mut to: &mut [u8]) -> usize {
-        to.write(from).unwrap()
+        /// Send a request and wait for the reply (only while the driver is not reading the stream)
+        fn query(&mut self, request: Request) -> Result<Vec<u8>, String> {
+            self.commands.query(&mut self.framer, request)
+        }
     }
 
-    fn serialport() -> Box<StaticReadWrite> {
-        let mut port = serial::open("/dev/ttyTEENSY").unwrap();
-        port.reconfigure(&|settings| {
-            try!(settings.set_baud_rate(serial::Baud115200));
-            settings.set_flow_control(serial::FlowNone);
-            Ok(())
-        }).unwrap();
-        port.set_timeout(Duration::milliseconds(100).to_std().unwrap()).unwrap();
-        if true {
-            Box::new(port.coffee(File::create("teensydump.dat").unwrap()))
-        } else {
-            Box::new(port)
-        }
+    lazy_static! {
+        /// The link while the driver is not running (the driver holds it while it is)
+        static ref LINK: Mutex<Option<Link>> = Mutex::new(None);
     }
 
-    static PARK_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// Only changed while holding `LINK`, so that the link is either there or in use by the driver
     static RUNNING: AtomicBool = ATOMIC_BOOL_INIT;
 
     impl ParkState {
-        pub fn metermaid() -> Option<ParkState> {
-            let val = if RUNNING.load(Ordering::SeqCst) {
-                PARK_STATE.load(Ordering::SeqCst) as u8
-            } else {
-                let mut port = serialport();
-                let mut buf = [0u8; 1];
-
-                let read = utils::retry(Some("[teensy] reading ParkState"), 3, Duration::milliseconds(50), || {
-                    Ok(())
-                        .and_then(|_| port.write_all(&['4' as u8]))
-                        .and_then(|_| port.read_exact(&mut buf))
-                });
-
-                match read {
-                    Ok(()) => buf[0],
-                    Err(_) => return None
+        /// Read the parking lot (from the packets, if the driver is running)
+        pub fn read() -> Result<ParkState, String> {
+            let val = {
+                let mut link = LINK.lock().unwrap();
+                if RUNNING.load(Ordering::SeqCst) {
+                    PARK_STATE.load(Ordering::SeqCst) as u8
+                } else {
+                    if link.is_none() {
+                        *link = Some(Link::open(&config::get()).map_err(|e| format!("could not open the Teensy: {}", e))?);
+                    }
+                    match link.as_mut().unwrap().query(Request::ParkState) {
+                        Ok(ref reply) if reply.len() == 1 => reply[0],
+                        Ok(reply) => return Err(format!("Teensy sent a {}-byte ParkState", reply.len())),
+                        Err(e) => {
+                            // the port may be gone or out of step, so open it afresh next time
+                            *link = None;
+                            return Err(e);
+                        }
+                    }
                 }
             };
 
             let masked = !val & !0b1110_1100;
-            println!("TEENSY: converting ParkState from 0x{:X} (0x{:X})", val, masked);
-
             match ParkState::try_from(masked) {
-                Ok(ps) => Some(ps),
-                Err(_) => Some(ParkState::Multiple)
+                Ok(ps) => Ok(ps),
+                Err(_) => Ok(ParkState::Multiple)
             }
         }
     }
 
-    type PngStuff = (Sender<CmdFrom>, Vec<Packet>, Option<usize>);
+    /// Where the driver's packets come from
+    enum Source {
+        Teensy(Link),
+        Replay(Framer<Player>),
+        /// After teardown, when the link has been handed back
+        Stopped,
+    }
+
+    impl Source {
+        fn next_message(&mut self) -> io::Result<Message> {
+            match *self {
+                Source::Teensy(ref mut link) => link.framer.next_message(),
+                Source::Replay(ref mut framer) => framer.next_message(),
+                Source::Stopped => Err(io::Error::new(io::ErrorKind::NotConnected, "Teensy driver stopped")),
+            }
+        }
+
+        fn stats(&self) -> framing::Stats {
+            match *self {
+                Source::Teensy(ref link) => link.framer.stats().clone(),
+                Source::Replay(ref framer) => framer.stats().clone(),
+                Source::Stopped => framing::Stats::default(),
+            }
+        }
+    }
 
     pub struct Teensy {
-        port: Box<StaticReadWrite>,
+        source: Source,
+        stats: StatsReporter,
         file: Writer<Packet>,
+        clock: DeviceClock,
         i: usize,
-        buf: Vec<Packet>,
         tx: Sender<CmdFrom>,
-        png: RestartableThread<PngStuff>,
+        plot: Plot,
+        calib: calib::Active,
+        imu: imu::Decoder,
+        timeline: imu::Timeline,
+        ctx: RecordingContext,
         start: time::Tm,
+        /// Whether the replayed dump has run out
+        finished: bool,
     }
 
-    #[repr(packed)]
-    #[derive(Copy, Clone)]
-    struct XYZ<T> {
-        x: T,
-        y: T,
-        z: T
-    }
-    #[repr(packed)]
-    #[allow(dead_code)]
-    struct Packet {
-        stamp  : time::Timespec,
-        dt     : (u16, u16),
-        ft     : [u8; 31],
-        n_acc  : u8,
-        n_gyro : u8,
-        imu    : [XYZ<i16>; 63]
-    }
-    impl Copy for Packet {}
-    impl Clone for Packet { fn clone(&self) -> Packet { *self } }
-
-    unsafe impl Writable for Packet {}
-
-    impl Packet {
-        unsafe fn new(buf: &[u8]) -> Result<Packet, String> {
-            fn checksum(buf: &[u8]) -> Result<(), String> {
-                //let sum = buf[..buf.len()-1].into_iter().fold(0, |a, b| { u8::wrapping_add(a, *b) });
-                let mut sum = Wrapping(0u8);
-                for b in &buf[..buf.len()-1] { sum += Wrapping(*b); }
-                let sum = sum.0;
-                match buf[buf.len()-1] {
-                    s if s == sum => Ok(()),
-                    s => Err(format!("Received Teensy packet with wrong checksum (it says {}, I calculate {})!", s, sum)),
-                }
-            }
-
-            unsafe fn only_analog(buf: &[u8]) -> Packet {
-                let mut p: Packet = Packet {
-                    stamp  : time::get_time(),
-                    dt     : (0, 0),
-                    ft     : mem::zeroed::<[u8; 31]>(),
-                    n_acc  : 0,
-                    n_gyro : 0,
-                    imu    : mem::zeroed::<[XYZ<i16>; 63]>(),
-                };
-                byte_copy(buf, &mut p.ft);
-                p
-            }
-
-            unsafe fn only_analog_dt(buf: &[u8]) -> Packet {
-                let mut p: Packet = Packet {
-                    stamp  : time::get_time(),
-                    dt     : (0, 0),
-                    ft     : mem::zeroed::<[u8; 31]>(),
-                    n_acc  : 0,
-                    n_gyro : 0,
-                    imu    : mem::zeroed::<[XYZ<i16>; 63]>(),
-                };
-                byte_copy(&buf[..4], mem::transmute::<&mut (u16, u16), &mut [u8; 4]>(&mut p.dt));
-                byte_copy(&buf[4..], &mut p.ft);
-                p
-            }
-
-            unsafe fn imu_and_analog(buf: &[u8], a: usize, g: usize) -> Packet {
-                let s = 2 + 6*(a + g + 1);
-                let mut p: Packet = Packet {
-                    stamp  : time::get_time(),
-                    dt     : (0, 0),
-                    ft     : mem::zeroed::<[u8; 31]>(),
-                    n_acc  : a as u8,
-                    n_gyro : g as u8,
-                    imu    : mem::zeroed::<[XYZ<i16>; 63]>()
-                };
-                byte_copy(&buf[s..], &mut p.ft);
-                ptr::copy::<XYZ<i16>>(buf[2..s].as_ptr() as *const XYZ<i16>, p.imu.as_mut_ptr(), (a+g+1) as usize);
-                p
-            }
+    impl Teensy {
+        fn replaying(&self) -> bool {
+            match self.source { Source::Replay(_) => true, _ => false }
+        }
 
-            unsafe fn imu_and_analog_dt(buf: &[u8], a: usize, g: usize) -> Packet {
-                let s = 2 + 6*(a + g + 1);
-                let mut p: Packet = Packet {
-                    stamp  : time::get_time(),
-                    dt     : (0, 0),
-                    ft     : mem::zeroed::<[u8; 31]>(),
-                    n_acc  : a as u8,
-                    n_gyro : g as u8,
-                    imu    : mem::zeroed::<[XYZ<i16>; 63]>()
-                };
-                byte_copy(&buf[s..s+4], mem::transmute::<&mut (u16, u16), &mut [u8; 4]>(&mut p.dt));
-                byte_copy(&buf[s+4..], &mut p.ft);
-                ptr::copy::<XYZ<i16>>(buf[2..s].as_ptr() as *const XYZ<i16>, p.imu.as_mut_ptr(), (a+g+1) as usize);
-                p
+        /// Send a request to the Teensy (the acknowledgement is handled by `ack`)
+        ///
+        /// A failure is only logged, since the stream may recover.
+        fn request(&mut self, request: Request) {
+            match self.source {
+                Source::Teensy(ref mut link) => {
+                    if let Err(e) = link.commands.send(link.framer.get_mut(), request) {
+                        errorln!("Error sending {:?} to Teensy: {}", request, e);
+                    }
+                }
+                Source::Replay(_) | Source::Stopped => println!("Not sending {:?}: not connected to the Teensy.", request),
             }
+        }
 
-            let mut pkt = match buf.len() {
-                x if x < 31 => return Err(format!("Implausibly small packet ({}) from Teensy!", x)),
-                31 => only_analog(buf),
-                32 => {
-                    try!(checksum(buf));
-                    only_analog(buf)
-                },
-                35 => only_analog_dt(buf),
-                36 => {
-                    try!(checksum(buf));
-                    only_analog_dt(buf)
-                },
-                x => {
-                    let a = buf[0] as usize;
-                    let g = buf[1] as usize;
-                    match x {
-                        t if t == 31 + 2 + 6*(a + g + 1) => imu_and_analog(buf, a, g),
-                        t if t == 35 + 2 + 6*(a + g + 1) => imu_and_analog_dt(buf, a, g),
-                        t if t == 31 + 2 + 6*(a + g + 1) + 1 => {
-                            try!(checksum(buf));
-                            imu_and_analog(buf, a, g)
-                        },
-                        t if t == 35 + 2 + 6*(a + g + 1) + 1 => {
-                            try!(checksum(buf));
-                            imu_and_analog_dt(buf, a, g)
-                        },
-                        _ => return Err(format!("Impossible packet size ({} with a={}, g={}) from Teensy!", x, a, g)),
-                    }
-                },
+        /// Deal with the Teensy's answer to a request
+        fn ack(&mut self, ack: Ack) {
+            let answered = match self.source {
+                Source::Teensy(ref mut link) => link.commands.ack(ack),
+                Source::Replay(_) | Source::Stopped => None,
             };
-
-            PARK_STATE.store(*pkt.ft.last().unwrap() as usize, Ordering::SeqCst);
-            *pkt.ft.last_mut().unwrap() &= !0b0001_0011; // FIXME make this a const somewhere
-
-            if pkt.dt.0 > 1000 {
-                println!("Delayed packet from Teensy! Packet follows:");
-                println!("{:#?}", pkt);
-
-                static DELAYS: AtomicUsize = ATOMIC_USIZE_INIT;
-                if DELAYS.fetch_add(1, Ordering::SeqCst) > 100 {
-                    panic!("too many delayed packets");
+            match answered {
+                Some((Request::FirmwareVersion, Ok(reply))) => {
+                    let version = String::from_utf8_lossy(&reply).into_owned();
+                    comms::tell(&self.tx, "web", format!("msg Teensy firmware {}", version)).unwrap();
+                }
+                Some((Request::ParkState, Ok(reply))) => {
+                    if let Some(&state) = reply.first() {
+                        PARK_STATE.store(state as usize, Ordering::SeqCst);
+                    }
                 }
+                Some((request, Ok(_))) => println!("Teensy acknowledged {:?}.", request),
+                Some((request, Err(e))) => {
+                    errorln!("Teensy refused {:?}: {}", request, e);
+                    comms::tell(&self.tx, "web", format!("msg Teensy refused {:?}: {}", request, e)).unwrap();
+                }
+                None => {}
             }
-
-            Ok(pkt)
         }
-    }
 
-    impl<T: Display> Debug for XYZ<T> {
-        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-            try!(write!(f, "({:#6}, {:#6}, {:#6})", self.x, self.y, self.z));
-            Ok(())
+        /// Complain about requests the Teensy never answered
+        fn expire(&mut self) {
+            let expired = match self.source {
+                Source::Teensy(ref mut link) => link.commands.expired(),
+                Source::Replay(_) | Source::Stopped => vec![],
+            };
+            for request in expired {
+                errorln!("Teensy did not acknowledge {:?}", request);
+                comms::tell(&self.tx, "web", format!("msg Teensy did not acknowledge {:?}", request)).unwrap();
+            }
         }
-    }
 
-    impl Debug for Packet {
-        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-            try!(writeln!(f, "Packet {{"));
-            try!(writeln!(f, "\tstamp: {:?}", self.stamp));
-            try!(writeln!(f, "\tdt: {:?}", self.dt));
-            try!(writeln!(f, "\tft: {:?}", self.ft));
-            try!(write!(f, "\tacc: ["));
-            for i in 0..self.n_acc {
-                try!(write!(f, "{:?}, ", self.imu[i as usize]));
-            }
-            try!(writeln!(f, "]"));
-            try!(write!(f, "\tgyro: ["));
-            for i in 0..self.n_gyro {
-                try!(write!(f, "{:?}, ", self.imu[(self.n_acc + i) as usize]));
-            }
-            if self.n_acc + self.n_gyro > 0 {
-                try!(writeln!(f, "\tmag: {:?}", self.imu[(self.n_acc + self.n_gyro) as usize]));
+        /// Decode packets read from now on against a different accelerometer reference
+        fn switch(&mut self, reference: Reference) {
+            let now = time::get_time();
+            self.imu.switch(now.sec as f64 + now.nsec as f64 / 1e9, reference);
+            if let Err(e) = self.imu.record(&self.ctx) {
+                errorln!("Could not record Teensy IMU settings: {}", e);
             }
-            try!(writeln!(f, "]"));
-            try!(write!(f, "}}"));
-            Ok(())
         }
     }
 
-    const BUF_LEN: usize = 6000;
-
     guilty! {
         impl Controllable for Teensy {
             const NAME: &'static str = "teensy";
             const BLOCK: Block = Block::Immediate;
+            type Command = Command;
 
-            fn setup(tx: Sender<CmdFrom>, cmd: Option<String>) -> Teensy {
+            fn setup(tx: Sender<CmdFrom>, cmd: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Teensy, String> {
                 match cmd.as_ref().map(|s| s as &str) {
                     Some("metermaid") => {
-                        tx.send(CmdFrom::Data(format!("send status {:?}", ParkState::metermaid()))).unwrap();
+                        comms::tell(&tx, "web", format!("status {:?}", ParkState::metermaid())).unwrap();
                     }
                     _ => {}
                 }
 
-                let mut port = serialport();
-                RUNNING.store(true, Ordering::SeqCst);
-                port.write_all(&['1' as u8]).unwrap();
-
-                // some stuff for the RestartableThread
-                let mut idx = 0;
-                //let mut fig = Figure::new();
-                //let mut data = Vec::with_capacity(10240);
-                let start = time::get_time();
-
-                Teensy {
-                    port: port,
-                    file: Writer::with_file("teensy.dat"),
-                    i: 0,
-                    start: time::now(),
-                    tx: tx,
-                    buf: Vec::with_capacity(BUF_LEN),
-                    png: RestartableThread::new("Teensy PNG thread", move |(sender, vec, id): PngStuff| {
-                        let decimate = 5;
-
-                        // process data
-                        let len = vec.len();
-                        let mut t  = Vec::with_capacity(len/decimate);
-                        let mut fx = Vec::with_capacity(len/decimate);
-                        let mut fy = Vec::with_capacity(len/decimate);
-                        let mut fz = Vec::with_capacity(len/decimate);
-                        //let mut tx = vec![0; len];
-                        //let mut ty = vec![0; len];
-                        //let mut tz = vec![0; len];
-                        let mut a  = Vec::with_capacity(len/decimate);
-
-                        for i in utils::step(0..len, decimate) {
-                            let diff = (vec[i].stamp - start).to_std().unwrap();
-                            t.push(diff.as_secs() as f64 + (diff.subsec_nanos() as f64 / 1.0e9));
-                            let mut ft = [(((vec[i].ft[0]  as u32) << 8) + (vec[i].ft[1]  as u32)) as i32,
-                                          (((vec[i].ft[2]  as u32) << 8) + (vec[i].ft[3]  as u32)) as i32,
-                                          (((vec[i].ft[4]  as u32) << 8) + (vec[i].ft[5]  as u32)) as i32,
-                                          (((vec[i].ft[6]  as u32) << 8) + (vec[i].ft[7]  as u32)) as i32,
-                                          (((vec[i].ft[8]  as u32) << 8) + (vec[i].ft[9]  as u32)) as i32,
-                                          (((vec[i].ft[10] as u32) << 8) + (vec[i].ft[11] as u32)) as i32];
-                            for val in &mut ft {
-                                if *val >= 2048 {
-                                    *val -= 4096;
+                let spec = replay::Spec::from_param(cmd.as_ref().map(|s| s as &str));
+                // any other start parameter names a calibration profile
+                let profile = match cmd.as_ref().map(|s| s as &str) {
+                    Some("metermaid") | None => None,
+                    Some(_) if spec.is_some() => None,
+                    Some(p) => Some(p),
+                };
+                // (asked before the driver takes the link, since ParkState::metermaid uses it too)
+                let calib = calib::Active::setup(tx.clone(), profile, ParkState::metermaid, ctx.clone(), config);
+
+                let decoder = imu::Decoder::new(config);
+                let source = match spec {
+                    Some(ref spec) => {
+                        println!("TEENSY: replaying {}", spec.path.display());
+                        let player = Player::open(spec).map_err(|e| format!("could not open {}: {}", spec.path.display(), e))?;
+                        // (ParkState::metermaid reports the replayed park state)
+                        let _shared = LINK.lock().unwrap();
+                        RUNNING.store(true, Ordering::SeqCst);
+                        Source::Replay(Framer::new(player, Kind::Teensy))
+                    }
+                    None => {
+                        let mut link = {
+                            let mut shared = LINK.lock().unwrap();
+                            let mut link = match shared.take() {
+                                Some(link) => link,
+                                None => Link::open(config).map_err(|e| format!("could not open the Teensy at {}: {}", config.teensy.port, e))?,
+                            };
+                            let dump = ctx.path("teensydump.dat");
+                            match Recorder::create(&dump) {
+                                Ok(recorder) => link.framer.get_mut().dump = Some(recorder),
+                                Err(e) => {
+                                    // leave the link for ParkState::read
+                                    *shared = Some(link);
+                                    return Err(format!("could not create {}: {}", dump.display(), e));
                                 }
                             }
-                            let mut aa = 0.0;
-                            aa += (((((vec[i].ft[18] as u32) << 8) + (vec[i].ft[19] as u32)) as i32) - 2048) as f64;
-                            aa += (((((vec[i].ft[22] as u32) << 8) + (vec[i].ft[23] as u32)) as i32) - 2048) as f64;
-                            aa += (((((vec[i].ft[24] as u32) << 8) + (vec[i].ft[25] as u32)) as i32) - 2048) as f64;
-
-                            a.push(aa / 4096.0 * 16.0 * 9.81 / 3.0);
-                            // proton mini40
-                            const BIAS: [f64; 6] = [-0.1884383674, 0.2850118688, -0.180718143, -0.191009933, 0.3639300747, -0.4307167708];
-                            const TF: [[f64; 6]; 6] = [[0.00679, 0.01658, -0.04923, 6.20566, 0.15882, -6.19201],
-                                                       [0.11638, -7.31729, -0.04322, 3.54949, -0.08024, 3.57115],
-                                                       [10.35231, 0.32653, 10.61091, 0.29668, 10.33382, 0.25761],
-                                                       [0.00022, -0.0414, 0.14917, 0.02435, -0.15234, 0.01567],
-                                                       [-0.16837, -0.00464, 0.08561, -0.03311, 0.08763, 0.03721],
-                                                       [0.00128, -0.08962, 0.00085, -0.08785, 0.00204, -0.0879]];
-                             
-                                                       
-                            const SCALE: f64 = 0.002;
-                            /* // STB mini40
-                            const BIAS: [f64; 6] = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
-                            const TF: [[f64; 6]; 6] = [[ 0.165175269,   6.193716635,    -0.05972626,    0.020033203,    -0.136667224,   -6.42215241 ],
-                                  [ 0.002429674,  -3.63579423,    0.466390998,    7.308900211,    -0.18369186,    -3.65179797 ],
-                                  [ -10.5385017,  0.802731009,    -10.1357248,    0.359714766,    -10.0934065,    0.442593679 ],
-                                  [ 0.144765089,  -0.032574325,   0.004132077,    0.038285567,    -0.145061852,   -0.010347366],
-                                  [ -0.089833077, -0.024635731,   0.165602185,    -0.009131771,   -0.080132747,   0.039589968 ],
-                                  [ 0.001846317,  0.085776855,    0.005262967,    0.088317691,    0.001450272,    0.087714269 ]];
-                                  */
-                            
-
-                            /* // zeroed out
-                            const BIAS: [f64; 6] = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
-                            const TF: [[f64; 6]; 6] = [[1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-                                                       [0.0, 1.0, 0.0, 0.0, 0.0, 0.0],
-                                                       [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
-                                                       [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
-                                                       [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
-                                                       [0.0, 0.0, 0.0, 0.0, 0.0, 1.0]];
-                            */
-
-                            fx.push((TF[0][0] * (((ft[0] as f64) * SCALE) - BIAS[0]))
-                                  + (TF[0][1] * (((ft[1] as f64) * SCALE) - BIAS[1]))
-                                  + (TF[0][2] * (((ft[2] as f64) * SCALE) - BIAS[2]))
-                                  + (TF[0][3] * (((ft[3] as f64) * SCALE) - BIAS[3]))
-                                  + (TF[0][4] * (((ft[4] as f64) * SCALE) - BIAS[4]))
-                                  + (TF[0][5] * (((ft[5] as f64) * SCALE) - BIAS[5])));
-                            fy.push((TF[1][0] * (((ft[0] as f64) * SCALE) - BIAS[0]))
-                                  + (TF[1][1] * (((ft[1] as f64) * SCALE) - BIAS[1]))
-                                  + (TF[1][2] * (((ft[2] as f64) * SCALE) - BIAS[2]))
-                                  + (TF[1][3] * (((ft[3] as f64) * SCALE) - BIAS[3]))
-                                  + (TF[1][4] * (((ft[4] as f64) * SCALE) - BIAS[4]))
-                                  + (TF[1][5] * (((ft[5] as f64) * SCALE) - BIAS[5])));
-                            fz.push((TF[2][0] * (((ft[0] as f64) * SCALE) - BIAS[0])
-                                  + (TF[2][1] * (((ft[1] as f64) * SCALE) - BIAS[1]))
-                                  + (TF[2][2] * (((ft[2] as f64) * SCALE) - BIAS[2]))
-                                  + (TF[2][3] * (((ft[3] as f64) * SCALE) - BIAS[3]))
-                                  + (TF[2][4] * (((ft[4] as f64) * SCALE) - BIAS[4]))
-                                  + (TF[2][5] * (((ft[5] as f64) * SCALE) - BIAS[5]))));
-
-                            // look for spikes
-                            // j-3 j-2 j-1 j
-                            //     |
-                            //     ^ checking for spike here
-                            if i > decimate*3 {
-                                let j = a.len() - 1;
-                                foreach!($v => [a, fx, fy, fz] {
-                                    if ($v[j-2] - $v[j-3]).abs() - ($v[j] - $v[j-3]).abs() > 1.0 {
-                                        println!("TEENSY: repairing spike at {} ({}={:?})", t[j], stringify!($v), &$v[j-3..j+1]);
-                                        $v[j-2] = $v[j-3];
-                                    }
-                                });
-                            }
+                            RUNNING.store(true, Ordering::SeqCst);
+                            link
+                        };
+                        link.framer.reset_stats();
+
+                        match link.firmware {
+                            Some(ref version) => println!("TEENSY: firmware {}", version),
+                            None => comms::tell(&tx, "web", "msg Teensy firmware is too old to acknowledge requests").unwrap(),
                         }
 
-                        #[derive(Serialize)] struct Data<'a> { t: &'a [i32], fx: &'a [i32], fy: &'a [i32], fz: &'a [i32], a: &'a [i32] }
-                        let id_str = if let Some(id) = id { format!(" {}", id) } else { String::new() };
-                        sender.send(CmdFrom::Data(format!("send{} kick teensy {} {}", id_str, idx, serde_json::to_string(&Data { t: &t.iter().map(|&f| (f * 1000.0) as i32).collect::<Vec<_>>(), fx: &fx.iter().map(|&f| (f * 1000.0) as i32).collect::<Vec<_>>(), fy: &fy.iter().map(|&f| (f * 1000.0) as i32).collect::<Vec<_>>(), fz: &fz.iter().map(|&f| (f * 1000.0) as i32).collect::<Vec<_>>(), a: &a.iter().map(|&f| (f * 1000.0) as i32).collect::<Vec<_>>() }).unwrap()))).unwrap();
-                        idx += 1;
-                    })
+                        // start on a known accelerometer reference
+                        if let Err(e) = link.query(Request::Reference(decoder.reference())) {
+                            errorln!("Could not set Teensy accelerometer reference: {}", e);
+                            comms::tell(&tx, "web", format!("msg Teensy did not switch to the {:?} reference: {}", decoder.reference(), e)).unwrap();
+                        }
+
+                        // (acknowledged in the stream, once step is reading it)
+                        if let Err(e) = link.commands.send(link.framer.get_mut(), Request::StartStreaming) {
+                            errorln!("Error starting Teensy: {}", e);
+                        }
+                        Source::Teensy(link)
+                    }
+                };
+                if let Err(e) = decoder.record(&ctx) {
+                    errorln!("Could not record Teensy IMU settings: {}", e);
                 }
+
+                Ok(Teensy {
+                    source: source,
+                    stats: StatsReporter::new(),
+                    file: Writer::with_file(&ctx, "teensy.dat"),
+                    clock: DeviceClock::new(),
+                    i: 0,
+                    start: time::now(),
+                    finished: false,
+                    tx: tx,
+                    plot: Plot::new(),
+                    calib: calib,
+                    imu: decoder,
+                    timeline: imu::Timeline::new(config.imu.rate_hz),
+                    ctx: ctx,
+                })
             }
 
-            fn step(&mut self, cmd: Option<String>) {
+            fn step(&mut self, cmd: Option<Command>) {
                 self.i += 1;
 
-                /*
-                let mut b = [0u8; 4096];
-                self.port.read_exact(&mut b).err().map(|e| println!("Teensy read error {:?}", e));
-                */
-                let mut size_buf = [0u8; 5];
-                let packet_size = match self.port.read_exact(&mut size_buf) {
-                    Ok(()) => {
-                        if &size_buf[..3] == b"aaa" {
-                            let hi = size_buf[3] as usize;
-                            let lo = size_buf[4] as usize;
-                            (hi << 8) + lo
-                        } else {
-                            errorln!("The Teensy did not send the expected packet size prefix! instead {:?}", size_buf);
-                            let mut scanning = [0u8; 1];
-                            let mut count = 0;
-                            while count < 3 {
-                                self.port.read_exact(&mut scanning).unwrap();
-                                if scanning[0] == 'a' as u8 {
-                                    count += 1;
-                                } else {
-                                    count = 0;
-                                }
-                            }
-                            self.port.read_exact(&mut scanning).unwrap();
-                            let hi = scanning[0] as usize;
-                            self.port.read_exact(&mut scanning).unwrap();
-                            let lo = scanning[0] as usize;
-                            (hi << 8) + lo
-                        }
-                    },
-                    Err(e) => {
-                        errorln!("Error reading packet size from the Teensy: {:?}", e);
-                        return;
+                match cmd {
+                    Some(Command::Metermaid) => {
+                        comms::tell(&self.tx, "web", format!("status {:?}", ParkState::metermaid())).unwrap();
                     }
-                };
-                let mut buf = vec![0u8; packet_size];
-                match self.port.read_exact(&mut buf[..]) {
-                    Ok(()) => {
-                        let packet = match catch_unwind(|| unsafe { Packet::new(&buf) }) {
-                            Ok(Ok(p)) => p,
-                            Ok(Err(s)) => {
-                                errorln!("{}", s);
-                                return;
-                            },
-                            Err(e) => {
-                                errorln!("teensy panicked -- stopping");
-                                self.port.write_all(&['2' as u8]).unwrap();
-                                resume_unwind(e)
-                            }
-                        };
-
-                        match cmd.as_ref().map(|s| s as &str) {
-                            Some(s) if s.starts_with("kick") => {
-                                println!("Teensy: transmitting plot");
-                                self.png.send((self.tx.clone(), self.buf.clone(), s.split(' ').skip(1).next().map(|s| s.parse().unwrap()))).unwrap();
-                            }
-                            Some("metermaid") => {
-                                self.tx.send(CmdFrom::Data(format!("send status {:?}", ParkState::metermaid()))).unwrap();
-                            }
-                            Some("ref int") => {
-                                println!("Switching accelerometers to internal reference.");
-                                self.port.write_all(&['5' as u8]).unwrap();
-                            }
-                            Some("ref ext") => {
-                                println!("Switching accelerometers to external reference.");
-                                self.port.write_all(&['6' as u8]).unwrap();
-                            }
-                            Some(s) if s.starts_with("burst") => {
-                                let bursts: u8 = s.split(' ').skip(1).next().unwrap().parse().unwrap();
-                                println!("Setting teensy burst mode to N={}.", bursts);
-                                self.port.write_all(&['7' as u8, bursts]).unwrap();
-                            }
-                            _ => {}
-                        }
+                    Some(Command::RefInt) => {
+                        println!("Switching accelerometers to internal reference.");
+                        self.request(Request::Reference(Reference::Int));
+                        self.switch(Reference::Int);
+                    }
+                    Some(Command::RefExt) => {
+                        println!("Switching accelerometers to external reference.");
+                        self.request(Request::Reference(Reference::Ext));
+                        self.switch(Reference::Ext);
+                    }
+                    Some(Command::Burst(bursts)) => {
+                        println!("Setting teensy burst mode to N={}.", bursts);
+                        self.request(Request::Burst(bursts));
+                    }
+                    Some(Command::Rate(hz)) => {
+                        println!("Setting teensy sample rate to {} Hz.", hz);
+                        self.request(Request::SampleRate(hz));
+                    }
+                    Some(Command::Version) => self.request(Request::FirmwareVersion),
+                    Some(Command::Tare) => self.calib.tare(),
+                    None => {}
+                }
 
-                        self.buf.circular_push(packet.clone());
+                let message = self.source.next_message();
+                self.stats.report(&self.tx, &self.source.stats(), false);
+                self.expire();
+                match message {
+                    Ok(Message::Ack(ack)) => self.ack(ack),
+                    Ok(Message::Data(frame)) => {
+                        let mut packet = Packet::new(&frame);
+
+                        self.clock.stamp(&mut packet);
+                        self.calib.packet(&packet);
+                        let decoded = self.imu.packet(&packet);
+                        let rows = self.timeline.push(packet.sync.corrected, &decoded);
+                        self.plot.packet(&packet, &self.calib.calibration, &decoded, &rows);
                         self.file.write(packet);
                     },
-                    Err(e)  => errorln!("Error reading packet from Teensy: {:?}", e)
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && self.replaying() => {
+                        if !self.finished {
+                            comms::tell(&self.tx, "web", "msg Teensy replay finished").unwrap();
+                            self.finished = true;
+                        }
+                        Duration::milliseconds(100).sleep();
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}, // nothing sent yet, try again
+                    Err(e) => errorln!("Error reading packet from Teensy: {:?}", e)
                 }
             }
 
             fn teardown(&mut self) {
-                self.port.write_all(&['2' as u8]).unwrap();
-                RUNNING.store(false, Ordering::SeqCst);
+                self.request(Request::StopStreaming);
+                self.stats.report(&self.tx, &self.source.stats(), true);
                 let end = time::now();
                 let millis = (end - self.start).num_milliseconds() as f64;
                 println!("{} Teensy packets grabbed in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+                println!("Teensy framing: {:?}", self.source.stats());
+
+                // hand the link back for ParkState::metermaid (the stop is acknowledged to whoever reads next)
+                let mut shared = LINK.lock().unwrap();
+                if let Source::Teensy(mut link) = mem::replace(&mut self.source, Source::Stopped) {
+                    link.framer.get_mut().dump = None;
+                    *shared = Some(link);
+                }
+                RUNNING.store(false, Ordering::SeqCst);
             }
         }
     }
 }
 
 #[cfg(not(feature = "hardware"))]
-stub!(Teensy);
+mod sim;
+#[cfg(not(feature = "hardware"))]
+pub use sim::Teensy;
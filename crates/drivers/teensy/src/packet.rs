@@ -0,0 +1,142 @@
+//! Teensy packet layout and parsing (shared by the hardware and simulated backends)
+
+use std::io::Write;
+use std::{ptr, mem};
+use std::fmt::{self, Display, Debug, Formatter};
+use std::sync::atomic::Ordering;
+use time;
+use clock::{self, Stamp, Clock, Source};
+
+use framing::Frame;
+
+use super::PARK_STATE;
+
+fn byte_copy(from: &[u8], mut to: &mut [u8]) -> usize {
+    to.write(from).unwrap()
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone)]
+pub struct XYZ<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T
+}
+describe!(XYZ<T> { x, y, z });
+
+#[repr(packed)]
+#[allow(dead_code)]
+pub struct Packet {
+    pub stamp  : time::Timespec,
+    pub sync   : Stamp,
+    pub dt     : (u16, u16),
+    pub ft     : [u8; 31],
+    pub n_acc  : u8,
+    pub n_gyro : u8,
+    pub imu    : [XYZ<i16>; 63]
+}
+impl Copy for Packet {}
+impl Clone for Packet { fn clone(&self) -> Packet { *self } }
+
+writable!(Packet as "teensy" { stamp, sync, dt, ft, n_acc, n_gyro, imu });
+
+/// Layout of `Packet` in files written before they had headers (which also predate `sync`)
+#[repr(packed)]
+#[allow(dead_code)]
+pub struct LegacyPacket {
+    pub stamp  : time::Timespec,
+    pub dt     : (u16, u16),
+    pub ft     : [u8; 31],
+    pub n_acc  : u8,
+    pub n_gyro : u8,
+    pub imu    : [XYZ<i16>; 63]
+}
+
+writable!(LegacyPacket as "teensy" { stamp, dt, ft, n_acc, n_gyro, imu });
+
+impl Packet {
+    /// Unpack a frame (see `framing`) into a packet
+    pub fn new(frame: &Frame) -> Packet {
+        let (buf, layout) = (&frame.payload, frame.layout);
+        let mut p: Packet = Packet {
+            stamp  : time::get_time(),
+            sync   : Stamp::now(),
+            dt     : layout.dt(buf).unwrap_or((0, 0)),
+            ft     : [0; 31],
+            n_acc  : layout.n_acc as u8,
+            n_gyro : layout.n_gyro as u8,
+            imu    : unsafe { mem::zeroed::<[XYZ<i16>; 63]>() },
+        };
+        byte_copy(&buf[layout.analog_start()..], &mut p.ft);
+        if layout.imu {
+            let n = layout.n_acc + layout.n_gyro + 1;
+            unsafe {
+                ptr::copy::<XYZ<i16>>(buf[2..layout.imu_end()].as_ptr() as *const XYZ<i16>, p.imu.as_mut_ptr(), n);
+            }
+        }
+
+        PARK_STATE.store(*p.ft.last().unwrap() as usize, Ordering::SeqCst);
+        *p.ft.last_mut().unwrap() &= !0b0001_0011; // FIXME make this a const somewhere
+
+        p
+    }
+}
+
+/// The Teensy's own clock, reconstructed by adding up the `dt` (in µs) of each packet
+pub struct DeviceClock {
+    clock: Clock,
+    elapsed_ns: u64,
+}
+
+impl DeviceClock {
+    pub fn new() -> DeviceClock {
+        DeviceClock {
+            clock: clock::register("teensy", Source::Device("sum of packet dt".into())),
+            elapsed_ns: 0,
+        }
+    }
+
+    /// Fill in the corrected timestamp of a packet (packets without `dt` only get the host time)
+    pub fn stamp(&mut self, packet: &mut Packet) {
+        let dt = packet.dt;
+        let device = if dt == (0, 0) {
+            None
+        } else {
+            self.elapsed_ns += dt.0 as u64 * 1000;
+            Some(self.elapsed_ns)
+        };
+        packet.sync = self.clock.stamp(packet.sync.raw, device);
+    }
+}
+
+impl<T: Display> Debug for XYZ<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "({:#6}, {:#6}, {:#6})", self.x, self.y, self.z));
+        Ok(())
+    }
+}
+
+impl Debug for Packet {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        try!(writeln!(f, "Packet {{"));
+        try!(writeln!(f, "\tstamp: {:?}", self.stamp));
+        try!(writeln!(f, "\tsync: {:?}", self.sync));
+        try!(writeln!(f, "\tdt: {:?}", self.dt));
+        try!(writeln!(f, "\tft: {:?}", self.ft));
+        try!(write!(f, "\tacc: ["));
+        for i in 0..self.n_acc {
+            try!(write!(f, "{:?}, ", self.imu[i as usize]));
+        }
+        try!(writeln!(f, "]"));
+        try!(write!(f, "\tgyro: ["));
+        for i in 0..self.n_gyro {
+            try!(write!(f, "{:?}, ", self.imu[(self.n_acc + i) as usize]));
+        }
+        if self.n_acc + self.n_gyro > 0 {
+            try!(writeln!(f, "\tmag: {:?}", self.imu[(self.n_acc + self.n_gyro) as usize]));
+        }
+        try!(writeln!(f, "]"));
+        try!(write!(f, "}}"));
+        Ok(())
+    }
+}
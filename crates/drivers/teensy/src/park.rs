@@ -0,0 +1,145 @@
+//! Service that watches the parking lot and announces end-effector changes
+//!
+//! `ParkState::metermaid` reads the parking lot on the spot. This service reads it every
+//! `POLL_NS` instead, and only believes a new reading once it has held for `park.debounce_ms`, so
+//! that an end-effector brushing past the sensors on its way in or out is not taken for a change.
+//! Each change goes to the supervisor as `CmdFrom::EndEffectorChanged`, and `current` answers
+//! from the latest reading without going to the hardware.
+
+use std::mem;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::sync::mpsc::Sender;
+
+use comms::{self, Controllable, CmdFrom, Block};
+use utils::RecordingContext;
+use utils::config::Config;
+use time;
+
+use super::ParkState;
+
+/// How often the parking lot is read (ns)
+const POLL_NS: i64 = 100_000_000;
+
+lazy_static! {
+    /// The debounced state while the service is running (inner `None` until the first reading)
+    static ref CURRENT: RwLock<Option<Option<ParkState>>> = RwLock::new(None);
+}
+
+/// The end-effector in use, as last seen by the service (or read on the spot if it isn't running)
+pub fn current() -> Option<ParkState> {
+    match *CURRENT.read().unwrap() {
+        Some(state) => state,
+        None => ParkState::metermaid(),
+    }
+}
+
+/// Commands understood by the parking lot service
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum Command {
+    /// Report the current end-effector to the web interface
+    Report,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        match s.trim() {
+            "report" => Ok(Command::Report),
+            _ => Err(format!("unknown command {:?}", s)),
+        }
+    }
+}
+
+pub struct ParkingLot {
+    tx: Sender<CmdFrom>,
+    /// How long a new reading has to hold (ns)
+    debounce: u64,
+    /// The state that was last announced
+    state: Option<ParkState>,
+    /// A different reading, and when it was first seen
+    candidate: Option<(ParkState, u64)>,
+    /// Why the last reading failed (only logged when it changes)
+    error: Option<String>,
+}
+
+impl ParkingLot {
+    /// Take a reading, returning the debounced state if it changed
+    fn poll(&mut self, now: u64) -> Option<ParkState> {
+        let reading = match ParkState::read() {
+            Ok(reading) => {
+                self.error = None;
+                reading
+            }
+            Err(e) => {
+                if self.error.as_ref() != Some(&e) {
+                    errorln!("Could not read ParkState: {}", e);
+                    self.error = Some(e);
+                }
+                return None;
+            }
+        };
+
+        if self.state == Some(reading) {
+            self.candidate = None;
+            return None;
+        }
+        match self.candidate {
+            Some((candidate, since)) if candidate == reading => {
+                if now - since >= self.debounce {
+                    self.candidate = None;
+                    Some(reading)
+                } else {
+                    None
+                }
+            }
+            _ => {
+                self.candidate = Some((reading, now));
+                None
+            }
+        }
+    }
+}
+
+guilty! {
+    impl Controllable for ParkingLot {
+        const NAME: &'static str = "park";
+        const BLOCK: Block = Block::Period(POLL_NS);
+        type Command = Command;
+
+        fn setup(tx: Sender<CmdFrom>, _: Option<String>, _: RecordingContext, config: &Config) -> Result<ParkingLot, String> {
+            // the first reading is taken as it is (there is nothing to debounce it against)
+            let state = ParkState::read().map_err(|e| errorln!("Could not read ParkState: {}", e)).ok();
+            println!("Parking lot: {:?}", state);
+            *CURRENT.write().unwrap() = Some(state);
+
+            Ok(ParkingLot {
+                tx: tx,
+                debounce: config.park.debounce_ms * 1_000_000,
+                state: state,
+                candidate: None,
+                error: None,
+            })
+        }
+
+        fn step(&mut self, cmd: Option<Command>) {
+            if let Some(Command::Report) = cmd {
+                comms::tell(&self.tx, "web", format!("status {:?}", self.state)).unwrap();
+            }
+
+            if let Some(to) = self.poll(time::precise_time_ns()) {
+                // also announced when the parking lot could not be read before, so that nothing
+                // waits forever for a first good reading
+                let from = mem::replace(&mut self.state, Some(to));
+                *CURRENT.write().unwrap() = Some(Some(to));
+                println!("Parking lot: {:?} -> {:?}", from, to);
+                self.tx.send(CmdFrom::EndEffectorChanged { from: from.map(|from| format!("{:?}", from)), to: format!("{:?}", to) }).unwrap();
+            }
+        }
+
+        fn teardown(&mut self) {
+            *CURRENT.write().unwrap() = None;
+        }
+    }
+}
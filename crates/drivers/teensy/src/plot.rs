@@ -0,0 +1,88 @@
+//! Live plot data for the web interface (shared by the hardware and simulated backends)
+
+use std::collections::VecDeque;
+use telemetry::Publisher;
+
+use packet::Packet;
+use calib::{self, Calibration};
+use imu::{Decoded, Row};
+
+/// Plotted quantities, in the order they are published
+const FIELDS: [&'static str; 4] = ["fx", "fy", "fz", "a"];
+
+/// Fields of the resampled IMU channel
+const IMU_FIELDS: [&'static str; 9] = ["ax", "ay", "az", "gx", "gy", "gz", "mx", "my", "mz"];
+
+/// Publishes decimated force and acceleration readings, and the resampled IMU
+pub struct Plot {
+    publisher: Publisher,
+    imu: Publisher,
+
+    /// Latest resampled IMU values (NaN until a sensor has reported)
+    latest: [f64; 9],
+
+    /// Latest samples, held back until they have been checked for spikes
+    window: VecDeque<[f64; 4]>,
+}
+
+impl Plot {
+    pub fn new() -> Plot {
+        Plot {
+            publisher: Publisher::series("teensy", &FIELDS),
+            imu: Publisher::series("teensy-imu", &IMU_FIELDS),
+            latest: [::std::f64::NAN; 9],
+            window: VecDeque::with_capacity(4),
+        }
+    }
+
+    /// Publish a packet (and the IMU rows it completed), if it is time for another sample
+    ///
+    /// Force samples go out two behind, so that spikes can be repaired first.
+    pub fn packet(&mut self, packet: &Packet, calibration: &Calibration, decoded: &Decoded, rows: &[Row]) {
+        for row in rows {
+            for s in 0..3 {
+                if let Some(v) = row.values[s] {
+                    self.latest[3*s..3*s + 3].copy_from_slice(&v);
+                }
+            }
+        }
+        if self.imu.due() {
+            self.imu.publish(self.latest.to_vec());
+        }
+
+        if !self.publisher.due() {
+            return;
+        }
+
+        self.window.push_back(forces(packet, calibration, decoded));
+        if self.window.len() < 4 {
+            return;
+        }
+
+        // look for spikes
+        // 0 1 2 3
+        //   |
+        //   ^ checking for spike here
+        {
+            let w = &mut self.window;
+            for k in 0..FIELDS.len() {
+                if (w[1][k] - w[0][k]).abs() - (w[3][k] - w[0][k]).abs() > 1.0 {
+                    println!("TEENSY: repairing spike ({}={:?})", FIELDS[k], w.iter().map(|s| s[k]).collect::<Vec<_>>());
+                    w[1][k] = w[0][k];
+                }
+            }
+        }
+
+        let sample = self.window.pop_front().unwrap();
+        self.publisher.publish(sample.to_vec());
+    }
+}
+
+/// Convert a packet's strain gauge and analog accelerometer readings into forces (calibrated)
+/// and acceleration (the mean of the three accelerometers, m/s^2)
+fn forces(packet: &Packet, calibration: &Calibration, decoded: &Decoded) -> [f64; 4] {
+    let wrench = calibration.wrench(&calib::gauges(&packet.ft));
+    let a = (decoded.analog[0] + decoded.analog[1] + decoded.analog[2]) / 3.0;
+
+    [wrench[0], wrench[1], wrench[2], a]
+}
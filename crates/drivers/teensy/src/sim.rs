@@ -0,0 +1,237 @@
+//! Simulated Teensy, used when the `hardware` feature is off
+//!
+//! Emits packets with the same layout as the real firmware (six strain gauges, three analog
+//! accelerometers, and one sample each from the digital IMU) at the real packet rate, so that
+//! everything downstream of the driver can be exercised without the rig.
+//!
+//! Started with `replay:<dump>[,<speed>]`, it instead parses a `teensydump.dat` captured by the
+//! hardware backend, exactly as the hardware backend parses the serial port.
+
+use comms::{Controllable, CmdFrom, Block};
+use scribe::Writer;
+use utils::prelude::*;
+use utils::RecordingContext;
+use utils::config::Config;
+use std::f64::consts::PI;
+use std::{io, mem};
+use std::sync::mpsc::Sender;
+use time::{self, Duration};
+use utils::replay::{self, Player};
+
+use clock::Stamp;
+use packet::{Packet, XYZ, DeviceClock};
+use plot::Plot;
+use calib;
+use imu::{self, Reference};
+use framing::{Framer, Kind};
+use super::{ParkState, Command, StatsReporter};
+
+/// Nominal packet period of the Teensy firmware (ns)
+const PERIOD_NS: u64 = 1_000_000;
+
+pub struct Teensy {
+    file: Writer<Packet>,
+    clock: DeviceClock,
+    i: usize,
+    tx: Sender<CmdFrom>,
+    plot: Plot,
+    calib: calib::Active,
+    imu: imu::Decoder,
+    timeline: imu::Timeline,
+    ctx: RecordingContext,
+    start: time::Tm,
+    /// Deadline for the next packet (in `time::precise_time_ns` units)
+    next: u64,
+    /// Dump being replayed instead of synthesizing packets
+    replay: Option<Framer<Player>>,
+    stats: StatsReporter,
+    /// Whether the replayed dump has run out
+    finished: bool,
+}
+
+/// Store a signed 12-bit reading into the big-endian pair at `ft[idx..idx+2]`
+fn put12(ft: &mut [u8], idx: usize, val: i32) {
+    let raw = (val & 0xFFF) as u16;
+    ft[idx]   = (raw >> 8) as u8;
+    ft[idx+1] = (raw & 0xFF) as u8;
+}
+
+impl Teensy {
+    /// Make up a plausible packet for time `t` (in seconds)
+    fn synthesize(t: f64) -> Packet {
+        let mut p = Packet {
+            stamp  : time::get_time(),
+            sync   : Stamp::now(),
+            dt     : ((PERIOD_NS / 1000) as u16, (PERIOD_NS / 1000) as u16),
+            ft     : unsafe { mem::zeroed::<[u8; 31]>() },
+            n_acc  : 1,
+            n_gyro : 1,
+            imu    : unsafe { mem::zeroed::<[XYZ<i16>; 63]>() },
+        };
+
+        // strain gauges: slow pressing motion, each gauge slightly out of phase
+        for g in 0..6 {
+            let phase = g as f64 * PI / 3.0;
+            put12(&mut p.ft, 2*g, (200.0 * (2.0 * PI * 0.5 * t + phase).sin()) as i32);
+        }
+
+        // analog accelerometers: 5 Hz vibration around mid-scale
+        for &ch in &[18, 22, 24] {
+            put12(&mut p.ft, ch, 2048 + (100.0 * (2.0 * PI * 5.0 * t).sin()) as i32);
+        }
+
+        // digital IMU: one accelerometer sample, one gyro sample, and the magnetometer
+        p.imu[0] = XYZ { x: 0, y: 0, z: 4096 + (256.0 * (2.0 * PI * 5.0 * t).sin()) as i16 };
+        p.imu[1] = XYZ { x: (64.0 * (2.0 * PI * 0.5 * t).cos()) as i16, y: 0, z: 0 };
+        p.imu[2] = XYZ { x: 200i16.to_be(), y: (-50i16).to_be(), z: 400i16.to_be() };
+
+        p
+    }
+
+    /// Decode packets from now on against a different accelerometer reference
+    fn switch(&mut self, reference: Reference) {
+        let now = time::get_time();
+        self.imu.switch(now.sec as f64 + now.nsec as f64 / 1e9, reference);
+        if let Err(e) = self.imu.record(&self.ctx) {
+            errorln!("Could not record Teensy IMU settings: {}", e);
+        }
+    }
+}
+
+guilty! {
+    impl Controllable for Teensy {
+        const NAME: &'static str = "teensy";
+        const BLOCK: Block = Block::Immediate;
+        type Command = Command;
+
+        fn setup(tx: Sender<CmdFrom>, cmd: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Teensy, String> {
+            match cmd.as_ref().map(|s| s as &str) {
+                Some("metermaid") => {
+                    comms::tell(&tx, "web", format!("status {:?}", ParkState::metermaid())).unwrap();
+                }
+                _ => {}
+            }
+
+            let replay = match replay::Spec::from_param(cmd.as_ref().map(|s| s as &str)) {
+                Some(spec) => {
+                    println!("TEENSY: replaying {}", spec.path.display());
+                    let player = Player::open(&spec).map_err(|e| format!("could not open {}: {}", spec.path.display(), e))?;
+                    Some(Framer::new(player, Kind::Teensy))
+                }
+                None => None,
+            };
+            if replay.is_none() {
+                println!("TEENSY: simulated device");
+            }
+
+            // any other start parameter names a calibration profile
+            let profile = match cmd.as_ref().map(|s| s as &str) {
+                Some("metermaid") | None => None,
+                Some(_) if replay.is_some() => None,
+                Some(p) => Some(p),
+            };
+            let calib = calib::Active::setup(tx.clone(), profile, ParkState::metermaid, ctx.clone(), config);
+
+            let decoder = imu::Decoder::new(config);
+            println!("Starting on the {:?} accelerometer reference (simulated).", decoder.reference());
+            if let Err(e) = decoder.record(&ctx) {
+                errorln!("Could not record Teensy IMU settings: {}", e);
+            }
+
+            Ok(Teensy {
+                file: Writer::with_file(&ctx, "teensy.dat"),
+                clock: DeviceClock::new(),
+                i: 0,
+                start: time::now(),
+                tx: tx,
+                plot: Plot::new(),
+                calib: calib,
+                imu: decoder,
+                timeline: imu::Timeline::new(config.imu.rate_hz),
+                ctx: ctx,
+                next: time::precise_time_ns(),
+                replay: replay,
+                stats: StatsReporter::new(),
+                finished: false,
+            })
+        }
+
+        fn step(&mut self, cmd: Option<Command>) {
+            self.i += 1;
+
+            let mut packet = if let Some(ref mut player) = self.replay {
+                let frame = player.next_frame();
+                self.stats.report(&self.tx, player.stats(), false);
+                match frame {
+                    Ok(frame) => Packet::new(&frame),
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        if !self.finished {
+                            self.stats.report(&self.tx, player.stats(), true);
+                            comms::tell(&self.tx, "web", "msg Teensy replay finished").unwrap();
+                            self.finished = true;
+                        }
+                        Duration::milliseconds(100).sleep();
+                        return;
+                    },
+                    Err(e) => {
+                        errorln!("Error reading packet from Teensy replay: {:?}", e);
+                        return;
+                    }
+                }
+            } else {
+                // pace ourselves like the serial port would
+                let now = time::precise_time_ns();
+                if now < self.next {
+                    Duration::nanoseconds((self.next - now) as i64).sleep();
+                } else if now - self.next > 100 * PERIOD_NS {
+                    self.next = now; // fell way behind, don't try to catch up
+                }
+                self.next += PERIOD_NS;
+
+                Teensy::synthesize(self.i as f64 * PERIOD_NS as f64 / 1.0e9)
+            };
+
+            match cmd {
+                Some(Command::Metermaid) => {
+                    comms::tell(&self.tx, "web", format!("status {:?}", ParkState::metermaid())).unwrap();
+                }
+                Some(Command::RefInt) => {
+                    println!("Switching accelerometers to internal reference (simulated).");
+                    self.switch(Reference::Int);
+                }
+                Some(Command::RefExt) => {
+                    println!("Switching accelerometers to external reference (simulated).");
+                    self.switch(Reference::Ext);
+                }
+                Some(Command::Burst(bursts)) => {
+                    println!("Setting teensy burst mode to N={} (simulated).", bursts);
+                }
+                Some(Command::Rate(hz)) => {
+                    println!("Setting teensy sample rate to {} Hz (simulated).", hz);
+                }
+                Some(Command::Version) => {
+                    comms::tell(&self.tx, "web", "msg Teensy firmware (simulated)").unwrap();
+                }
+                Some(Command::Tare) => self.calib.tare(),
+                None => {}
+            }
+
+            self.clock.stamp(&mut packet);
+            self.calib.packet(&packet);
+            let decoded = self.imu.packet(&packet);
+            let rows = self.timeline.push(packet.sync.corrected, &decoded);
+            self.plot.packet(&packet, &self.calib.calibration, &decoded, &rows);
+            self.file.write(packet);
+        }
+
+        fn teardown(&mut self) {
+            if let Some(ref player) = self.replay {
+                self.stats.report(&self.tx, player.stats(), true);
+                println!("Teensy replay framing: {:?}", player.stats());
+            }
+            let end = time::now();
+            let millis = (end - self.start).num_milliseconds() as f64;
+            println!("{} simulated Teensy packets generated in {} s ({} FPS)!", self.i, millis/1000.0, 1000.0*(self.i as f64)/millis);
+        }
+    }
+}
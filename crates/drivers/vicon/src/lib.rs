@@ -1,31 +1,37 @@
 //! Service to remote-control the Vicon ROS node
 
 #[macro_use] extern crate utils;
-#[cfg_attr(not(feature="hardware"), macro_use)] extern crate comms;
+extern crate comms;
+extern crate scribe;
+extern crate clock;
+extern crate time;
 #[macro_use] extern crate guilt_by_association;
 
 group_attr!{
     #[cfg(feature = "hardware")]
 
-    extern crate scribe;
-    extern crate time;
-
-    use comms::{Controllable, CmdFrom, Block};
+    use comms::{Controllable, CmdFrom, Block, NoCommands};
     use scribe::Writer;
     use std::process::Command;
     use std::sync::mpsc::Sender;
-    use std::{env, str};
+    use std::str;
     use std::time::{SystemTime, UNIX_EPOCH};
+    use utils::RecordingContext;
+    use utils::config::Config;
 
     pub struct Vicon {
         tx: Sender<CmdFrom>,
         file: String,
         start: time::Tm,
+        /// Where the readings go at teardown
+        ctx: RecordingContext,
+        /// SSH destination of the ROS machine
+        host: String,
     }
 
-    fn roscmd(cmd: &str) -> bool {
+    fn roscmd(host: &str, cmd: &str) -> bool {
         Command::new("ssh")
-            .arg("aburka@158.130.11.59")
+            .arg(host)
             .arg(["source /opt/ros/indigo/setup.bash",
                   "export ROS_PACKAGE_PATH=/home/aburka/ros:$ROS_PACKAGE_PATH",
                   cmd]
@@ -34,18 +40,27 @@ group_attr!{
             .success()
     }
 
-    fn rospub(filename: &str, targets: &[&str]) {
-        assert!(roscmd(&format!(r#"rostopic pub -1 /vicon/targets vicon/Targets '{{filename: "{}", targets: [{}]}}'"#,
+    fn rospub(host: &str, filename: &str, targets: &[&str]) {
+        assert!(roscmd(host, &format!(r#"rostopic pub -1 /vicon/targets vicon/Targets '{{filename: "{}", targets: [{}]}}'"#,
                                 filename, targets.iter().map(|s| format!(r#""{}""#, s)).collect::<Vec<_>>().join(", "))))
     }
 
-    fn roscheck(filename: &str) -> bool {
-        roscmd(&format!(r#"wc -l '{}'"#, filename))
+    fn roscheck(host: &str, filename: &str) -> bool {
+        roscmd(host, &format!(r#"wc -l '{}'"#, filename))
+    }
+
+    /// Read the ROS machine's wall clock (in ns since the UNIX epoch)
+    fn remote_time(host: &str) -> Option<i64> {
+        Command::new("ssh")
+            .arg(host)
+            .arg("date +%s%N")
+            .output().ok()
+            .and_then(|out| str::from_utf8(&out.stdout).ok().and_then(|s| s.trim().parse().ok()))
     }
 
-    fn transfer(filename: &str) -> Vec<u8> {
+    fn transfer(host: &str, filename: &str) -> Vec<u8> {
         Command::new("ssh")
-            .arg("aburka@158.130.11.59")
+            .arg(host)
             .arg(format!("cat {}", filename))
             .output().unwrap()
             .stdout
@@ -55,35 +70,41 @@ group_attr!{
         impl Controllable for Vicon {
             const NAME: &'static str = "vicon";
             const BLOCK: Block = Block::Infinite;
+            // setup() waits for `rostopic pub -1` (which latches for a few seconds) and up to five
+            // ssh round trips to measure the ROS machine's clock
+            const SETUP_DEADLINE_MS: u64 = 30_000;
+            type Command = NoCommands;
 
-            fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> Vicon {
+            fn setup(tx: Sender<CmdFrom>, _: Option<String>, ctx: RecordingContext, config: &Config) -> Result<Vicon, String> {
                 let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
                 let filename = format!("vicon_{}.{}.csv", now.as_secs(), now.subsec_nanos());
 
-                rospub(&filename, &["proton:NewMarker",
-                                    "proton:NewMarker1",
-                                    "proton:NewMarker2",
-                                    "proton:NewMarker3",
-                                    "proton:NewMarker4",
-                                    "proton:Root"]);
+                let host = config.vicon.host.clone();
+                rospub(&host, &filename, &["proton:NewMarker",
+                                           "proton:NewMarker1",
+                                           "proton:NewMarker2",
+                                           "proton:NewMarker3",
+                                           "proton:NewMarker4",
+                                           "proton:Root"]);
 
-                Vicon { tx: tx, file: filename, start: time::now() }
+                // the Vicon timestamps come from the ROS machine, so measure how far off its clock is
+                clock::register_remote("vicon", "ROS machine wall clock", 5, || remote_time(&host));
+
+                Ok(Vicon { tx: tx, file: filename, start: time::now(), ctx: ctx, host: host })
             }
 
-            fn step(&mut self, _: Option<String>) {
+            fn step(&mut self, _: Option<NoCommands>) {
             }
 
             fn teardown(&mut self) {
-                let dir = env::current_dir().unwrap();
-
-                rospub("PAUSE", &[]);
-                if !roscheck(&self.file) {
-                    self.tx.send(CmdFrom::Data("send msg Vicon node crashed! No data received for latest dataset.".into())).unwrap();
+                rospub(&self.host, "PAUSE", &[]);
+                if !roscheck(&self.host, &self.file) {
+                    comms::tell(&self.tx, "web", "msg Vicon node crashed! No data received for latest dataset.").unwrap();
                 }
-                let readings = transfer(&self.file);
+                let readings = transfer(&self.host, &self.file);
                 let n = readings.iter().filter(|&&b| b == b'\n').count();
 
-                Writer::<[u8]>::with_file(dir.join("vicon.tsv").to_str().unwrap()).write(&readings);
+                Writer::<[u8]>::with_file(&self.ctx, "vicon.tsv").write(&readings);
 
                 let end = time::now();
                 let millis = (end - self.start).num_milliseconds() as f64;
@@ -94,4 +115,6 @@ group_attr!{
 }
 
 #[cfg(not(feature = "hardware"))]
-stub!(Vicon);
+mod sim;
+#[cfg(not(feature = "hardware"))]
+pub use sim::Vicon;
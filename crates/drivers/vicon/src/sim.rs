@@ -0,0 +1,72 @@
+//! Simulated Vicon node, used when the `hardware` feature is off
+//!
+//! Nothing is streamed during the episode (just like the real thing, which records on the ROS
+//! side). On teardown, a 100 Hz trajectory of the tracked targets is made up for the elapsed time
+//! and written to vicon.tsv.
+
+use comms::{Controllable, CmdFrom, Block, NoCommands};
+use scribe::Writer;
+use std::f64::consts::PI;
+use std::sync::mpsc::Sender;
+use time;
+use utils::RecordingContext;
+use utils::config::Config;
+
+const TARGETS: &'static [&'static str] = &["proton:NewMarker",
+                                           "proton:NewMarker1",
+                                           "proton:NewMarker2",
+                                           "proton:NewMarker3",
+                                           "proton:NewMarker4",
+                                           "proton:Root"];
+
+/// Sample rate of the made-up trajectory (Hz)
+const RATE: f64 = 100.0;
+
+pub struct Vicon {
+    start: time::Tm,
+    stamp: time::Timespec,
+    /// Where the trajectory goes at teardown
+    ctx: RecordingContext,
+}
+
+guilty! {
+    impl Controllable for Vicon {
+        const NAME: &'static str = "vicon";
+        const BLOCK: Block = Block::Infinite;
+        type Command = NoCommands;
+
+        fn setup(_: Sender<CmdFrom>, _: Option<String>, ctx: RecordingContext, _: &Config) -> Result<Vicon, String> {
+            println!("Vicon: simulated node");
+
+            Ok(Vicon { start: time::now(), stamp: time::get_time(), ctx: ctx })
+        }
+
+        fn step(&mut self, _: Option<NoCommands>) {
+        }
+
+        fn teardown(&mut self) {
+            let end = time::now();
+            let secs = (end - self.start).num_milliseconds() as f64 / 1000.0;
+            let n = (secs * RATE) as usize;
+
+            let t0 = self.stamp.sec as f64 + self.stamp.nsec as f64 / 1.0e9;
+            let mut readings = String::new();
+            for i in 0..n {
+                let t = i as f64 / RATE;
+                // the end effector sweeps slowly in a circle, with the markers riding along
+                let (cx, cy, cz) = (0.5 + 0.1 * (2.0 * PI * 0.1 * t).cos(),
+                                    0.1 * (2.0 * PI * 0.1 * t).sin(),
+                                    1.0);
+                for (j, target) in TARGETS.iter().enumerate() {
+                    let off = j as f64 * 0.02;
+                    readings.push_str(&format!("{:.9}\t{}\t{:.6}\t{:.6}\t{:.6}\n",
+                                               t0 + t, target, cx + off, cy - off, cz + off));
+                }
+            }
+
+            Writer::<[u8]>::with_file(&self.ctx, "vicon.tsv").write(readings.as_bytes());
+
+            println!("{} simulated Vicon packets generated in {} s ({} FPS)!", n, secs, n as f64 / secs);
+        }
+    }
+}
@@ -11,32 +11,68 @@ extern crate teensy;
 extern crate chrono;
 extern crate shlex;
 
-use comms::{Controllable, CmdFrom, Power, Block};
-use flow::{FLOWS, Comms};
+use comms::{Controllable, CmdFrom, Power, Block, NoCommands, Status};
+use flow::{Flow, FLOWS, Comms};
 use teensy::ParkState;
-use std::{env, fs, thread};
+use std::{fs, thread};
 use std::io::{self, BufRead, Write};
 use std::process::Command;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 use shlex::Shlex;
+use utils::RecordingContext;
+use utils::config::Config;
+
+#[derive(Clone)] struct CLIComms;
+impl Comms for CLIComms {
+    type Error = io::Error;
+
+    fn print(&self, _: String) -> Result<(), Self::Error> {
+        /* quiet */
+        Ok(())
+    }
+
+    fn send(&self, msg: String) -> Result<(), Self::Error> {
+        println!("\t{} ", &msg[4..]);
+        Ok(())
+    }
+
+    fn rpc<T, F: Fn(String) -> Result<T, String>>(&self, prompt: String, validator: F) -> Result<Option<T>, Self::Error> {
+        loop {
+            print!("\t{}: ", &prompt[7..]);
+            io::stdout().flush()?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            match validator(input.clone()) {
+                Ok(ret) => return Ok(Some(ret)),
+                Err(msg) => println!("\t{}", &msg[7..])
+            }
+        }
+    }
+}
 
 /// Controllable struct for the CLI
 pub struct CLI {
     tx: Sender<CmdFrom>,
+    /// Where services started from the CLI record (changed with `cd`)
+    ctx: RecordingContext,
+    config: Config,
 }
 
 guilty!{
     impl Controllable for CLI {
         const NAME: &'static str = "cli";
         const BLOCK: Block = Block::Immediate;
+        // step() waits for the operator to type something
+        const STEP_DEADLINE_MS: Option<u64> = None;
+        type Command = NoCommands;
 
-        fn setup(tx: Sender<CmdFrom>, _: Option<String>) -> CLI {
-            CLI { tx: tx }
+        fn setup(tx: Sender<CmdFrom>, _: Option<String>, ctx: RecordingContext, config: &Config) -> Result<CLI, String> {
+            Ok(CLI { tx: tx, ctx: ctx, config: config.clone() })
         }
 
-        fn step(&mut self, _: Option<String>) {
+        fn step(&mut self, _: Option<NoCommands>) {
             print!("> ");
             io::stdout().flush().unwrap();
 
@@ -71,7 +107,24 @@ guilty!{
                             } else {
                                 self.flow(None);
                             },
-                        Some("cd") => { env::set_current_dir(words.next().unwrap()).unwrap(); },
+                        Some("resume") =>
+                            if let Some(flowname) = words.next() {
+                                self.resume(&flowname);
+                            } else {
+                                errorln!("No flow (resume <flow>)");
+                            },
+                        Some("abort") =>
+                            if let Some(flowname) = words.next() {
+                                self.abort(&flowname);
+                            } else {
+                                errorln!("No flow (abort <flow>)");
+                            },
+                        Some("cd") =>
+                            if let Some(dir) = words.next() {
+                                self.cd(&dir);
+                            } else {
+                                println!("{}", self.ctx.root().display());
+                            },
                         Some("sleep") => {
                             if let Some(ms_str) = words.next() {
                                 if let Ok(ms) = ms_str.parse::<u64>() {
@@ -86,7 +139,7 @@ guilty!{
                         Some("start") => {
                             while let Some(dev) = words.next() {
                                 let mut split = dev.splitn(2, '/');
-                                self.start(split.next().unwrap(), split.next());
+                                self.start(split.next().unwrap(), split.next(), &self.ctx);
                             }
                         },
                         Some("stop") => {
@@ -94,9 +147,14 @@ guilty!{
                                 self.stop(&dev);
                             }
                         },
+                        Some("config") => {
+                            self.config();
+                        },
                         Some("status") => {
-                            println!("parked: {:?}", teensy::ParkState::metermaid());
+                            println!("parked: {:?}", teensy::park::current());
                             println!("scribe: {:?}", scribe::COUNT.load(Ordering::SeqCst));
+                            self.scribe();
+                            self.health();
                         },
                         Some("quit") => {
                             self.tx.send(CmdFrom::Quit).unwrap();
@@ -110,8 +168,18 @@ guilty!{
                         Some("poweroff") => {
                             self.tx.send(CmdFrom::Power(Power::PowerOff)).unwrap();
                         }
-                        Some("data") => {
-                            self.tx.send(CmdFrom::Data(words.collect::<Vec<_>>().join(" "))).unwrap();
+                        Some("to") => {
+                            if let Some(svc) = words.next() {
+                                self.send(&svc, &words.collect::<Vec<_>>().join(" "));
+                            } else {
+                                errorln!("No service (to <service> <command>)");
+                            }
+                        },
+                        Some("set") => {
+                            match (words.next(), words.next()) {
+                                (Some(var), Some(val)) => self.tx.send(CmdFrom::Set(var, val)).unwrap(),
+                                _ => errorln!("Missing variable or value (set <variable> <value>)"),
+                            }
                         },
                         _ => println!("Unknown command!")
                     }
@@ -126,15 +194,70 @@ guilty!{
 }
 
 impl CLI {
-    fn start(&self, dev: &str, data: Option<&str>) {
-        if !rpc!(self.tx, CmdFrom::Start, dev.to_owned(), data.map(|s| s.to_owned())).unwrap() {
-            errorln!("Failed to start {}", dev);
+    fn start(&self, dev: &str, data: Option<&str>, ctx: &RecordingContext) {
+        match comms::start(&self.tx, dev, data.map(|s| s.to_owned()), ctx.clone(), Duration::from_millis(self.config.services.lifecycle_timeout_ms)) {
+            Ok(Status::Running) => println!("Started {}", dev),
+            Ok(status) => errorln!("Failed to start {} ({})", dev, status),
+            Err(e) => errorln!("Failed to start {}: {}", dev, e),
+        }
+    }
+
+    /// Print the configuration, and where it came from
+    fn config(&self) {
+        println!("# from {}", self.config.sources.join(", then "));
+        print!("{}", self.config.to_toml());
+    }
+
+    /// Change where services started from the CLI record
+    fn cd(&mut self, dir: &str) {
+        let root = self.ctx.path(dir);
+        if root.is_dir() {
+            self.ctx = RecordingContext::new(root);
+        } else {
+            errorln!("No such directory {:?}", root);
         }
     }
 
     fn stop(&self, dev: &str) {
-        if !rpc!(self.tx, CmdFrom::Stop, dev.to_owned()).unwrap() {
-            errorln!("Failed to stop {}", dev);
+        match comms::stop(&self.tx, dev, Duration::from_millis(self.config.services.lifecycle_timeout_ms)) {
+            Ok(Status::Stopped) => println!("Stopped {}", dev),
+            Ok(status) => errorln!("Failed to stop {} ({})", dev, status),
+            Err(e) => errorln!("Failed to stop {}: {}", dev, e),
+        }
+    }
+
+    fn health(&self) {
+        for report in rpc!(self.tx, CmdFrom::Health).unwrap() {
+            let rate = match (report.rate, report.expected_rate) {
+                (Some(rate), Some(expected)) => format!("{:.1}/{:.1} Hz", rate, expected),
+                (Some(rate), None)           => format!("{:.1} Hz", rate),
+                (None, _)                    => String::new(),
+            };
+            println!("{:>10}: {:24} restarts: {}  {}", report.name, report.state.to_string(), report.restarts, rate);
+            if let Some(why) = report.last_panic {
+                println!("{:>10}  last panic: {}", "", why);
+            }
+            if !report.stats.is_empty() {
+                println!("{:>10}  {}", "", report.stats.iter().map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<_>>().join("  "));
+            }
+        }
+    }
+
+    fn scribe(&self) {
+        for stats in scribe::stats() {
+            println!("{:>24}: {}/{} queued ({:?}, max {})  {} written ({:.0} kB/s)  {} dropped  latency {:.1}/{:.1} ms",
+                     stats.name, stats.depth, stats.capacity, stats.policy, stats.max_depth,
+                     stats.written, stats.throughput / 1000.0, stats.dropped,
+                     stats.mean_latency_ms, stats.max_latency_ms);
+            if let Some(why) = stats.stopped {
+                println!("{:>24}  stopped: {}", "", why);
+            }
+        }
+    }
+
+    fn send(&self, dev: &str, cmd: &str) {
+        if let Err(e) = rpc!(self.tx, CmdFrom::Send, dev.to_owned(), cmd.to_owned()).unwrap() {
+            errorln!("{} rejected {:?}: {}", dev, cmd, e);
         }
     }
 
@@ -143,127 +266,126 @@ impl CLI {
     }
 
     fn episode(&self, surface: &str, sec: u64) {
-        match teensy::ParkState::metermaid() {
+        match teensy::park::current() {
             None => errorln!("Failed to read end-effector state"),
             Some(teensy::ParkState::None) => errorln!("No end-effector"),
             Some(teensy::ParkState::Multiple) => errorln!("Multiple end-effectors"),
-            Some(endeff) =>
-                if let Ok(_) = env::set_current_dir("data") {
-                    let datedir = chrono::Local::today().format("%Y%m%d").to_string();
-                    loop {
-                        if let Ok(_) = env::set_current_dir(&datedir) {
-                            let mut epnum = 1;
-                            for entry in fs::read_dir(".").expect("list episode dir") {
-                                if let Ok(entry) = entry {
-                                    if let Ok(typ) = entry.file_type() {
-                                        if typ.is_dir() {
-                                            if let Ok(name) = entry.file_name().into_string() {
-                                                if name.starts_with(surface) && name.ends_with(endeff.short()) {
-                                                    let a = surface.len();
-                                                    let b = name.len() - endeff.short().len();
-                                                    let num = name[a..b].parse::<u64>().unwrap() + 1;
-                                                    if num > epnum {
-                                                        epnum = num;
-                                                    }
-                                                }
-                                            }
+            Some(endeff) => {
+                let datadir = self.ctx.path("data");
+                if !datadir.is_dir() {
+                    errorln!("No data directory");
+                    return;
+                }
+
+                let datedir = datadir.join(chrono::Local::today().format("%Y%m%d").to_string());
+                if let Err(_) = fs::create_dir_all(&datedir) {
+                    errorln!("Failed to create date directory");
+                    return;
+                }
+
+                let mut epnum = 1;
+                for entry in fs::read_dir(&datedir).expect("list episode dir") {
+                    if let Ok(entry) = entry {
+                        if let Ok(typ) = entry.file_type() {
+                            if typ.is_dir() {
+                                if let Ok(name) = entry.file_name().into_string() {
+                                    if name.starts_with(surface) && name.ends_with(endeff.short()) {
+                                        let a = surface.len();
+                                        let b = name.len() - endeff.short().len();
+                                        let num = name[a..b].parse::<u64>().unwrap() + 1;
+                                        if num > epnum {
+                                            epnum = num;
                                         }
                                     }
                                 }
                             }
-                            let epdir = format!("{}{}{}", surface, epnum, endeff.short());
-                            if let Ok(_) = fs::create_dir(&epdir) {
-                                if let Ok(_) = env::set_current_dir(&epdir) {
-                                    match endeff {
-                                        teensy::ParkState::OptoForce => self.start("optoforce", None),
-                                        teensy::ParkState::BioTac    => self.start("biotac", None),
-                                        teensy::ParkState::Stick     => {},
-                                        _ => unreachable!() // checked above
-                                    }
-                                    self.start("teensy", None);
-                                    self.sleep(sec * 1000);
-                                    self.stop("teensy");
-                                    match endeff { // TODO RAII
-                                        teensy::ParkState::OptoForce => self.stop("optoforce"),
-                                        teensy::ParkState::BioTac    => self.stop("biotac"),
-                                        teensy::ParkState::Stick     => {},
-                                        _ => unreachable!() // checked above
-                                    }
-
-                                    env::set_current_dir("..").expect("leave episode dir");
-                                    println!("Success!");
-                                } else {
-                                    errorln!("Failed to enter episode directory");
-                                }
-                            } else {
-                                errorln!("Failed to create episode directory");
-                            }
-
-                            env::set_current_dir("../..").expect("leave episode dir");
-                            break;
-                        } else if let Ok(_) = fs::create_dir(&datedir) {
-                            continue;
-                        } else {
-                            errorln!("Failed to create/enter date directory");
-                            break;
                         }
                     }
-                } else {
-                    errorln!("No data directory");
                 }
-        }
-    }
-
-    fn flow(&self, name: Option<&str>) {
-        #[derive(Clone)] struct CLIComms;
-        impl Comms for CLIComms {
-            type Error = io::Error;
-
-            fn print(&self, _: String) -> Result<(), Self::Error> {
-                /* quiet */
-                Ok(())
-            }
-
-            fn send(&self, msg: String) -> Result<(), Self::Error> {
-                println!("\t{} ", &msg[4..]);
-                Ok(())
-            }
-
-            fn rpc<T, F: Fn(String) -> Result<T, String>>(&self, prompt: String, validator: F) -> Result<Option<T>, Self::Error> {
-                loop {
-                    print!("\t{}: ", &prompt[7..]);
-                    io::stdout().flush()?;
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input)?;
-                    match validator(input.clone()) {
-                        Ok(ret) => return Ok(Some(ret)),
-                        Err(msg) => println!("\t{}", &msg[7..])
+                let epdir = datedir.join(format!("{}{}{}", surface, epnum, endeff.short()));
+                if let Ok(_) = fs::create_dir(&epdir) {
+                    let ctx = RecordingContext::new(&epdir);
+                    match endeff {
+                        teensy::ParkState::OptoForce => self.start("optoforce", None, &ctx),
+                        teensy::ParkState::BioTac    => self.start("biotac", None, &ctx),
+                        teensy::ParkState::Stick     => {},
+                        _ => unreachable!() // checked above
+                    }
+                    self.start("teensy", None, &ctx);
+                    self.sleep(sec * 1000);
+                    self.stop("teensy");
+                    match endeff { // TODO RAII
+                        teensy::ParkState::OptoForce => self.stop("optoforce"),
+                        teensy::ParkState::BioTac    => self.stop("biotac"),
+                        teensy::ParkState::Stick     => {},
+                        _ => unreachable!() // checked above
                     }
+
+                    println!("Success!");
+                } else {
+                    errorln!("Failed to create episode directory");
                 }
             }
         }
+    }
 
+    fn flow(&self, name: Option<&str>) {
         if let Some(name) = name {
             let mut locked_flows = FLOWS.write().unwrap();
             if let Some(found) = locked_flows.get_mut(&*name) {
                 println!("Starting {}!", found.name);
-                loop {
-                    print!("\t(press enter to continue) ");
-                    io::stdout().flush().unwrap();
-                    io::stdin().read_line(&mut String::new()).unwrap();
-                    match found.run(ParkState::None, &self.tx, CLIComms) {
-                        Ok(flow::EventContour::Finishing) => break,
-                        Ok(_) => continue,
-                        Err(e) => { println!("Error while running flow: {:?}", e); break; }
-                    }
-                }
+                self.run_flow(found);
             } else {
                 println!("\tERROR: flow \"{}\" not found!", name);
             }
         } else {
             let locked_flows = FLOWS.read().unwrap();
             for (_, flow) in locked_flows.iter() {
-                println!("\t{} => {}", flow.shortname, flow.name);
+                if flow.is_interrupted() {
+                    println!("\t{} => {} (interrupted: resume or abort it)", flow.shortname, flow.name);
+                } else {
+                    println!("\t{} => {}", flow.shortname, flow.name);
+                }
+            }
+        }
+    }
+
+    fn resume(&self, name: &str) {
+        let mut locked_flows = FLOWS.write().unwrap();
+        if let Some(found) = locked_flows.get_mut(name) {
+            match found.resume(&self.tx, CLIComms) {
+                Ok(()) => {
+                    println!("Resuming {}!", found.name);
+                    self.run_flow(found);
+                }
+                Err(e) => println!("Error while resuming flow: {}", e),
+            }
+        } else {
+            println!("\tERROR: flow \"{}\" not found!", name);
+        }
+    }
+
+    fn abort(&self, name: &str) {
+        let mut locked_flows = FLOWS.write().unwrap();
+        if let Some(found) = locked_flows.get_mut(name) {
+            if let Err(e) = found.abort(&self.tx, CLIComms) {
+                println!("Error while aborting flow: {}", e);
+            }
+        } else {
+            println!("\tERROR: flow \"{}\" not found!", name);
+        }
+    }
+
+    /// Step through a flow, waiting for the enter key between states
+    fn run_flow(&self, flow: &mut Flow) {
+        loop {
+            print!("\t(press enter to continue) ");
+            io::stdout().flush().unwrap();
+            io::stdin().read_line(&mut String::new()).unwrap();
+            match flow.run(ParkState::None, &self.tx, CLIComms) {
+                Ok(flow::EventContour::Finishing) => break,
+                Ok(_) => continue,
+                Err(e) => { println!("Error while running flow: {:?}", e); break; }
             }
         }
     }
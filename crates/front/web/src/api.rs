@@ -0,0 +1,362 @@
+//! Versioned JSON API for scripts (mounted at `/api/v1/`)
+//!
+//! Everything here can also be done from the HTML interface. Requests with a body take a JSON
+//! object (an empty body counts as `{}`), and every response is JSON. Errors look like
+//! `{"error": {"status": 404, "message": "..."}}`. The API is described by `openapi.json`, which is
+//! served at `/api/v1/openapi.json` -- keep it in sync with the routes in `router`.
+//!
+//! Apart from the description and `/login`, requests need a session token (see `auth`) in an
+//! `Authorization: Bearer` header. Changing anything takes an operator.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::sync::{Mutex, mpsc};
+use std::time::Duration;
+use iron::prelude::*;
+use iron::status::{self, Status as HttpStatus};
+use iron::middleware::Handler;
+use iron::modifiers::Header;
+use hyper::header::ContentType;
+use router::Router;
+use urlencoded::UrlEncodedQuery;
+use url::percent_encoding::percent_decode;
+use serde_json::{self, Value as JsonValue};
+use comms::{self, CmdFrom, Status};
+use flow::{self, FLOWS};
+use flow::manifest::{self, Manifest};
+use teensy::park;
+use utils::{self, config, RecordingContext};
+
+use super::{ws, flow_action, flows_json, SESSIONS, Error, ErrorKind};
+use super::auth::{self, Format, Role};
+
+/// The description of this API
+const OPENAPI: &'static str = include_str!("../openapi.json");
+
+/// Build the router for everything under `/api/v1/`
+pub fn router(tx: mpsc::Sender<CmdFrom>) -> Router {
+    let mut router = Router::new();
+    router.get("/openapi.json", openapi(), "api_openapi");
+    router.post("/login", login(), "api_login");
+    router.post("/logout", logout(), "api_logout");
+    router.get("/status", overview(tx.clone()), "api_status");
+    router.get("/config", auth::require(Role::Operator, Format::Json, configuration()), "api_config");
+    router.get("/services", services(tx.clone()), "api_services");
+    router.get("/services/:service", services(tx.clone()), "api_service");
+    router.post("/services/:service/:action", auth::require(Role::Operator, Format::Json, service_action(tx.clone())), "api_service_action");
+    router.get("/flows", flows(), "api_flows");
+    router.get("/flows/:flow", flows(), "api_flow");
+    router.post("/flows/:flow/:action", auth::require(Role::Operator, Format::Json, flow_action_json(tx.clone())), "api_flow_action");
+    router.get("/episodes", episodes(), "api_episodes");
+    router.get("/episodes/:date/:flow/:num", episode(), "api_episode");
+    router
+}
+
+/// JSON response
+fn reply(status: HttpStatus, value: JsonValue) -> Response {
+    let mut resp = Response::with((status, value.to_string()));
+    resp.set_mut(Header(ContentType::json()));
+    resp
+}
+
+/// JSON error response
+pub fn error<S: Into<String>>(status: HttpStatus, message: S) -> Response {
+    reply(status, json!({ "error": { "status": status.to_u16(), "message": message.into() } }))
+}
+
+/// A URL parameter (e.g. `:service`)
+fn param(req: &Request, name: &str) -> String {
+    let raw = req.extensions.get::<Router>().unwrap().find(name).unwrap();
+    percent_decode(raw.as_bytes()).decode_utf8_lossy().to_string()
+}
+
+/// Whether a (decoded) URL segment names one entry of a directory, rather than a path of its own
+fn plain_component(segment: &str) -> bool {
+    if segment.contains('/') || segment.contains('\\') {
+        return false;
+    }
+    let mut components = Path::new(segment).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name == segment,
+        _ => false,
+    }
+}
+
+/// An optional query parameter (e.g. `?flow=...`)
+fn query(req: &mut Request, name: &str) -> Option<String> {
+    req.get_ref::<UrlEncodedQuery>().ok().and_then(|q| q.get(name)).map(|v| v[0].clone())
+}
+
+/// The request body, which must be a JSON object (or empty)
+fn body(req: &mut Request) -> Result<JsonValue, Response> {
+    let mut text = String::new();
+    req.body.by_ref().take(config::get().web.request_size).read_to_string(&mut text)
+        .map_err(|e| error(status::BadRequest, format!("Could not read request body: {}", e)))?;
+    if text.trim().is_empty() {
+        return Ok(json!({}));
+    }
+    match serde_json::from_str::<JsonValue>(&text) {
+        Ok(value @ JsonValue::Object(_)) => Ok(value),
+        Ok(_) => Err(error(status::BadRequest, "Request body must be a JSON object")),
+        Err(e) => Err(error(status::BadRequest, format!("Request body is not valid JSON: {}", e))),
+    }
+}
+
+/// An optional string field of the request body
+fn field(body: &JsonValue, name: &str) -> Result<Option<String>, Response> {
+    match body.get(name) {
+        None | Some(&JsonValue::Null) => Ok(None),
+        Some(&JsonValue::String(ref s)) => Ok(Some(s.clone())),
+        Some(_) => Err(error(status::BadRequest, format!("\"{}\" must be a string", name))),
+    }
+}
+
+macro_rules! try_reply {
+    ($e:expr) => {
+        match $e {
+            Ok(x) => x,
+            Err(resp) => return Ok(resp),
+        }
+    }
+}
+
+/// Handler for the OpenAPI description
+fn openapi() -> Box<Handler> {
+    Box::new(|_: &mut Request| -> IronResult<Response> {
+        let mut resp = Response::with((status::Ok, OPENAPI));
+        resp.set_mut(Header(ContentType::json()));
+        Ok(resp)
+    })
+}
+
+/// Handler for logging in, which returns a session token
+fn login() -> Box<Handler> {
+    Box::new(|req: &mut Request| -> IronResult<Response> {
+        let body = try_reply!(body(req));
+        let user = try_reply!(field(&body, "user")).unwrap_or_default();
+        let password = try_reply!(field(&body, "password")).unwrap_or_default();
+        Ok(match auth::login(&user, &password) {
+            Ok((token, login)) => reply(status::Ok, json!({ "token": token.to_string(), "user": login.user, "role": login.role })),
+            Err(Error(ErrorKind::BadLogin, ..)) => error(status::Unauthorized, "Wrong user name or password"),
+            Err(e) => error(status::InternalServerError, format!("Could not log in: {}", e)),
+        })
+    })
+}
+
+/// Handler for logging out (the session token stops working)
+fn logout() -> Box<Handler> {
+    Box::new(|req: &mut Request| -> IronResult<Response> {
+        if let Some(token) = auth::token(&req.headers) {
+            auth::logout(&token);
+        }
+        Ok(reply(status::Ok, json!({})))
+    })
+}
+
+/// Handler for an overview of the rig
+fn overview(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
+    let mtx = Mutex::new(tx);
+    Box::new(move |_: &mut Request| -> IronResult<Response> {
+        let reports = try_reply!(rpc!(mtx.lock().unwrap(), CmdFrom::Health)
+                                     .map_err(|_| error(status::InternalServerError, "Failed to get health table")));
+        let datadir = flow::DATADIR.read().unwrap().clone();
+        let running = FLOWS.read().unwrap().values().filter(|f| f.is_active()).map(|f| f.shortname.clone()).collect::<Vec<_>>();
+        Ok(reply(status::Ok, json!({
+            "server": ws::SERVER_ID.to_string(),
+            "datadir": datadir,
+            "diskfree": utils::df(&utils::original_dir().join(&datadir)).ok(),
+            "endeffector": park::current(),
+            "clients": SESSIONS.lock().unwrap().connected().len(),
+            "services": reports,
+            "flows": running,
+        })))
+    })
+}
+
+/// Handler for the settings the rig is running with
+fn configuration() -> Box<Handler> {
+    Box::new(|_: &mut Request| -> IronResult<Response> {
+        let config = config::get();
+        Ok(reply(status::Ok, json!({ "sources": config.sources, "settings": *config })))
+    })
+}
+
+/// Handler for the health of all services, or one (`:service`)
+fn services(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
+    let mtx = Mutex::new(tx);
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+        let reports = try_reply!(rpc!(mtx.lock().unwrap(), CmdFrom::Health)
+                                     .map_err(|_| error(status::InternalServerError, "Failed to get health table")));
+        Ok(match req.extensions.get::<Router>().unwrap().find("service") {
+            Some(_) => {
+                let service = param(req, "service");
+                match reports.into_iter().find(|r| r.name == service) {
+                    Some(report) => reply(status::Ok, json!(report)),
+                    None => error(status::NotFound, format!("No service called \"{}\"", service)),
+                }
+            }
+            None => reply(status::Ok, json!(reports)),
+        })
+    })
+}
+
+/// Handler for starting, stopping or sending a command to a service
+///
+/// Start takes an optional `param`, and send takes a `command` (e.g. "disk start").
+fn service_action(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
+    let mtx = Mutex::new(tx);
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+        let service = param(req, "service");
+        let action = param(req, "action");
+        let body = try_reply!(body(req));
+
+        // don't hold the lock while waiting for the service
+        let tx = mtx.lock().unwrap().clone();
+        let timeout = Duration::from_millis(config::get().services.lifecycle_timeout_ms);
+
+        let outcome = |expected: Status, outcome: comms::Result<Status>| match outcome {
+            Ok(ref state) if *state == expected => reply(status::Ok, json!({ "service": service, "status": state })),
+            Ok(state) => error(status::InternalServerError, format!("{} is {}", service, state)),
+            Err(e) => error(status::GatewayTimeout, format!("{} did not respond: {}", service, e)),
+        };
+
+        Ok(match &*action {
+            "start" => {
+                let param = try_reply!(field(&body, "param"));
+                outcome(Status::Running, comms::start(&tx, &*service, param, RecordingContext::default(), timeout))
+            }
+            "stop" => outcome(Status::Stopped, comms::stop(&tx, &*service, timeout)),
+            "send" => {
+                let cmd = match try_reply!(field(&body, "command")) {
+                    Some(cmd) => cmd,
+                    None => return Ok(error(status::BadRequest, "Missing \"command\"")),
+                };
+                match rpc!(tx, CmdFrom::Send, service.clone(), cmd) {
+                    Ok(Ok(())) => reply(status::Ok, json!({ "service": service })),
+                    Ok(Err(e)) => error(status::BadRequest, e),
+                    Err(_) => error(status::InternalServerError, format!("Failed to reach {}", service)),
+                }
+            }
+            _ => error(status::NotFound, format!("What does {} mean?", action)),
+        })
+    })
+}
+
+/// Handler for all flows (usable with the current end effector), or one (`:flow`)
+fn flows() -> Box<Handler> {
+    Box::new(|req: &mut Request| -> IronResult<Response> {
+        let flows = flows_json(None);
+        Ok(match req.extensions.get::<Router>().unwrap().find("flow") {
+            Some(_) => {
+                let flow = param(req, "flow");
+                match flows.get(&flow) {
+                    Some(found) => reply(status::Ok, found.clone()),
+                    None => error(status::NotFound, format!("No flow called \"{}\"", flow)),
+                }
+            }
+            None => reply(status::Ok, JsonValue::Array(flows.into_iter().map(|(_, f)| f).collect())),
+        })
+    })
+}
+
+/// Handler for starting/continuing/resuming/aborting a flow
+///
+/// Flows ask the operator questions, so this needs the `wsid` of a websocket client to ask.
+fn flow_action_json(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
+    let mtx = Mutex::new(tx);
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+        let flow = param(req, "flow");
+        let action = param(req, "action");
+        let body = try_reply!(body(req));
+        let wsid = match try_reply!(field(&body, "wsid")) {
+            Some(wsid) => wsid,
+            None => return Ok(error(status::BadRequest, "Missing \"wsid\"")),
+        };
+
+        let tx = mtx.lock().unwrap().clone();
+        Ok(match flow_action(&tx, &flow, &action, &wsid) {
+            (status::Ok, message) => reply(status::Ok, json!({ "flow": flow, "message": message })),
+            (code, message) => error(code, message),
+        })
+    })
+}
+
+/// Handler for a summary of all recorded episodes (optionally filtered by `?date=` and `?flow=`)
+fn episodes() -> Box<Handler> {
+    Box::new(|req: &mut Request| -> IronResult<Response> {
+        let date = query(req, "date");
+        let flow = query(req, "flow");
+        let datadir = utils::original_dir().join(&*flow::DATADIR.read().unwrap());
+        let found = try_reply!(find_episodes(&datadir, date.as_ref().map(|s| &**s), flow.as_ref().map(|s| &**s))
+                                   .map_err(|e| error(status::InternalServerError, format!("Could not list episodes: {}", e))));
+        Ok(reply(status::Ok, JsonValue::Array(found.into_iter().map(|(path, m)| json!({
+            "path": path,
+            "flow": m.flow,
+            "shortname": m.shortname,
+            "id": m.id,
+            "started": m.started,
+            "finished": m.finished,
+            "files": m.files.len(),
+            "bytes": m.files.iter().map(|f| f.size).sum::<u64>(),
+        })).collect())))
+    })
+}
+
+/// Handler for the full manifest of one episode
+fn episode() -> Box<Handler> {
+    Box::new(|req: &mut Request| -> IronResult<Response> {
+        let segments = [param(req, "date"), param(req, "flow"), param(req, "num")];
+        let path = segments.join("/");
+        if !segments.iter().all(|s| plain_component(s)) {
+            return Ok(error(status::BadRequest, format!("Bad episode {:?}", path)));
+        }
+        let dir = utils::original_dir().join(&*flow::DATADIR.read().unwrap()).join(&path);
+        Ok(if dir.join(manifest::FILE_NAME).exists() {
+            match Manifest::load(&dir) {
+                Ok(m) => reply(status::Ok, json!(m)),
+                Err(e) => error(status::InternalServerError, format!("Could not read manifest of {}: {}", path, e)),
+            }
+        } else {
+            error(status::NotFound, format!("No finished episode {}", path))
+        })
+    })
+}
+
+/// Find finished episodes (date/flow/number directories with a manifest) in the data directory
+fn find_episodes(datadir: &Path, date: Option<&str>, flow: Option<&str>) -> io::Result<Vec<(String, Manifest)>> {
+    fn subdirs(dir: &Path, only: Option<&str>) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    if only.map_or(true, |o| o == name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names.sort_by_key(|n| (n.parse::<u64>().ok(), n.clone()));
+        Ok(names)
+    }
+
+    let mut found = vec![];
+    if !datadir.exists() {
+        return Ok(found);
+    }
+    for d in subdirs(datadir, date)? {
+        for f in subdirs(&datadir.join(&d), flow)? {
+            for n in subdirs(&datadir.join(&d).join(&f), None)? {
+                let dir = datadir.join(&d).join(&f).join(&n);
+                if dir.join(manifest::FILE_NAME).exists() {
+                    match Manifest::load(&dir) {
+                        Ok(m) => found.push((format!("{}/{}/{}", d, f, n), m)),
+                        Err(e) => println!("Skipping episode {:?}: {}", dir, e),
+                    }
+                }
+            }
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,278 @@
+//! Logins, and what each user is allowed to do
+//!
+//! Users are listed in the `auth.users_file` (maintained with `nri-passwd`) with a role and a bcrypt
+//! hash of their password. Logging in (with the form at `/login`, or `POST /api/v1/login`) hands
+//! out a session token, which browsers keep in a cookie and scripts send as an `Authorization:
+//! Bearer` header. The same token is checked on the websocket handshake. Sessions last for
+//! `auth.session_hours`, or until the server restarts.
+//!
+//! Roles are ordered: viewers can watch, operators can also run services and flows, and admins can
+//! also power off or reboot the NUC.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use bcrypt;
+use iron::prelude::*;
+use iron::status;
+use iron::middleware::{BeforeMiddleware, Handler};
+use iron::modifiers::RedirectRaw;
+use iron::typemap::Key;
+use hyper::header::{Headers, Authorization, Bearer, Cookie};
+use serde_json;
+use uuid::Uuid;
+use utils;
+
+use super::{config, Result, ErrorKind};
+
+lazy_static! {
+    /// Session tokens that have been handed out
+    static ref LOGINS: Mutex<HashMap<Uuid, Login>> = Mutex::new(HashMap::new());
+}
+
+/// What a user is allowed to do (each role can do everything the ones before it can)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Watch sensors and flows
+    Viewer,
+    /// Start and stop services, run flows, answer prompts
+    Operator,
+    /// Power off and reboot the NUC
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Role, String> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {:?} (expected viewer, operator or admin)", s)),
+        }
+    }
+}
+
+/// An entry in the users file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct User {
+    role: Role,
+    /// bcrypt hash of the password
+    hash: String,
+}
+
+/// A logged-in user
+///
+/// Requests that got past `Authenticate` carry one of these in their extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Login {
+    pub user: String,
+    pub role: Role,
+    expires: Instant,
+}
+
+impl Key for Login {
+    type Value = Login;
+}
+
+/// Where the users file is
+pub fn users_file() -> PathBuf {
+    let config = config::get();
+    config.path(&config.auth.users_file)
+}
+
+/// Read the users file (a missing file means nobody can log in)
+fn users() -> Result<BTreeMap<String, User>> {
+    let path = users_file();
+    if path.exists() {
+        Ok(serde_json::from_str(&utils::slurp(path)?)?)
+    } else {
+        Ok(BTreeMap::new())
+    }
+}
+
+fn save_users(users: &BTreeMap<String, User>) -> Result<()> {
+    serde_json::to_writer_pretty(File::create(users_file())?, users)?;
+    Ok(())
+}
+
+/// Add a user to the users file, or change an existing user's password and role
+pub fn set_user(user: &str, role: Role, password: &str) -> Result<()> {
+    let mut all = users()?;
+    all.insert(user.to_owned(), User { role: role, hash: bcrypt::hash(password, config::get().auth.bcrypt_cost)? });
+    save_users(&all)
+}
+
+/// Remove a user from the users file
+///
+/// Returns false if there was no such user.
+pub fn remove_user(user: &str) -> Result<bool> {
+    let mut all = users()?;
+    if all.remove(user).is_some() {
+        save_users(&all)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Names and roles of everyone in the users file
+pub fn list_users() -> Result<Vec<(String, Role)>> {
+    Ok(users()?.into_iter().map(|(name, u)| (name, u.role)).collect())
+}
+
+/// Check a user's password and start a session, returning its token
+pub fn login(user: &str, password: &str) -> Result<(Uuid, Login)> {
+    let role = match users()?.remove(user) {
+        Some(ref u) if bcrypt::verify(password, &u.hash)? => u.role,
+        _ => {
+            println!("Failed login as {:?}", user);
+            return Err(ErrorKind::BadLogin.into());
+        }
+    };
+
+    let token = Uuid::new_v4();
+    let login = Login {
+        user: user.to_owned(),
+        role: role,
+        expires: Instant::now() + Duration::from_secs(config::get().auth.session_hours * 60 * 60),
+    };
+    LOGINS.lock().unwrap().insert(token, login.clone());
+    println!("{} logged in ({})", user, role);
+    Ok((token, login))
+}
+
+/// End a session
+pub fn logout(token: &Uuid) {
+    if let Some(login) = LOGINS.lock().unwrap().remove(token) {
+        println!("{} logged out", login.user);
+    }
+}
+
+/// Who a session token belongs to (`None` if it is unknown or expired)
+pub fn check(token: &Uuid) -> Option<Login> {
+    let mut logins = LOGINS.lock().unwrap();
+    let now = Instant::now();
+    logins.retain(|_, login| login.expires > now);
+    logins.get(token).cloned()
+}
+
+/// Find a session token in request headers (a bearer token, or else the session cookie)
+pub fn token(headers: &Headers) -> Option<Uuid> {
+    if let Some(&Authorization(Bearer { ref token })) = headers.get::<Authorization<Bearer>>() {
+        return token.parse().ok();
+    }
+    headers.get::<Cookie>().and_then(|&Cookie(ref cookies)| {
+        cookies.iter()
+               .filter_map(|c| {
+                   let mut parts = c.splitn(2, '=');
+                   match (parts.next(), parts.next()) {
+                       (Some(name), Some(value)) if name.trim() == config::get().auth.cookie => value.trim().parse().ok(),
+                       _ => None,
+                   }
+               })
+               .next()
+    })
+}
+
+/// Value of a Set-Cookie header that stores a session token (or clears it, if `None`)
+pub fn cookie(token: Option<&Uuid>) -> String {
+    let config = config::get();
+    match token {
+        Some(token) => format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+                               config.auth.cookie, token, config.auth.session_hours * 60 * 60),
+        None => format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", config.auth.cookie),
+    }
+}
+
+/// Whether a request comes from a user with at least the given role
+pub fn allowed(req: &Request, role: Role) -> bool {
+    req.extensions.get::<Login>().map_or(false, |login| login.role >= role)
+}
+
+/// How to tell a client it is not allowed to do something
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Plain text (for the HTML interface)
+    Text,
+    /// An error object (for the JSON API, see `api`)
+    Json,
+}
+
+/// Only let requests from users with at least the given role through to a handler
+pub fn require<H: Handler>(role: Role, format: Format, handler: H) -> Box<Handler> {
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+        if allowed(req, role) {
+            handler.handle(req)
+        } else {
+            let message = format!("Only an {} can do that", role);
+            Ok(match format {
+                Format::Text => Response::with((status::Forbidden, message)),
+                Format::Json => super::api::error(status::Forbidden, message),
+            })
+        }
+    })
+}
+
+/// Error for requests that are not logged in
+#[derive(Debug)]
+struct NotLoggedIn;
+
+impl fmt::Display for NotLoggedIn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for NotLoggedIn {
+    fn description(&self) -> &str {
+        "not logged in"
+    }
+}
+
+/// Middleware that finds out who sent each request
+///
+/// Requests with a valid session are tagged with a `Login`. The rest are turned away (browsers go
+/// to the login page, and API clients get 401), unless they are for the login page itself or the
+/// static files it needs.
+pub struct Authenticate;
+
+impl Authenticate {
+    pub fn new() -> Authenticate {
+        Authenticate
+    }
+}
+
+impl BeforeMiddleware for Authenticate {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if let Some(login) = token(&req.headers).and_then(|token| check(&token)) {
+            req.extensions.insert::<Login>(login);
+            return Ok(());
+        }
+
+        let path = req.url.path();
+        match (path[0], path.get(2).cloned()) {
+            ("login", _) | ("css", _) | ("fonts", _) | ("js", _) => Ok(()),
+            ("api", Some("login")) | ("api", Some("openapi.json")) => Ok(()),
+            ("api", _) => Err(IronError { error: Box::new(NotLoggedIn), response: super::api::error(status::Unauthorized, "Log in first") }),
+            _ => Err(IronError::new(NotLoggedIn, (status::Found, RedirectRaw("/login".to_owned())))),
+        }
+    }
+}
@@ -5,6 +5,7 @@
 #[macro_use] extern crate comms;
 extern crate utils;
 extern crate teensy;
+extern crate telemetry;
 
 #[macro_use] extern crate log;
 extern crate time;
@@ -23,24 +24,27 @@ extern crate url;
 extern crate hyper;
 extern crate websocket;
 extern crate uuid;
+extern crate bcrypt;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde_json;
 
 use std::collections::HashMap;
+use std::io;
 use std::path::Path;
 use std::sync::{Mutex, RwLock, mpsc};
-use std::thread::JoinHandle;
-use std::str;
+use std::thread::{self, JoinHandle};
+use std::str::{self, FromStr};
 use std::sync::PoisonError;
 use std::sync::mpsc::RecvError;
 use time::Duration;
-use comms::{Controllable, CmdFrom, Power, Block};
+use comms::{Controllable, CmdFrom, Power, Block, Status};
 use teensy::ParkState;
+use teensy::park;
 use regex::Regex;
 use iron::prelude::*;
 use iron::status;
 use iron::middleware::Handler;
-use iron::modifiers::Header;
+use iron::modifiers::{Header, RedirectRaw};
 use hbs::Handlebars;
 use staticfile::Static;
 use mount::Mount;
@@ -48,7 +52,7 @@ use router::Router;
 #[allow(unused_imports)] use urlencoded::{UrlEncodedQuery, UrlEncodedBody};
 use url::percent_encoding::percent_decode;
 use hyper::server::Listening;
-use hyper::header::ContentType;
+use hyper::header::{ContentType, SetCookie};
 use hyper::mime::{Mime, TopLevel, SubLevel};
 use websocket::result::WebSocketError;
 use uuid::Uuid;
@@ -57,22 +61,48 @@ use serde_json::Value as JsonValue;
 /// parsing and running flows
 extern crate flow;
 /// configuration
-use utils::config;
+use utils::RecordingContext;
+use utils::config::{self, Config};
 /// a few little iron middlewares
 mod middleware;
 /// websocket server and utilities
 mod ws;
+/// websocket client sessions
+mod session;
+/// websocket message types
+mod protocol;
+/// versioned JSON API
+mod api;
+/// logins and roles
+pub mod auth;
 
 use self::flow::{Flow, FLOWS, Comms};
+use self::session::SESSIONS;
+use self::protocol::ToClient;
+use self::auth::{Format, Role, Login};
 
 error_chain! {
     errors {
         Poison {}
+        NoClient(id: usize) {
+            description("no such websocket client")
+            display("websocket client {} is gone", id)
+        }
+        Backlog(id: usize) {
+            description("websocket client is not keeping up")
+            display("websocket client {} is not keeping up", id)
+        }
+        BadLogin {
+            description("wrong user name or password")
+        }
     }
 
     foreign_links {
         WebSocket(WebSocketError);
         MPSCRecv(RecvError);
+        Io(io::Error);
+        Json(serde_json::Error);
+        Bcrypt(bcrypt::BcryptError);
     }
 }
 
@@ -110,7 +140,7 @@ fn relpath(path: &str) -> String {
 lazy_static! {
     static ref TEMPLATES: RwLock<Handlebars> = utils::watch(Handlebars::new(),
                                                             &TEMPLATES,
-                                                            Path::new(config::TEMPLATE_PATH),
+                                                            Path::new(&config::get().web.template_path),
                                                             "hbs",
                                                             |hbs, path| {
         let source = utils::slurp(&path).unwrap();
@@ -120,9 +150,9 @@ lazy_static! {
 
 fn get_flows() -> HashMap<String, Flow> {
     let flows = FLOWS.read().unwrap();
-    let eff = teensy::ParkState::metermaid().unwrap();
+    let eff = park::current();
     flows.iter().filter_map(|(n, f)| {
-        if f.endeffs.is_empty() || f.endeffs.contains(&eff) {
+        if f.endeffs.is_empty() || eff.map_or(false, |eff| f.endeffs.contains(&eff)) {
             Some((n.clone(), f.clone()))
         } else {
             None
@@ -135,6 +165,40 @@ fn render(template: &str, data: JsonValue) -> String {
     TEMPLATES.read().unwrap().render(template, &data).unwrap()
 }
 
+/// The flow list as seen by one websocket client
+///
+/// Running flows are marked with their controlling client, and whether that is somebody else.
+fn flows_json(wsid: Option<usize>) -> serde_json::Map<String, JsonValue> {
+    let controllers = SESSIONS.lock().unwrap().controllers();
+    get_flows().into_iter().map(|(name, flow)| {
+        let mut value = serde_json::to_value(&flow).unwrap();
+        if let Some(&controller) = controllers.get(&name) {
+            value["controller"] = json!(controller);
+            value["observing"] = json!(Some(controller) != wsid);
+        }
+        (name, value)
+    }).collect()
+}
+
+/// Render the flow list as seen by one websocket client
+///
+/// Running flows controlled by somebody else are shown read-only.
+fn render_flows(wsid: Option<usize>) -> String {
+    render("flows", json!({ "flows": flows_json(wsid) }))
+}
+
+/// Send the flow list and free disk space to every connected client (except `skip`)
+fn broadcast_flows(skip: Option<usize>) {
+    let ids = SESSIONS.lock().unwrap().connected();
+    for id in ids.into_iter().filter(|&id| Some(id) != skip) {
+        let flows = ToClient::Flows { html: render_flows(Some(id)) };
+        let (datadir, free) = disk_space();
+        let mut locked_sessions = SESSIONS.lock().unwrap();
+        let _ = locked_sessions.send(id, &flows.message(None));
+        let _ = locked_sessions.send(id, &ToClient::DiskFree { datadir: datadir, free: free }.message(None));
+    }
+}
+
 /// Handler for the main page of the web interface
 fn index() -> Box<Handler> {
     Box::new(move |req: &mut Request| -> IronResult<Response> {
@@ -147,7 +211,9 @@ fn index() -> Box<Handler> {
                                   Service::new("Teensy"          , "teensy"    , &render("frame_teensy", json!({ "sensor": "teensy" }))),
                           ],
                           "flows": get_flows(),
-                          "server": format!("{}:{}", req.url.host(), config::WS_PORT)
+                          "server": format!("{}:{}", req.url.host(), config::get().web.ws_port),
+                          "user": req.extensions.get::<Login>().map(|login| login.user.clone()),
+                          "role": req.extensions.get::<Login>().map(|login| login.role),
                       });
 
                       let mut resp = Response::new();
@@ -184,6 +250,54 @@ macro_rules! params {
     }
 }
 
+/// Render the login page (with an error message, if the last attempt failed)
+fn login_page(error: Option<&str>) -> Response {
+    let mut resp = Response::new();
+    resp.set_mut(render("login", json!({ "error": error })))
+        .set_mut(Header(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![]))))
+        .set_mut(if error.is_some() { status::Unauthorized } else { status::Ok });
+    resp
+}
+
+/// Handler for showing the login page
+fn login_form() -> Box<Handler> {
+    Box::new(move |_: &mut Request| -> IronResult<Response> {
+                      Ok(login_page(None))
+                  })
+}
+
+/// Handler for logging in (sets the session cookie and goes to the main page)
+fn login() -> Box<Handler> {
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+                      params!(req => [URL]
+                              [GET]
+                              [POST user, password]);
+
+                      Ok(match auth::login(&user, &password) {
+                          Ok((token, _)) => {
+                              let mut resp = Response::with((status::Found, RedirectRaw("/".to_owned())));
+                              resp.headers.set(SetCookie(vec![auth::cookie(Some(&token))]));
+                              resp
+                          }
+                          Err(Error(ErrorKind::BadLogin, ..)) => login_page(Some("Wrong user name or password")),
+                          Err(e) => login_page(Some(&format!("Could not log in: {}", e))),
+                      })
+                  })
+}
+
+/// Handler for logging out (clears the session cookie and goes to the login page)
+fn logout() -> Box<Handler> {
+    Box::new(move |req: &mut Request| -> IronResult<Response> {
+                      if let Some(token) = auth::token(&req.headers) {
+                          auth::logout(&token);
+                      }
+
+                      let mut resp = Response::with((status::Found, RedirectRaw("/login".to_owned())));
+                      resp.headers.set(SetCookie(vec![auth::cookie(None)]));
+                      Ok(resp)
+                  })
+}
+
 /// Handler for controlling the NUC itself
 fn nuc(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
     let mtx = Mutex::new(tx);
@@ -211,6 +325,9 @@ fn nuc(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
 }
 
 /// Handler for starting/stopping a service
+///
+/// Start and stop requests are answered once the service is really running (or stopped), has
+/// failed, or `services.lifecycle_timeout_ms` (see `utils::config`) has passed.
 fn control(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
     let mtx = Mutex::new(tx);
     Box::new(move |req: &mut Request| -> IronResult<Response> {
@@ -218,82 +335,141 @@ fn control(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
                               [GET cmd]
                               [POST]);
 
+                      // don't hold the lock while waiting for the service
+                      let tx = mtx.lock().unwrap().clone();
+                      let timeout = ::std::time::Duration::from_millis(config::get().services.lifecycle_timeout_ms);
+
                       Ok(match &*action {
-                              "start" => if rpc!(mtx.lock().unwrap(), CmdFrom::Start, service.to_owned(), Some(cmd)).unwrap() {
-                                  Response::with((status::Ok, format!("Started {}", service)))
-                              } else {
-                                  Response::with((status::InternalServerError, format!("Failed to start {}", service)))
-                              },
-                              "stop" => if rpc!(mtx.lock().unwrap(), CmdFrom::Stop, service.to_owned()).unwrap() {
-                                  Response::with((status::Ok, format!("Stopped {}", service)))
-                              } else {
-                                  Response::with((status::InternalServerError, format!("Failed to stop {}", service)))
+                              "start" => match comms::start(&tx, &*service, Some(cmd), RecordingContext::default(), timeout) {
+                                  Ok(Status::Running) => Response::with((status::Ok, format!("Started {}", service))),
+                                  Ok(outcome) => Response::with((status::InternalServerError, format!("Failed to start {}: {}", service, outcome))),
+                                  Err(e) => Response::with((status::GatewayTimeout, format!("Failed to start {}: {}", service, e))),
                               },
-                              "kick" => match mtx.lock().unwrap().send(CmdFrom::Data(format!("kick {}", service))) {
-                                  Ok(_) => Response::with((status::Ok, format!("Kicked {}", service))),
-                                  Err(_) => Response::with((status::InternalServerError, format!("Failed to kick {}", service))),
+                              "stop" => match comms::stop(&tx, &*service, timeout) {
+                                  Ok(Status::Stopped) => Response::with((status::Ok, format!("Stopped {}", service))),
+                                  Ok(outcome) => Response::with((status::InternalServerError, format!("Failed to stop {}: {}", service, outcome))),
+                                  Err(e) => Response::with((status::GatewayTimeout, format!("Failed to stop {}: {}", service, e))),
                               },
                               _ => Response::with((status::BadRequest, format!("What does {} mean?", action))),
                           })
                   })
 }
 
-/// Handler for starting/continuing a flow
+/// Handler for getting the supervisor's health table (as JSON)
+fn health(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
+    let mtx = Mutex::new(tx);
+    Box::new(move |_: &mut Request| -> IronResult<Response> {
+                      Ok(match rpc!(mtx.lock().unwrap(), CmdFrom::Health) {
+                          Ok(reports) => {
+                              let mut resp = Response::with((status::Ok, serde_json::to_string(&reports).unwrap()));
+                              resp.set_mut(Header(ContentType::json()));
+                              resp
+                          }
+                          Err(_) => Response::with((status::InternalServerError, "Failed to get health table")),
+                      })
+                  })
+}
+
+/// Handler for starting/continuing/resuming a flow
 fn flow(tx: mpsc::Sender<CmdFrom>) -> Box<Handler> {
     let mtx = Mutex::new(tx);
     Box::new(move |req: &mut Request| -> IronResult<Response> {
                       params!(req => [URL flow, action]
                        [GET]
                        [POST wsid]);
-                      let mut id_parts = wsid.split('_');
-                      let srvid: Uuid = id_parts.next().unwrap().parse().unwrap();
-                      let wsid = id_parts.next().unwrap().parse().unwrap();
-                      let comms = ws::Comms::new(wsid);
 
-                      if srvid != *ws::SERVER_ID {
-                          return Ok(Response::with((status::ImATeapot, "Reload first!".to_string())));
-                      }
+                      let tx = mtx.lock().unwrap().clone();
+                      Ok(Response::with(flow_action(&tx, &flow, &action, &wsid)))
+                  })
+}
 
-                      // step 1: figure out what to do, do it, and pre-construct the HTTP response
-                      let mut resp = match &*action {
-                          a @ "start" | a @ "continue" | a @ "abort" => {
-                              let mut locked_flows = FLOWS.write().unwrap();
-                              if let Some(found) = locked_flows.get_mut(&*flow) {
-                                  if a == "abort" {
-                                      found.abort(&*mtx.lock().unwrap(), comms.clone()).unwrap();
-                                      Response::with((status::Ok, format!("Aborting \"{}\" flow", flow)))
-                                  } else {
-                                      match found.run(ParkState::metermaid().unwrap(), &*mtx.lock().unwrap(), comms.clone()) {
-                                          Ok(contour) => Response::with((status::Ok, format!("{:?} \"{}\" flow", contour, flow))),
-                                          Err(e) => { println!("{:?}", e); Response::with((status::InternalServerError, "bad")) }
-                                      }
-                                  }
-                              } else {
-                                  Response::with((status::BadRequest, format!("Could not find \"{}\" flow", flow)))
-                              }
-                          }
-                          _ => Response::with((status::BadRequest, format!("What does {} mean?", action))),
-                      };
+/// Start, continue, resume or abort a flow on behalf of a websocket client
+///
+/// `wsid` is the client's ID as sent in its "hello" message. The client that starts (or resumes) a
+/// flow controls it until it finishes or is aborted, or the client goes away for good. Other
+/// clients are turned away in the meantime. Returns the HTTP status and a message for the operator.
+fn flow_action(tx: &mpsc::Sender<CmdFrom>, flow: &str, action: &str, wsid: &str) -> (status::Status, String) {
+    let mut id_parts = wsid.split('_');
+    let srvid = id_parts.next().and_then(|s| s.parse::<Uuid>().ok());
+    let wsid = match id_parts.next().and_then(|s| s.parse::<usize>().ok()) {
+        Some(wsid) => wsid,
+        None => return (status::BadRequest, format!("Bad websocket ID {:?}", wsid)),
+    };
+
+    if srvid != Some(*ws::SERVER_ID) {
+        return (status::ImATeapot, "Reload first!".to_string());
+    }
 
-                      // next send some WebSocket updates (retry logic to increase reliability)
-                      let data = json!({
-                          "flows": get_flows()
-                      });
-                      utils::retry(Some("[web] send flow info to client"), 10, Duration::milliseconds(500), || {
-                          Ok(())
-                              .and_then(|_| comms.send(format!("flow {}", render("flows", data.clone()))))
-                              .and_then(|_| comms.send(format!("diskfree {}", disk_free())))
-                      }).unwrap_or_else(|_| {
-                          // if the WebSocket communications fail, back out and abort the flow
-                          let mut locked_flows = FLOWS.write().unwrap();
-                          if let Some(found) = locked_flows.get_mut(&*flow) {
-                              found.abort(&*mtx.lock().unwrap(), comms.clone()).unwrap();
-                              resp = Response::with((status::Ok, format!("Aborting \"{}\" flow", flow)));
-                          }
-                      });
+    control_flow(tx, flow, action, wsid)
+}
 
-                      Ok(resp)
-                  })
+/// Start, continue, resume or abort a flow on behalf of websocket client `wsid` (see `flow_action`)
+fn control_flow(tx: &mpsc::Sender<CmdFrom>, flow: &str, action: &str, wsid: usize) -> (status::Status, String) {
+    let comms = ws::Comms::new(wsid);
+
+    if let Err(holder) = SESSIONS.lock().unwrap().acquire(flow, wsid) {
+        return (status::Conflict, format!("\"{}\" flow is controlled by operator {}", flow, holder));
+    }
+
+    // step 1: figure out what to do, do it, and pre-construct the HTTP response
+    let mut resp = match action {
+        a @ "start" | a @ "continue" | a @ "abort" | a @ "resume" => {
+            let mut locked_flows = FLOWS.write().unwrap();
+            if let Some(found) = locked_flows.get_mut(flow) {
+                if a == "abort" {
+                    found.abort(tx, comms.clone()).unwrap();
+                    (status::Ok, format!("Aborting \"{}\" flow", flow))
+                } else {
+                    let resumed = if a == "resume" {
+                        found.resume(tx, comms.clone())
+                    } else {
+                        Ok(())
+                    };
+                    match park::current() {
+                        Some(park) => match resumed.and_then(|()| found.run(park, tx, comms.clone())) {
+                            Ok(contour) => (status::Ok, format!("{:?} \"{}\" flow", contour, flow)),
+                            Err(e) => { println!("{:?}", e); (status::InternalServerError, "bad".to_string()) }
+                        },
+                        None => (status::ServiceUnavailable, "Could not read the parking lot".to_string()),
+                    }
+                }
+            } else {
+                (status::BadRequest, format!("Could not find \"{}\" flow", flow))
+            }
+        }
+        _ => (status::BadRequest, format!("What does {} mean?", action)),
+    };
+    release_if_idle(flow);
+
+    // next send some WebSocket updates (retry logic to increase reliability)
+    let flows = render_flows(Some(wsid));
+    utils::retry(Some("[web] send flow info to client"), 10, Duration::milliseconds(500), || {
+        Ok(())
+            .and_then(|_| comms.send(format!("flow {}", flows)))
+            .and_then(|_| comms.send(format!("diskfree {}", disk_free())))
+    }).unwrap_or_else(|_| {
+        // if the WebSocket communications fail, back out and abort the flow
+        let mut locked_flows = FLOWS.write().unwrap();
+        if let Some(found) = locked_flows.get_mut(flow) {
+            found.abort(tx, comms.clone()).unwrap();
+            resp = (status::Ok, format!("Aborting \"{}\" flow", flow));
+        }
+        drop(locked_flows);
+        release_if_idle(flow);
+    });
+
+    // everyone else gets to watch
+    broadcast_flows(Some(wsid));
+
+    resp
+}
+
+/// Give up control of a flow that is no longer running (or does not exist)
+fn release_if_idle(flow: &str) {
+    let idle = FLOWS.read().unwrap().get(flow).map_or(true, |f| !f.is_active());
+    if idle {
+        SESSIONS.lock().unwrap().release(flow);
+    }
 }
 
 trait RegexSplit {
@@ -308,13 +484,59 @@ impl RegexSplit for str {
 
 /// Measure free disk space in gigabytes
 pub fn disk_free() -> String {
-    let datadir = &*flow::DATADIR.read().unwrap();
-    format!("{} {}", datadir,
-            if let Ok(bytes) = utils::df(Path::new(datadir)) {
-                format!("{}G", bytes/1024/1024/1024)
-            } else {
-                "DNE!".into()
-            })
+    let (datadir, free) = disk_space();
+    format!("{} {}", datadir, free)
+}
+
+/// The data directory, and the free space there in gigabytes
+fn disk_space() -> (String, String) {
+    let datadir = flow::DATADIR.read().unwrap().clone();
+    let free = if let Ok(bytes) = utils::df(Path::new(&datadir)) {
+        format!("{}G", bytes/1024/1024/1024)
+    } else {
+        "DNE!".into()
+    };
+    (datadir, free)
+}
+
+/// Commands understood by the web server
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Command {
+    /// Relay a message to one websocket client (by ID), or to all of them
+    Relay(Option<usize>, ToClient),
+    /// The end-effector changed (from, if it was known, to): continue the flows that were waiting
+    /// for it
+    EndEffector(Option<ParkState>, ParkState),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Command, String> {
+        if s.starts_with("endeffector ") {
+            let parse = |w: &str| w.parse::<ParkState>().map_err(|_| format!("unknown end-effector {:?}", w));
+            let mut words = s.split_whitespace().skip(1);
+            return match (words.next(), words.next(), words.next()) {
+                (Some("unknown"), Some(to), None) => Ok(Command::EndEffector(None, parse(to)?)),
+                (Some(from), Some(to), None) => Ok(Command::EndEffector(Some(parse(from)?), parse(to)?)),
+                _ => Err(format!("{:?} should be \"endeffector <from|unknown> <to>\"", s)),
+            };
+        }
+
+        let (id, msg) = match s.find(' ') {
+            Some(i) => match s[..i].parse() {
+                Ok(id) => (Some(id), &s[i+1..]),
+                Err(_) => (None, s),
+            },
+            None => (None, s),
+        };
+
+        if msg.is_empty() {
+            Err("empty message".to_owned())
+        } else {
+            Ok(Command::Relay(id, msg.parse()?))
+        }
+    }
 }
 
 /// Controllable struct for the web server
@@ -326,58 +548,88 @@ pub struct Web {
     websocket: Option<JoinHandle<()>>,
 
     /// Private channel for sending events to WebSocket clients
-    wstx: Option<mpsc::Sender<(ws::Message<'static>, Option<usize>)>>,
+    wstx: Option<mpsc::Sender<(ToClient, Option<usize>)>>,
+
+    /// Channel to the supervisor (for flows continued by `Command::EndEffector`)
+    tx: mpsc::Sender<CmdFrom>,
 }
 
 guilty!{
     impl Controllable for Web {
         const NAME: &'static str = "web";
         const BLOCK: Block = Block::Infinite;
+        type Command = Command;
 
-        fn setup(tx: mpsc::Sender<CmdFrom>, _: Option<String>) -> Web {
+        fn setup(tx: mpsc::Sender<CmdFrom>, _: Option<String>, _: RecordingContext, config: &Config) -> ::std::result::Result<Web, String> {
             let (wstx, wsrx) = mpsc::channel();
             let ctx = tx.clone();
-            let thread = ws::spawn(ctx, wsrx);
+            let thread = ws::spawn(ctx, wsrx, config.web.ws_port);
 
             let mut router = Router::new();
             router.get("/", index(), "index");
-            router.post("/nuc/:action", nuc(tx.clone()), "nuc_action");
-            router.post("/control/:service/:action", control(tx.clone()), "service_action");
-            router.post("/flow/:flow/:action", flow(tx.clone()), "flow_action");
+            router.get("/login", login_form(), "login_form");
+            router.post("/login", login(), "login");
+            router.post("/logout", logout(), "logout");
+            router.post("/nuc/:action", auth::require(Role::Admin, Format::Text, nuc(tx.clone())), "nuc_action");
+            router.post("/control/:service/:action", auth::require(Role::Operator, Format::Text, control(tx.clone())), "service_action");
+            router.get("/health", health(tx.clone()), "health");
+            router.post("/flow/:flow/:action", auth::require(Role::Operator, Format::Text, flow(tx.clone())), "flow_action");
 
             let mut mount = Mount::new();
             for p in &["css", "fonts", "js"] {
                 mount.mount(&format!("/{}/", p),
                             Static::new(Path::new(&relpath("bootstrap")).join(p)));
             }
+            mount.mount("/api/v1/", api::router(tx.clone()));
             mount.mount("/", router);
 
             let mut chain = Chain::new(mount);
+            chain.link_before(auth::Authenticate::new());
             chain.link_after(middleware::Catchall::new());
             chain.link_after(middleware::Drain::new());
 
-            let listening = Iron::new(chain).http(("0.0.0.0", config::HTTP_PORT)).unwrap();
+            let listening = Iron::new(chain).http(("0.0.0.0", config.web.http_port)).unwrap();
 
             // make sure the watchers get started
             &*FLOWS;
             &*TEMPLATES;
 
-            Web { listening: listening, websocket: Some(thread), wstx: Some(wstx) }
+            Ok(Web { listening: listening, websocket: Some(thread), wstx: Some(wstx), tx: tx })
         }
 
-        fn step(&mut self, data: Option<String>) {
-            if let Some(d) = data {
-                let (idx, opt_id) =
-                    if let Some(id_str) = d.split(' ').next() {
-                        if let Some(id) = id_str.parse().ok() {
-                            (id_str.len()+1, Some(id))
-                        } else {
-                            (0, None)
-                        }
-                    } else {
-                        (0, None)
+        fn step(&mut self, cmd: Option<Command>) {
+            match cmd {
+                Some(Command::Relay(id, msg)) => {
+                    self.wstx.as_ref().unwrap().send((msg, id)).unwrap();
+                }
+                Some(Command::EndEffector(from, to)) => {
+                    let text = match from {
+                        Some(from) => format!("End-effector changed from {:?} to {:?}", from, to),
+                        None => format!("End-effector is {:?}", to),
                     };
-                self.wstx.as_ref().unwrap().send((ws::Message::text(d[idx..].to_owned()), opt_id)).unwrap();
+                    self.wstx.as_ref().unwrap().send((ToClient::Msg { text: text }, None)).unwrap();
+
+                    // carry on with the flows that asked for this end-effector, for whoever runs them
+                    // (off this thread, like the HTTP handlers, since it waits on the flows and clients)
+                    let tx = self.tx.clone();
+                    thread::spawn(move || {
+                        let waiting = FLOWS.read().unwrap().iter()
+                                           .filter(|&(_, f)| f.waiting_for() == Some(to))
+                                           .map(|(name, _)| name.clone())
+                                           .collect::<Vec<_>>();
+                        let controllers = SESSIONS.lock().unwrap().controllers();
+                        for name in waiting {
+                            if let Some(&wsid) = controllers.get(&name) {
+                                let (_, outcome) = control_flow(&tx, &name, "continue", wsid);
+                                println!("{} (the {:?} end-effector is in)", outcome, to);
+                            }
+                        }
+
+                        // the flow list depends on the end-effector
+                        broadcast_flows(None);
+                    });
+                }
+                None => {}
             }
         }
 
@@ -30,7 +30,7 @@ impl Drain {
     }
 
     fn drain(req: &mut Request, resp: &mut Response) {
-        io::copy(&mut req.body.by_ref().take(config::REQUEST_SIZE), &mut io::sink()).unwrap();
+        io::copy(&mut req.body.by_ref().take(config::get().web.request_size), &mut io::sink()).unwrap();
         let mut buf = [0];
         if let Ok(n) = req.body.read(&mut buf) {
             if n > 0 {
@@ -0,0 +1,188 @@
+//! Messages exchanged with websocket clients
+//!
+//! Every message, in both directions, is a JSON envelope:
+//!
+//! ```text
+//! {"type": "prompt", "id": 7, "payload": {"text": "Please enter the object name"}}
+//! ```
+//!
+//! The `id` is optional. Clients put one on requests that they want answered: the reply (`ack`,
+//! `error` or `welcome`) carries the same `id`. The server puts one on prompts, and the client's
+//! `answer` must carry it back.
+//!
+//! On connecting, the server sends `hello` with the protocol versions it speaks. The client picks
+//! one by sending its own `hello` (the server answers `welcome`) before making any other request.
+//!
+//! The handshake must carry a session token (see `auth`). Anyone logged in can watch, but
+//! `command`, `set` and `answer` take an operator.
+//!
+//! Live sensor data is not polled: a client sends `subscribe` with the telemetry channels it wants,
+//! and the server pushes `telemetry` messages with whatever is new. The server stops pushing when
+//! a client has `telemetry.window` (see `utils::config`) of those unacknowledged, so the client
+//! must send `received` after handling each one.
+//!
+//! Services still notify the web server with plain text (e.g. "status Some(stick)"); that is
+//! translated here (see the `FromStr` impl for `ToClient`).
+
+use std::str::FromStr;
+use serde_json::{self, Value as JsonValue};
+use websocket::Message;
+use telemetry::Sample;
+use super::auth::Role;
+
+/// Protocol versions this server speaks (newest last)
+pub const VERSIONS: &'static [u32] = &[1];
+
+/// What goes over the wire
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(default)]
+    payload: JsonValue,
+}
+
+/// Messages from the server to a client
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ToClient {
+    /// First message on every connection (and again after reconnecting)
+    Hello { versions: Vec<u32>, wsid: String, token: String, user: String, role: Role, datadir: String, free: String, bluefox: JsonValue },
+    /// Answer to the client's `hello`, with the version that will be used
+    Welcome { version: u32 },
+    /// A request was carried out
+    Ack,
+    /// A request (or a message that could not be understood) was rejected
+    Error { message: String },
+    /// The flow list, rendered for this client
+    Flows { html: String },
+    /// Free space in the data directory
+    #[serde(rename = "diskfree")]
+    DiskFree { datadir: String, free: String },
+    /// Something for the operator to read
+    Msg { text: String },
+    /// A question for the operator (answer with `FromClient::Answer`, using the same ID)
+    Prompt { text: String },
+    /// New samples in a subscribed telemetry channel (`missed` counts those that were dropped
+    /// because the client fell behind)
+    Telemetry { channel: String, fields: Vec<String>, samples: Vec<Sample>, missed: u64 },
+    /// A service's health changed
+    Health { service: String, state: String, description: String },
+    /// A service crashed
+    Panic { service: String, reason: String },
+    /// A service started
+    Start { service: String },
+    /// A service stopped
+    Stop { service: String },
+    /// The end effector changed (`None` if it is unknown)
+    Status { endeffector: Option<String> },
+}
+
+/// Messages from a client to the server
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum FromClient {
+    /// Protocol versions the client speaks
+    Hello { versions: Vec<u32> },
+    /// Take over a previous session (see `session::Registry::reconnect`)
+    Reconnect { token: String },
+    /// Answer to the prompt with the same ID (`None` to cancel it)
+    Answer { text: Option<String> },
+    /// Send a command to a service
+    Command { service: String, command: String },
+    /// Start receiving telemetry from some channels
+    Subscribe { channels: Vec<String> },
+    /// Stop receiving telemetry from some channels
+    Unsubscribe { channels: Vec<String> },
+    /// A `telemetry` message was handled, so send more
+    Received,
+    /// Set a supervisor variable
+    Set { var: String, value: String },
+}
+
+/// A client message that could not be understood
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejection {
+    /// ID of the message, if it got that far
+    pub id: Option<u64>,
+    pub reason: String,
+}
+
+impl ToClient {
+    /// Wrap up the message for sending (`id` is the request it answers or the prompt ID, if any)
+    pub fn message(&self, id: Option<u64>) -> Message<'static> {
+        let mut value = serde_json::to_value(self).unwrap();
+        if let Some(id) = id {
+            value["id"] = json!(id);
+        }
+        Message::text(value.to_string())
+    }
+}
+
+impl FromClient {
+    /// Unwrap a message from a client, returning it along with its ID
+    pub fn parse(text: &str) -> Result<(Option<u64>, FromClient), Rejection> {
+        let envelope = serde_json::from_str::<Envelope>(text)
+            .map_err(|e| Rejection { id: None, reason: format!("not a valid message: {}", e) })?;
+        let id = envelope.id;
+        let msg = serde_json::from_value(json!({ "type": envelope.kind, "payload": envelope.payload }))
+            .map_err(|e| Rejection { id: id, reason: format!("bad {:?} message: {}", envelope.kind, e) })?;
+        Ok((id, msg))
+    }
+}
+
+/// Pick the newest version that both sides speak
+pub fn negotiate(theirs: &[u32]) -> Option<u32> {
+    VERSIONS.iter().rev().find(|&v| theirs.contains(v)).cloned()
+}
+
+/// Translate a notification from a service
+///
+/// Understands "msg <text>", "prompt <text>", "flow <html>", "diskfree <datadir> <free>",
+/// "health <service> <state> <description>",
+/// "panic <service> <reason>", "start <service>", "stop <service>" and "status <endeffector>".
+impl FromStr for ToClient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ToClient, String> {
+        let (kind, rest) = match s.find(' ') {
+            Some(i) => (&s[..i], &s[i+1..]),
+            None => (s, ""),
+        };
+        let mut words = rest.splitn(3, ' ').map(String::from);
+        let mut word = |what: &str| match words.next() {
+            Some(ref w) if !w.is_empty() => Ok(w.clone()),
+            _ => Err(format!("{:?} is missing the {}", s, what)),
+        };
+
+        Ok(match kind {
+            "msg" => ToClient::Msg { text: rest.to_owned() },
+            "prompt" => ToClient::Prompt { text: rest.to_owned() },
+            "flow" => ToClient::Flows { html: rest.to_owned() },
+            "diskfree" => {
+                let mut parts = rest.rsplitn(2, ' ');
+                let free = parts.next().unwrap_or("").to_owned();
+                let datadir = parts.next().ok_or_else(|| format!("{:?} is missing the free space", s))?.to_owned();
+                ToClient::DiskFree { datadir: datadir, free: free }
+            }
+            "health" => ToClient::Health { service: word("service")?, state: word("state")?, description: word("description").unwrap_or_default() },
+            "panic" => {
+                let service = word("service")?;
+                let reason = rest[service.len()..].trim().to_owned();
+                ToClient::Panic { service: service, reason: reason }
+            }
+            "start" => ToClient::Start { service: word("service")? },
+            "stop" => ToClient::Stop { service: word("service")? },
+            "status" => ToClient::Status {
+                endeffector: if rest.starts_with("Some(") && rest.ends_with(')') {
+                    Some(rest[5..rest.len()-1].to_owned())
+                } else {
+                    None
+                }
+            },
+            _ => return Err(format!("unknown notification {:?}", s)),
+        })
+    }
+}
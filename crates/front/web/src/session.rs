@@ -0,0 +1,340 @@
+//! Websocket client sessions
+//!
+//! Every browser that connects gets a numeric ID (used in `wsid`s, flow controllers, etc.) and a
+//! secret reconnect token. IDs are never reused. When a browser reconnects (e.g. after a reload),
+//! it can present its token to take over its old ID, including any prompt it was being asked and
+//! any flows it was controlling, as long as the same user is logged in. A client that stays
+//! disconnected for longer than `web.ws_reconnect_grace_ms` (see `utils::config`) is forgotten.
+//!
+//! Each running flow is controlled by one client at a time. Everyone else can watch its progress
+//! but not drive it.
+//!
+//! Clients also subscribe to telemetry channels. `push_telemetry` sends each client what is new in
+//! its channels, unless it has too many telemetry messages it hasn't acknowledged yet (in which
+//! case it catches up later, possibly missing some samples).
+//!
+//! Nothing is written to a connection while `SESSIONS` is locked: each connection has a thread
+//! that sends what is queued for it, so a slow client only holds up itself. A client whose queue
+//! (`web.ws_send_queue` messages) is full misses whatever is sent to it until it drains.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::TcpStream;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use websocket::Message;
+use websocket::sync::client;
+use uuid::Uuid;
+
+use telemetry;
+
+use super::{config, Result, ErrorKind};
+use super::protocol::ToClient;
+
+lazy_static! {
+    pub static ref SESSIONS: Mutex<Registry> = Mutex::new(Registry::new());
+}
+
+/// A pending question for a client
+struct Prompt {
+    /// Sent along with the question, and expected back with the answer
+    id: u64,
+
+    /// Where the answer goes (`None` to cancel)
+    answer: mpsc::Sender<Option<String>>,
+
+    /// The question itself (so it can be asked again after a reconnect)
+    text: String,
+}
+
+/// Write half of a connection, with the thread that drains its queue
+struct Outbox {
+    queue: mpsc::SyncSender<Message<'static>>,
+}
+
+impl Outbox {
+    fn new(mut writer: client::Writer<TcpStream>) -> Outbox {
+        let (tx, rx) = mpsc::sync_channel::<Message<'static>>(config::get().web.ws_send_queue);
+        thread::spawn(move || {
+            // ends when the Outbox is dropped (and the queue is empty), or the connection breaks
+            for msg in rx {
+                if writer.send_message(&msg).is_err() {
+                    break;
+                }
+            }
+        });
+        Outbox { queue: tx }
+    }
+
+    /// Queue a message for client `id`, failing if its queue is full or the connection is broken
+    fn send(&self, id: usize, msg: &Message<'static>) -> Result<()> {
+        match self.queue.try_send(msg.clone()) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => Err(ErrorKind::Backlog(id).into()),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(ErrorKind::NoClient(id).into()),
+        }
+    }
+}
+
+/// One websocket client
+struct Client {
+    /// Secret used to reclaim this ID after a reconnect
+    token: Uuid,
+
+    /// Who is logged in on this connection
+    user: String,
+
+    /// Write half of the connection (`None` while disconnected)
+    writer: Option<Outbox>,
+
+    /// When the client disconnected (if it is disconnected)
+    gone_since: Option<Instant>,
+
+    /// Prompt the client is being asked, if any
+    prompt: Option<Prompt>,
+
+    /// Subscribed telemetry channels, with the next sample to send from each (`None` until the
+    /// first push, which sends the channel's whole history)
+    subscriptions: BTreeMap<String, Option<u64>>,
+
+    /// Telemetry messages sent but not yet acknowledged
+    unacked: usize,
+}
+
+/// All websocket clients, and the flows they control
+pub struct Registry {
+    next_id: usize,
+    next_prompt: u64,
+    clients: BTreeMap<usize, Client>,
+    /// Controlling client of each running flow (by flow shortname)
+    controllers: HashMap<String, usize>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry {
+            next_id: 0,
+            next_prompt: 0,
+            clients: BTreeMap::new(),
+            controllers: HashMap::new(),
+        }
+    }
+
+    /// Register a new connection for a logged-in user, returning its ID and reconnect token
+    pub fn connect(&mut self, writer: client::Writer<TcpStream>, user: &str) -> (usize, Uuid) {
+        let id = self.next_id;
+        let token = Uuid::new_v4();
+        self.next_id += 1;
+        self.clients.insert(id, Client {
+            token: token,
+            user: user.to_owned(),
+            writer: Some(Outbox::new(writer)),
+            gone_since: None,
+            prompt: None,
+            subscriptions: BTreeMap::new(),
+            unacked: 0,
+        });
+        (id, token)
+    }
+
+    /// Move the connection of client `id` to the disconnected client holding `token`
+    ///
+    /// Returns the old ID, or `None` if there is no such disconnected client of the same user (in
+    /// which case nothing changes). Any prompt the old client was being asked is asked again.
+    pub fn reconnect(&mut self, id: usize, token: &Uuid) -> Option<usize> {
+        let user = match self.clients.get(&id) {
+            Some(client) => client.user.clone(),
+            None => return None,
+        };
+        let old = match self.clients.iter().find(|&(_, c)| c.gone_since.is_some() && c.token == *token && c.user == user) {
+            Some((&old, _)) => old,
+            None => return None,
+        };
+        let writer = match self.clients.remove(&id) {
+            Some(Client { writer: Some(writer), .. }) => writer,
+            Some(client) => { self.clients.insert(id, client); return None; }
+            None => return None,
+        };
+
+        let client = self.clients.get_mut(&old).unwrap();
+        client.writer = Some(writer);
+        client.gone_since = None;
+        client.unacked = 0; // acks for messages sent on the old connection will never come
+        if let Some(ref prompt) = client.prompt {
+            let msg = ToClient::Prompt { text: prompt.text.clone() }.message(Some(prompt.id));
+            let _ = client.writer.as_ref().unwrap().send(old, &msg);
+        }
+        Some(old)
+    }
+
+    /// Note that client `id` has lost its connection
+    ///
+    /// The client keeps its ID, prompt and flows until `expire` is called.
+    pub fn disconnect(&mut self, id: usize) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.writer = None;
+            client.gone_since = Some(Instant::now());
+        }
+    }
+
+    /// Forget client `id` if it has been disconnected for longer than the grace period
+    ///
+    /// A prompt it was being asked is cancelled, and the flows it controlled are up for grabs.
+    /// Returns whether the client was forgotten.
+    pub fn expire(&mut self, id: usize) -> bool {
+        let grace = Duration::from_millis(config::get().web.ws_reconnect_grace_ms);
+        let expired = self.clients.get(&id).and_then(|c| c.gone_since).map_or(false, |t| t.elapsed() >= grace);
+        if expired {
+            if let Some(prompt) = self.clients.remove(&id).unwrap().prompt {
+                let _ = prompt.answer.send(None);
+            }
+            self.controllers.retain(|_, &mut c| c != id);
+        }
+        expired
+    }
+
+    /// IDs of all connected clients
+    pub fn connected(&self) -> Vec<usize> {
+        self.clients.iter().filter(|&(_, c)| c.writer.is_some()).map(|(&id, _)| id).collect()
+    }
+
+    /// Send a message to one client
+    ///
+    /// Fails if the client is unknown (or the connection is broken, or too much is queued for it).
+    /// Messages to a client that is temporarily disconnected are dropped.
+    pub fn send(&mut self, id: usize, msg: &Message<'static>) -> Result<()> {
+        match self.clients.get(&id) {
+            Some(client) => match client.writer {
+                Some(ref writer) => writer.send(id, msg),
+                None => Ok(()),
+            },
+            None => Err(ErrorKind::NoClient(id).into()),
+        }
+    }
+
+    /// Send a message to every connected client
+    pub fn broadcast(&mut self, msg: &Message<'static>) {
+        for (&id, client) in &self.clients {
+            if let Some(ref writer) = client.writer {
+                let _ = writer.send(id, msg);
+            }
+        }
+    }
+
+    /// Ask client `id` a question, with the answer going to `answer`
+    pub fn ask(&mut self, id: usize, text: String, answer: mpsc::Sender<Option<String>>) -> Result<()> {
+        let prompt = self.next_prompt;
+        self.next_prompt += 1;
+        self.send(id, &ToClient::Prompt { text: text.clone() }.message(Some(prompt)))?;
+        self.clients.get_mut(&id).unwrap().prompt = Some(Prompt { id: prompt, answer: answer, text: text });
+        Ok(())
+    }
+
+    /// Pass on client `id`'s answer to prompt `prompt` (`None` to cancel)
+    ///
+    /// Returns false if nobody is waiting for that answer.
+    pub fn answer(&mut self, id: usize, prompt: Option<u64>, answer: Option<String>) -> bool {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return false,
+        };
+        match client.prompt {
+            Some(ref p) if Some(p.id) == prompt => {}
+            _ => return false,
+        }
+        client.prompt.take().unwrap().answer.send(answer).is_ok()
+    }
+
+    /// Make client `id` the controller of a flow
+    ///
+    /// Succeeds if the flow is not controlled by another client, otherwise returns that client's ID.
+    pub fn acquire(&mut self, flow: &str, id: usize) -> ::std::result::Result<(), usize> {
+        match self.controllers.get(flow) {
+            Some(&holder) if holder != id && self.clients.contains_key(&holder) => return Err(holder),
+            _ => {}
+        }
+        self.controllers.insert(flow.to_owned(), id);
+        Ok(())
+    }
+
+    /// Let go of a flow (because it finished or was aborted)
+    pub fn release(&mut self, flow: &str) {
+        self.controllers.remove(flow);
+    }
+
+    /// Controlling client of each running flow
+    pub fn controllers(&self) -> HashMap<String, usize> {
+        self.controllers.clone()
+    }
+
+    /// Start sending telemetry from some channels to client `id`
+    pub fn subscribe(&mut self, id: usize, channels: Vec<String>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            for channel in channels {
+                client.subscriptions.entry(channel).or_insert(None);
+            }
+        }
+    }
+
+    /// Stop sending telemetry from some channels to client `id`
+    pub fn unsubscribe(&mut self, id: usize, channels: &[String]) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            for channel in channels {
+                client.subscriptions.remove(channel);
+            }
+        }
+    }
+
+    /// Note that client `id` has handled a telemetry message
+    pub fn received(&mut self, id: usize) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.unacked = client.unacked.saturating_sub(1);
+        }
+    }
+
+    /// Send every connected client what is new in the channels it subscribes to
+    ///
+    /// Clients with `telemetry.window` unacknowledged messages are skipped. They pick up
+    /// where they left off once they catch up, and are told how many samples they missed.
+    pub fn push_telemetry(&mut self) {
+        let window = config::get().telemetry.window;
+        for (&id, client) in &mut self.clients {
+            let writer = match client.writer {
+                Some(ref writer) => writer,
+                None => continue,
+            };
+
+            for (channel, next) in &mut client.subscriptions {
+                if client.unacked >= window {
+                    break;
+                }
+
+                let delta = match telemetry::since(channel, *next) {
+                    Some(delta) => delta,
+                    None => continue,
+                };
+                if delta.samples.is_empty() && delta.missed == 0 {
+                    continue;
+                }
+
+                let fields = telemetry::info(channel).map(|i| i.fields).unwrap_or_default();
+                let msg = ToClient::Telemetry {
+                    channel: channel.clone(),
+                    fields: fields,
+                    samples: delta.samples,
+                    missed: delta.missed,
+                };
+                if writer.send(id, &msg.message(None)).is_err() {
+                    break;
+                }
+                client.unacked += 1;
+                *next = Some(delta.next);
+            }
+        }
+    }
+
+    /// Close all connections
+    pub fn close_all(&mut self) {
+        self.broadcast(&Message::close());
+    }
+}
@@ -1,23 +1,29 @@
-use std::collections::HashMap;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::result::Result as StdResult;
-use std::sync::{mpsc, Mutex};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::sync::Arc;
 use std::{thread, str};
+use std::time::Duration;
 use comms::CmdFrom;
 use super::{config, flow};
-pub use websocket::{Message, OwnedMessage};
-use websocket::sync::client::{self, ClientBuilder};
+use websocket::OwnedMessage;
+use websocket::sync::client::{ClientBuilder, Reader};
 use websocket::sync::server::Server;
 use uuid::Uuid;
 
 use super::{utils, Result, Error};
+use super::session::SESSIONS;
+use super::protocol::{self, ToClient, FromClient};
+use super::auth::{self, Role, Login};
 
 lazy_static! {
     pub static ref SERVER_ID: Uuid = Uuid::new_v4();
-    pub static ref RPC_SENDERS: Mutex<HashMap<usize, mpsc::Sender<Option<String>>>> = Mutex::new(HashMap::new());
-    pub static ref WS_SENDERS:  Mutex<Vec<client::Writer<TcpStream>>> = Mutex::new(Vec::new());
 }
 
+/// Set by `ouroboros` to make the listener stop at the next connection
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
 #[derive(Clone)]
 pub struct Comms {
     wsid: usize
@@ -40,24 +46,20 @@ impl flow::Comms for Comms {
     }
 
     fn send(&self, msg: String) -> Result<()> {
-        let mut locked_senders = WS_SENDERS.lock()?;
-        if ::std::env::var("NRI_WS_FAIL").ok().map_or(false, |s| s == "1") { // FIXME remove this debugging gizmo
-            Err("NoDataAvailable".into())
-        } else {
-            Ok(locked_senders[self.wsid].send_message(&Message::text(msg))?)
-        }
+        let msg = msg.parse::<ToClient>()?;
+        let mut locked_sessions = SESSIONS.lock()?;
+        locked_sessions.send(self.wsid, &msg.message(None))
     }
 
     fn rpc<T, F: Fn(String) -> StdResult<T, String>>(&self, prompt: String, validator: F) -> Result<Option<T>> {
+        // the flow puts "prompt " in front of the prompt (and of the admonishment)
+        let unprefixed = |s: &str| s.trim_left_matches("prompt ").to_owned();
+        let prompt = unprefixed(&prompt);
+
         let go = |prompt: &str| -> Result<Option<String>> {
             let (tx, rx) = mpsc::channel();
             println!("Waiting on RPC from WSID {}", self.wsid);
-            {
-                let mut locked_senders = WS_SENDERS.lock()?;
-                let mut locked_rpcs = RPC_SENDERS.lock()?;
-                locked_rpcs.insert(self.wsid, tx);
-                locked_senders[self.wsid].send_message(&Message::text(prompt.to_owned()))?;
-            }
+            SESSIONS.lock()?.ask(self.wsid, prompt.to_owned(), tx)?;
             Ok(rx.recv()?)
         };
 
@@ -67,7 +69,7 @@ impl flow::Comms for Comms {
                 match validator(answer) {
                     Ok(ret) => return Ok(Some(ret)),
                     Err(admonish) => {
-                        maybe_answer = go(&format!("{} {}", admonish, prompt))?;
+                        maybe_answer = go(&format!("{} {}", unprefixed(&admonish), prompt))?;
                     }
                 }
             } else {
@@ -77,103 +79,85 @@ impl flow::Comms for Comms {
     }
 }
 
-pub fn spawn(ctx: mpsc::Sender<CmdFrom>, wsrx: mpsc::Receiver<(Message<'static>, Option<usize>)>) -> thread::JoinHandle<()> {
+pub fn spawn(ctx: mpsc::Sender<CmdFrom>, wsrx: mpsc::Receiver<(ToClient, Option<usize>)>, port: u16) -> thread::JoinHandle<()> {
+    SHUTDOWN.store(false, Ordering::SeqCst);
     thread::spawn(move || {
-        let ws = Server::bind(("0.0.0.0", config::WS_PORT)).unwrap();
+        let ws = Server::bind(("0.0.0.0", port)).unwrap();
 
         let mut relays = Vec::new();
 
+        let running = Arc::new(AtomicBool::new(true));
+        let pump = {
+            let running = running.clone();
+            let period = Duration::from_millis(config::get().telemetry.push_ms);
+            thread::spawn(move || {
+                // push telemetry to subscribers until the marshal shuts down
+                while running.load(Ordering::SeqCst) {
+                    SESSIONS.lock().unwrap().push_telemetry();
+                    thread::sleep(period);
+                }
+            })
+        };
+
         let marshal = thread::spawn(move || {
             // relay messages from above to all WS threads
             while let Ok((msg, id)) = wsrx.recv() {
+                let msg = msg.message(None);
                 if let Some(id) = id {
                     let tic = ::time::now();
-                    let _ = WS_SENDERS.lock().unwrap().get_mut(id).map(|s| s.send_message(&msg));
+                    let _ = SESSIONS.lock().unwrap().send(id, &msg);
                     let toc = ::time::now();
                     println!("WEB: send to {}: {}ms", id, (toc - tic).num_milliseconds());
                 } else {
-                    WS_SENDERS.lock().unwrap().iter_mut().map(|ref mut s| {
-                        let _ = s.send_message(&msg);
-                    }).count();
+                    SESSIONS.lock().unwrap().broadcast(&msg);
                 }
             }
 
             println!("web: shutting down websocket servers");
+            running.store(false, Ordering::SeqCst);
+            pump.join().unwrap();
             // kill all WS threads now
-            WS_SENDERS.lock().unwrap().iter_mut().map(|ref mut s| {
-                let _ = s.send_message(&Message::close());
-            }).count();
+            SESSIONS.lock().unwrap().close_all();
             println!("web: finished shutting down websocket servers");
         });
 
-        'listener: for request in ws.filter_map(StdResult::ok) {
-            if request.protocols().contains(&"ouroboros".to_owned()) {
-                // Shutdown signal
-                
+        'listener: for request in ws {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                // this connection was only made to wake us up (see `ouroboros`)
                 println!("Websocket listener received shutdown signal");
-                request.reject().unwrap();
+                if let Ok(request) = request {
+                    let _ = request.reject();
+                }
                 break 'listener;
-            } else if request.protocols().contains(&"rust-websocket".to_owned()) {
+            }
+
+            let request = match request {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            if request.protocols().contains(&"rust-websocket".to_owned()) {
                 // We have a protocol we want to use
 
-                let mut client = request.use_protocol("rust-websocket").accept().unwrap(); // Send the response
+                let login = match auth::token(&request.request.headers).and_then(|token| auth::check(&token)) {
+                    Some(login) => login,
+                    None => {
+                        println!("Websocket connection without a login!");
+                        let _ = request.reject();
+                        continue;
+                    }
+                };
 
-                let ip = client.peer_addr().unwrap();
-                println!("Websocket connection from {}", ip);
+                let client = request.use_protocol("rust-websocket").accept().unwrap(); // Send the response
 
-                let mut locked_senders = WS_SENDERS.lock().unwrap();
-                let wsid = locked_senders.len();
+                let ip = client.peer_addr().unwrap();
+                println!("Websocket connection from {} ({})", ip, login.user);
 
-                client.send_message(&Message::text(format!("hello {}",
-                                                           json!({
-                                                               "wsid": format!("{}_{}", *SERVER_ID, wsid),
-                                                               "diskfree": super::disk_free(),
-                                                               "bluefox": utils::in_original_dir("read bluefox settings", || utils::slurp(config::BLUEFOX_SETTINGS).unwrap()).unwrap().parse::<::serde_json::Value>().unwrap()
-                                                           })))).unwrap();
+                let (receiver, sender) = client.split().unwrap();
+                let (wsid, token) = SESSIONS.lock().unwrap().connect(sender, &login.user);
+                hello(wsid, &token, &login);
 
-                let (mut receiver, sender) = client.split().unwrap();
-                locked_senders.push(sender);
                 let cctx = ctx.clone();
-                relays.push(thread::spawn(move || {
-                    for message in receiver.incoming_messages() {
-                        let message = message.unwrap();
-
-                        match message {
-                            OwnedMessage::Close(..) => {
-                                println!("Websocket client {} disconnected", ip);
-                                return;
-                            },
-                            OwnedMessage::Text(text) => {
-                                println!("Received WS text {:?}", text);
-                                if text.starts_with("RPC") {
-                                    let space = text.find(' ').unwrap();
-                                    let mut id_parts = text[3..space].split('_');
-                                    let srvid = id_parts.next().unwrap().parse::<Uuid>().unwrap();
-                                    let wsid = id_parts.next().unwrap().parse::<usize>().unwrap();
-                                    let msg = text[space+1..].to_owned();
-
-                                    if srvid == *SERVER_ID {
-                                        let mut locked_senders = WS_SENDERS.lock().unwrap();
-                                        let locked_rpcs = RPC_SENDERS.lock().unwrap();
-                                        println!("Received RPC for WSID {}_{}: {}", srvid, wsid, msg);
-                                        if let Some(rpc) = locked_rpcs.get(&wsid) {
-                                            if msg == "ABORT" {
-                                                rpc.send(None).unwrap();
-                                            } else {
-                                                rpc.send(Some(msg)).unwrap();
-                                            }
-                                        } else {
-                                            locked_senders[wsid].send_message(&Message::text("RPC ERROR: nobody listening")).unwrap();
-                                        }
-                                    }
-                                } else {
-                                    cctx.send(CmdFrom::Data(text)).unwrap();
-                                }
-                            },
-                            _ => ()
-                        }
-                    }
-                }));
+                relays.push(thread::spawn(move || converse(cctx, receiver, ip, wsid, login)));
             } else {
                 println!("Websocket connection with no suitable protocols!");
             }
@@ -185,9 +169,137 @@ pub fn spawn(ctx: mpsc::Sender<CmdFrom>, wsrx: mpsc::Receiver<(Message<'static>,
     })
 }
 
+/// Greet a (re)connected client with its ID, reconnect token and the current state of things
+fn hello(wsid: usize, token: &Uuid, login: &Login) {
+    let bluefox = utils::slurp(config::get().path(&config::get().bluefox.settings)).ok()
+                        .and_then(|s| s.parse::<::serde_json::Value>().ok())
+                        .unwrap_or(::serde_json::Value::Null);
+    let (datadir, free) = super::disk_space();
+    let msg = ToClient::Hello {
+        versions: protocol::VERSIONS.to_vec(),
+        wsid: format!("{}_{}", *SERVER_ID, wsid),
+        token: token.to_string(),
+        user: login.user.clone(),
+        role: login.role,
+        datadir: datadir,
+        free: free,
+        bluefox: bluefox,
+    };
+    let _ = SESSIONS.lock().unwrap().send(wsid, &msg.message(None));
+
+    // rendering takes the flows lock, so don't hold the sessions lock meanwhile
+    let flows = ToClient::Flows { html: super::render_flows(Some(wsid)) };
+    let _ = SESSIONS.lock().unwrap().send(wsid, &flows.message(None));
+}
+
+/// Handle everything a client sends until it disconnects
+///
+/// Nothing but `hello` is accepted until a protocol version has been agreed on, and viewers can
+/// only watch. Messages that cannot be understood or carried out are answered with an error (they
+/// don't end the conversation).
+fn converse(ctx: mpsc::Sender<CmdFrom>, mut receiver: Reader<TcpStream>, ip: SocketAddr, mut wsid: usize, login: Login) {
+    let mut version = None;
+
+    for message in receiver.incoming_messages() {
+        let text = match message {
+            Ok(OwnedMessage::Text(text)) => text,
+            Ok(OwnedMessage::Close(..)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+
+        println!("Received WS text {:?}", text);
+        let (id, reply) = match FromClient::parse(&text) {
+            Ok((id, FromClient::Hello { versions })) => {
+                version = protocol::negotiate(&versions);
+                (id, match version {
+                    Some(v) => ToClient::Welcome { version: v },
+                    None => ToClient::Error { message: format!("no common protocol version (server speaks {:?})", protocol::VERSIONS) },
+                })
+            }
+            Ok((id, _)) if version.is_none() => {
+                (id, ToClient::Error { message: "say hello first".into() })
+            }
+            Ok((id, FromClient::Answer { .. })) |
+            Ok((id, FromClient::Command { .. })) |
+            Ok((id, FromClient::Set { .. })) if login.role < Role::Operator => {
+                (id, ToClient::Error { message: format!("{} is only a {}", login.user, login.role) })
+            }
+            Ok((id, FromClient::Reconnect { token })) => {
+                let old = token.parse::<Uuid>().ok()
+                    .and_then(|token| SESSIONS.lock().unwrap().reconnect(wsid, &token).map(|old| (old, token)));
+                if let Some((old, token)) = old {
+                    println!("Websocket client {} is back as {} (was {})", ip, old, wsid);
+                    wsid = old;
+                    hello(wsid, &token, &login);
+                    (id, ToClient::Ack)
+                } else {
+                    (id, ToClient::Error { message: "no such session to reconnect to".into() })
+                }
+            }
+            Ok((id, FromClient::Answer { text })) => {
+                println!("Received answer from WSID {} to prompt {:?}: {:?}", wsid, id, text);
+                if SESSIONS.lock().unwrap().answer(wsid, id, text) {
+                    continue;
+                } else {
+                    (id, ToClient::Error { message: "nobody is waiting for that answer".into() })
+                }
+            }
+            Ok((id, FromClient::Command { service, command })) => (id, result(deliver(&ctx, &service, &command))),
+            Ok((id, FromClient::Subscribe { channels })) => {
+                SESSIONS.lock().unwrap().subscribe(wsid, channels);
+                (id, ToClient::Ack)
+            }
+            Ok((id, FromClient::Unsubscribe { channels })) => {
+                SESSIONS.lock().unwrap().unsubscribe(wsid, &channels);
+                (id, ToClient::Ack)
+            }
+            Ok((_, FromClient::Received)) => {
+                SESSIONS.lock().unwrap().received(wsid);
+                continue;
+            }
+            Ok((id, FromClient::Set { var, value })) => {
+                (id, result(ctx.send(CmdFrom::Set(var, value)).map_err(|_| "supervisor is gone".to_owned())))
+            }
+            Err(rejection) => (rejection.id, ToClient::Error { message: rejection.reason }),
+        };
+
+        if let ToClient::Error { ref message } = reply {
+            println!("Rejected WS text {:?}: {}", text, message);
+        }
+        let _ = SESSIONS.lock().unwrap().send(wsid, &reply.message(id));
+    }
+
+    println!("Websocket client {} disconnected", ip);
+    SESSIONS.lock().unwrap().disconnect(wsid);
+
+    // give it a chance to come back before letting go of its prompt and flows
+    thread::sleep(Duration::from_millis(config::get().web.ws_reconnect_grace_ms));
+    if SESSIONS.lock().unwrap().expire(wsid) {
+        println!("Websocket client {} (ID {}) is gone for good", ip, wsid);
+        super::broadcast_flows(None);
+    }
+}
+
+/// Reply to a request that was carried out (or not)
+fn result(res: StdResult<(), String>) -> ToClient {
+    match res {
+        Ok(()) => ToClient::Ack,
+        Err(e) => ToClient::Error { message: e },
+    }
+}
+
+/// Send a command to a service and wait for it to be accepted
+fn deliver(tx: &mpsc::Sender<CmdFrom>, service: &str, cmd: &str) -> StdResult<(), String> {
+    rpc!(tx, CmdFrom::Send, service.to_owned(), cmd.trim().to_owned()).map_err(|e| e.to_string())?
+}
+
+/// Stop the websocket listener
+///
+/// The flag is set in-process, and the connection only wakes the listener up, so that nobody else
+/// can shut it down by connecting.
 pub fn ouroboros() {
-    let _ = ClientBuilder::new(&format!("ws://0.0.0.0:{}", config::WS_PORT)).unwrap()
-        .add_protocol("ouroboros")
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    let _ = ClientBuilder::new(&format!("ws://127.0.0.1:{}", config::get().web.ws_port)).unwrap()
         .connect_insecure();
 }
 
@@ -1,8 +1,437 @@
-pub const HTTP_PORT        : u16          = 3000                                          ;
-pub const WS_PORT          : u16          = 3001                                          ;
-pub const TEMPLATE_PATH    : &'static str = "crates/front/web/templates"                  ;
-pub const FLOW_PATH        : &'static str = "crates/front/web/flows"                      ;
-pub const REQUEST_SIZE     : u64          = 1024 * 1024                                   ;
-pub const DATADIR          : &'static str = "/mnt/ssd/data"                               ;
-pub const BLUEFOX_SETTINGS : &'static str = "crates/drivers/bluefox/camera_settings.json" ;
+//! Runtime configuration
+//!
+//! Settings are layered: the compiled-in defaults (`defaults.toml`, which lists every setting),
+//! then `/etc/nri/config.toml`, then the per-rig file (`nri.toml` in the original directory, or
+//! wherever `$NRI_CONFIG` points), then environment variables named `NRI_<SECTION>_<SETTING>`
+//! (e.g. `NRI_WEB_HTTP_PORT=8080`). Missing files are skipped. Each layer only needs to mention
+//! the settings it changes. Other `NRI_` variables are ignored (with a warning), since not all of
+//! them are meant for the configuration.
+//!
+//! The supervisor calls `load()` and `set()` at startup, and services receive the configuration in
+//! `Controllable::setup`. Code that runs outside a service reads it with `get()` (which returns the
+//! defaults if nothing has been loaded, e.g. in the standalone tools).
 
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use toml::{self, Value};
+use toml::value::Table;
+
+use super::{original_dir, slurp};
+
+error_chain! {
+    foreign_links {
+        Io(io::Error);
+        Toml(toml::de::Error);
+    }
+
+    errors {
+        Layer(source: String) {
+            description("bad configuration file")
+            display("bad configuration in {}", source)
+        }
+        Unknown(setting: String, source: String) {
+            description("unknown setting")
+            display("unknown setting {} (in {})", setting, source)
+        }
+        Invalid(setting: String, why: String) {
+            description("invalid setting")
+            display("invalid setting {}: {}", setting, why)
+        }
+    }
+}
+
+/// Compiled-in defaults
+const DEFAULTS: &'static str = include_str!("defaults.toml");
+
+/// Machine-wide configuration file
+pub const SYSTEM_FILE: &'static str = "/etc/nri/config.toml";
+
+/// Per-rig configuration file (relative to the original directory)
+pub const RIG_FILE: &'static str = "nri.toml";
+
+/// Environment variable that overrides `RIG_FILE`
+pub const RIG_FILE_VAR: &'static str = "NRI_CONFIG";
+
+/// Prefix of environment variables that override single settings
+const ENV_PREFIX: &'static str = "NRI_";
+
+lazy_static! {
+    static ref CURRENT: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::defaults()));
+}
+
+/// The web interface
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Web {
+    pub http_port: u16,
+    pub ws_port: u16,
+    /// How long a disconnected websocket client can take to come back before it is forgotten
+    pub ws_reconnect_grace_ms: u64,
+    /// Messages that can be waiting to go out to one websocket client
+    pub ws_send_queue: usize,
+    pub template_path: String,
+    /// Largest request body that will be read
+    pub request_size: u64,
+}
+
+/// Logins to the web interface
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Auth {
+    pub users_file: String,
+    pub cookie: String,
+    pub session_hours: u64,
+    pub bcrypt_cost: u32,
+}
+
+/// Starting and stopping services, and the supervisor's health monitor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Services {
+    /// How long a service can take to start or stop
+    pub lifecycle_timeout_ms: u64,
+    /// Consecutive restarts before giving up on a service
+    pub max_restarts: u32,
+    /// Delay before the first restart (doubled for each consecutive restart)
+    pub backoff_ms: u64,
+    /// Longest delay between restarts
+    pub max_backoff_ms: u64,
+    /// A service that runs this long without panicking is considered stable again
+    pub stable_after_ms: u64,
+    /// Whether a service that misses its setup or step deadline is restarted (otherwise it is only
+    /// reported)
+    pub restart_hung: bool,
+    /// Period over which step rates are measured
+    pub rate_window_ms: u64,
+    /// Fraction of the requested step rate below which a service is considered slow
+    pub min_rate: f64,
+}
+
+/// Flows and where they record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Flows {
+    pub path: String,
+    /// Initial data directory (it can be changed at runtime)
+    pub datadir: String,
+    /// How long a flow waits for a service to report that it is ready
+    pub ready_timeout_ms: u64,
+}
+
+/// Disk writer queues
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scribe {
+    pub queue_depth: usize,
+    pub frame_queue_depth: usize,
+    /// Writers stop (and warn) when the disk has less free space than this
+    pub min_free_bytes: u64,
+    pub disk_check_ms: u64,
+}
+
+/// Live sensor data for the web interface
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub series_hz: f64,
+    pub image_hz: f64,
+    pub history: usize,
+    pub push_ms: u64,
+    /// Unacknowledged telemetry messages a client can have before it is skipped
+    pub window: usize,
+}
+
+/// Teensy (proton pack board)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Teensy {
+    pub port: String,
+    /// Directory of ATI calibration files and their profiles
+    pub calibrations: String,
+    /// Calibration profile used when the start parameter and end-effector don't pick one
+    pub calibration: String,
+    /// Packets averaged by the `tare` command
+    pub tare_packets: usize,
+    /// How long the Teensy has to acknowledge a request
+    pub ack_timeout_ms: u64,
+}
+
+/// Scale and offset for each axis (x, y, z) of a sensor: the reading is `(raw - offset) * scale`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Axes {
+    pub scale: [f64; 3],
+    pub offset: [f64; 3],
+}
+
+/// Inertial sensors on the Teensy (see `teensy::imu`)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Imu {
+    /// Analog accelerometer reference selected when the Teensy starts ("int" or "ext")
+    pub reference: String,
+    /// Rate of the uniform timeline that IMU samples are resampled onto
+    pub rate_hz: f64,
+    /// Digital accelerometer (m/s^2)
+    pub acc: Axes,
+    /// Gyroscope (rad/s)
+    pub gyro: Axes,
+    /// Magnetometer (µT)
+    pub mag: Axes,
+    /// Analog accelerometers against the internal reference (m/s^2)
+    pub analog_int: Axes,
+    /// Analog accelerometers against the external reference (m/s^2)
+    pub analog_ext: Axes,
+}
+
+/// Parking lot watcher (see `teensy::park`)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Park {
+    /// How long a new reading has to hold before it counts as an end-effector change
+    pub debounce_ms: u64,
+}
+
+/// OptoForce sensor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Optoforce {
+    pub port: String,
+}
+
+/// Bluefox camera
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bluefox {
+    pub settings: String,
+}
+
+/// Structure Sensor, and the USB hub port used to power-cycle it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Structure {
+    pub uhubctl: String,
+    pub hub: String,
+    pub hub_port: u32,
+}
+
+/// Vicon ROS node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vicon {
+    /// SSH destination (`user@host`)
+    pub host: String,
+}
+
+/// The NUC itself
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Nuc {
+    pub wifi_script: String,
+}
+
+/// All settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Where the settings came from, in order (not a setting)
+    #[serde(skip)]
+    pub sources: Vec<String>,
+
+    pub web: Web,
+    pub auth: Auth,
+    pub services: Services,
+    pub flows: Flows,
+    pub scribe: Scribe,
+    pub telemetry: Telemetry,
+    pub teensy: Teensy,
+    pub imu: Imu,
+    pub park: Park,
+    pub optoforce: Optoforce,
+    pub bluefox: Bluefox,
+    pub structure: Structure,
+    pub vicon: Vicon,
+    pub nuc: Nuc,
+}
+
+impl Config {
+    /// Just the compiled-in defaults
+    pub fn defaults() -> Config {
+        let mut config: Config = defaults().try_into().expect("bad compiled-in configuration");
+        config.sources.push("defaults".to_owned());
+        config
+    }
+
+    /// Resolve a path setting (relative paths are relative to the original directory)
+    pub fn path<P: AsRef<Path>>(&self, setting: P) -> PathBuf {
+        original_dir().join(setting)
+    }
+
+    /// Check settings that parse but make no sense
+    pub fn validate(&self) -> Result<()> {
+        fn invalid<T>(setting: &str, why: &str) -> Result<T> {
+            Err(ErrorKind::Invalid(setting.to_owned(), why.to_owned()).into())
+        }
+
+        if self.web.http_port == 0 { return invalid("web.http_port", "must not be 0"); }
+        if self.web.ws_port == 0 { return invalid("web.ws_port", "must not be 0"); }
+        if self.web.http_port == self.web.ws_port {
+            return invalid("web.ws_port", "must be different from web.http_port");
+        }
+        if self.web.ws_send_queue == 0 { return invalid("web.ws_send_queue", "must not be 0"); }
+        if self.web.request_size == 0 { return invalid("web.request_size", "must not be 0"); }
+        if !self.path(&self.web.template_path).is_dir() {
+            return invalid("web.template_path", &format!("{} is not a directory", self.web.template_path));
+        }
+        if !self.path(&self.flows.path).is_dir() {
+            return invalid("flows.path", &format!("{} is not a directory", self.flows.path));
+        }
+        if self.auth.cookie.is_empty() || self.auth.cookie.contains(|c: char| c == '=' || c == ';' || c.is_whitespace()) {
+            return invalid("auth.cookie", "must be a non-empty cookie name");
+        }
+        if self.auth.session_hours == 0 { return invalid("auth.session_hours", "must not be 0"); }
+        if self.auth.bcrypt_cost < 4 || self.auth.bcrypt_cost > 31 {
+            return invalid("auth.bcrypt_cost", "must be between 4 and 31");
+        }
+        if self.services.lifecycle_timeout_ms == 0 { return invalid("services.lifecycle_timeout_ms", "must not be 0"); }
+        if self.services.backoff_ms == 0 { return invalid("services.backoff_ms", "must not be 0"); }
+        if self.services.max_backoff_ms < self.services.backoff_ms {
+            return invalid("services.max_backoff_ms", "must not be less than services.backoff_ms");
+        }
+        if self.services.rate_window_ms == 0 { return invalid("services.rate_window_ms", "must not be 0"); }
+        if !(self.services.min_rate >= 0.0 && self.services.min_rate <= 1.0) {
+            return invalid("services.min_rate", "must be between 0 and 1");
+        }
+        if self.flows.ready_timeout_ms == 0 { return invalid("flows.ready_timeout_ms", "must not be 0"); }
+        if self.scribe.queue_depth == 0 { return invalid("scribe.queue_depth", "must not be 0"); }
+        if self.scribe.frame_queue_depth == 0 { return invalid("scribe.frame_queue_depth", "must not be 0"); }
+        if self.scribe.disk_check_ms == 0 { return invalid("scribe.disk_check_ms", "must not be 0"); }
+        if !self.path(&self.teensy.calibrations).is_dir() {
+            return invalid("teensy.calibrations", &format!("{} is not a directory", self.teensy.calibrations));
+        }
+        if self.teensy.tare_packets == 0 { return invalid("teensy.tare_packets", "must not be 0"); }
+        if self.teensy.ack_timeout_ms == 0 { return invalid("teensy.ack_timeout_ms", "must not be 0"); }
+        if self.imu.reference != "int" && self.imu.reference != "ext" {
+            return invalid("imu.reference", "must be \"int\" or \"ext\"");
+        }
+        if !(self.imu.rate_hz > 0.0) { return invalid("imu.rate_hz", "must be positive"); }
+        if !(self.telemetry.series_hz > 0.0) { return invalid("telemetry.series_hz", "must be positive"); }
+        if !(self.telemetry.image_hz > 0.0) { return invalid("telemetry.image_hz", "must be positive"); }
+        if self.telemetry.history == 0 { return invalid("telemetry.history", "must not be 0"); }
+        if self.telemetry.push_ms == 0 { return invalid("telemetry.push_ms", "must not be 0"); }
+        if self.telemetry.window == 0 { return invalid("telemetry.window", "must not be 0"); }
+        Ok(())
+    }
+
+    /// The settings as TOML (the same format as the configuration files)
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("could not write configuration as TOML")
+    }
+}
+
+/// The compiled-in defaults, as a table
+fn defaults() -> Value {
+    DEFAULTS.parse().expect("bad compiled-in configuration")
+}
+
+/// Check that every setting in a layer exists in the defaults (catches typos)
+fn check_keys(defaults: &Table, layer: &Table, prefix: &str, source: &str) -> Result<()> {
+    for (key, value) in layer {
+        let name = format!("{}{}", prefix, key);
+        match (defaults.get(key), value) {
+            (Some(&Value::Table(ref d)), &Value::Table(ref l)) => check_keys(d, l, &format!("{}.", name), source)?,
+            (Some(&Value::Table(_)), _) | (None, _) => bail!(ErrorKind::Unknown(name, source.to_owned())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Apply a layer on top of the settings so far
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut b)), Value::Table(l)) => merge(b, l),
+            (Some(b), value) => *b = value,
+            (None, _) => unreachable!(),
+        }
+    }
+}
+
+/// Parse an environment variable like `NRI_TEENSY_PORT` into a one-setting layer
+///
+/// The value is taken as a string if the setting is a string, and as a TOML value otherwise.
+/// Returns `None` if the variable does not name a setting.
+fn env_layer(defaults: &Table, var: &str, raw: &str) -> Result<Option<Table>> {
+    let name = var[ENV_PREFIX.len()..].to_lowercase();
+    let mut parts = name.splitn(2, '_');
+    let (section, setting) = match (parts.next(), parts.next()) {
+        (Some(section), Some(setting)) => (section, setting),
+        _ => return Ok(None),
+    };
+    let value = match defaults.get(section).and_then(|s| s.get(setting)) {
+        Some(&Value::String(_)) => Value::String(raw.to_owned()),
+        Some(_) => {
+            let parsed = format!("value = {}", raw).parse::<Value>()
+                .chain_err(|| ErrorKind::Layer(format!("${}", var)))?;
+            parsed["value"].clone()
+        }
+        None => return Ok(None),
+    };
+
+    let mut inner = Table::new();
+    inner.insert(setting.to_owned(), value);
+    let mut layer = Table::new();
+    layer.insert(section.to_owned(), Value::Table(inner));
+    Ok(Some(layer))
+}
+
+/// Load the configuration from all layers, and validate it
+pub fn load() -> Result<Config> {
+    let defaults = match defaults() {
+        Value::Table(t) => t,
+        _ => unreachable!(),
+    };
+    let mut merged = defaults.clone();
+    let mut sources = vec!["defaults".to_owned()];
+
+    let apply = |merged: &mut Table, sources: &mut Vec<String>, layer: Table, source: String| -> Result<()> {
+        check_keys(&defaults, &layer, "", &source)?;
+        merge(merged, layer);
+        // deserialize after each layer, so type errors are blamed on the right file
+        Value::Table(merged.clone()).try_into::<Config>().chain_err(|| ErrorKind::Layer(source.clone()))?;
+        sources.push(source);
+        Ok(())
+    };
+
+    // the rig file is optional, unless someone asked for a particular one
+    let (rig, required) = match env::var(RIG_FILE_VAR) {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from(RIG_FILE), false),
+    };
+    for &(ref path, required) in &[(PathBuf::from(SYSTEM_FILE), false), (original_dir().join(rig), required)] {
+        let source = path.display().to_string();
+        if !path.exists() {
+            if required {
+                bail!(ErrorKind::Layer(format!("{} (from ${}), which does not exist", source, RIG_FILE_VAR)));
+            }
+            continue;
+        }
+
+        let text = slurp(path).chain_err(|| ErrorKind::Layer(source.clone()))?;
+        match text.parse::<Value>().chain_err(|| ErrorKind::Layer(source.clone()))? {
+            Value::Table(t) => apply(&mut merged, &mut sources, t, source)?,
+            _ => bail!(ErrorKind::Layer(source)),
+        }
+    }
+
+    let mut vars = env::vars().filter(|&(ref k, _)| k.starts_with(ENV_PREFIX) && k != RIG_FILE_VAR).collect::<Vec<_>>();
+    vars.sort();
+    for (var, raw) in vars {
+        match env_layer(&defaults, &var, &raw)? {
+            Some(layer) => apply(&mut merged, &mut sources, layer, format!("${}", var))?,
+            None => errorln!("Ignoring ${}: there is no such setting", var),
+        }
+    }
+
+    let mut config = Value::Table(merged).try_into::<Config>()?;
+    config.sources = sources;
+    config.validate()?;
+    Ok(config)
+}
+
+/// The current configuration
+pub fn get() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// Replace the current configuration (the supervisor does this once, at startup)
+pub fn set(config: Config) {
+    *CURRENT.write().unwrap() = Arc::new(config);
+}
@@ -0,0 +1,46 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref ORIGINAL_DIR: PathBuf = env::current_dir().expect("could not get current directory");
+}
+
+/// The directory the program was started in
+///
+/// Relative paths in the configuration are relative to this. Nothing in the program changes the
+/// current directory, but call this early (before any library gets a chance to) to be sure.
+pub fn original_dir() -> &'static Path {
+    &ORIGINAL_DIR
+}
+
+/// Where a service should put what it records
+///
+/// Passed to services when they are started (see `comms::CmdTo::Start`). A flow gives each service
+/// the episode directory; otherwise it is the directory the program was started in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingContext {
+    root: PathBuf,
+}
+
+impl RecordingContext {
+    /// Record into `root` (relative paths are taken relative to `original_dir()`)
+    pub fn new<P: AsRef<Path>>(root: P) -> RecordingContext {
+        RecordingContext { root: original_dir().join(root) }
+    }
+
+    /// The directory that streams are written into
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where to write the stream called `name` (e.g. `"teensy.dat"`)
+    pub fn path<S: AsRef<Path>>(&self, name: S) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+impl Default for RecordingContext {
+    fn default() -> RecordingContext {
+        RecordingContext { root: original_dir().to_owned() }
+    }
+}
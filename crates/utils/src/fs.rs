@@ -1,31 +1,14 @@
 use errno::errno;
 use libc;
 
-use std::{env, fs, io, mem, thread};
+use std::{fs, io, mem, thread};
 use std::ffi::CString;
 use std::fs::File;
 use std::io::Read;
 use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Mutex, RwLock};
-
-pub fn in_original_dir<F: FnOnce() -> R, R>(action: &str, f: F) -> io::Result<R> {
-    lazy_static! {
-        static ref ORIGINAL_DIR: PathBuf = env::current_dir().unwrap();
-        static ref CRITICAL_SECTION: Mutex<()> = Mutex::new(());
-    }
-
-    // ensure that two threads don't fight over the current dir
-    let _guard = CRITICAL_SECTION.lock().unwrap();
-
-    let before = env::current_dir()?;
-    println!("Executing {} in {} (currently in {})", action, ORIGINAL_DIR.display(), before.display());
-    env::set_current_dir(&*ORIGINAL_DIR)?;
-    let ret = f();
-    env::set_current_dir(before)?;
-    Ok(ret)
-}
+use std::sync::{mpsc, RwLock};
 
 pub fn slurp<P: AsRef<Path>>(p: P) -> io::Result<String> {
     let mut data = String::new();
@@ -4,14 +4,20 @@ extern crate time;
 extern crate notify;
 extern crate libc;
 extern crate errno;
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate serde_derive;
+extern crate serde;
+extern crate toml;
 
 #[macro_use] mod macros;
 pub mod config;
+pub_use_mod!(context);
 mod extension_traits;
 pub_use_mod!(fs);
 pub_use_mod!(iter);
 pub_use_mod!(misc);
 pub mod prof;
+pub mod replay;
 pub use prof::PROF;
 
 pub mod prelude {
@@ -0,0 +1,344 @@
+//! Recording and replaying raw driver input streams
+//!
+//! A driver that wants its input to be reproducible offline tees the raw bytes it receives from
+//! the hardware into a `Recorder`. The resulting dump is a sequence of timestamped chunks, which a
+//! `Player` later hands back (at the original pace, or faster) so that the driver's parsing code
+//! sees exactly the same stream. Camera frames are already saved (in a container, or one per file in
+//! older recordings) with a `_times.csv` index, so `Frames` replays those directly.
+//!
+//! Drivers enter replay mode when started with the parameter `replay:<path>[,<speed>]`, where
+//! `speed` is a multiplier on the recorded timing (default 1; 0 means as fast as possible).
+
+use std::{fs, io};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use time::{self, Duration};
+
+use extension_traits::*;
+use super::original_dir;
+
+/// Identifies a timestamped dump (files without it are treated as raw untimed bytes)
+const MAGIC: &'static [u8; 8] = b"NRIDUMP1";
+
+/// Chunk size used when replaying a raw (untimed) dump
+const RAW_CHUNK: usize = 4096;
+
+/// Parsed form of a `replay:<path>[,<speed>]` start parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spec {
+    pub path: PathBuf,
+    pub speed: f64,
+}
+
+impl Spec {
+    /// Extract a replay spec from a service's start parameter, if it asks for one
+    pub fn from_param(param: Option<&str>) -> Option<Spec> {
+        let rest = match param {
+            Some(s) if s.starts_with("replay:") => &s["replay:".len()..],
+            _ => return None,
+        };
+
+        let (path, speed) = match rest.rfind(',') {
+            Some(i) => match rest[i+1..].parse::<f64>() {
+                Ok(speed) if speed >= 0.0 => (&rest[..i], speed),
+                _ => (rest, 1.0),
+            },
+            None => (rest, 1.0),
+        };
+
+        Some(Spec { path: PathBuf::from(path), speed: speed })
+    }
+
+    /// The path, resolved against the directory the program was started in
+    pub fn resolve(&self) -> io::Result<PathBuf> {
+        fs::canonicalize(original_dir().join(&self.path))
+    }
+}
+
+fn put_u32(buf: &mut [u8], x: u32) {
+    for i in 0..4 { buf[i] = (x >> (8 * i)) as u8; }
+}
+
+fn put_u64(buf: &mut [u8], x: u64) {
+    for i in 0..8 { buf[i] = (x >> (8 * i)) as u8; }
+}
+
+fn get_u32(buf: &[u8]) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[i] as u32) << (8 * i))
+}
+
+fn get_u64(buf: &[u8]) -> u64 {
+    (0..8).fold(0, |acc, i| acc | (buf[i] as u64) << (8 * i))
+}
+
+/// Writes a timestamped dump of a raw input stream
+///
+/// Every call to `record` (or `write`) becomes one chunk, stamped with the time elapsed since the
+/// recorder was created.
+pub struct Recorder<W: Write> {
+    out: W,
+    start: u64,
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Create a dump file (usually at `RecordingContext::path` of the driver's context)
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Recorder::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(Recorder { out: out, start: time::precise_time_ns() })
+    }
+
+    /// Append one chunk to the dump
+    pub fn record(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let mut header = [0u8; 12];
+        put_u64(&mut header[..8], time::precise_time_ns() - self.start);
+        put_u32(&mut header[8..], chunk.len() as u32);
+        self.out.write_all(&header)?;
+        self.out.write_all(chunk)
+    }
+}
+
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Keeps replayed data on the recorded schedule
+struct Pacer {
+    speed: f64,
+    start: Option<u64>,
+}
+
+impl Pacer {
+    fn new(speed: f64) -> Pacer {
+        Pacer { speed: speed, start: None }
+    }
+
+    /// Sleep until `offset` ns (in recorded time) after the first call
+    fn wait(&mut self, offset: u64) {
+        if self.speed == 0.0 { return; }
+
+        let now = time::precise_time_ns();
+        let start = *self.start.get_or_insert(now.saturating_sub((offset as f64 / self.speed) as u64));
+        let target = start + (offset as f64 / self.speed) as u64;
+        if target > now {
+            Duration::nanoseconds((target - now) as i64).sleep();
+        }
+    }
+}
+
+/// Plays back a dump written by `Recorder` (or a raw untimed byte dump)
+///
+/// Implements `Read` (and a do-nothing `Write`), so it can stand in for the device a driver would
+/// normally talk to.
+pub struct Player {
+    input: BufReader<File>,
+    timed: bool,
+    pacer: Pacer,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl Player {
+    /// Open a dump for playback
+    pub fn open(spec: &Spec) -> io::Result<Player> {
+        let mut input = BufReader::new(File::open(spec.resolve()?)?);
+
+        let mut magic = [0u8; 8];
+        let n = read_up_to(&mut input, &mut magic)?;
+        let timed = &magic[..n] == MAGIC;
+
+        Ok(Player {
+            input: input,
+            timed: timed,
+            pacer: Pacer::new(spec.speed),
+            pending: if timed { vec![] } else { magic[..n].to_vec() },
+            pos: 0,
+        })
+    }
+
+    /// Whether the dump has timestamps (otherwise it is played back as fast as possible)
+    pub fn is_timed(&self) -> bool {
+        self.timed
+    }
+
+    /// Get the next recorded chunk, waiting until it is due
+    ///
+    /// Returns `Ok(None)` at the end of the dump.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.pos < self.pending.len() {
+            let rest = self.pending.split_off(self.pos);
+            self.pending.clear();
+            self.pos = 0;
+            return Ok(Some(rest));
+        }
+
+        if self.timed {
+            let mut header = [0u8; 12];
+            match read_up_to(&mut self.input, &mut header)? {
+                0 => return Ok(None),
+                12 => {},
+                _ => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk header")),
+            }
+
+            let mut chunk = vec![0u8; get_u32(&header[8..]) as usize];
+            self.input.read_exact(&mut chunk)?;
+            self.pacer.wait(get_u64(&header[..8]));
+            Ok(Some(chunk))
+        } else {
+            let mut chunk = vec![0u8; RAW_CHUNK];
+            match read_up_to(&mut self.input, &mut chunk)? {
+                0 => Ok(None),
+                n => { chunk.truncate(n); Ok(Some(chunk)) }
+            }
+        }
+    }
+}
+
+impl Read for Player {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            match self.next_chunk()? {
+                Some(chunk) => { self.pending = chunk; self.pos = 0; },
+                None => return Ok(0),
+            }
+        }
+
+        let n = (&self.pending[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Commands written to a replayed device go nowhere
+impl Write for Player {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+    fn flush(&mut self)             -> io::Result<()>    { Ok(())        }
+}
+
+/// Fill as much of `buf` as possible, stopping only at EOF
+fn read_up_to<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Frames stored together in one file, looked up by the index of the write that stored them (this
+/// is how `scribe::Container` is plugged in, since scribe builds on this crate)
+pub trait FrameStore: Send {
+    fn frame(&mut self, index: usize) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Where a recorded frame was saved
+enum Location {
+    /// A file of its own (older recordings)
+    File(String),
+    /// A chunk in a container
+    Chunk(String, usize),
+}
+
+/// Plays back camera frames indexed by `<name>_times.csv`
+///
+/// Each row names either the frame's own file (`<name>{}.dat`), or a container followed by the
+/// frame's chunk index in an extra last column.
+pub struct Frames {
+    dir: PathBuf,
+    rows: Vec<(usize, Location, f64)>,
+    stores: HashMap<String, Box<FrameStore>>,
+    next: usize,
+    pacer: Pacer,
+}
+
+impl Frames {
+    /// Open a recording for playback, opening any containers it uses with `open_store`
+    ///
+    /// The spec's path is either the timestamp file itself or the episode directory containing
+    /// `<name>_times.csv`.
+    pub fn open<S, F>(spec: &Spec, name: &str, open_store: F) -> io::Result<Frames>
+        where S: FrameStore + 'static, F: Fn(PathBuf) -> io::Result<S>
+    {
+        let path = spec.resolve()?;
+        let csv = if path.is_dir() { path.join(format!("{}_times.csv", name)) } else { path };
+        let dir = csv.parent().map(Path::to_owned).unwrap_or_default();
+
+        let mut rows = vec![];
+        let mut stores = HashMap::new();
+        for line in BufReader::new(File::open(&csv)?).lines() {
+            let line = line?;
+            let fields = line.trim().split(',').collect::<Vec<_>>();
+            let location = match fields.len() {
+                3 | 5 => Location::File(fields[1].to_owned()), // newer files add the monotonic stamps
+                6 => match fields[5].parse() {
+                    Ok(chunk) => Location::Chunk(fields[1].to_owned(), chunk),
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            if let Location::Chunk(ref container, _) = location {
+                if !stores.contains_key(container) {
+                    let store = open_store(dir.join(container))?;
+                    stores.insert(container.clone(), Box::new(store) as Box<FrameStore>);
+                }
+            }
+            match (fields[0].parse(), fields[2].parse()) {
+                (Ok(i), Ok(stamp)) => rows.push((i, location, stamp)),
+                _ => continue,
+            }
+        }
+
+        Ok(Frames { dir: dir, rows: rows, stores: stores, next: 0, pacer: Pacer::new(spec.speed) })
+    }
+
+    /// Number of frames in the recording
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Load the next frame (with its recorded index), waiting until it is due
+    ///
+    /// Returns `Ok(None)` after the last frame.
+    pub fn next_frame(&mut self) -> io::Result<Option<(usize, Vec<u8>)>> {
+        if self.next >= self.rows.len() { return Ok(None); }
+
+        let (i, ref location, stamp) = self.rows[self.next];
+        let offset = ((stamp - self.rows[0].2) * 1e9) as u64;
+        self.next += 1;
+
+        let data = match *location {
+            Location::File(ref file) => {
+                let mut data = vec![];
+                File::open(self.dir.join(file))?.read_to_end(&mut data)?;
+                data
+            }
+            Location::Chunk(ref container, chunk) => {
+                match self.stores.get_mut(container).unwrap().frame(chunk)? {
+                    Some(data) => data,
+                    None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                                      format!("frame {} is missing from {}", i, container))),
+                }
+            }
+        };
+        self.pacer.wait(offset);
+        Ok(Some((i, data)))
+    }
+}
@@ -1,54 +1,31 @@
-extern crate time;
+extern crate scribe;
+extern crate biotac;
 
 extern crate nri;
 
-use std::fmt;
+use scribe::Value;
 use std::iter::once;
 
-#[repr(packed)]
-struct Packet {
-    stamp: time::Timespec,
-    pdc: u32,
-    pac: [u32; 22],
-    tdc: u32,
-    tac: u32,
-    electrode: [u32; 19],
-}
-
-impl fmt::Debug for Packet {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        try!(write!(f, "{:.9}, ",
-                    self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64));
-        try!(write!(f, "{}, ",
-                    self.pdc));
-        for i in 0..22 {
-            try!(write!(f, "{}, ",
-                        self.pac[i]));
-        }
-        try!(write!(f, "{}, ",
-                    self.tdc));
-        try!(write!(f, "{}, ",
-                    self.tac));
-        for i in 0..18 {
-            try!(write!(f, "{}, ",
-                        self.electrode[i]));
-        }
-        try!(write!(f, "{}",
-                    self.electrode[18]));
-        Ok(())
-    }
-}
-
 fn main() {
-    let s: String = 
+    let s: String =
         once(String::from("Timestamp"))
         .chain(once(String::from("PDC")))
         .chain((0..22).map(|i| format!("PAC #{}", i)))
         .chain(once(String::from("TDC")))
         .chain(once(String::from("TAC")))
         .chain((0..19).map(|i| format!("Electrode #{}", i)))
+        .chain(once(String::from(nri::SYNC_HEADER)))
         .collect::<Vec<String>>()
         .join(", ");
-    nri::read_binary::<Packet>(&s);
+    nri::read_scribe(&s, biotac::packet_schema(), |p: &Value| {
+        format!("{:.9}, {}, {}, {}, {}, {}, {}",
+                p.get("stamp").and_then(Value::as_secs).unwrap(),
+                p.get("pdc").unwrap(),
+                p.get("pac").unwrap(),
+                p.get("tdc").unwrap(),
+                p.get("tac").unwrap(),
+                p.get("electrode").unwrap(),
+                nri::sync_columns(p))
+    });
 }
 
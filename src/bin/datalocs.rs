@@ -65,6 +65,7 @@ use std::io::{self, BufReader};
 use std::path::{Path, PathBuf};
 
 use flow::{Flow, FlowCmd};
+use flow::manifest::{self, Manifest};
 use utils::prelude::*;
 
 #[derive(Clone, Serialize)]
@@ -94,6 +95,20 @@ struct Answers {
     warm: Option<u8>,
 }
 
+impl Answers {
+    /// Store the experimenter's answer to one of the rating prompts
+    fn record(&mut self, prompt: &str, answer: i32) -> Result<()> {
+        match prompt {
+            "soft/hard"       => self.hard   = Some(u8(answer)?),
+            "smooth/rough"    => self.rough  = Some(u8(answer)?),
+            "slippery/sticky" => self.sticky = Some(u8(answer)?),
+            "cool/warm"       => self.warm   = Some(u8(answer)?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
 fn serialize_cropinfo<S: Serializer>(ci: &CropInfo, ser: S) -> StdResult<S::Ok, S::Error> {
     ser.serialize_str(&format!("{}-{}-{}", ci.date, ci.end_effector, ci.episode_number))
 }
@@ -363,26 +378,29 @@ quick_main!(|| -> Result<i32> {
                             };
 
                             if spec == &(source.date.to_string(), source.episode_number.to_string()) {
-                                let flowpath = Path::new(&nicknames[&loc1[..]])
-                                                    .join(&spec.0)
-                                                    .join(format!("{}cam", source.end_effector))
-                                                    .join(&spec.1)
-                                                    .join(format!("{}cam.flow", source.end_effector));
-                                let flow = Flow::parse(
-                                    String::new(),
-                                    BufReader::new(
-                                        File::open(&flowpath)
-                                             .chain_err(|| Io("open", flowpath))?))?;
+                                let epdir = Path::new(&nicknames[&loc1[..]])
+                                                 .join(&spec.0)
+                                                 .join(format!("{}cam", source.end_effector))
+                                                 .join(&spec.1);
                                 let mut exp_answers = Answers::default();
-                                for state in &flow.states {
-                                    for &(ref cmd, _) in &state.script {
-                                        if let FlowCmd::Int { ref prompt, data: Some(answer), .. } = *cmd {
-                                            match &prompt[..] {
-                                                "soft/hard"       => exp_answers.hard   = Some(u8(answer)?),
-                                                "smooth/rough"    => exp_answers.rough  = Some(u8(answer)?),
-                                                "slippery/sticky" => exp_answers.sticky = Some(u8(answer)?),
-                                                "cool/warm"       => exp_answers.warm   = Some(u8(answer)?),
-                                                _ => {}
+                                if epdir.join(manifest::FILE_NAME).exists() {
+                                    for answer in Manifest::load(&epdir)?.answers {
+                                        if let manifest::Value::Int(value) = answer.value {
+                                            exp_answers.record(&answer.prompt, value)?;
+                                        }
+                                    }
+                                } else {
+                                    // recorded before episodes had manifests, so dig the answers out of the transcript
+                                    let flowpath = epdir.join(format!("{}cam.flow", source.end_effector));
+                                    let flow = Flow::parse(
+                                        String::new(),
+                                        BufReader::new(
+                                            File::open(&flowpath)
+                                                 .chain_err(|| Io("open", flowpath))?))?;
+                                    for state in &flow.states {
+                                        for &(ref cmd, _) in &state.script {
+                                            if let FlowCmd::Int { ref prompt, data: Some(answer), .. } = *cmd {
+                                                exp_answers.record(prompt, answer)?;
                                             }
                                         }
                                     }
@@ -0,0 +1,95 @@
+#[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate guilt_by_association;
+
+extern crate comms;
+extern crate flow;
+extern crate utils;
+extern crate cli;
+extern crate web;
+extern crate teensy;
+extern crate optoforce;
+extern crate structure;
+extern crate bluefox;
+extern crate biotac;
+#[macro_use(services)] extern crate nri;
+
+error_chain! {
+    links {
+        Flow(flow::Error, flow::ErrorKind);
+        Config(utils::config::Error, utils::config::ErrorKind);
+    }
+}
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use comms::Controllable;
+use flow::Flow;
+use flow::check;
+use utils::config;
+
+/// Name of a service, as flows refer to it
+fn name<T: Controllable>() -> &'static str {
+    guilty!(T::NAME)
+}
+
+/// Names of a list of services
+macro_rules! names {
+    ($($s:ty),*) => {
+        [ $( name::<$s>() ),* ]
+    };
+}
+
+quick_main!(|| -> Result<i32> {
+    let matches = clap_app! { nri_flowcheck =>
+        (version: crate_version!())
+        (author: crate_authors!("\n"))
+        (about: "Checks flows for mistakes without running them on the rig")
+
+        (@arg DIR:     -d --dir [dir] "Flow directory (default: the one the web interface uses)")
+        (@arg DRYRUN:  -n --dryrun [flow] "Simulate a flow (by short name) and print what it would do")
+        (@arg ANSWER:  -a --answer [text]... "Scripted answer for the next prompt in the dry run")
+        (@arg ANSWERS: -A --answers [file] "File of scripted answers for the dry run (one per line)")
+    }.get_matches();
+
+    config::set(config::load()?);
+    let flow_path = config::get().flows.path.clone();
+    let dir = Path::new(matches.value_of("DIR").unwrap_or(&flow_path));
+
+    // the same services the supervisor runs
+    let services = services!(names!());
+
+    let problems = check::check_dir(dir, Some(&services))?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!("{} problem(s) found", problems.len());
+
+    if let Some(shortname) = matches.value_of("DRYRUN") {
+        let path = dir.join(format!("{}.flow", shortname));
+        let flow = Flow::parse_file(&path)?;
+
+        let mut answers = matches.values_of("ANSWER").map_or(vec![], |a| a.map(String::from).collect());
+        if let Some(file) = matches.value_of("ANSWERS") {
+            let file = File::open(file).chain_err(|| format!("could not open {}", file))?;
+            for line in BufReader::new(file).lines() {
+                answers.push(line.chain_err(|| "could not read answers")?);
+            }
+        }
+
+        println!("");
+        println!("Dry run of {}:", flow.name);
+        let mut state = String::new();
+        for step in check::dry_run(&flow, answers.into_iter())? {
+            if step.state != state {
+                println!("- {}", step.state);
+                state = step.state;
+            }
+            println!("    [{:>8.3}s] {}", step.ms as f64 / 1000.0, step.command);
+        }
+    }
+
+    Ok(if problems.is_empty() { 0 } else { 1 })
+});
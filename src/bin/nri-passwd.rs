@@ -0,0 +1,75 @@
+#[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
+
+extern crate utils;
+extern crate web;
+
+error_chain! {
+    links {
+        Web(web::Error, web::ErrorKind);
+        Config(utils::config::Error, utils::config::ErrorKind);
+    }
+}
+
+use std::io::{self, BufRead, Write};
+
+use utils::config;
+use web::auth::{self, Role};
+
+/// Ask for a line on stdin (the prompt goes to stderr, so it doesn't get in the way of piping)
+fn ask(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush().chain_err(|| "could not write prompt")?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line).chain_err(|| "could not read password")?;
+    Ok(line.trim_right_matches(|c| c == '\r' || c == '\n').to_owned())
+}
+
+quick_main!(|| -> Result<()> {
+    let matches = clap_app! { nri_passwd =>
+        (version: crate_version!())
+        (author: crate_authors!("\n"))
+        (about: "Manages who can log in to the web interface (the password is read from stdin)")
+
+        (@arg USER:   "User to add, or whose password or role to change")
+        (@arg ROLE:   -r --role [role] "viewer, operator or admin (default: viewer)")
+        (@arg DELETE: -d --delete "Remove the user instead")
+        (@arg LIST:   -l --list "List users and their roles")
+    }.get_matches();
+
+    config::set(config::load()?);
+
+    if matches.is_present("LIST") {
+        for (user, role) in auth::list_users()? {
+            println!("{}\t{}", user, role);
+        }
+        return Ok(());
+    }
+
+    let user = match matches.value_of("USER") {
+        Some(user) => user,
+        None => bail!("which user? (see --help)"),
+    };
+
+    if matches.is_present("DELETE") {
+        if auth::remove_user(user)? {
+            println!("Removed {} from {}", user, auth::users_file().display());
+        } else {
+            bail!("there is no user called {}", user);
+        }
+        return Ok(());
+    }
+
+    let role = matches.value_of("ROLE").unwrap_or("viewer").parse::<Role>()?;
+    let password = ask(&format!("Password for {}: ", user))?;
+    if password.is_empty() {
+        bail!("the password cannot be empty");
+    }
+    if ask("Again: ")? != password {
+        bail!("the passwords do not match");
+    }
+
+    auth::set_user(user, role, &password)?;
+    println!("{} is now a{} {} (in {})", user, if role == Role::Viewer { "" } else { "n" }, role, auth::users_file().display());
+    Ok(())
+});
@@ -1,24 +1,18 @@
-extern crate time;
+extern crate scribe;
+extern crate optoforce;
 
 extern crate nri;
 
-use std::fmt;
-
-struct Data {
-    stamp: time::Timespec,
-    xyz: [f64; 3]
-}
-
-impl fmt::Debug for Data {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        try!(write!(f, "{:.9}, {:.9}, {:.9}, {:.9}",
-                    self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                    self.xyz[0], self.xyz[1], self.xyz[2]));
-        Ok(())
-    }
-}
+use scribe::Value;
 
 fn main() {
-    nri::read_binary::<Data>("Timestamp, X, Y, Z");
+    nri::read_scribe(&format!("Timestamp, X, Y, Z, {}", nri::SYNC_HEADER), optoforce::packet_schema(), |p: &Value| {
+        let xyz = p.get("xyz").unwrap();
+        let f = |axis| xyz.get(axis).and_then(Value::as_f64).unwrap();
+        format!("{:.9}, {:.9}, {:.9}, {:.9}, {}",
+                p.get("stamp").and_then(Value::as_secs).unwrap(),
+                f("x"), f("y"), f("z"),
+                nri::sync_columns(p))
+    });
 }
 
@@ -10,6 +10,7 @@ extern crate rayon;
 extern crate tempdir;
 extern crate thread_local_object;
 
+extern crate flow;
 extern crate nri;
 extern crate utils;
 
@@ -39,6 +40,10 @@ error_chain! {
         }
     }
 
+    links {
+        Flow(flow::Error, flow::ErrorKind);
+    }
+
     foreign_links {
         Cast(cast::Error);
         Csv(csv::Error);
@@ -484,14 +489,26 @@ quick_main!(|| -> Result<i32> {
     for epdir in matches.values_of("EPDIR").unwrap() {
         println!("Processing {}...", epdir);
 
+        // step 0: skip episodes that the manifest says have no camera footage (older episodes have no manifest)
+
+        if Path::new(epdir).join(flow::manifest::FILE_NAME).exists() {
+            let manifest = flow::manifest::Manifest::load(epdir)?;
+            if !manifest.ran("bluefox") {
+                println!("\tno bluefox recording in {} episode {}, skipping", manifest.flow, manifest.id);
+                continue;
+            }
+        }
+
         // step 1: read bluefox data from CSV files
         
         let mut bt = csv::Reader::from_path([epdir, "bluefox", "bluefox_times.csv"].iter().collect::<PathBuf>())?;
         let mut april = csv::Reader::from_path([epdir, "bluefox", "april.csv"].iter().collect::<PathBuf>())?;
 
-        // format of bluefox_times.csv is "Frame number (int), Filename (str), Unix Timestamp (float)"
+        // format of bluefox_times.csv is "Frame number (int), Filename (str), Unix Timestamp (float), Raw monotonic (ns), Corrected monotonic (ns)"
+        // (the monotonic columns are missing or empty in older recordings)
         let frames = bt.deserialize()
-                       .map(|r| r.map_err(Into::into))
+                       .map(|r| r.map_err(Into::into)
+                                 .map(|(num, fname, stamp, _, _): (u32, String, f64, Option<u64>, Option<u64>)| (num, fname, stamp)))
                        .collect::<Result<Vec<(u32, String, f64)>>>()?;
         // format of april.csv is "Frame number (int), Tag IDs (ints, semicolon-sep), Tag centers (float comma-sep coord pairs, semicolon-sep), ..."
         let aprils = april.deserialize()
@@ -1,101 +1,164 @@
-extern crate time;
 #[macro_use] extern crate closet;
 extern crate spawner;
+extern crate scribe;
+extern crate teensy;
+extern crate utils;
 
 extern crate nri;
 
 use spawner::Spawner;
-use std::cell::Cell;
-use std::{fmt, env};
+use scribe::Value;
+use std::cell::RefCell;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::Path;
+use std::sync::Arc;
+use teensy::calib::{self, Calibration};
+use teensy::imu::{Decoded, Decoder, Row, Timeline};
+use utils::config::{self, Config};
 
-#[derive(Copy, Clone)]
-enum OutputMode {
-    FT,
-    ACC,
-    GYRO,
-    MAG,
-}
-use OutputMode::*;
-
-thread_local! {
-    static OUTPUT_MODE: Cell<OutputMode> = Cell::new(FT);
-}
-
-#[repr(packed)]
-struct XYZ<T> {
-    x: T,
-    y: T,
-    z: T
-}
-#[repr(packed)]
-struct Packet {
-    stamp:  time::Timespec,
-    dt:     u32,
-    ft:     [u8; 30],
-    count:  u8,
-    n_acc:  u8,
-    n_gyro: u8,
-    imu:    [XYZ<i16>; 63]
-}
-
-impl fmt::Debug for Packet {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let a = self.n_acc as usize;
-        let g = self.n_gyro as usize;
-
-        match OUTPUT_MODE.with(|om| om.get()) {
-            FT => {
-                try!(write!(f, "{:.9}, {}, {}",
-                            self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                            self.dt,
-                            self.count));
-                for i in 0..30 {
-                    try!(write!(f, ", {}", self.ft[i]));
-                }
-            },
-            ACC => {
-                for i in 0..a {
-                    try!(write!(f, "{:.9}, {}, {}, {}, {}",
-                                self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                                i,
-                                self.imu[i].x,
-                                self.imu[i].y,
-                                self.imu[i].z));
-                    if i != a-1 {
-                        try!(write!(f, "\n"));
-                    }
-                }
-            },
-            GYRO => {
-                for i in 0..g {
-                    try!(write!(f, "{:.9}, {}, {}, {}, {}",
-                                self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                                i,
-                                self.imu[i + a].x,
-                                self.imu[i + a].y,
-                                self.imu[i + a].z));
-                    if i != g-1 {
-                        try!(write!(f, "\n"));
-                    }
-                }
-            },
-            MAG => {
-                if a + g > 0 {
-                    try!(write!(f, "{:.9}, {}, {}, {}",
-                                self.stamp.sec as f64 + self.stamp.nsec as f64 / 1_000_000_000f64,
-                                i16::from_be(self.imu[a + g].x),
-                                i16::from_be(self.imu[a + g].y),
-                                i16::from_be(self.imu[a + g].z)));
+fn stamp(p: &Value) -> f64 {
+    p.get("stamp").and_then(Value::as_secs).unwrap()
+}
+
+/// Device time (ns) of a packet: the corrected sync stamp, or the host time in files from before
+/// there were sync stamps
+fn device_ns(p: &Value) -> u64 {
+    p.get("sync").and_then(|sync| sync.get("corrected")).and_then(Value::as_u64)
+     .unwrap_or_else(|| (stamp(p) * 1e9) as u64)
+}
+
+fn int(v: Option<&Value>) -> i64 {
+    v.and_then(Value::as_i64).unwrap()
+}
+
+fn imu(p: &Value, i: usize) -> (i64, i64, i64) {
+    let xyz = p.get("imu").and_then(|imu| imu.at(i)).unwrap();
+    (int(xyz.get("x")), int(xyz.get("y")), int(xyz.get("z")))
+}
+
+fn ft(p: &Value) -> String {
+    format!("{:.9}, {}, {}, {}",
+            stamp(p),
+            p.get("dt").unwrap(),
+            p.get("ft").unwrap(),
+            nri::sync_columns(p))
+}
+
+fn ft_bytes(p: &Value) -> [u8; 31] {
+    let mut ft = [0u8; 31];
+    for i in 0..31 {
+        ft[i] = int(p.get("ft").and_then(|ft| ft.at(i))) as u8;
+    }
+    ft
+}
+
+fn wrench(p: &Value, calibration: &Calibration) -> String {
+    let w = calibration.wrench_at(stamp(p), &calib::gauges(&ft_bytes(p)));
+    format!("{:.9}, {:.6}, {:.6}, {:.6}, {:.6}, {:.6}, {:.6}, {}",
+            stamp(p), w[0], w[1], w[2], w[3], w[4], w[5], nri::sync_columns(p))
+}
+
+fn decode(p: &Value, decoder: &Decoder) -> Decoded {
+    let a = int(p.get("n_acc")) as usize;
+    let g = int(p.get("n_gyro")) as usize;
+    let n = if a + g > 0 { a + g + 1 } else { 0 };
+    let raw = (0..n).map(|i| {
+        let (x, y, z) = imu(p, i);
+        [x as i16, y as i16, z as i16]
+    }).collect::<Vec<_>>();
+    decoder.decode(stamp(p), &raw, a, g, &ft_bytes(p))
+}
+
+fn imu_rows(rows: Vec<Row>) -> String {
+    rows.iter().map(|row| {
+        let mut line = row.t.to_string();
+        for values in &row.values {
+            match *values {
+                Some(v) => line.push_str(&format!(", {:.6}, {:.6}, {:.6}", v[0], v[1], v[2])),
+                None => line.push_str(", , , "),
+            }
+        }
+        line
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// The configuration, for data recorded without its settings
+fn configured() -> Config {
+    config::load().unwrap_or_else(|e| {
+        println!("Could not load the configuration ({}), so using the defaults", e);
+        Config::defaults()
+    })
+}
+
+/// The IMU settings recorded with the data, or else the configured ones
+fn decoder(inname: &str) -> Decoder {
+    let dir = Path::new(inname).parent().unwrap_or(Path::new("."));
+    Decoder::recorded(dir).unwrap_or_else(|e| {
+        println!("No recorded IMU settings ({}), so using the configured ones", e);
+        Decoder::new(&configured())
+    })
+}
+
+/// The calibration recorded with the data, or else the configured default
+fn calibration(inname: &str) -> Calibration {
+    let dir = Path::new(inname).parent().unwrap_or(Path::new("."));
+    match Calibration::recorded(dir) {
+        Ok(cal) => cal,
+        Err(e) => {
+            println!("No recorded calibration ({}), so using the configured one", e);
+            match calib::choose(&configured(), None, None) {
+                Ok(cal) => cal,
+                Err(e) => {
+                    println!("Could not load the configured calibration ({}), so the wrench is in volts", e);
+                    Calibration::raw()
                 }
-            },
+            }
         }
-        Ok(())
+    }
+}
+
+fn acc(p: &Value) -> String {
+    let a = int(p.get("n_acc")) as usize;
+    (0..a).map(|i| {
+        let (x, y, z) = imu(p, i);
+        format!("{:.9}, {}, {}, {}, {}, {}", stamp(p), i, x, y, z, nri::sync_columns(p))
+    }).collect::<Vec<_>>().join("\n")
+}
+
+fn gyro(p: &Value) -> String {
+    let a = int(p.get("n_acc")) as usize;
+    let g = int(p.get("n_gyro")) as usize;
+    (0..g).map(|i| {
+        let (x, y, z) = imu(p, i + a);
+        format!("{:.9}, {}, {}, {}, {}, {}", stamp(p), i, x, y, z, nri::sync_columns(p))
+    }).collect::<Vec<_>>().join("\n")
+}
+
+fn mag(p: &Value) -> String {
+    let a = int(p.get("n_acc")) as usize;
+    let g = int(p.get("n_gyro")) as usize;
+    if a + g > 0 {
+        // the magnetometer is big-endian
+        let (x, y, z) = imu(p, a + g);
+        format!("{:.9}, {}, {}, {}, {}",
+                stamp(p),
+                i16::from_be(x as i16),
+                i16::from_be(y as i16),
+                i16::from_be(z as i16),
+                nri::sync_columns(p))
+    } else {
+        String::new()
     }
 }
 
 fn main() {
     let inname = nri::parse_in_arg(&mut env::args().skip(1));
+    let calibration = Arc::new(calibration(&inname));
+    println!("Calibration {:?} (transducer {:?}, {} and {})",
+             calibration.profile, calibration.serial, calibration.force_units, calibration.torque_units);
+    let decoder = Arc::new(decoder(&inname));
 
     let mut spawner = Spawner::new();
     let bars = nri::MultiProgress::new();
@@ -103,34 +166,67 @@ fn main() {
 
     let ftbar = bars.add(nri::make_bar(0));
     spawner.spawn_collected(clone_army!([inname] move || {
-        OUTPUT_MODE.with(|om| om.set(FT));
-        let mut header = String::from("Timestamp, Teensy dt, Packet number");
-        for i in 0..30 {
+        let mut header = String::from("Timestamp, Teensy dt 1, Teensy dt 2");
+        for i in 0..31 {
             header.push_str(&format!(", FT{}", i));
         }
-        nri::do_binary::<Packet>(&header, nri::Bar::Multi("FT", ftbar),
-                                 (inname.clone(), Some(Path::new(&inname).with_extension("ft.csv").to_str().unwrap().to_string())));
+        header.push_str(&format!(", {}", nri::SYNC_HEADER));
+        nri::do_scribe(&header, nri::Bar::Multi("FT", ftbar),
+                       (inname.clone(), Some(Path::new(&inname).with_extension("ft.csv").to_str().unwrap().to_string())),
+                       teensy::packet_schema(), ft);
+    }));
+
+    let wrenchbar = bars.add(nri::make_bar(0));
+    spawner.spawn_collected(clone_army!([inname, calibration] move || {
+        nri::do_scribe(&format!("Timestamp, Fx, Fy, Fz, Tx, Ty, Tz, {}", nri::SYNC_HEADER), nri::Bar::Multi("Wrench", wrenchbar),
+                       (inname.clone(), Some(Path::new(&inname).with_extension("wrench.csv").to_str().unwrap().to_string())),
+                       teensy::packet_schema(), |p| wrench(p, &calibration));
+    }));
+
+    let imubar = bars.add(nri::make_bar(0));
+    spawner.spawn_collected(clone_army!([inname, decoder] move || {
+        let mut header = String::from("Device time (ns)");
+        for &(sensor, unit, axes) in &[("Acc", "m/s^2", ["X", "Y", "Z"]),
+                                       ("Gyro", "rad/s", ["X", "Y", "Z"]),
+                                       ("Mag", "uT", ["X", "Y", "Z"]),
+                                       ("Analog acc", "m/s^2", ["1", "2", "3"])] {
+            for axis in &axes {
+                header.push_str(&format!(", {} {} ({})", sensor, axis, unit));
+            }
+        }
+        let outname = Path::new(&inname).with_extension("imu.csv").to_str().unwrap().to_string();
+        let timeline = RefCell::new(Timeline::new(decoder.config.rate_hz));
+        nri::do_scribe(&header, nri::Bar::Multi("IMU", imubar),
+                       (inname.clone(), Some(outname.clone())),
+                       teensy::packet_schema(), |p| imu_rows(timeline.borrow_mut().push(device_ns(p), &decode(p, &decoder))));
+
+        // rows still waiting for the other sensors when the data ran out
+        let rest = imu_rows(timeline.into_inner().finish());
+        if !rest.is_empty() {
+            let mut file = OpenOptions::new().append(true).open(&outname).unwrap();
+            writeln!(file, "{}", rest).unwrap();
+        }
     }));
 
     let accbar = bars.add(nri::make_bar(0));
     spawner.spawn_collected(clone_army!([inname] move || {
-        OUTPUT_MODE.with(|om| om.set(ACC));
-        nri::do_binary::<Packet>("Timestamp, FIFO position, Acc X, Acc Y, Acc Z", nri::Bar::Multi("Acc", accbar),
-                                 (inname.clone(), Some(Path::new(&inname).with_extension("acc.csv").to_str().unwrap().to_string())));
+        nri::do_scribe(&format!("Timestamp, FIFO position, Acc X, Acc Y, Acc Z, {}", nri::SYNC_HEADER), nri::Bar::Multi("Acc", accbar),
+                       (inname.clone(), Some(Path::new(&inname).with_extension("acc.csv").to_str().unwrap().to_string())),
+                       teensy::packet_schema(), acc);
     }));
 
     let gyrobar = bars.add(nri::make_bar(0));
     spawner.spawn_collected(clone_army!([inname] move || {
-        OUTPUT_MODE.with(|om| om.set(GYRO));
-        nri::do_binary::<Packet>("Timestamp, FIFO position, Gyro X, Gyro Y, Gyro Z", nri::Bar::Multi("Gyro", gyrobar),
-                                 (inname.clone(), Some(Path::new(&inname).with_extension("gyro.csv").to_str().unwrap().to_string())));
+        nri::do_scribe(&format!("Timestamp, FIFO position, Gyro X, Gyro Y, Gyro Z, {}", nri::SYNC_HEADER), nri::Bar::Multi("Gyro", gyrobar),
+                       (inname.clone(), Some(Path::new(&inname).with_extension("gyro.csv").to_str().unwrap().to_string())),
+                       teensy::packet_schema(), gyro);
     }));
 
     let magbar = bars.add(nri::make_bar(0));
     spawner.spawn_collected(clone_army!([inname] move || {
-        OUTPUT_MODE.with(|om| om.set(MAG));
-        nri::do_binary::<Packet>("Timestamp, Mag X, Mag Y, Mag Z", nri::Bar::Multi("Mag", magbar),
-                                 (inname.clone(), Some(Path::new(&inname).with_extension("mag.csv").to_str().unwrap().to_string())));
+        nri::do_scribe(&format!("Timestamp, Mag X, Mag Y, Mag Z, {}", nri::SYNC_HEADER), nri::Bar::Multi("Mag", magbar),
+                       (inname.clone(), Some(Path::new(&inname).with_extension("mag.csv").to_str().unwrap().to_string())),
+                       teensy::packet_schema(), mag);
     }));
 
     bars.join_and_clear().unwrap();
@@ -7,12 +7,14 @@ extern crate regex;
 extern crate ssh2;
 extern crate walkdir;
 
+extern crate flow;
 extern crate nri;
+extern crate scribe;
 extern crate utils;
 #[macro_use] extern crate closet;
 
 use std::{fs, thread};
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio, ExitStatus};
@@ -26,6 +28,7 @@ use regex::Regex;
 use ssh2::{Session, Sftp};
 use walkdir::WalkDir;
 
+use flow::manifest::{self, Manifest};
 use nri::{MultiProgress, make_bar, make_bar_bytes};
 
 error_chain! {
@@ -36,6 +39,10 @@ error_chain! {
         }
     }
 
+    links {
+        Flow(flow::Error, flow::ErrorKind);
+    }
+
     foreign_links {
         Ssh(ssh2::Error);
         Glob(globset::Error);
@@ -125,9 +132,32 @@ fn try_main() -> Result<()> {
             }
         };
 
+        /* check recorded files against the episode manifests before touching anything */
+        let manifests = glob(&epdir, manifest::FILE_NAME)?;
+        for path in &manifests {
+            let dir = path.parent().unwrap();
+            let manifest = Manifest::load(dir)?;
+            println!("Checking {} files in episode {}...", manifest.files.len(), manifest.id);
+            let mut ok = true;
+            for file in &manifest.files {
+                match file.check(dir) {
+                    Ok(None) => {}
+                    Ok(Some(problem)) => {
+                        println!("{}: {}", dir.join(&file.path).display(), problem);
+                        ok = false;
+                    }
+                    // raw data is deleted once it has been processed (see below), so it may be gone already
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound && is_raw(&file.path) => {}
+                    Err(e) => return Err(e).chain_err(|| Io("check", dir.join(&file.path))),
+                }
+            }
+            if !ok { bail!("{} does not match its manifest", dir.display()); }
+        }
+
         /* process data if necessary */
         let dats = glob(&epdir, "*.dat")?;
-        if dats.len() != 0 {
+        let containers = glob(&epdir, "*.chunks")?;
+        if dats.len() + containers.len() != 0 {
             /* ./run.sh all $epdir */
             println!("Processing data...");
             let status = Command::new("./run.sh")
@@ -139,6 +169,15 @@ fn try_main() -> Result<()> {
             }
         }
 
+        /* check that every frame container is intact before anything is deleted */
+        let mut frames = 0;
+        for container in &containers {
+            println!("Verifying {}...", container.display());
+            frames += scribe::Container::open(container)
+                .and_then(|mut c| c.verify())
+                .chain_err(|| Io("verify", container.clone()))?;
+        }
+
         /* check PNG/CSV/DAT file counts */
         println!("Checking file counts...");
         let dats  = glob(&epdir, "*.dat")?;
@@ -150,16 +189,16 @@ fn try_main() -> Result<()> {
         let stcsvs = glob(&epdir, "structure_times.csv")?;
         let bcsvs = glob(&epdir, "biotac.csv")?;
         let ocsvs = glob(&epdir, "optoforce.csv")?;
-        println!("{} pngs, {} csvs, {} dats", pngs.len(), csvs.len(), dats.len());
+        println!("{} pngs, {} csvs, {} dats, {} container frames", pngs.len(), csvs.len(), dats.len(), frames);
         let expected_csv = 4*flows.len() + blcsvs.len()*3/2 + stcsvs.len() + bcsvs.len() + ocsvs.len();
-        let expected_png = dats.len() - bcsvs.len() - ocsvs.len() - 2*flows.len() + crops.len();
+        let expected_png = dats.len() + frames - bcsvs.len() - ocsvs.len() - 2*flows.len() + crops.len();
         let mut ok = true;
         if csvs.len() != expected_csv {
             println!("Wrong number of CSV files! (4*{} + {}*3/2 + {} + {} + {} = {} != {})", flows.len(), blcsvs.len(), stcsvs.len(), bcsvs.len(), ocsvs.len(), expected_csv, csvs.len());
             ok = false;
         }
-        if dats.len() > 0 && pngs.len() != expected_png {
-            println!("Wrong number of PNG files! ({} - {} - {} - 2*{} + {} = {} != {})", dats.len(), bcsvs.len(), ocsvs.len(), flows.len(), crops.len(), expected_png, pngs.len());
+        if dats.len() + frames > 0 && pngs.len() != expected_png {
+            println!("Wrong number of PNG files! ({} + {} - {} - {} - 2*{} + {} = {} != {})", dats.len(), frames, bcsvs.len(), ocsvs.len(), flows.len(), crops.len(), expected_png, pngs.len());
             ok = false;
         }
 
@@ -172,6 +211,16 @@ fn try_main() -> Result<()> {
                 fs::remove_file(&dat).chain_err(|| Io("delete", dat))?;
             }
         }
+        if containers.len() > 0 {
+            println!("Deleting *.chunks files...");
+            for container in containers {
+                let index = scribe::sink::index_path(&container);
+                fs::remove_file(&container).chain_err(|| Io("delete", container))?;
+                if index.exists() {
+                    fs::remove_file(&index).chain_err(|| Io("delete", index))?;
+                }
+            }
+        }
 
         /* check disk space */
         let size = local_du(&epdir)?;
@@ -181,7 +230,7 @@ fn try_main() -> Result<()> {
             bail!("not enough space at destination");
         }
 
-        if flows.len() + pngs.len() + csvs.len() == 0 {
+        if flows.len() + pngs.len() + csvs.len() + manifests.len() == 0 {
             eprintln!("no files to transfer");
             continue 'args;
         }
@@ -209,7 +258,7 @@ fn try_main() -> Result<()> {
 
         let bars = MultiProgress::new();
         bars.set_move_cursor(true);
-        let num_bar = bars.add(make_bar((flows.len() + pngs.len() + csvs.len()) as u64)); // the first progress bar counts files transferred/evaluated
+        let num_bar = bars.add(make_bar((flows.len() + pngs.len() + csvs.len() + manifests.len()) as u64)); // the first progress bar counts files transferred/evaluated
         let size_bar = bars.add(make_bar_bytes(size.0 as u64)); // the second progress bar measures disk space
         num_bar.set_message("Files");
         size_bar.set_message("Bytes");
@@ -218,7 +267,7 @@ fn try_main() -> Result<()> {
             num_bar.set_position(0);
             for line in BufReader::new(child.stdout.as_mut().unwrap()).lines() {
                 let line = line.chain_err(|| Io("read output", "rsync".into()))?;
-                if line.ends_with(".flow") || line.ends_with(".png") || line.ends_with(".csv") {
+                if line.ends_with(".flow") || line.ends_with(".png") || line.ends_with(".csv") || line.ends_with(manifest::FILE_NAME) {
                     num_bar.inc(1);
                 }
             }
@@ -294,6 +343,11 @@ fn check_scp(SshInfo { user, pass, host, dir }: SshInfo) -> Result<()> {
     Ok(())
 }
 
+/// Is this a raw data file that gets deleted after processing?
+fn is_raw(path: &str) -> bool {
+    path.ends_with(".dat") || path.ends_with(".chunks") || path.ends_with(".idx")
+}
+
 /// find $dir -name $name
 fn glob<P: AsRef<Path>>(dir: P, name: &str) -> Result<Vec<PathBuf>> {
     let pattern = Glob::new(&format!("*/{}", name))?.compile_matcher();
@@ -0,0 +1,357 @@
+//! Service health monitoring and restart policy for the supervisor
+//!
+//! The supervisor tells the `Monitor` about every lifecycle event it sees (start and stop requests,
+//! `Timeout`/`Timein` around setup, panics) and calls `tick()` regularly. The monitor enforces the
+//! setup and step deadlines each service declares (see `Controllable`), measures step rates from
+//! each service's heartbeat, and decides when (and whether) to restart a service that panicked or
+//! hung. It answers with a list of `Action`s for the supervisor to carry out.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use comms::Block;
+use comms::health::{Heartbeat, Report, State};
+use utils::RecordingContext;
+use utils::config;
+
+/// How often the supervisor should call `tick()` (ms)
+pub const TICK_MS: u64 = 250;
+
+/// Watchdog and restart settings (see `config::Services`)
+pub struct Policy {
+    /// Number of consecutive restarts before giving up on a service
+    pub max_restarts: u32,
+    /// Delay before the first restart (doubled for each consecutive restart)
+    pub backoff: Duration,
+    /// Longest delay between restarts
+    pub max_backoff: Duration,
+    /// A service that runs this long without panicking is considered stable again
+    pub stable_after: Duration,
+    /// Whether services that miss a deadline are restarted like ones that panicked
+    pub restart_hung: bool,
+    /// Period over which step rates are measured
+    pub rate_window: Duration,
+    /// Fraction of the requested step rate below which a service is considered slow
+    pub min_rate: f64,
+}
+
+impl Policy {
+    pub fn new(config: &config::Services) -> Policy {
+        Policy {
+            max_restarts: config.max_restarts,
+            backoff:      Duration::from_millis(config.backoff_ms),
+            max_backoff:  Duration::from_millis(config.max_backoff_ms),
+            stable_after: Duration::from_millis(config.stable_after_ms),
+            restart_hung: config.restart_hung,
+            rate_window:  Duration::from_millis(config.rate_window_ms),
+            min_rate:     config.min_rate,
+        }
+    }
+}
+
+/// Something the supervisor needs to do
+pub enum Action {
+    /// Start the service again, with the same parameter and recording context as last time
+    Restart(&'static str, Option<String>, RecordingContext),
+    /// Leave the service's current instance behind (it is stuck) so that it can be restarted
+    Abandon(&'static str),
+    /// Send a message to the web interface
+    Notify(String),
+}
+
+/// What the monitor knows about one service
+struct Entry {
+    name: &'static str,
+    heartbeat: Arc<Heartbeat>,
+    /// Requested step rate (Hz), for services with a fixed period
+    expected_rate: Option<f64>,
+    /// Longest a single step() may take (None if it isn't held to a deadline)
+    step_deadline: Option<Duration>,
+
+    state: State,
+    /// Start parameter and recording context, reused for automatic restarts
+    param: Option<String>,
+    ctx: RecordingContext,
+    restarts: u32,
+    last_panic: Option<String>,
+    /// Counters reported by the service
+    stats: BTreeMap<String, u64>,
+
+    /// When setup() started, and when it should be done by
+    setup: Option<(Instant, Instant)>,
+    /// When setup() finished
+    running_since: Option<Instant>,
+    /// When the pending restart is due
+    restart_at: Option<Instant>,
+
+    /// Start of the current rate measurement window, and the step count at that time
+    window: (Instant, usize),
+    rate: Option<f64>,
+    slow: bool,
+}
+
+impl Entry {
+    fn set_state(&mut self, state: State, actions: &mut Vec<Action>) {
+        if self.state != state {
+            println!("HEALTH: {} is {}", self.name, state);
+            actions.push(Action::Notify(format!("health {} {} {}", self.name, state.key(), state)));
+            self.state = state;
+        }
+    }
+
+    /// State to return to once nothing is wrong any more
+    fn healthy(&self) -> State {
+        if self.slow { State::Slow } else { State::Running }
+    }
+
+    /// Schedule a restart with exponential backoff after the service died (or was abandoned), or
+    /// give up if it keeps dying
+    fn restart_later(&mut self, policy: &Policy, reason: String, actions: &mut Vec<Action>) {
+        let now = Instant::now();
+        self.last_panic = Some(reason);
+        self.setup = None;
+        self.rate = None;
+
+        if self.running_since.take().map_or(false, |since| now - since >= policy.stable_after) {
+            self.restarts = 0;
+        }
+        self.restarts += 1;
+
+        if self.restarts > policy.max_restarts {
+            self.restart_at = None;
+            self.set_state(State::GaveUp, actions);
+        } else {
+            let delay = cmp::min(policy.backoff * (1 << cmp::min(self.restarts - 1, 16)), policy.max_backoff);
+            self.restart_at = Some(now + delay);
+            let attempt = self.restarts;
+            self.set_state(State::Restarting(attempt), actions);
+        }
+    }
+
+    /// The service missed a deadline in `phase` ("setup" or "step")
+    fn hung(&mut self, policy: &Policy, phase: &'static str, actions: &mut Vec<Action>) {
+        self.set_state(State::Hung(phase), actions);
+        if policy.restart_hung {
+            actions.push(Action::Abandon(self.name));
+            self.restart_later(policy, format!("missed its {} deadline", phase), actions);
+        }
+    }
+}
+
+/// Health table for all services
+pub struct Monitor {
+    policy: Policy,
+    entries: Vec<Entry>,
+    /// State changes waiting to be returned from tick()
+    pending: Vec<Action>,
+    last_tick: Instant,
+}
+
+impl Monitor {
+    pub fn new(policy: Policy) -> Monitor {
+        Monitor { policy: policy, entries: vec![], pending: vec![], last_tick: Instant::now() }
+    }
+
+    /// Start keeping track of a service, holding each step() to `step_deadline` (if any)
+    pub fn register(&mut self, name: &'static str, heartbeat: Arc<Heartbeat>, block: Block, step_deadline: Option<Duration>) {
+        self.entries.push(Entry {
+            name: name,
+            heartbeat: heartbeat,
+            expected_rate: match block {
+                Block::Period(ns) if ns > 0 => Some(1.0e9 / ns as f64),
+                _ => None,
+            },
+            step_deadline: step_deadline,
+            state: State::Stopped,
+            param: None,
+            ctx: RecordingContext::default(),
+            restarts: 0,
+            last_panic: None,
+            stats: BTreeMap::new(),
+            setup: None,
+            running_since: None,
+            restart_at: None,
+            window: (Instant::now(), 0),
+            rate: None,
+            slow: false,
+        });
+    }
+
+    fn find(&mut self, name: &str) -> Option<&mut Entry> {
+        let name = name.to_lowercase();
+        self.entries.iter_mut().find(|e| e.name.to_lowercase() == name)
+    }
+
+    /// A service is being started on request
+    ///
+    /// This also clears any give-up state and cancels a pending restart.
+    pub fn starting(&mut self, name: &str, param: Option<String>, ctx: RecordingContext) {
+        let mut actions = vec![];
+        if let Some(e) = self.find(name) {
+            if e.state == State::Stopped || e.state == State::GaveUp || e.restart_at.is_some() {
+                e.restarts = 0;
+                e.restart_at = None;
+                e.param = param;
+                e.ctx = ctx;
+                e.stats.clear();
+                e.set_state(State::Starting, &mut actions);
+            }
+        }
+        self.pending.extend(actions);
+    }
+
+    /// A service is being stopped on request
+    pub fn stopped(&mut self, name: &str) {
+        let mut actions = vec![];
+        if let Some(e) = self.find(name) {
+            e.restart_at = None;
+            e.setup = None;
+            e.running_since = None;
+            e.rate = None;
+            e.set_state(State::Stopped, &mut actions);
+        }
+        self.pending.extend(actions);
+    }
+
+    /// A service reported its counters
+    pub fn stats(&mut self, name: &str, stats: BTreeMap<String, u64>) {
+        if let Some(e) = self.find(name) {
+            e.stats = stats;
+        }
+    }
+
+    /// A service has started setup() and promises to finish within `ms`
+    ///
+    /// Returns false if there is no such service.
+    pub fn timeout(&mut self, name: &str, ms: u64) -> bool {
+        let mut actions = vec![];
+        let found = if let Some(e) = self.find(name) {
+            let now = Instant::now();
+            e.setup = Some((now, now + Duration::from_millis(ms)));
+            e.set_state(State::Starting, &mut actions);
+            true
+        } else {
+            false
+        };
+        self.pending.extend(actions);
+        found
+    }
+
+    /// A service has finished setup()
+    ///
+    /// Returns how long setup() took (None if it was never announced with `timeout`).
+    pub fn timein(&mut self, name: &str) -> Option<Duration> {
+        let mut actions = vec![];
+        let took = if let Some(e) = self.find(name) {
+            e.setup.take().map(|(started, _)| {
+                let now = Instant::now();
+                e.running_since = Some(now);
+                e.window = (now, e.heartbeat.steps());
+                e.rate = None;
+                e.slow = false;
+                e.set_state(State::Running, &mut actions);
+                now - started
+            })
+        } else {
+            None
+        };
+        self.pending.extend(actions);
+        took
+    }
+
+    /// A service panicked
+    ///
+    /// Schedules a restart with exponential backoff, or gives up if the service keeps panicking.
+    pub fn panicked(&mut self, name: &str, reason: String) {
+        let mut actions = vec![];
+        let name = name.to_lowercase();
+        let policy = &self.policy;
+        if let Some(e) = self.entries.iter_mut().find(|e| e.name.to_lowercase() == name) {
+            if e.state == State::Stopped {
+                // it was on the way out anyway
+                e.last_panic = Some(reason);
+                e.setup = None;
+                e.rate = None;
+                return;
+            }
+
+            e.restart_later(policy, reason, &mut actions);
+        }
+        self.pending.extend(actions);
+    }
+
+    /// Check deadlines, step rates and pending restarts
+    ///
+    /// Returns immediately (with only the state changes recorded since the last call) if called
+    /// more often than every `TICK_MS`.
+    pub fn tick(&mut self) -> Vec<Action> {
+        let mut actions = ::std::mem::replace(&mut self.pending, vec![]);
+
+        let now = Instant::now();
+        if now - self.last_tick < Duration::from_millis(TICK_MS) {
+            return actions;
+        }
+        self.last_tick = now;
+
+        let policy = &self.policy;
+        for e in &mut self.entries {
+            if let Some(at) = e.restart_at {
+                if now >= at {
+                    e.restart_at = None;
+                    actions.push(Action::Restart(e.name, e.param.clone(), e.ctx.clone()));
+                    e.set_state(State::Starting, &mut actions);
+                }
+                continue;
+            }
+
+            match e.state {
+                State::Starting => {
+                    if e.setup.map_or(false, |(_, deadline)| now > deadline) {
+                        e.hung(policy, "setup", &mut actions);
+                    }
+                }
+
+                State::Running | State::Slow | State::Hung("step") => {
+                    if now - e.window.0 >= policy.rate_window {
+                        let steps = e.heartbeat.steps();
+                        let elapsed = now - e.window.0;
+                        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1.0e9;
+                        let rate = (steps - e.window.1) as f64 / secs;
+                        e.rate = Some(rate);
+                        e.slow = e.expected_rate.map_or(false, |expected| rate < expected * policy.min_rate);
+                        e.window = (now, steps);
+                    }
+
+                    let stuck = match (e.step_deadline, e.heartbeat.stepping_for()) {
+                        (Some(deadline), Some(t)) => t > deadline,
+                        _ => false,
+                    };
+                    if !stuck {
+                        let state = e.healthy();
+                        e.set_state(state, &mut actions);
+                    } else if e.state != State::Hung("step") {
+                        e.hung(policy, "step", &mut actions);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        actions
+    }
+
+    /// The current health table
+    pub fn report(&self) -> Vec<Report> {
+        self.entries.iter().map(|e| Report {
+            name: e.name,
+            state: e.state.clone(),
+            restarts: e.restarts,
+            rate: e.rate,
+            expected_rate: e.expected_rate,
+            last_panic: e.last_panic.clone(),
+            stats: e.stats.clone(),
+        }).collect()
+    }
+}
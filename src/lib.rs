@@ -4,6 +4,7 @@ extern crate libc;
 extern crate hprof;
 extern crate num_cpus;
 extern crate indicatif;
+extern crate scribe;
 #[macro_use] extern crate closet;
 
 use std::{env, fs, process, mem, ptr, thread};
@@ -114,6 +115,25 @@ macro_rules! attempt {
     }
 }
 
+/// The services the supervisor runs, in the order they are spawned
+///
+/// Invoke as `services!(m!(args))` to get `m!(args ::cli::CLI, ::web::Web, ...)`, so that other
+/// macros can go over the list. The caller has to link all the service crates.
+#[macro_export]
+macro_rules! services {
+    ($m:ident!($($arg:tt)*)) => {
+        $m!($($arg)*
+            ::cli::CLI,
+            ::web::Web,
+            ::teensy::park::ParkingLot,
+            ::teensy::Teensy,
+            ::optoforce::Optoforce,
+            ::structure::Structure,
+            ::bluefox::Bluefox,
+            ::biotac::Biotac)
+    }
+}
+
 trait Leakable {
     type Target: ?Sized;
 
@@ -181,42 +201,108 @@ pub fn do_binary<Data: Debug>(header: &str, bars: Bar, (inname, outname): (Strin
     indentln!("file size = {} ({} packets)", vec.len(), vec.len() as f64 / mem::size_of::<Data>() as f64);
 
     let chunks = vec.chunks(mem::size_of::<Data>());
-    let (bar, clear) = match bars {
-        Bar::Multi(label, bar) => {
-            bar.set_length(chunks.len() as u64);
-            bar.set_message(label);
-            (Some(bar), false)
-        },
-        Bar::Single => (Some(make_bar(chunks.len() as u64)), true),
-        Bar::None => (None, false)
-    };
+    let (bar, clear) = start_bar(bars, chunks.len() as u64);
     let mut datums = Vec::with_capacity(chunks.len());
     for (i, chunk) in chunks.enumerate() {
         if let Some(ref bar) = bar { if i % 100 == 0 { bar.inc(100); } }
-        let mut data: Data = unsafe { mem::uninitialized() };
-        unsafe {
-            ptr::copy(chunk.as_ptr(), &mut data as *mut _ as *mut _, mem::size_of_val(&data));
-        }
+        let data: Data = decode_binary(chunk);
         outfile.as_mut().map(|ref mut f| attempt!(writeln!(f, "{:?}", data)));
         datums.push(data);
     }
 
-    if let Some(bar) = bar {
-        if clear {
-            bar.finish_and_clear();
-        } else {
-            bar.finish();
-        }
-    }
+    finish_bar(bar, clear);
     indentln!("translated {} packets", datums.len());
     datums
 }
 
+/// Copy one packet out of a byte buffer
+fn decode_binary<Data>(chunk: &[u8]) -> Data {
+    let mut data: Data = unsafe { mem::uninitialized() };
+    unsafe {
+        ptr::copy(chunk.as_ptr(), &mut data as *mut _ as *mut _, mem::size_of_val(&data));
+    }
+    data
+}
+
 pub fn read_binary<Data: Debug>(header: &str) -> Vec<Data> {
     let (inname, outname) = parse_inout_args(&mut env::args());
     do_binary::<Data>(header, Bar::Single, (inname, Some(outname)))
 }
 
+/// Like `do_binary`, but for files of packets written by `scribe::Writer`
+///
+/// The packet layout comes from the file header (`legacy` is assumed for files without one), so
+/// instead of a struct, `format` gets each decoded packet and returns CSV rows for it (any number
+/// of lines, or an empty string to skip it). Returns the number of packets read.
+pub fn do_scribe<F>(header: &str, bars: Bar, (inname, outname): (String, Option<String>), legacy: scribe::Schema, format: F) -> usize
+    where F: Fn(&scribe::Value) -> String
+{
+    let reader = attempt!(scribe::Reader::open(&inname, Some(legacy)));
+    let len = attempt!(fs::metadata(&inname)).len() / reader.header().schema.size() as u64;
+    indentln!("format version {}, {} packets of {} bytes", reader.header().version, reader.header().schema.name, reader.header().schema.size());
+
+    let mut outfile = outname.map(|n| attempt!(File::create(n)));
+    outfile.as_mut().map(|ref mut f| attempt!(writeln!(f, "{}", header)));
+
+    let (bar, clear) = start_bar(bars, len);
+    let mut count = 0;
+    for (i, packet) in reader.enumerate() {
+        if let Some(ref bar) = bar { if i % 100 == 0 { bar.inc(100); } }
+        let rows = format(&attempt!(packet));
+        if !rows.is_empty() {
+            outfile.as_mut().map(|ref mut f| attempt!(writeln!(f, "{}", rows)));
+        }
+        count += 1;
+    }
+
+    finish_bar(bar, clear);
+    indentln!("translated {} packets", count);
+    count
+}
+
+/// Header for the columns produced by `sync_columns`
+pub const SYNC_HEADER: &'static str = "Raw monotonic (ns), Corrected monotonic (ns)";
+
+/// Raw and corrected timestamps of a packet (left empty for files recorded before packets had them)
+pub fn sync_columns(p: &scribe::Value) -> String {
+    match p.get("sync") {
+        Some(sync) => format!("{}, {}",
+                              sync.get("raw").and_then(scribe::Value::as_u64).unwrap(),
+                              sync.get("corrected").and_then(scribe::Value::as_u64).unwrap()),
+        None => String::from(", "),
+    }
+}
+
+pub fn read_scribe<F>(header: &str, legacy: scribe::Schema, format: F) -> usize
+    where F: Fn(&scribe::Value) -> String
+{
+    let (inname, outname) = parse_inout_args(&mut env::args());
+    do_scribe(header, Bar::Single, (inname, Some(outname)), legacy, format)
+}
+
+/// Set up the progress bar(s) for `len` items (returns whether to clear the bar when finished)
+fn start_bar(bars: Bar, len: u64) -> (Option<ProgressBar>, bool) {
+    match bars {
+        Bar::Multi(label, bar) => {
+            bar.set_length(len);
+            bar.set_message(label);
+            (Some(bar), false)
+        },
+        Bar::Single => (Some(make_bar(len)), true),
+        Bar::None => (None, false)
+    }
+}
+
+fn finish_bar(bar: Option<ProgressBar>, clear: bool) {
+    if let Some(bar) = bar {
+        if clear {
+            bar.finish_and_clear();
+        } else {
+            bar.finish();
+        }
+    }
+}
+
 pub trait Pixels<T> {
     fn pixel(&self, i: usize) -> T;
 }
@@ -241,9 +327,10 @@ pub fn do_camera<T: Copy, Data: Debug + Pixels<T>, F: for<'a> Fn(String, C, &'a
     let csvfile = attempt!(File::open(&inname));
     let mut csvrdr = csv::ReaderBuilder::new()
                                         .has_headers(false)
+                                        .flexible(true) // older recordings lack the monotonic columns
                                         .from_reader(csvfile);
     let mut csvwtr = csv::Writer::from_writer(vec![]);
-    attempt!(csvwtr.serialize(("Frame number", "Filename", "Unix timestamp")));
+    attempt!(csvwtr.serialize(("Frame number", "Filename", "Unix timestamp", "Raw monotonic (ns)", "Corrected monotonic (ns)")));
 
     let records = csvrdr.deserialize().collect::<Vec<_>>();
 
@@ -253,20 +340,35 @@ pub fn do_camera<T: Copy, Data: Debug + Pixels<T>, F: for<'a> Fn(String, C, &'a
     let bar = Arc::new(make_bar(records.len() as u64));
     for i in 0..num_threads {
         print!("{}...", i);
-        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let (tx, rx) = mpsc::channel::<(usize, PathBuf, Option<(PathBuf, usize)>)>();
         let name = String::from(name);
         let (func, param) = (func.clone(), param.clone());
         threads.push(Some((
             thread::spawn(clone_army!([bar] move || {
                 let prof = Profiler::new(format!("thread #{}", i).leak());
-                for dat_path in rx {
+                let mut container = None; // opened on first use
+                for (num, dat_path, chunk) in rx {
                     bar.inc(1);
                     let _g = prof.enter("frame");
                     let dat = dat_path.to_str().unwrap().to_string();
                     let png = dat_path.parent().unwrap().join(&name).join(dat_path.file_name().unwrap()).with_extension("png").to_str().unwrap().to_string();
                     let rows = {
                         let _g = prof.enter("do_binary");
-                        do_binary::<Data>("", Bar::None, (dat, None))
+                        // newer recordings keep the frames in a container instead (and some early
+                        // ones did so without saying, using the frame number as the chunk index)
+                        let chunk = match chunk {
+                            Some(chunk) => Some(chunk),
+                            None if !dat_path.exists() => Some((dat_path.with_file_name(format!("{}.chunks", name)), num)),
+                            None => None,
+                        };
+                        match chunk {
+                            None => do_binary::<Data>("", Bar::None, (dat, None)),
+                            Some((path, index)) => {
+                                let container = container.get_or_insert_with(|| attempt!(scribe::Container::open(&path)));
+                                let bytes = attempt!(container.get(index)).expect(&format!("frame {} is missing from {}", num, path.display()));
+                                bytes.chunks(mem::size_of::<Data>()).map(decode_binary).collect::<Vec<Data>>()
+                            }
+                        }
                     };
                     let pixels = {
                         let _g = prof.enter("into vec");
@@ -301,11 +403,16 @@ pub fn do_camera<T: Copy, Data: Debug + Pixels<T>, F: for<'a> Fn(String, C, &'a
     let mut i = 0;
     let mut t = 0;
     for row in records {
-        let (num, fname, stamp): (usize, String, f64) = row.expect(&format!("failed to parse row {} of {}", i, inname));
-        attempt!(csvwtr.serialize((num, Path::new(&fname).with_extension("png").to_str().unwrap().to_string(), stamp)));
+        let (num, fname, stamp, raw, corrected, chunk): (usize, String, f64, Option<u64>, Option<u64>, Option<usize>) = row.expect(&format!("failed to parse row {} of {}", i, inname));
         i += 1;
-        let dat_path = Path::new(&inname).with_file_name(fname);
-        attempt!(threads[t].as_ref().unwrap().1.send(dat_path));
+        // rows naming a container give the frame's chunk index in the last column
+        let (dat_path, chunk) = match chunk {
+            Some(chunk) => (Path::new(&inname).with_file_name(format!("{}{}.dat", name, num)),
+                            Some((Path::new(&inname).with_file_name(fname), chunk))),
+            None => (Path::new(&inname).with_file_name(fname), None),
+        };
+        attempt!(csvwtr.serialize((num, dat_path.with_extension("png").file_name().unwrap().to_str().unwrap().to_string(), stamp, raw, corrected)));
+        attempt!(threads[t].as_ref().unwrap().1.send((num, dat_path, chunk)));
         t = (t + 1) % 4;
     }
 
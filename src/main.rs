@@ -124,30 +124,29 @@ extern crate optoforce;
 extern crate structure;
 extern crate bluefox;
 extern crate biotac;
+#[macro_use(services)] extern crate nri;
 
 use std::{fs, panic, thread};
+use std::collections::BTreeMap;
 use std::process::{self, Command};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender};
-use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
 use std::time::Duration;
-use comms::{Controllable, CmdTo, CmdFrom, Power};
-use cli::CLI;
-use web::Web;
-use teensy::Teensy;
-use optoforce::Optoforce;
-use structure::Structure;
-use bluefox::Bluefox;
-use biotac::Biotac;
+use comms::{Controllable, CmdTo, CmdFrom, Power, Block, Status};
+use comms::health::Heartbeat;
+use utils::RecordingContext;
+use utils::config;
 
 #[macro_use] extern crate log;
 extern crate env_logger;
 extern crate hprof;
-extern crate chrono;
 
-use chrono::Utc;
+mod health;
 
 error_chain! {
+    links {
+        Config(utils::config::Error, utils::config::ErrorKind);
+    }
 }
 
 /// Helper function for rxspawn! macro
@@ -182,6 +181,14 @@ macro_rules! downcast {
     }
 }
 
+/// What a service's manager thread hears about
+enum Incarnation {
+    /// Instance number N of the service finished (by quitting, failing or panicking)
+    Ended(usize, thread::Result<comms::Result<()>>),
+    /// The supervisor gave up on the current instance (see `health::Action::Abandon`)
+    Abandon,
+}
+
 /// Service descriptor
 struct Service {
     /// short identifier
@@ -191,6 +198,14 @@ struct Service {
     /// synchronized Sender for commands from the master thread
     /// (should always be Some after Service::start runs)
     tx: Arc<Mutex<Sender<CmdTo>>>,
+    /// channel to the manager thread
+    manager: Sender<Incarnation>,
+    /// step progress, shared with the service thread
+    heartbeat: Arc<Heartbeat>,
+    /// blocking mode requested by the service
+    block: Block,
+    /// step deadline requested by the service
+    step_deadline: Option<u64>,
 }
 
 impl Service {
@@ -199,18 +214,47 @@ impl Service {
         let (tx, _) = channel();
         let tx_arc = Arc::new(Mutex::new(tx));
         let tx_ref = tx_arc.clone();
+        let heartbeat = Arc::new(Heartbeat::new());
+        let heartbeat_ref = heartbeat.clone();
+        let (manager_tx, manager_rx) = channel();
+        let ended_tx = manager_tx.clone();
         let thread = thread::Builder::new()
             .name(format!("{} manager", guilty!(T::NAME)))
             .spawn(move || {
-                loop {
+                // each instance runs on a thread of its own, so that a stuck one can be left behind
+                for n in 0.. {
                     let (thread_tx, thread_rx) = channel::<CmdTo>();
                     let cloned_master_tx = master_tx.clone();
                     *tx_ref.lock().expect("mutex poisoned") = thread_tx;
 
-                    match panic::catch_unwind(panic::AssertUnwindSafe(|| comms::go::<T>(thread_rx, cloned_master_tx))) {
-                        Ok(Ok(())) => { break }
+                    let (heartbeat, ended) = (heartbeat_ref.clone(), ended_tx.clone());
+                    thread::Builder::new()
+                        .name(guilty!(T::NAME).to_owned())
+                        .spawn(move || {
+                            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| comms::go::<T>(thread_rx, cloned_master_tx, heartbeat)));
+                            let _ = ended.send(Incarnation::Ended(n, result));
+                        })
+                        .expect("thread creation failed");
+
+                    let result = loop {
+                        match manager_rx.recv() {
+                            // (instances abandoned earlier may still finish)
+                            Ok(Incarnation::Ended(m, result)) => if m == n { break Some(result) },
+                            Ok(Incarnation::Abandon) => break None,
+                            Err(_) => return,
+                        }
+                    };
+
+                    match result {
+                        None => {
+                            // start over with a fresh instance (the stuck one finds its channel
+                            // closed if it ever gets going again)
+                            heartbeat_ref.clear();
+                        }
+
+                        Some(Ok(Ok(()))) => { break }
 
-                        Ok(Err(e)) => {
+                        Some(Ok(Err(e))) => {
                             master_tx.send(
                                 CmdFrom::Panicked {
                                     thread: guilty!(T::NAME),
@@ -218,7 +262,7 @@ impl Service {
                                 }).expect("master is dead");
                         }
 
-                        Err(e) => {
+                        Some(Err(e)) => {
                             master_tx.send(
                                 CmdFrom::Panicked {
                                     thread: guilty!(T::NAME),
@@ -236,7 +280,11 @@ impl Service {
         Ok(Service {
             name: guilty!(T::NAME),
             thread: thread,
-            tx: tx_arc
+            tx: tx_arc,
+            manager: manager_tx,
+            heartbeat: heartbeat,
+            block: guilty!(T::BLOCK),
+            step_deadline: guilty!(T::STEP_DEADLINE_MS),
         })
     }
 }
@@ -258,16 +306,50 @@ fn send_to(services: &[Service], s: String, cmd: CmdTo) -> Result<bool> {
     }
 }
 
-fn start(services: &[Service], s: String, d: Option<String>) -> Result<bool> {
-    send_to(services, s, CmdTo::Start(d))
+/// Leave a stuck service's current instance behind, so that it can be started again
+fn abandon(services: &[Service], s: &str) {
+    if let Some(srv) = find(services, s.to_owned()) {
+        srv.manager.send(Incarnation::Abandon).expect("manager is dead");
+    }
+}
+
+/// Send a command to a service without waiting to hear whether it was accepted
+fn tell(services: &[Service], s: &str, d: String) -> Result<bool> {
+    let (reply_tx, _) = channel();
+    send_to(services, s.to_owned(), CmdTo::Data(d, reply_tx))
 }
 
-fn stop(services: &[Service], s: String) -> Result<bool> {
-    send_to(services, s, CmdTo::Stop)
+/// Start a service, reporting its progress on `status` (which is told right away if there is no
+/// such service)
+fn start(services: &[Service], s: String, d: Option<String>, ctx: RecordingContext, status: Sender<Status>) -> Result<()> {
+    if !send_to(services, s.clone(), CmdTo::Start(d, ctx, status.clone()))? {
+        let _ = status.send(Status::Failed(format!("no such service {:?}", s)));
+    }
+    Ok(())
+}
+
+/// Stop a service, reporting on `status` once it has been torn down
+fn stop(services: &[Service], s: String, status: Sender<Status>) -> Result<()> {
+    if !send_to(services, s.clone(), CmdTo::Stop(status.clone()))? {
+        let _ = status.send(Status::Failed(format!("no such service {:?}", s)));
+    }
+    Ok(())
+}
+
+/// Look for flows that were interrupted by a crash or reboot, and tell the operator about them
+///
+/// Problems are only logged, since they must not stop the supervisor.
+fn report_interrupted(services: &[Service]) {
+    for dir in flow::recover() {
+        println!("Found interrupted flow in {} (resume or abort it)", dir.display());
+        if let Err(e) = tell(services, "web", format!("msg Found interrupted flow in {} (resume or abort it)", dir.display())) {
+            errorln!("Could not tell the web interface about {}: {}", dir.display(), e);
+        }
+    }
 }
 
 fn stop_all<I: Iterator<Item=Service>>(services: I) {
-    for Service { name, thread, tx } in services {
+    for Service { name, thread, tx, .. } in services {
         tx.lock().expect("mutex poisoned")
           .send(CmdTo::Quit).expect("manager is dead");
         thread.join().unwrap_or_else(|e| errorln!("Failed to join {} thread: {:?}", name, e));
@@ -299,30 +381,56 @@ fn try_main() -> Result<()> {
     prof!("main", {
 
         env_logger::init().chain_err(|| "failed to set up logger")?;
-        utils::in_original_dir("orig dir init", || {}).chain_err(|| "failed to change directory")?;
+        println!("Running in {}", utils::original_dir().display());
+
+        // everything after this sees the rig's settings (a bad configuration file stops us here)
+        let config = config::load()?;
+        println!("Configuration from {}", config.sources.join(", then "));
+        config::set(config);
 
         info!("Hello, world!");
 
         let (reply_tx, reply_rx) = channel();
 
-        let mut services = rxspawn!(reply_tx; CLI, Web, Teensy, Optoforce, Structure, Bluefox, Optoforce, Biotac);
-        let mut timers = HashMap::new();
+        let scribe_tx = reply_tx.clone();
+        scribe::report_to(move |event| {
+            let cmd = match event {
+                scribe::Event::Failed { stream, error } =>
+                    CmdFrom::Scribe { stream: stream, error: error, disk_full: false },
+                scribe::Event::DiskFull { stream, free } =>
+                    CmdFrom::Scribe { stream: stream, error: format!("only {} MB free", free / 1024 / 1024), disk_full: true },
+            };
+            let _ = scribe_tx.send(cmd);
+        });
+
+        let mut services = services!(rxspawn!(reply_tx;));
+        let mut monitor = health::Monitor::new(health::Policy::new(&config::get().services));
+        let mut serials = BTreeMap::new();
+        for svc in &services {
+            monitor.register(svc.name, svc.heartbeat.clone(), svc.block, svc.step_deadline.map(Duration::from_millis));
+        }
 
         thread::sleep(Duration::from_millis(500)); // wait for threads to start
 
-        start(&services, "cli".to_owned(), None)?;
-        start(&services, "web".to_owned(), None)?;
+        for &s in &["cli", "web", "park"] {
+            monitor.starting(s, None, RecordingContext::default());
+            start(&services, s.to_owned(), None, RecordingContext::default(), channel().0)?;
+        }
+
+        report_interrupted(&services);
 
         loop {
-            match reply_rx.recv() {
+            match reply_rx.recv_timeout(Duration::from_millis(health::TICK_MS)) {
                 Ok(cmd) => match cmd {
-                    CmdFrom::Start(s, d, tx) => {
-                        println!("STARTING {}", s);
-                        tx.send(start(&services, s, d)?).chain_err(|| "could not send start command")?;
+                    CmdFrom::Start(s, d, ctx, tx) => {
+                        println!("STARTING {} (recording into {})", s, ctx.root().display());
+                        monitor.starting(&s, d.clone(), ctx.clone());
+                        start(&services, s, d, ctx, tx)?;
                     },
                     CmdFrom::Stop(s, tx)  => {
                         println!("STOPPING {}", s);
-                        tx.send(stop(&services, s)?).chain_err(|| "could not send stop command")?;
+                        monitor.stopped(&s);
+                        stop(&services, s, tx)?;
                     },
                     CmdFrom::Quit         => {
                         println!("STOPPING ALL");
@@ -346,51 +454,95 @@ fn try_main() -> Result<()> {
                         match power {
                             Power::PowerOff   => { cmd = Command::new("sudo"); cmd.args(&["/sbin/shutdown", "-hP", "now"]); }
                             Power::Reboot     => { cmd = Command::new("sudo"); cmd.arg("/sbin/reboot"); }
-                            Power::RebootWifi => { cmd = Command::new("/home/nri/software/nri/net.sh"); }
+                            Power::RebootWifi => { cmd = Command::new(&config::get().nuc.wifi_script); }
                         }
                         cmd.spawn().chain_err(|| "could not start process")?
                            .wait().chain_err(|| "process did not complete successfully")?;
                     },
-                    CmdFrom::Timeout { thread: who, .. }  => {
-                        // TODO actually time the service and do something if it times out
-                        if find(&services, who.to_owned()).is_some() {
-                            timers.insert(who, Utc::now());
-                        } else {
+                    CmdFrom::Timeout { thread: who, ms }  => {
+                        if !monitor.timeout(who, ms) {
                             bail!("Nonexistent service asked for timeout");
                         }
                     },
                     CmdFrom::Timein { thread: who }    => {
-                        if let Some(then) = timers.remove(who) {
-                            println!("Service {} took {} ms", who, Utc::now().signed_duration_since(then));
+                        if let Some(took) = monitor.timein(who) {
+                            println!("Service {} took {} ms", who, took.as_secs() * 1000 + took.subsec_nanos() as u64 / 1_000_000);
                         } else {
-                            bail!("Timein with no matching timeout");
+                            // e.g. an abandoned instance finally finishing setup()
+                            errorln!("Ignoring timein from {} (no setup was pending)", who);
+                        }
+                    },
+                    CmdFrom::Health(tx) => {
+                        let _ = tx.send(monitor.report());
+                    },
+                    CmdFrom::Serial(svc, serial) => {
+                        println!("Service {} has serial number {}", svc, serial);
+                        serials.insert(svc, serial);
+                    },
+                    CmdFrom::Serials(tx) => {
+                        let _ = tx.send(serials.clone());
+                    },
+                    CmdFrom::Stats(svc, stats) => {
+                        monitor.stats(&svc, stats);
+                    },
+                    CmdFrom::EndEffectorChanged { from, to } => {
+                        let from = from.unwrap_or_else(|| "unknown".into());
+                        println!("End-effector changed from {} to {}", from, to);
+                        tell(&services, "web", format!("endeffector {} {}", from, to))?;
+                    },
+                    CmdFrom::Send(s, d, tx) => {
+                        if !send_to(&services, s.clone(), CmdTo::Data(d, tx.clone()))? {
+                            let _ = tx.send(Err(format!("no such service {:?}", s)));
                         }
                     },
-                    CmdFrom::Data(d)      => {
-                        let mut words = d.split(' ');
-                        match &*words.next().unwrap_or("") {
-                            "send" => { send_to(&services, "web".to_owned(), CmdTo::Data(d[5..].to_owned()))?; },
-                            "kick" => { send_to(&services, words.next().unwrap_or("").to_owned(), CmdTo::Data(join!("kick" => words, " ")))?; },
-                            "to"   => { send_to(&services, words.next().unwrap_or("").to_owned(), CmdTo::Data(join!(words, " ")))?; },
-                            "set"  => match (words.next(), words.next()) {
-                                (Some("DATADIR"), Some(dir)) => {
-                                    println!("Setting DATADIR = {:?}", dir);
-                                    *flow::DATADIR.write().unwrap() = dir.into();
-                                    send_to(&services, "web".to_owned(), CmdTo::Data(format!("diskfree {}", web::disk_free())))?;
-                                },
-                                (Some(d), None)              => { errorln!("No value provided to set variable {}", d); },
-                                (Some(d), _)                 => { errorln!("Unknown variable {}", d); },
-                                (None, _)                    => { errorln!("No variable name"); }
-                            },
-                            _      => { errorln!("Strange message {} received from a service", d); }
+                    CmdFrom::Set(var, val) => match &*var {
+                        "DATADIR" => {
+                            println!("Setting DATADIR = {:?}", val);
+                            *flow::DATADIR.write().unwrap() = val.into();
+                            tell(&services, "web", format!("diskfree {}", web::disk_free()))?;
+                            report_interrupted(&services);
+                        },
+                        _ => { errorln!("Unknown variable {}", var); }
+                    },
+                    CmdFrom::Scribe { stream, error, disk_full } => {
+                        errorln!("Stopped writing {}: {}", stream, error);
+                        tell(&services, "web", format!("msg Stopped writing {}: {}", stream, error))?;
+                        if disk_full {
+                            // the scribe thread has already closed the files, so wind the recording down
+                            println!("STOPPING SENSORS");
+                            for svc in services.iter().filter(|svc| svc.name != "cli" && svc.name != "web" && svc.name != "park") {
+                                monitor.stopped(svc.name);
+                                stop(&services, svc.name.to_owned(), channel().0)?;
+                            }
                         }
                     },
+                    CmdFrom::SetupFailed { thread: who, reason: why } => {
+                        errorln!("Service {} failed to start (reason: {})", who, why);
+                        // retried with the same backoff as a crash
+                        monitor.panicked(who, why);
+                    },
                     CmdFrom::Panicked { thread: who, panic_reason: why } => {
                         errorln!("Service {} panicked! (reason: {})", who, why);
-                        send_to(&services, "web".to_owned(), CmdTo::Data(format!("panic {} {}", who, why)))?;
+                        tell(&services, "web", format!("panic {} {}", who, why))?;
+                        monitor.panicked(who, why);
                     },
                 },
-                Err(_) => { stop_all(services.drain(1..)); break; }
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => { stop_all(services.drain(1..)); break; }
+            }
+
+            for action in monitor.tick() {
+                match action {
+                    health::Action::Restart(who, d, ctx) => {
+                        println!("RESTARTING {}", who);
+                        start(&services, who.to_owned(), d, ctx, channel().0)?;
+                    },
+                    health::Action::Abandon(who) => {
+                        println!("ABANDONING {}", who);
+                        abandon(&services, who);
+                    },
+                    health::Action::Notify(msg) => { tell(&services, "web", msg)?; },
+                }
             }
         }
 